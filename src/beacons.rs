@@ -0,0 +1,196 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, with_deadline};
+use esp_radio::wifi::{PromiscuousPkt, Sniffer};
+use ieee80211::{
+    match_frames,
+    mgmt_frame::{BeaconFrame, DeauthenticationFrame, DisassociationFrame},
+};
+
+use crate::{
+    CANDIDATES, MAX_CANDIDATES, WifiConfig, knows_ssid, neighbors, rssi, scan_seq,
+    security::note_deauth,
+};
+
+// beacons come every ~100ms, the RSSI average gets one sample per WG per fold
+const FOLD_INTERVAL: Duration = Duration::from_secs(10);
+const SIGHTING_QUEUE_LEN: usize = 16;
+// 802.11 header and the beacon's fixed fields, the elements follow
+const BEACON_ELEMENTS_OFFSET: usize = 24 + 12;
+const QBSS_LOAD_ELEMENT_ID: u8 = 11;
+const MOBILITY_DOMAIN_ELEMENT_ID: u8 = 54;
+
+/// one beacon from a WG that might be a candidate
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BeaconSighting {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub signal_strength: i8,
+    // channel utilization from the QBSS load element, 255 = always busy
+    pub channel_load: Option<u8>,
+    // the MDID from the mobility domain element, the WG does 802.11r
+    pub mobility_domain: Option<u16>,
+}
+
+// filled by the sniffer callback, which can't wait for the candidate lock
+static SIGHTINGS: Channel<CriticalSectionRawMutex, BeaconSighting, SIGHTING_QUEUE_LEN> =
+    Channel::new();
+
+// the body of the beacon's first element with `element_id`
+fn beacon_element(frame: &[u8], element_id: u8) -> Option<&[u8]> {
+    let mut elements = frame.get(BEACON_ELEMENTS_OFFSET..)?;
+    while let [id, len, rest @ ..] = elements {
+        let body = rest.get(..*len as usize)?;
+        if *id == element_id {
+            return Some(body);
+        }
+        elements = &rest[*len as usize..];
+    }
+    None
+}
+
+// the channel utilization a QoS AP advertises in its beacons
+fn qbss_load(frame: &[u8]) -> Option<u8> {
+    // station count (2), channel utilization (1), admission capacity (2)
+    match beacon_element(frame, QBSS_LOAD_ELEMENT_ID)? {
+        [_, _, utilization, _, _] => Some(*utilization),
+        _ => None,
+    }
+}
+
+// the mobility domain an 802.11r AP advertises, MDID (2) and FT capabilities (1)
+fn mobility_domain(frame: &[u8]) -> Option<u16> {
+    match beacon_element(frame, MOBILITY_DOMAIN_ELEMENT_ID)? {
+        [lo, hi, _] => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => None,
+    }
+}
+
+// the WG we're associated with, deauths for it are counted
+static ASSOCIATED: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> =
+    Mutex::new(Cell::new(None));
+
+// counts deauths aimed at the associated WG
+fn check_deauth(bssid: [u8; 6], channel: u8) {
+    if ASSOCIATED.lock(|a| a.get()) == Some(bssid) {
+        note_deauth(bssid, channel);
+    }
+}
+
+// sniffer callback, keeps beacons carrying a known SSID, or none for hidden
+// WGs, deauth/disassoc frames and the associated WG's neighbor hints
+fn on_frame(packet: PromiscuousPkt<'_>) {
+    let channel = packet.rx_cntl.channel as u8;
+    neighbors::on_frame(packet.data, ASSOCIATED.lock(|a| a.get()));
+    let _ = match_frames! {
+        packet.data,
+        deauth = DeauthenticationFrame => {
+            check_deauth(deauth.header.bssid.0, channel);
+        }
+        disassoc = DisassociationFrame => {
+            check_deauth(disassoc.header.bssid.0, channel);
+        }
+        beacon = BeaconFrame => {
+            let ssid = beacon.ssid().unwrap_or_default();
+            if !ssid.is_empty() && !knows_ssid(ssid) {
+                return;
+            }
+            let bssid = beacon.header.bssid.0;
+            if ASSOCIATED.lock(|a| a.get()) == Some(bssid) {
+                rssi::note_beacon(bssid, packet.rx_cntl.rssi as i8);
+            }
+            // dropped while the queue is full, another beacon follows shortly
+            let _ = SIGHTINGS.try_send(BeaconSighting {
+                bssid,
+                channel,
+                signal_strength: packet.rx_cntl.rssi as i8,
+                channel_load: qbss_load(packet.data),
+                mobility_domain: mobility_domain(packet.data),
+            });
+        }
+    };
+}
+
+/// listen for beacons on the current channel, and for deauth storms against
+/// `associated`. Only while associated, it doesn't move the radio
+pub fn start(sniffer: &mut Sniffer<'static>, associated: Option<[u8; 6]>) {
+    ASSOCIATED.lock(|a| a.set(associated));
+    sniffer.set_receive_cb(on_frame);
+    if let Err(e) = sniffer.set_promiscuous_mode(true) {
+        info!("Failed to start beacon sniffer {:?}", e);
+    }
+}
+
+/// stop listening, before a scan or capture takes the radio
+pub fn stop(sniffer: &mut Sniffer<'static>) {
+    ASSOCIATED.lock(|a| a.set(None));
+    let _ = sniffer.set_promiscuous_mode(false);
+}
+
+/// folds sniffed beacons into CANDIDATES, so RSSI, channel load and
+/// last_seen_scan stay current between scans. The load is the channel's, so
+/// every WG on that channel gets it. Only refreshes WGs a scan already admitted, new
+/// BSSIDs still need a scan for their security and pinning checks
+#[embassy_executor::task]
+pub async fn beacon_task() -> ! {
+    info!("Start beacon task");
+    loop {
+        // newest sighting per WG this interval
+        let mut latest: heapless::Vec<BeaconSighting, MAX_CANDIDATES> = heapless::Vec::new();
+        let deadline = Instant::now() + FOLD_INTERVAL;
+        while let Ok(sighting) = with_deadline(deadline, SIGHTINGS.receive()).await {
+            match latest.iter_mut().find(|s| s.bssid == sighting.bssid) {
+                // a beacon without the element doesn't clear an earlier reading
+                Some(s) => {
+                    *s = BeaconSighting {
+                        channel_load: sighting.channel_load.or(s.channel_load),
+                        ..sighting
+                    }
+                }
+                None => {
+                    let _ = latest.push(sighting);
+                }
+            }
+        }
+        if latest.is_empty() {
+            continue;
+        }
+
+        let seq = scan_seq();
+        CANDIDATES
+            .modify(|candidates| fold_sightings(candidates, &latest, seq))
+            .await;
+    }
+}
+
+// the newest sighting of each WG into its candidate, the channel load into
+// every candidate on that channel
+fn fold_sightings(candidates: &mut [WifiConfig], latest: &[BeaconSighting], seq: u32) {
+    for sighting in latest {
+        let Some(wifi) = candidates.iter_mut().find(|w| w.bssid == sighting.bssid) else {
+            continue;
+        };
+        let previous = wifi.clone();
+        wifi.signal_strength = sighting.signal_strength;
+        wifi.channel = sighting.channel;
+        wifi.last_seen_scan = seq;
+        wifi.mobility_domain = sighting.mobility_domain;
+        wifi.update_rssi_ema(&previous);
+    }
+    for sighting in latest {
+        let Some(load) = sighting.channel_load else {
+            continue;
+        };
+        for wifi in candidates
+            .iter_mut()
+            .filter(|w| w.channel == sighting.channel)
+        {
+            wifi.channel_load = Some(load);
+        }
+    }
+}
@@ -6,29 +6,67 @@
     holding buffers for the duration of a data transfer."
 )]
 
-use core::cell::RefCell;
-use core::net::Ipv4Addr;
+#[cfg(not(any(feature = "defmt", feature = "log")))]
+compile_error!("the firmware logs through defmt or log, enable one of the two features");
 
-use alloc::vec::Vec;
+#[cfg(feature = "defmt")]
 use defmt::info;
 use embassy_executor::Spawner;
-use embassy_futures::select;
-use embassy_net::tcp::TcpSocket;
-use embassy_net::{Runner, StackResources};
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::mutex::Mutex;
-use embassy_sync::signal::Signal;
-use embassy_time::{Duration, Timer};
+use embassy_net::{Runner, Stack, StackResources};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Input;
+use esp_hal::interrupt::software::SoftwareInterruptControl;
+use esp_hal::peripherals::LPWR;
 use esp_hal::timer::timg::TimerGroup;
 use esp_hal::{clock::CpuClock, rng::Rng};
-use esp_radio::wifi::{ModeConfig, WifiController, WifiDevice, WifiEvent};
-use esp_radio::{
-    Controller,
-    wifi::{self, ClientConfig},
+use esp_radio::Controller;
+use esp_radio::wifi::{Sniffer, WifiController, WifiDevice};
+#[cfg(feature = "log")]
+use log::info;
+use wifi_scan_demo::beacons::beacon_task;
+use wifi_scan_demo::board::{ActiveBoard, Board};
+use wifi_scan_demo::bootguard::{check_boot_loop, reset_was_unexpected, stable_task};
+use wifi_scan_demo::button::{ButtonActions, button_task};
+use wifi_scan_demo::calendar::QuietHours;
+use wifi_scan_demo::chip;
+use wifi_scan_demo::console::console_task;
+use wifi_scan_demo::diagnostics::{AP_SOCKETS, ap_net_config, dhcp_server_task};
+use wifi_scan_demo::enterprise::set_enterprise_credential;
+use wifi_scan_demo::eventlog::set_previous_boot;
+use wifi_scan_demo::failover::{FailoverPolicy, failover_task};
+use wifi_scan_demo::health::{ProbeBuffers, run_health_checks};
+use wifi_scan_demo::http::http_task;
+#[cfg(feature = "indicator")]
+use wifi_scan_demo::indicator::{Indicator, indicator_task};
+use wifi_scan_demo::keepalive::keepalive_task;
+use wifi_scan_demo::mac::{MacPolicy, apply_mac_policy, draw_random_mac};
+use wifi_scan_demo::manager::{LIBRARY_SOCKETS, NetResources, WifiManagerConfig, wifi_mgr};
+use wifi_scan_demo::mdns::mdns_task;
+use wifi_scan_demo::memory::{memory_task, paint_main_stack};
+#[cfg(any(feature = "esp32", feature = "esp32s3"))]
+use wifi_scan_demo::multicore::start_network_core;
+use wifi_scan_demo::netconfig::{
+    NetworkConfigs, dhcp_config, lease_task, remember_lease, set_lease_cache, set_network_configs,
 };
-use wifi_scan_demo::persistence::{LOAD_WIFI, STORE_WIFI, persistence};
+use wifi_scan_demo::ota::{ota_task, rollback_task};
+use wifi_scan_demo::panic::set_last_panic;
+use wifi_scan_demo::persistence::{
+    FACTORY_RESET, LOAD_ENTERPRISE, LOAD_EVENTLOG, LOAD_LEASES, LOAD_NETWORK_CONFIGS, LOAD_PANIC,
+    LOAD_PROVISIONED, LOAD_SETTINGS, LOAD_STATS, LOAD_WIFI, persistence,
+};
+use wifi_scan_demo::realtime::{jitter_task, start_realtime_executor};
+use wifi_scan_demo::roaming::{active_preset, set_active_preset};
+use wifi_scan_demo::rssi::signal_task;
+use wifi_scan_demo::scanner::best_connection_task;
+use wifi_scan_demo::sleep::{sleep_task, take_wake_cache};
+use wifi_scan_demo::sntp::sntp_task;
+use wifi_scan_demo::stats::record_boot;
+use wifi_scan_demo::timeseries::{SeriesUpload, sample_task, upload_task};
+use wifi_scan_demo::txpower::{TxPowerProfile, set_active_tx_power};
+use wifi_scan_demo::watchdog::watchdog_task;
 use wifi_scan_demo::{
-    KNOWN_CREDS, WifiConfig, get_client_config_from_candidate, scan_and_score_wgs,
+    Band, CANDIDATES, ScanOptions, WifiConfig, set_fast_transition, set_latency_scoring,
+    set_preferred_band, set_provisioned_credentials,
 };
 use {esp_backtrace as _, esp_println as _};
 
@@ -48,319 +86,355 @@ macro_rules! mk_static {
     }};
 }
 
-/// used by the main loop to notify the connection state machine if this WG connected
-/// true when connected
-/// false when not connected
-pub static WG_CONNECT_STATUS: Signal<CriticalSectionRawMutex, bool> = Signal::new();
-pub static SCAN_CMD: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-pub static SCAN_COMPLETE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-
-pub static DISCONNECT_DETECTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-
-pub static CANDIDATES: Mutex<CriticalSectionRawMutex, RefCell<Vec<WifiConfig>>> =
-    Mutex::new(RefCell::new(Vec::new()));
+// when to hand over to (and back from) a backup uplink
+const FAILOVER_POLICY: FailoverPolicy = FailoverPolicy::new()
+    .with_offline_after(Duration::from_secs(5 * 60))
+    .with_recover_after(Duration::from_secs(60));
+
+// scans passive by default, switch to ScanMode::Active where probing is allowed and
+// speed matters. The internet probe's PROBE_HOST/PROBE_PATH come from .cargo/config.toml.
+// .with_diagnostics_ap(DiagnosticsAp::new()) keeps a SoftAP with the status pages up
+// for technicians, its passphrase is DIAG_AP_PASSWORD
+const WIFI_MANAGER_CONFIG: WifiManagerConfig = WifiManagerConfig::new()
+    .with_scan(ScanOptions::new())
+    .with_quiet_hours(QUIET_HOURS)
+    .with_scan_count(10)
+    .with_retry_delay(Duration::from_secs(3))
+    .with_socket_timeout(Duration::from_secs(10));
+
+// no scans or roaming while connected in these windows, e.g.
+// QuietHours::new().with_window(QuietWindow::new(8, 0, 18, 0)) keeps roaming to the night
+const QUIET_HOURS: QuietHours = QuietHours::new();
+
+// favoured by the ranking, Some(Band::Ghz5) on dual-band chips. The ESP32 only does 2.4 GHz
+const PREFERRED_BAND: Option<Band> = None;
+
+// status LED patterns with the indicator feature, e.g.
+// Indicator::new().with_online(LedPattern::Blink(Duration::from_secs(2)))
+#[cfg(feature = "indicator")]
+const INDICATOR: Indicator = Indicator::new();
+
+// rank WGs lower for slow associations and DHCP, true where some gateways are
+// known to take long to hand out a lease
+const LATENCY_SCORING: bool = false;
+
+// rank WGs sharing our WG's 802.11r mobility domain higher. Off: esp-radio
+// doesn't expose the driver's FT switch yet, so such roams still take the full
+// handshake
+const FAST_TRANSITION: bool = false;
+
+// the STA MAC, MacPolicy::RandomPerBoot for products that mustn't be
+// trackable or MacPolicy::Fixed to take over a replaced unit's address
+const MAC_POLICY: MacPolicy = MacPolicy::Factory;
+
+// the stack's socket slots, add the application's own sockets to the library's
+const SOCKETS: usize = LIBRARY_SOCKETS;
+
+// log the worst wakeup latency of a task on the realtime executor, during
+// scans and outside them
+const JITTER_PROBE: bool = false;
+
+// how long the reset button must stay down at boot
+const RESET_HOLD: Duration = Duration::from_secs(3);
+
+// after boot the button rescans on a short press, forgets the current AP
+// when held for `long` and wipes the flash when held for `very_long`
+const BUTTON_ACTIONS: ButtonActions = ButtonActions::new();
 
 #[esp_rtos::main]
 async fn main(spawner: Spawner) -> ! {
+    // before anything goes deep, /status shows how much of it gets used
+    paint_main_stack();
     // generator version: 0.6.0
+    #[cfg(feature = "log")]
+    esp_println::logger::init_logger_from_env();
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
-    let peripherals = esp_hal::init(config);
-
-    esp_alloc::heap_allocator!(#[unsafe(link_section = ".dram2_uninit")] size: 98767);
-
-    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let mut peripherals = esp_hal::init(config);
+    // while ADC1 is free to feed the TRNG, rev B and C boards take it for the
+    // battery gauge
+    let random_mac = draw_random_mac(peripherals.RNG.reborrow(), peripherals.ADC1.reborrow());
+    // before split, boards with a battery gauge box it
+    esp_alloc::heap_allocator!(#[unsafe(link_section = ".dram2_uninit")] size: chip::HEAP_SIZE);
+    let board = ActiveBoard::split(peripherals);
+    info!("Board {} on {}", ActiveBoard::NAME, chip::NAME);
+    set_preferred_band(PREFERRED_BAND);
+    set_latency_scoring(LATENCY_SCORING);
+    set_fast_transition(FAST_TRANSITION);
+
+    let timg0 = TimerGroup::new(board.timg0);
+    let sw_ints = SoftwareInterruptControl::new(board.sw_interrupt);
+    #[cfg(not(any(feature = "esp32c3", feature = "esp32c6")))]
     esp_rtos::start(timg0.timer0);
+    #[cfg(any(feature = "esp32c3", feature = "esp32c6"))]
+    esp_rtos::start(timg0.timer0, sw_ints.software_interrupt0);
 
     info!("Embassy initialized!");
 
+    // waking from deep sleep, the WG we were on is in RTC memory
+    let wake = take_wake_cache();
+    // the driver takes the MAC once, at init
+    apply_mac_policy(MAC_POLICY, wake.as_ref().map(|w| w.mac), random_mac);
+
     let radio_init = &*mk_static!(
         Controller<'static>,
         esp_radio::init().expect("Failed to initialize Wi-Fi/BLE controller")
     );
 
-    let (mut _wifi_controller, _interfaces) =
-        esp_radio::wifi::new(&radio_init, peripherals.WIFI, Default::default())
+    let (wifi_controller, interfaces) =
+        esp_radio::wifi::new(&radio_init, board.wifi, Default::default())
             .expect("Failed to initialize Wi-Fi controller");
 
-    let wifi_interface = _interfaces.sta;
-
-    let config = embassy_net::Config::dhcpv4(Default::default());
-
-    let rng = Rng::new();
-
-    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    // holding the button through the first seconds after power-up wipes the
+    // persisted state, persistence checks for it before loading
+    if let Some(button) = &board.button {
+        if reset_button_held(button).await {
+            FACTORY_RESET.signal(());
+        }
+    }
 
-    let (stack, runner) = embassy_net::new(
-        wifi_interface,
-        config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
-        seed,
-    );
+    // read before anything can reset again
+    let unexpected = reset_was_unexpected();
 
+    // persistence opens the sealed passphrases with it
+    #[cfg(not(feature = "esp32"))]
+    wifi_scan_demo::secret::derive_storage_key(board.hmac);
+    // nothing to seal them with, they rely on flash encryption
+    #[cfg(feature = "esp32")]
+    wifi_scan_demo::secret::check_flash_encryption();
     // spawn other threads
-    spawner.spawn(persistence(peripherals.FLASH)).ok();
-
-    let persisted_config = LOAD_WIFI.wait().await;
-    spawner
-        .spawn(wifi_mgr(_wifi_controller, persisted_config.clone()))
-        .ok();
-    spawner.spawn(best_connection_task(persisted_config)).ok();
-
-    spawner.spawn(net_task(runner)).ok();
-    // spawner.spawn(very_busy_loop()).ok();
-
-    // todo: consider moving into separate task
-    let mut rx_buffer = [0; 1024];
-    let mut tx_buffer = [0; 1024];
-
-    // the main loop is as follows
-    // wait for link up
-    //  when up, wait for dhcp assignment
-    //   when assigned,
-    loop {
-        if !stack.is_link_up() {
-            // wait for link up
-            Timer::after(Duration::from_millis(500)).await;
+    spawner.spawn(persistence(board.flash, wake.is_none())).ok();
+
+    let loaded_config = LOAD_WIFI.wait().await;
+    // crashing over and over, stick to the first known credential this boot
+    let safe = check_boot_loop(record_boot(LOAD_STATS.wait().await, unexpected));
+    let wake = wake.filter(|_| !safe);
+    let persisted_config = match safe {
+        true => None,
+        false => wake.as_ref().map(|w| w.wifi.clone()).or(loaded_config),
+    };
+    set_lease_cache(LOAD_LEASES.wait().await.unwrap_or_default());
+    if let Some(wake) = wake.as_ref() {
+        // the first round connects to it straight away, no scan
+        CANDIDATES.insert(wake.wifi.clone()).await;
+        if let Some(lease) = wake.lease {
+            remember_lease(wake.wifi.bssid, lease);
         }
-        // link is up
-
-        'link_loop: loop {
-            if let Some(config) = stack.config_v4() {
-                info!("Got IP: {:#}", config.address);
-
-                'socket_loop: loop {
-                    Timer::after(Duration::from_secs(1)).await;
-                    info!("Hello world!");
-                    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
-
-                    socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
-
-                    // 1.1.1.1:80, if we can connect, we're good
-                    let remote_endpoint = (Ipv4Addr::new(1, 1, 1, 1), 80);
-
-                    info!("Connecting...");
-
-                    let r = socket.connect(remote_endpoint).await;
-
-                    if let Err(e) = r {
-                        info!("connect error: {:?}", e);
-                        WG_CONNECT_STATUS.signal(false);
-                        break 'link_loop;
-                    } else {
-                        info!("Socket connected");
-                        WG_CONNECT_STATUS.signal(true);
-                    }
-                    Timer::after(Duration::from_millis(3000)).await;
-                }
-            } else {
-                info!("Waiting to get ip addr");
-
-                Timer::after(Duration::from_millis(5000)).await;
-            }
+    }
+    // first boot takes the TX power from the environment
+    match LOAD_SETTINGS.wait().await {
+        Some(settings) => {
+            set_active_preset(settings.preset);
+            set_active_tx_power(settings.tx_power);
         }
-
-        Timer::after(Duration::from_millis(500)).await;
+        None => set_active_tx_power(TxPowerProfile::from_env()),
     }
+    info!("Roaming preset {:?}", active_preset());
+    // first boot takes the static IP settings from the environment
+    set_network_configs(
+        LOAD_NETWORK_CONFIGS
+            .wait()
+            .await
+            .unwrap_or_else(NetworkConfigs::from_env),
+    );
+    // a factory image's networks replace the build's
+    let provisioned = LOAD_PROVISIONED.wait().await;
+    if !provisioned.is_empty() {
+        set_provisioned_credentials(provisioned);
+    }
+    if let Some(enterprise) = LOAD_ENTERPRISE.wait().await {
+        set_enterprise_credential(enterprise);
+    }
+    if let Some(events) = LOAD_EVENTLOG.wait().await {
+        set_previous_boot(events);
+    }
+    if let Some(panic) = LOAD_PANIC.wait().await {
+        set_last_panic(panic);
+    }
+    #[cfg(feature = "tls")]
+    wifi_scan_demo::tls::set_tls_roots(wifi_scan_demo::persistence::LOAD_TLS_ROOTS.wait().await);
 
-    info!("Something went terribly wrong");
-}
-
-// actively searches for the best connection
-#[embassy_executor::task]
-async fn best_connection_task(persisted_config: Option<WifiConfig>) -> ! {
-    // persistence will load the previous connection from flash, if any
-
-    let mut local_persisted = persisted_config.clone();
-    // on first boot, scan nearby wifis
-    SCAN_CMD.signal(());
+    let rng = Rng::new();
+    let network = NetworkParts {
+        controller: wifi_controller,
+        sta: interfaces.sta,
+        ap: interfaces.ap,
+        sniffer: interfaces.sniffer,
+        #[cfg(feature = "espnow")]
+        esp_now: interfaces.esp_now,
+        lpwr: board.lpwr,
+        persisted_config,
+        scan_at_boot: wake.is_none(),
+        safe,
+        seeds: [random_seed(&rng), random_seed(&rng)],
+    };
+    // the second core takes the network side where there is one, so scans
+    // and sniffed frames can't hold up the application on this core
+    #[cfg(any(feature = "esp32", feature = "esp32s3"))]
+    start_network_core(
+        board.cpu_ctrl,
+        sw_ints.software_interrupt0,
+        sw_ints.software_interrupt1,
+        move |spawner| spawn_network(spawner, network),
+    );
+    #[cfg(any(feature = "esp32c3", feature = "esp32c6"))]
+    spawn_network(spawner, network);
+
+    spawner.spawn(stable_task()).ok();
+    spawner.spawn(memory_task()).ok();
+    spawner.spawn(console_task(board.console)).ok();
+    if let Some(button) = board.button {
+        spawner.spawn(button_task(button, BUTTON_ACTIONS)).ok();
+    }
+    #[cfg(feature = "indicator")]
+    if let Some(led) = board.status_led {
+        spawner.spawn(indicator_task(led, INDICATOR)).ok();
+    }
+    #[cfg(feature = "rpc")]
+    spawner
+        .spawn(wifi_scan_demo::rpc::rpc_task(board.usb_serial))
+        .ok();
+    spawner.spawn(watchdog_task(timg0.wdt)).ok();
+    spawner.spawn(rollback_task()).ok();
+    // products with a backup modem pass its enable pin here, handed out by
+    // their Board::split like the other pins
+    spawner.spawn(failover_task(None, FAILOVER_POLICY)).ok();
+
+    // latency-critical application tasks go on this one, it preempts the
+    // thread executor on this core
+    let realtime = start_realtime_executor(sw_ints.software_interrupt2);
+    if JITTER_PROBE {
+        realtime.spawn(jitter_task()).ok();
+    }
 
-    let mut new_best_found = false;
+    // the application's own work goes here, on core 0. It reaches the network
+    // side through WifiManager, CONNECTION_STATE and the library's other statics
     loop {
-        if SCAN_COMPLETE.signaled() {
-            SCAN_COMPLETE.wait().await;
-            let candidates = CANDIDATES.lock().await;
-            let candidate_ref = candidates.borrow();
-            let best_candidate = candidate_ref.first();
-            info!("Scan complete, best = {}", best_candidate);
-            match (best_candidate, &local_persisted) {
-                (None, None) => {
-                    // no candidates and no persisted
-                }
-                (None, Some(x)) => {
-                    // no candidates, persisted still better
-                }
-                (Some(c), None) => {
-                    // a new winner emerges
-                    STORE_WIFI.signal(c.clone());
-                    local_persisted = Some(c.clone());
-                    new_best_found = true;
-                }
-                (Some(c), Some(p)) => {
-                    if c == p {
-                        // same as persisted,
-                        new_best_found = true;
-                    }
-                    if c > p {
-                        STORE_WIFI.signal(c.clone());
-                        local_persisted = Some(c.clone());
-                        new_best_found = true;
-                    }
-                }
-            }
-        }
-
-        {
-            match esp_radio::wifi::sta_state() {
-                wifi::WifiStaState::Connected => {
-                    // scan once an hour if we haven't found a new best
-                    if !new_best_found {
-                        match select::select(
-                            Timer::after(Duration::from_secs(60 * 60)),
-                            DISCONNECT_DETECTED.wait(),
-                        )
-                        .await
-                        {
-                            select::Either::First(_) => SCAN_CMD.signal(()),
-                            select::Either::Second(_) => {} // break,
-                        }
-                    }
-                }
-                wifi::WifiStaState::Disconnected => {
-                    // scan once every 5 minutes if we are currently chronically disconnected
-                    Timer::after(Duration::from_secs(5 * 60)).await;
-                    SCAN_CMD.signal(());
-                }
-                _ => {}
-            }
-        }
-        Timer::after(Duration::from_secs(10)).await
+        Timer::after(Duration::from_secs(60 * 60)).await;
     }
 }
 
-#[embassy_executor::task]
-async fn wifi_mgr(
-    mut controller: WifiController<'static>,
+// the radio and what the network side needs to start, handed to core 1 on
+// dual-core chips
+struct NetworkParts {
+    controller: WifiController<'static>,
+    sta: WifiDevice<'static>,
+    ap: WifiDevice<'static>,
+    sniffer: Sniffer<'static>,
+    #[cfg(feature = "espnow")]
+    esp_now: esp_radio::esp_now::EspNow<'static>,
+    lpwr: LPWR<'static>,
     persisted_config: Option<WifiConfig>,
-) -> ! {
-    info!("Start wifi mgr task");
-    info!("Device Capabilities: {:?}", controller.capabilities());
-
-    let default_config = if let Some(persist) = persisted_config {
-        get_client_config_from_candidate(&persist)
-    } else {
-        ClientConfig::default()
-            .with_ssid(KNOWN_CREDS.0.ssid.into())
-            .with_password(KNOWN_CREDS.0.password.into())
-    };
-
-    let client_config = ModeConfig::Client(default_config.clone());
-
-    controller.set_config(&client_config).unwrap();
-
-    info!("Starting wifi");
-    controller.start_async().await.unwrap();
-    info!("Started wifi");
+    scan_at_boot: bool,
+    safe: bool,
+    // for the STA and diagnostics AP stacks
+    seeds: [u64; 2],
+}
 
-    loop {
-        match esp_radio::wifi::sta_state() {
-            wifi::WifiStaState::Connected => {
-                run_connected(&mut controller).await;
-            }
+// creates the network stacks and spawns everything that uses the radio or a
+// socket onto `spawner`'s executor
+fn spawn_network(spawner: Spawner, parts: NetworkParts) {
+    let config = embassy_net::Config::dhcpv4(dhcp_config());
+    let NetResources {
+        stack: stack_resources,
+        probe: probe_buffers,
+    } = mk_static!(NetResources<SOCKETS>, NetResources::new());
+    let (stack, runner) = embassy_net::new(parts.sta, config, stack_resources, parts.seeds[0]);
 
-            _ => run_disconnected(&mut controller).await,
-        }
-        Timer::after(Duration::from_millis(3000)).await
+    spawner
+        .spawn(wifi_mgr(
+            parts.controller,
+            parts.sniffer,
+            stack,
+            parts.persisted_config.clone(),
+            WIFI_MANAGER_CONFIG,
+        ))
+        .ok();
+    // safe mode doesn't scan, score or roam
+    if !parts.safe {
+        spawner
+            .spawn(best_connection_task(
+                parts.persisted_config,
+                parts.scan_at_boot,
+                WIFI_MANAGER_CONFIG,
+            ))
+            .ok();
+        spawner.spawn(beacon_task()).ok();
+        spawner.spawn(signal_task(WIFI_MANAGER_CONFIG.signal)).ok();
     }
-}
 
-async fn run_disconnected(controller: &mut WifiController<'static>) {
-    // we're currently disconnected
-    if SCAN_CMD.signaled() {
-        // clear signal
-        SCAN_CMD.wait().await;
-        do_scan(controller).await
-    }
-    info!("Currently disconnected");
-    // pick best next candidate
-    let candidates = CANDIDATES.lock().await;
-    let mut candidates_mut = candidates.borrow_mut();
-    if let Some(best) = candidates_mut.first() {
-        controller
-            .set_config(&ModeConfig::Client(get_client_config_from_candidate(best)))
-            .unwrap();
-        info!("Attempting to connect to {}", best);
+    spawner.spawn(net_task(runner)).ok();
+    spawner.spawn(health_task(stack, probe_buffers)).ok();
+    spawner.spawn(lease_task(stack)).ok();
+    if let Some(keepalive) = WIFI_MANAGER_CONFIG.keepalive {
+        spawner.spawn(keepalive_task(stack, keepalive)).ok();
     }
-    match controller.connect_async().await {
-        Ok(_) => {
-            if let Some(best) = candidates_mut.first_mut() {
-                best.connect_success = Some(true);
-            }
-            info!("Wifi Connected!");
-        }
-        Err(err) => {
-            if let Some(best) = candidates_mut.first_mut() {
-                best.connect_success = Some(false);
-            }
-            info!("Failed to connect to wifi {:?}", err);
+    if let Some(series) = WIFI_MANAGER_CONFIG.time_series {
+        spawner.spawn(sample_task(series)).ok();
+        if series.upload == SeriesUpload::Http {
+            spawner.spawn(upload_task(stack, series)).ok();
         }
     }
-}
-
-async fn run_connected(controller: &mut WifiController<'static>) {
-    info!("Connected, waiting for disconnect or scan");
-    let disconnect_evt = controller.wait_for_event(WifiEvent::StaDisconnected);
-
-    let scan_event = SCAN_CMD.wait();
-
-    match select::select(disconnect_evt, scan_event).await {
-        select::Either::First(_) => {
-            // we're disconnected, pick the next gateway
-            let candidates = CANDIDATES.lock().await;
-            let mut candidates_mut = candidates.borrow_mut();
-            // update the old best, noting the disconnect
-            if let Some(old_best) = candidates_mut.first_mut() {
-                old_best.connect_success = Some(false);
-            }
-            // re-sort the candidates
-            candidates_mut.sort_by(|x, y| x.cmp(y).reverse());
-            DISCONNECT_DETECTED.signal(());
-            // new best
-        }
-        select::Either::Second(_) => {
-            do_scan(controller).await;
+    spawner.spawn(ota_task(stack)).ok();
+    spawner.spawn(sleep_task(stack, parts.lpwr)).ok();
+    spawner.spawn(sntp_task(stack)).ok();
+    spawner.spawn(http_task(stack, stack, false)).ok();
+    match WIFI_MANAGER_CONFIG.diagnostics_ap {
+        Some(ap) if ap.access_point_config().is_some() => {
+            let (ap_stack, ap_runner) = embassy_net::new(
+                parts.ap,
+                ap_net_config(),
+                mk_static!(StackResources<AP_SOCKETS>, StackResources::new()),
+                parts.seeds[1],
+            );
+            info!("Diagnostics AP {} up", ap.ssid);
+            spawner.spawn(net_task(ap_runner)).ok();
+            spawner.spawn(dhcp_server_task(ap_stack)).ok();
+            spawner.spawn(http_task(ap_stack, stack, true)).ok();
         }
+        Some(_) => info!("Diagnostics AP needs a passphrase of 8 characters or more, it stays off"),
+        None => {}
     }
+    spawner.spawn(mdns_task(stack)).ok();
+    #[cfg(feature = "mqtt")]
+    spawner.spawn(wifi_scan_demo::mqtt::mqtt_task(stack)).ok();
+    #[cfg(feature = "espnow")]
+    spawner
+        .spawn(wifi_scan_demo::peers::peer_hints_task(parts.esp_now))
+        .ok();
+    #[cfg(feature = "netlog")]
+    spawner
+        .spawn(wifi_scan_demo::netlog::netlog_task(stack))
+        .ok();
 }
 
-async fn do_scan(controller: &mut WifiController<'static>) {
-    let mut wg = scan_and_score_wgs(controller).await;
-    let candidates = CANDIDATES.lock().await;
-    let mut candidates_mut = candidates.borrow_mut();
+fn random_seed(rng: &Rng) -> u64 {
+    (rng.random() as u64) << 32 | rng.random() as u64
+}
 
-    for w in &mut wg {
-        match candidates_mut.binary_search_by_key(&w.bssid, |w| w.bssid) {
-            Ok(x) => w.connect_success = candidates_mut[x].connect_success,
-            Err(_) => {}
+// true if the button is down now and stays down for RESET_HOLD
+async fn reset_button_held(button: &Input<'_>) -> bool {
+    let pressed_at = Instant::now();
+    while pressed_at.elapsed() < RESET_HOLD {
+        if button.is_high() {
+            return false;
         }
+        Timer::after(Duration::from_millis(50)).await;
     }
-    // replace candidates
-    wg.sort_by(|x, y| x.cmp(y).reverse());
-    *candidates_mut = wg;
-
-    SCAN_COMPLETE.signal(());
+    info!("Reset button held for {}s", RESET_HOLD.as_secs());
+    true
 }
 
-/// this can be enabled to show that our very busy loop can still run at a decent rate
+// validates internet connectivity through the STA, see run_health_checks
 #[embassy_executor::task]
-async fn very_busy_loop() {
-    loop {
-        info!("-");
-        Timer::after(Duration::from_millis(20)).await
-    }
+async fn health_task(stack: Stack<'static>, probe_buffers: &'static mut ProbeBuffers) -> ! {
+    run_health_checks(stack, &WIFI_MANAGER_CONFIG.health, probe_buffers).await
 }
 
-#[embassy_executor::task]
+// one for the STA and one for the diagnostics AP
+#[embassy_executor::task(pool_size = 2)]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
+    // never waits on anything a check-in could tell apart from a hang, a
+    // stuck stack freezes the executor and the hardware watchdog resets us
     runner.run().await
 }
@@ -0,0 +1,154 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{Security, WifiConfig};
+
+/// WGs that can be blacklisted at once, the one closest to parole makes room
+pub const MAX_BLACKLISTED: usize = 8;
+// the first auth failure costs this long, each repeat doubles it
+const BASE_COOL_OFF: Duration = Duration::from_secs(30);
+const MAX_COOL_OFF: Duration = Duration::from_secs(10 * 60);
+// a WG the user told us to forget stays out of the ranking this long
+const FORGET_FOR: Duration = Duration::from_secs(60 * 60);
+// strikes are forgotten this long after the cool-off ends
+const STRIKE_MEMORY: Duration = Duration::from_secs(30 * 60);
+
+/// a WG that rejected our credentials
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlacklistEntry {
+    pub bssid: [u8; 6],
+    pub until: Instant,
+    // auth failures in a row, each doubles the cool-off
+    pub strikes: u8,
+    // what the WG looked like when it rejected us
+    pub channel: u8,
+    pub security: Security,
+}
+
+impl BlacklistEntry {
+    fn forgotten(&self, now: Instant) -> bool {
+        now >= self.until + STRIKE_MEMORY
+    }
+}
+
+/// WGs skipped by the candidate picker until their cool-off ends
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Blacklist {
+    pub entries: heapless::Vec<BlacklistEntry, MAX_BLACKLISTED>,
+}
+
+impl Blacklist {
+    pub const fn new() -> Self {
+        return Self {
+            entries: heapless::Vec::new(),
+        };
+    }
+
+    /// skip `wifi` for a while, longer each time it happens again. Returns
+    /// the cool-off
+    pub fn add(&mut self, wifi: &WifiConfig) -> Duration {
+        let now = Instant::now();
+        self.entries.retain(|e| !e.forgotten(now));
+        let strikes = match self.entries.iter().position(|e| e.bssid == wifi.bssid) {
+            Some(index) => self.entries.swap_remove(index).strikes.saturating_add(1),
+            None => 1,
+        };
+        let cool_off = Duration::from_secs(
+            (BASE_COOL_OFF.as_secs() << (strikes - 1).min(8)).min(MAX_COOL_OFF.as_secs()),
+        );
+        self.insert(wifi, strikes, now + cool_off);
+        cool_off
+    }
+
+    /// skip `wifi` for `hold_off` without counting a strike
+    pub fn hold_off(&mut self, wifi: &WifiConfig, hold_off: Duration) {
+        let now = Instant::now();
+        self.entries.retain(|e| !e.forgotten(now));
+        let strikes = match self.entries.iter().position(|e| e.bssid == wifi.bssid) {
+            Some(index) => self.entries.swap_remove(index).strikes,
+            None => 0,
+        };
+        self.insert(wifi, strikes, now + hold_off);
+    }
+
+    fn insert(&mut self, wifi: &WifiConfig, strikes: u8, until: Instant) {
+        if self.entries.is_full() {
+            let soonest = (0..self.entries.len())
+                .min_by_key(|&i| self.entries[i].until)
+                .unwrap();
+            self.entries.swap_remove(soonest);
+        }
+        let _ = self.entries.push(BlacklistEntry {
+            bssid: wifi.bssid,
+            until,
+            strikes,
+            channel: wifi.channel,
+            security: wifi.security,
+        });
+    }
+
+    pub fn contains(&self, bssid: &[u8; 6]) -> bool {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .any(|e| e.bssid == *bssid && now < e.until)
+    }
+
+    /// a scan found `wifi` on another channel or with other security, it was
+    /// likely reconfigured, so give it another chance. True if it was listed
+    pub fn clear_if_changed(&mut self, wifi: &WifiConfig) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| {
+            e.bssid != wifi.bssid || (e.channel == wifi.channel && e.security == wifi.security)
+        });
+        self.entries.len() != before
+    }
+
+    /// it let us in, forget its strikes
+    pub fn remove(&mut self, bssid: &[u8; 6]) {
+        self.entries.retain(|e| e.bssid != *bssid);
+    }
+}
+
+static BLACKLIST: Mutex<CriticalSectionRawMutex, RefCell<Blacklist>> =
+    Mutex::new(RefCell::new(Blacklist::new()));
+
+/// an auth failure on `wifi`, the candidate picker skips it for a while
+pub fn blacklist(wifi: &WifiConfig) {
+    let cool_off = BLACKLIST.lock(|b| b.borrow_mut().add(wifi));
+    info!(
+        "Blacklisting {:?} for {}s after an auth failure",
+        wifi.bssid,
+        cool_off.as_secs()
+    );
+}
+
+/// the user asked to forget `wifi`, e.g. with the button, the candidate
+/// picker skips it for FORGET_FOR
+pub fn forget(wifi: &WifiConfig) {
+    BLACKLIST.lock(|b| b.borrow_mut().hold_off(wifi, FORGET_FOR));
+    info!("Forgetting {:?} for {}s", wifi.bssid, FORGET_FOR.as_secs());
+}
+
+pub fn is_blacklisted(bssid: &[u8; 6]) -> bool {
+    BLACKLIST.lock(|b| b.borrow().contains(bssid))
+}
+
+/// called with every scan result
+pub fn clear_if_changed(wifi: &WifiConfig) {
+    if BLACKLIST.lock(|b| b.borrow_mut().clear_if_changed(wifi)) {
+        info!(
+            "{:?} changed since it rejected us, off the blacklist",
+            wifi.bssid
+        );
+    }
+}
+
+/// called after a successful connect
+pub fn clear_blacklisted(bssid: &[u8; 6]) {
+    BLACKLIST.lock(|b| b.borrow_mut().remove(bssid));
+}
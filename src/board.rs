@@ -0,0 +1,221 @@
+use alloc::boxed::Box;
+
+use esp_hal::{
+    Async, Blocking,
+    analog::adc::{Adc, AdcChannel, AdcConfig, AdcPin, Attenuation},
+    gpio::{
+        AnalogPin, Input, InputConfig, InputPin, Level, Output, OutputConfig, OutputPin, Pull,
+        interconnect::{PeripheralInput, PeripheralOutput},
+    },
+    peripherals::{ADC1, FLASH, LPWR, Peripherals, TIMG0, UART1, WIFI},
+    uart::{self, Uart},
+};
+
+#[cfg(any(feature = "esp32", feature = "esp32s3"))]
+use esp_hal::peripherals::CPU_CTRL;
+#[cfg(not(feature = "esp32"))]
+use esp_hal::peripherals::HMAC;
+use esp_hal::peripherals::SW_INTERRUPT;
+#[cfg(feature = "rpc")]
+use esp_hal::usb_serial_jtag::UsbSerialJtag;
+
+#[cfg(all(
+    any(feature = "board-rev-b", feature = "board-rev-c"),
+    not(feature = "esp32")
+))]
+compile_error!("revisions B and C carry an ESP32, build them with the esp32 feature");
+#[cfg(all(feature = "rpc", feature = "esp32"))]
+compile_error!("the ESP32 has no USB serial-JTAG port for rpc, use a C3, S3 or C6");
+
+/// the peripherals main needs, with the board specific pins already set up.
+/// A None means the revision doesn't have that part
+pub struct BoardParts {
+    pub timg0: TIMG0<'static>,
+    // esp-rtos needs one on RISC-V chips, two to start the second core on the others
+    pub sw_interrupt: SW_INTERRUPT<'static>,
+    // starts core 1, see src/multicore.rs
+    #[cfg(any(feature = "esp32", feature = "esp32s3"))]
+    pub cpu_ctrl: CPU_CTRL<'static>,
+    pub wifi: WIFI<'static>,
+    pub flash: FLASH<'static>,
+    // derives the passphrase storage key, see src/secret.rs
+    #[cfg(not(feature = "esp32"))]
+    pub hmac: HMAC<'static>,
+    // the RTC, for deep sleep
+    pub lpwr: LPWR<'static>,
+    // high = on
+    pub status_led: Option<Output<'static>>,
+    // provisioning button, low while pressed
+    pub button: Option<Input<'static>>,
+    // low = PCB antenna, high = external connector
+    pub antenna_switch: Option<Output<'static>>,
+    pub battery: Option<Box<dyn BatterySense>>,
+    // UART1 on pins of its own, for provisioning (src/console.rs). UART0 carries
+    // the bootloader's and esp-println's logs
+    pub console: Uart<'static, Async>,
+    // the USB serial-JTAG port, the host protocol's (src/rpc.rs)
+    #[cfg(feature = "rpc")]
+    pub usb_serial: UsbSerialJtag<'static, Async>,
+}
+
+/// a hardware revision, pick one with the `board-*` features
+pub trait Board {
+    const NAME: &'static str;
+    /// hand out the pins this revision wires up
+    fn split(p: Peripherals) -> BoardParts;
+}
+
+/// reads the battery voltage
+pub trait BatterySense {
+    fn read_mv(&mut self) -> u32;
+}
+
+/// a battery on an ADC1 pin behind a resistor divider
+pub struct AdcBattery<PIN: AdcChannel + 'static> {
+    adc: Adc<'static, ADC1<'static>, Blocking>,
+    pin: AdcPin<PIN, ADC1<'static>>,
+    // battery voltage / pin voltage
+    divider: u32,
+}
+
+impl<PIN: AdcChannel + AnalogPin + 'static> AdcBattery<PIN> {
+    pub fn new(adc1: ADC1<'static>, pin: PIN, divider: u32) -> Self {
+        let mut config = AdcConfig::new();
+        let pin = config.enable_pin(pin, Attenuation::_11dB);
+        Self {
+            adc: Adc::new(adc1, config),
+            pin,
+            divider,
+        }
+    }
+}
+
+impl<PIN: AdcChannel + 'static> BatterySense for AdcBattery<PIN> {
+    fn read_mv(&mut self) -> u32 {
+        // uncalibrated, 11dB attenuation reads roughly 0..3100mV over 12 bits
+        let raw = self.adc.read_blocking(&mut self.pin) as u32;
+        raw * 3100 / 4095 * self.divider
+    }
+}
+
+fn button(pin: impl InputPin + 'static) -> Input<'static> {
+    Input::new(pin, InputConfig::default().with_pull(Pull::Up))
+}
+
+// 115200 8N1 like the ROM's console, on UART1 so no log line lands in a reply
+fn console(
+    uart1: UART1<'static>,
+    tx: impl PeripheralOutput<'static>,
+    rx: impl PeripheralInput<'static>,
+) -> Uart<'static, Async> {
+    Uart::new(uart1, uart::Config::default())
+        .expect("console UART config")
+        .with_tx(tx)
+        .with_rx(rx)
+        .into_async()
+}
+
+// only the ESP32 boards have a plain LED or an RF switch
+#[cfg_attr(not(feature = "esp32"), allow(dead_code))]
+fn output(pin: impl OutputPin + 'static) -> Output<'static> {
+    Output::new(pin, Level::Low, OutputConfig::default())
+}
+
+/// the chip's own devkit. ESP32 DevKitC: LED on GPIO2, BOOT button on GPIO0.
+/// The C3, S3 and C6 devkits have an addressable RGB LED a plain pin can't
+/// drive, only their BOOT button (GPIO9, GPIO0, GPIO9) is used
+pub struct DevKit;
+
+impl Board for DevKit {
+    const NAME: &'static str = "devkit";
+    fn split(p: Peripherals) -> BoardParts {
+        BoardParts {
+            timg0: p.TIMG0,
+            sw_interrupt: p.SW_INTERRUPT,
+            #[cfg(any(feature = "esp32", feature = "esp32s3"))]
+            cpu_ctrl: p.CPU_CTRL,
+            wifi: p.WIFI,
+            flash: p.FLASH,
+            #[cfg(not(feature = "esp32"))]
+            hmac: p.HMAC,
+            lpwr: p.LPWR,
+            #[cfg(feature = "esp32")]
+            status_led: Some(output(p.GPIO2)),
+            #[cfg(not(feature = "esp32"))]
+            status_led: None,
+            #[cfg(any(feature = "esp32", feature = "esp32s3"))]
+            button: Some(button(p.GPIO0)),
+            #[cfg(any(feature = "esp32c3", feature = "esp32c6"))]
+            button: Some(button(p.GPIO9)),
+            antenna_switch: None,
+            battery: None,
+            #[cfg(feature = "esp32")]
+            console: console(p.UART1, p.GPIO17, p.GPIO16),
+            #[cfg(feature = "esp32c3")]
+            console: console(p.UART1, p.GPIO4, p.GPIO5),
+            #[cfg(feature = "esp32s3")]
+            console: console(p.UART1, p.GPIO17, p.GPIO18),
+            #[cfg(feature = "esp32c6")]
+            console: console(p.UART1, p.GPIO4, p.GPIO5),
+            #[cfg(feature = "rpc")]
+            usb_serial: UsbSerialJtag::new(p.USB_DEVICE).into_async(),
+        }
+    }
+}
+
+/// rev B: the devkit layout plus a LiPo on GPIO35 behind a 1:2 divider
+#[cfg(feature = "esp32")]
+pub struct RevB;
+
+#[cfg(feature = "esp32")]
+impl Board for RevB {
+    const NAME: &'static str = "rev-b";
+    fn split(p: Peripherals) -> BoardParts {
+        BoardParts {
+            timg0: p.TIMG0,
+            sw_interrupt: p.SW_INTERRUPT,
+            cpu_ctrl: p.CPU_CTRL,
+            wifi: p.WIFI,
+            flash: p.FLASH,
+            lpwr: p.LPWR,
+            status_led: Some(output(p.GPIO2)),
+            button: Some(button(p.GPIO0)),
+            antenna_switch: None,
+            battery: Some(Box::new(AdcBattery::new(p.ADC1, p.GPIO35, 2))),
+            console: console(p.UART1, p.GPIO17, p.GPIO16),
+        }
+    }
+}
+
+/// rev C: LED moved to GPIO27, RF switch on GPIO21, battery on GPIO34
+#[cfg(feature = "esp32")]
+pub struct RevC;
+
+#[cfg(feature = "esp32")]
+impl Board for RevC {
+    const NAME: &'static str = "rev-c";
+    fn split(p: Peripherals) -> BoardParts {
+        BoardParts {
+            timg0: p.TIMG0,
+            sw_interrupt: p.SW_INTERRUPT,
+            cpu_ctrl: p.CPU_CTRL,
+            wifi: p.WIFI,
+            flash: p.FLASH,
+            lpwr: p.LPWR,
+            status_led: Some(output(p.GPIO27)),
+            button: Some(button(p.GPIO0)),
+            // starts on the PCB antenna
+            antenna_switch: Some(output(p.GPIO21)),
+            battery: Some(Box::new(AdcBattery::new(p.ADC1, p.GPIO34, 2))),
+            console: console(p.UART1, p.GPIO17, p.GPIO16),
+        }
+    }
+}
+
+/// the revision this firmware is built for
+#[cfg(feature = "board-rev-c")]
+pub type ActiveBoard = RevC;
+#[cfg(all(feature = "board-rev-b", not(feature = "board-rev-c")))]
+pub type ActiveBoard = RevB;
+#[cfg(not(any(feature = "board-rev-b", feature = "board-rev-c")))]
+pub type ActiveBoard = DevKit;
@@ -0,0 +1,80 @@
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_futures::select::select;
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    rtc_cntl::{SocResetReason, reset_reason},
+    system::Cpu,
+};
+
+use crate::{stats::record_stable, status::wait_until_online};
+
+/// unexpected resets in a row that put the next boot into safe mode
+pub const BOOT_LOOP_THRESHOLD: u32 = 5;
+// a boot that stays up this long without a probe getting through still counts as stable
+const STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+// "RSTD", the reset was ours
+const DELIBERATE_MAGIC: u32 = 0x5253_5444;
+
+// survives a software reset, not a power cycle
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RESET_INTENT: u32 = 0;
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// call right before resetting on purpose, so the next boot doesn't count it
+pub fn mark_deliberate_reset() {
+    // SAFETY: a single word, only written right before a reset
+    critical_section::with(|_| unsafe { *addr_of_mut!(RESET_INTENT) = DELIBERATE_MAGIC });
+}
+
+/// whether this boot follows a crash, a watchdog or a reset we didn't ask for.
+/// Power-ups, brownouts and deep sleep wakes aren't the firmware's fault
+pub fn reset_was_unexpected() -> bool {
+    // SAFETY: read and cleared once at boot, before any reset can be marked
+    let intent = critical_section::with(|_| unsafe {
+        let intent = *addr_of_mut!(RESET_INTENT);
+        *addr_of_mut!(RESET_INTENT) = 0;
+        intent
+    });
+    let reason = reset_reason(Cpu::ProCpu);
+    info!("Reset reason {:?}", reason);
+    match reason {
+        Some(
+            SocResetReason::ChipPowerOn
+            | SocResetReason::CoreDeepSleep
+            | SocResetReason::SysBrownOut,
+        ) => false,
+        _ => intent != DELIBERATE_MAGIC,
+    }
+}
+
+/// past BOOT_LOOP_THRESHOLD unexpected resets in a row, this boot runs in safe
+/// mode: no scoring, roaming or scans, just the first known credential
+pub fn check_boot_loop(unexpected_resets: u32) -> bool {
+    let safe = unexpected_resets >= BOOT_LOOP_THRESHOLD;
+    if safe {
+        info!(
+            "{} unexpected resets in a row, booting into safe mode",
+            unexpected_resets
+        );
+    }
+    SAFE_MODE.store(safe, Ordering::Relaxed);
+    safe
+}
+
+pub fn safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// clears the unexpected reset count once this boot got online or stayed up
+/// for STABLE_AFTER, so the next boot starts normally
+#[embassy_executor::task]
+pub async fn stable_task() {
+    select(wait_until_online(), Timer::after(STABLE_AFTER)).await;
+    info!("Boot is stable");
+    record_stable();
+}
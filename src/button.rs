@@ -0,0 +1,105 @@
+//! the provisioning button after boot: a short press rescans, a long one
+//! forgets the current AP and a very long one wipes the flash. The boot-time
+//! hold that resets before anything loads stays in main
+
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Input;
+
+use crate::{WIFI_REQUEST, WifiRequest, persistence::FACTORY_RESET, scanner::scan_now};
+
+// a level change shorter than this is contact bounce
+const DEBOUNCE: Duration = Duration::from_millis(30);
+const POLL: Duration = Duration::from_millis(50);
+
+/// what a press does, by how long the button was held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PressAction {
+    // scan_now
+    Rescan,
+    // WifiRequest::Forget
+    Forget,
+    // FACTORY_RESET
+    FactoryReset,
+}
+
+/// press durations, anything shorter than `long` is a short press
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ButtonActions {
+    pub long: Duration,
+    pub very_long: Duration,
+}
+
+impl ButtonActions {
+    pub const fn new() -> Self {
+        return Self {
+            long: Duration::from_secs(3),
+            very_long: Duration::from_secs(10),
+        };
+    }
+    pub const fn with_long(mut self, long: Duration) -> Self {
+        self.long = long;
+        self
+    }
+    pub const fn with_very_long(mut self, very_long: Duration) -> Self {
+        self.very_long = very_long;
+        self
+    }
+
+    /// the action for a press of `held`
+    pub fn action(&self, held: Duration) -> PressAction {
+        if held >= self.very_long {
+            PressAction::FactoryReset
+        } else if held >= self.long {
+            PressAction::Forget
+        } else {
+            PressAction::Rescan
+        }
+    }
+}
+
+impl Default for ButtonActions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// waits for presses of `button` (low while pressed) and acts on release, so
+/// the press length is known. A very long press acts once it's reached,
+/// without waiting for the release
+#[embassy_executor::task]
+pub async fn button_task(mut button: Input<'static>, actions: ButtonActions) -> ! {
+    info!("Start button task");
+    loop {
+        // still held from the boot-time check, or from the last very long press
+        while button.is_low() {
+            Timer::after(POLL).await;
+        }
+        button.wait_for_low().await;
+        Timer::after(DEBOUNCE).await;
+        if button.is_high() {
+            continue;
+        }
+        let pressed_at = Instant::now();
+        while button.is_low() && pressed_at.elapsed() < actions.very_long {
+            Timer::after(POLL).await;
+        }
+        let action = actions.action(pressed_at.elapsed());
+        info!(
+            "Button held {}ms, {:?}",
+            pressed_at.elapsed().as_millis(),
+            action
+        );
+        match action {
+            PressAction::Rescan => scan_now(),
+            PressAction::Forget => {
+                if WIFI_REQUEST.try_send(WifiRequest::Forget).is_err() {
+                    info!("Forget dropped, request queue full");
+                }
+            }
+            // persistence reboots once the flash is wiped
+            PressAction::FactoryReset => FACTORY_RESET.signal(()),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! quiet hours: daily windows, in local time from SNTP, during which a
+//! connected unit neither scans nor roams so it doesn't take the radio away
+//! from the application. Reconnecting after a drop is never held back
+
+use crate::sntp::epoch_secs;
+
+/// windows a QuietHours holds
+pub const MAX_QUIET_WINDOWS: usize = 4;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// from `start` up to (not including) `end`, in minutes after local midnight.
+/// An `end` before `start` runs past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QuietWindow {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl QuietWindow {
+    pub const fn new(start_hour: u8, start_minute: u8, end_hour: u8, end_minute: u8) -> Self {
+        assert!(start_hour < 24 && end_hour < 24 && start_minute < 60 && end_minute < 60);
+        Self {
+            start: start_hour as u16 * 60 + start_minute as u16,
+            end: end_hour as u16 * 60 + end_minute as u16,
+        }
+    }
+
+    /// true if `minute` after local midnight falls in the window
+    pub fn contains(&self, minute: u16) -> bool {
+        match self.start <= self.end {
+            true => self.start <= minute && minute < self.end,
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+}
+
+/// the scan calendar. Empty (the default) never holds anything back
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QuietHours {
+    pub windows: [Option<QuietWindow>; MAX_QUIET_WINDOWS],
+    // local time = UTC + this, SNTP only knows UTC
+    pub utc_offset_minutes: i16,
+}
+
+impl QuietHours {
+    pub const fn new() -> Self {
+        return Self {
+            windows: [None; MAX_QUIET_WINDOWS],
+            utc_offset_minutes: 0,
+        };
+    }
+    pub const fn with_window(mut self, window: QuietWindow) -> Self {
+        let mut i = 0;
+        while i < MAX_QUIET_WINDOWS {
+            if self.windows[i].is_none() {
+                self.windows[i] = Some(window);
+                return self;
+            }
+            i += 1;
+        }
+        panic!("more quiet windows than MAX_QUIET_WINDOWS");
+    }
+    pub const fn with_utc_offset_minutes(mut self, utc_offset_minutes: i16) -> Self {
+        self.utc_offset_minutes = utc_offset_minutes;
+        self
+    }
+
+    /// true if any window covers the unix time `epoch`. Without a time (SNTP
+    /// hasn't synced) it counts as quiet, so an unknown hour never roams
+    pub fn is_quiet_at(&self, epoch: Option<u64>) -> bool {
+        if self.windows.iter().all(Option::is_none) {
+            return false;
+        }
+        let Some(epoch) = epoch else {
+            return true;
+        };
+        let local = epoch as i64 + self.utc_offset_minutes as i64 * 60;
+        let minute = local.div_euclid(60).rem_euclid(MINUTES_PER_DAY as i64) as u16;
+        self.windows.iter().flatten().any(|w| w.contains(minute))
+    }
+
+    /// is_quiet_at the current SNTP time
+    pub fn is_quiet(&self) -> bool {
+        self.is_quiet_at(epoch_secs())
+    }
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,205 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+
+use crate::{
+    CandidateList, MAX_MISSED_SCANS, WifiConfig,
+    blacklist::clear_if_changed,
+    candidate_cap, push_or_evict, scan_seq,
+    stats::seed_from_stats,
+    telemetry::{AllocSite, report_oom},
+    try_push_candidate,
+};
+
+/// what came of using a candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    // associated, at this many seconds since boot (Instant)
+    Connected { at: u64 },
+    // refused us or dropped the link
+    Failed,
+    // connect_async didn't finish within the connect timeout
+    TimedOut,
+    // the probe through it was (or no longer was) sent to a login page
+    CaptivePortal(bool),
+    // its gateway answered the health check's ping after this many ms
+    GatewayRtt(u16),
+    // associating took this many ms
+    AssociateTime(u16),
+    // an address arrived this many ms after associating
+    LeaseTime(u16),
+}
+
+// folds `ms` into the average, weight 1/4 like the RSSI average
+fn average_ms(avg: Option<u16>, ms: u16) -> Option<u16> {
+    let avg = avg.map_or(ms as u32, |avg| (avg as u32 * 3 + ms as u32) / 4);
+    Some(avg as u16)
+}
+
+/// the ranked scan results. Always sorted best first and never longer than
+/// `candidate_cap()`; every method locks for one synchronous step, so nothing
+/// awaits while holding it
+pub struct CandidateStore {
+    list: Mutex<CriticalSectionRawMutex, CandidateList>,
+}
+
+impl CandidateStore {
+    pub const fn new() -> Self {
+        Self {
+            list: Mutex::new(CandidateList::new()),
+        }
+    }
+
+    // restores the invariants after every change, the weakest go first
+    fn settle(list: &mut CandidateList) {
+        list.sort_by(|x, y| x.cmp(y).reverse());
+        list.truncate(candidate_cap());
+    }
+
+    pub async fn best(&self) -> Option<WifiConfig> {
+        self.list.lock().await.first().cloned()
+    }
+
+    /// the candidate at `index` in the ranking
+    pub async fn get(&self, index: usize) -> Option<WifiConfig> {
+        self.list.lock().await.get(index).cloned()
+    }
+
+    pub async fn find(&self, bssid: &[u8; 6]) -> Option<WifiConfig> {
+        self.list
+            .lock()
+            .await
+            .iter()
+            .find(|w| w.bssid == *bssid)
+            .cloned()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.list.lock().await.is_empty()
+    }
+
+    /// a copy of the whole ranking, on the heap unless `heapless-core`
+    pub async fn snapshot(&self) -> CandidateList {
+        self.list.lock().await.clone()
+    }
+
+    /// the best N that pass `keep`, fixed capacity so callers don't allocate
+    pub async fn top<const N: usize>(
+        &self,
+        keep: impl Fn(&WifiConfig) -> bool,
+    ) -> heapless::Vec<WifiConfig, N> {
+        self.list
+            .lock()
+            .await
+            .iter()
+            .filter(|w| keep(w))
+            .take(N)
+            .cloned()
+            .collect()
+    }
+
+    /// adds a WG we know without a scan, false if it's listed or doesn't fit
+    pub async fn insert(&self, wifi: WifiConfig) -> bool {
+        let mut list = self.list.lock().await;
+        if list.contains(&wifi) || list.len() >= candidate_cap() {
+            return false;
+        }
+        let pushed = try_push_candidate(&mut list, wifi);
+        Self::settle(&mut list);
+        pushed
+    }
+
+    /// notes `outcome` on `bssid` and re-ranks, returns the candidate as it is now
+    pub async fn mark_result(&self, bssid: [u8; 6], outcome: Outcome) -> Option<WifiConfig> {
+        let mut list = self.list.lock().await;
+        let wifi = list.iter_mut().find(|w| w.bssid == bssid)?;
+        match outcome {
+            Outcome::Connected { at } => {
+                wifi.connect_success = Some(true);
+                wifi.connect_timed_out = false;
+                wifi.last_connected = Some(at);
+            }
+            Outcome::Failed => {
+                wifi.connect_success = Some(false);
+                wifi.connect_timed_out = false;
+            }
+            Outcome::TimedOut => {
+                wifi.connect_success = Some(false);
+                wifi.connect_timed_out = true;
+            }
+            Outcome::CaptivePortal(captive) => {
+                if wifi.captive_portal != captive {
+                    info!("{:?} captive portal = {}", wifi.bssid, captive);
+                }
+                wifi.captive_portal = captive;
+            }
+            Outcome::GatewayRtt(ms) => wifi.gateway_rtt_ms = average_ms(wifi.gateway_rtt_ms, ms),
+            Outcome::AssociateTime(ms) => wifi.associate_ms = average_ms(wifi.associate_ms, ms),
+            Outcome::LeaseTime(ms) => wifi.lease_ms = average_ms(wifi.lease_ms, ms),
+        }
+        let wifi = wifi.clone();
+        Self::settle(&mut list);
+        Some(wifi)
+    }
+
+    /// changes candidates in place, e.g. from sniffed beacons, then re-ranks.
+    /// `f` can't add or drop any
+    pub async fn modify(&self, f: impl FnOnce(&mut [WifiConfig])) {
+        let mut list = self.list.lock().await;
+        f(&mut list);
+        Self::settle(&mut list);
+    }
+
+    /// takes a fresh scan as the ranking. WGs it saw again keep their history,
+    /// WGs it missed stay until they've been missing for MAX_MISSED_SCANS scans
+    pub async fn replace(&self, mut scan: CandidateList) {
+        let mut list = self.list.lock().await;
+        for w in &mut scan {
+            clear_if_changed(w);
+            match list.iter().find(|c| c.bssid == w.bssid) {
+                Some(old) => {
+                    w.connect_success = old.connect_success;
+                    w.connect_timed_out = old.connect_timed_out;
+                    w.last_connected = old.last_connected;
+                    w.captive_portal = old.captive_portal;
+                    w.gateway_rtt_ms = old.gateway_rtt_ms;
+                    w.associate_ms = old.associate_ms;
+                    w.lease_ms = old.lease_ms;
+                    w.mobility_domain = old.mobility_domain;
+                    // the scan can't see the load, keep the sniffed one while it's
+                    // still the same channel
+                    if old.channel == w.channel {
+                        w.channel_load = old.channel_load;
+                    }
+                    w.update_rssi_ema(old);
+                }
+                // nothing seen since boot, fall back to the stored tallies
+                None => seed_from_stats(w),
+            }
+        }
+        let seq = scan_seq();
+        let cap = candidate_cap();
+        let mut out_of_memory = false;
+        for old in list.drain(..) {
+            if scan.contains(&old) {
+                continue;
+            }
+            if old.is_stale(seq) {
+                info!(
+                    "Forgetting {:?}, not seen in {} scans",
+                    old.bssid, MAX_MISSED_SCANS
+                );
+            } else if scan.len() >= cap {
+                // the table never grows past the cap, the lowest ranked goes
+                push_or_evict(&mut scan, old, cap);
+            } else if !try_push_candidate(&mut scan, old) {
+                // the fresh results matter more, let the rest go
+                out_of_memory = true;
+            }
+        }
+        if out_of_memory {
+            report_oom(AllocSite::Candidates);
+        }
+        Self::settle(&mut scan);
+        *list = scan;
+    }
+}
@@ -0,0 +1,166 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_radio::wifi::{PromiscuousPkt, Sniffer};
+
+/// RAM set aside for a capture, frames that don't fit are counted and dropped
+pub const CAPTURE_LEN: usize = 16 * 1024;
+/// longest capture the API accepts
+pub const MAX_CAPTURE: Duration = Duration::from_secs(60);
+// bytes kept of each frame, enough for the management frame bodies we care about
+const SNAPLEN: usize = 256;
+// pcap link type for raw 802.11 frames
+const LINKTYPE_IEEE802_11: u32 = 105;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+unsafe extern "C" {
+    // from the WiFi blobs, esp-radio doesn't wrap it. Only sticks while the
+    // station isn't associated
+    fn esp_wifi_set_channel(primary: u8, second: u32) -> i32;
+}
+
+/// capture management frames on `channel` for `duration`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CaptureRequest {
+    pub channel: u8,
+    pub duration: Duration,
+}
+
+impl CaptureRequest {
+    /// "<channel>/<secs>", e.g. "6/10"
+    pub fn from_path(path: &str) -> Option<Self> {
+        let (channel, secs) = path.split_once('/')?;
+        let channel: u8 = channel.parse().ok()?;
+        let secs: u64 = secs.parse().ok()?;
+        if !(1..=14).contains(&channel) || secs == 0 {
+            return None;
+        }
+        Some(Self {
+            channel,
+            duration: Duration::from_secs(secs).min(MAX_CAPTURE),
+        })
+    }
+}
+
+/// how the last capture went
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CaptureSummary {
+    pub frames: u32,
+    // frames that didn't fit in CAPTURE_LEN
+    pub dropped: u32,
+}
+
+// the capture in pcap format, global header first
+struct CaptureBuffer {
+    pcap: heapless::Vec<u8, CAPTURE_LEN>,
+    active: bool,
+    started: Instant,
+    summary: CaptureSummary,
+}
+
+static CAPTURE: Mutex<CriticalSectionRawMutex, RefCell<CaptureBuffer>> =
+    Mutex::new(RefCell::new(CaptureBuffer {
+        pcap: heapless::Vec::new(),
+        active: false,
+        started: Instant::from_ticks(0),
+        summary: CaptureSummary {
+            frames: 0,
+            dropped: 0,
+        },
+    }));
+
+fn pcap_header() -> [u8; PCAP_HEADER_LEN] {
+    let mut header = [0u8; PCAP_HEADER_LEN];
+    header[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    // version 2.4
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // timezone and sigfigs stay zero
+    header[16..20].copy_from_slice(&(SNAPLEN as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_IEEE802_11.to_le_bytes());
+    header
+}
+
+// sniffer callback, keeps management frames only
+fn on_frame(packet: PromiscuousPkt<'_>) {
+    let data = packet.data;
+    // frame control type bits, 0 = management
+    if data.is_empty() || (data[0] >> 2) & 0b11 != 0 {
+        return;
+    }
+    CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        if !c.active {
+            return;
+        }
+        let kept = data.len().min(SNAPLEN);
+        let at = c.started.elapsed().as_micros();
+        let mut record = [0u8; PCAP_RECORD_HEADER_LEN];
+        record[0..4].copy_from_slice(&((at / 1_000_000) as u32).to_le_bytes());
+        record[4..8].copy_from_slice(&((at % 1_000_000) as u32).to_le_bytes());
+        record[8..12].copy_from_slice(&(kept as u32).to_le_bytes());
+        record[12..16].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        if c.pcap.len() + record.len() + kept > CAPTURE_LEN {
+            c.summary.dropped += 1;
+            return;
+        }
+        let _ = c.pcap.extend_from_slice(&record);
+        let _ = c.pcap.extend_from_slice(&data[..kept]);
+        c.summary.frames += 1;
+    })
+}
+
+/// switches the radio to `request.channel` and records management frames
+/// until `request.duration` is up. The station must be disconnected
+pub async fn run_capture(sniffer: &mut Sniffer<'static>, request: CaptureRequest) {
+    info!(
+        "Capturing on channel {} for {}s",
+        request.channel,
+        request.duration.as_secs()
+    );
+    CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        c.pcap.clear();
+        let _ = c.pcap.extend_from_slice(&pcap_header());
+        c.summary = CaptureSummary::default();
+        c.started = Instant::now();
+        c.active = true;
+    });
+    sniffer.set_receive_cb(on_frame);
+    // second channel none
+    let err = unsafe { esp_wifi_set_channel(request.channel, 0) };
+    if err != 0 {
+        info!("Failed to set capture channel {}", err);
+    }
+    if let Err(e) = sniffer.set_promiscuous_mode(true) {
+        info!("Failed to start sniffer {:?}", e);
+    }
+    Timer::after(request.duration).await;
+    let _ = sniffer.set_promiscuous_mode(false);
+    let summary = CAPTURE.lock(|c| {
+        let mut c = c.borrow_mut();
+        c.active = false;
+        c.summary
+    });
+    info!("Capture done, {:?}", summary);
+}
+
+/// length of the last capture in bytes, 0 if there never was one
+pub fn capture_len() -> usize {
+    CAPTURE.lock(|c| c.borrow().pcap.len())
+}
+
+/// copies capture bytes from `offset` into `out`, returns how many
+pub fn read_capture(offset: usize, out: &mut [u8]) -> usize {
+    CAPTURE.lock(|c| {
+        let c = c.borrow();
+        let available = c.pcap.get(offset..).unwrap_or_default();
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        n
+    })
+}
@@ -0,0 +1,49 @@
+//! what differs between the supported ESP32 variants, pick one with the
+//! `esp32`, `esp32c3`, `esp32s3` or `esp32c6` feature
+
+#[cfg(not(any(
+    feature = "esp32",
+    feature = "esp32c3",
+    feature = "esp32s3",
+    feature = "esp32c6"
+)))]
+compile_error!("select the chip with one of the esp32, esp32c3, esp32s3 or esp32c6 features");
+
+#[cfg(any(
+    all(
+        feature = "esp32",
+        any(feature = "esp32c3", feature = "esp32s3", feature = "esp32c6")
+    ),
+    all(feature = "esp32c3", any(feature = "esp32s3", feature = "esp32c6")),
+    all(feature = "esp32s3", feature = "esp32c6"),
+))]
+compile_error!("the chip features are mutually exclusive, build with --no-default-features");
+
+/// the chip this firmware is built for
+#[cfg(feature = "esp32")]
+pub const NAME: &str = "esp32";
+#[cfg(feature = "esp32c3")]
+pub const NAME: &str = "esp32c3";
+#[cfg(feature = "esp32s3")]
+pub const NAME: &str = "esp32s3";
+#[cfg(feature = "esp32c6")]
+pub const NAME: &str = "esp32c6";
+
+/// bytes of heap, placed in the DRAM the second stage bootloader hands back
+/// (`.dram2_uninit`). What's left there once the radio blobs are linked,
+/// `memory.heap.peak` on /status shows how much of it a unit really needs
+#[cfg(feature = "esp32")]
+pub const HEAP_SIZE: usize = 98767;
+#[cfg(feature = "esp32c3")]
+pub const HEAP_SIZE: usize = 66320;
+#[cfg(feature = "esp32s3")]
+pub const HEAP_SIZE: usize = 73744;
+#[cfg(feature = "esp32c6")]
+pub const HEAP_SIZE: usize = 65536;
+
+/// the eFuse key the HMAC peripheral derives the storage key from
+/// (src/secret.rs), burned at manufacturing with `espefuse.py burn_key
+/// BLOCK_KEY5 key.bin HMAC_UP`, which read protects it. The ESP32 has no HMAC
+/// peripheral and seals nothing
+#[cfg(any(feature = "esp32c3", feature = "esp32s3", feature = "esp32c6"))]
+pub const STORAGE_KEY: esp_hal::hmac::KeyId = esp_hal::hmac::KeyId::Key5;
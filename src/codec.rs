@@ -0,0 +1,75 @@
+use core::fmt;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+/// turns persisted records into bytes and back, the flash layout doesn't care which
+pub trait Codec {
+    /// serialize `value` into `buf`, returning the used part
+    fn encode<'a, T: Serialize>(value: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], CodecError>;
+    /// deserialize a `T` from the start of `bytes`, trailing bytes are ignored
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodecError {
+    // the record doesn't fit the buffer, or the serializer failed
+    Encode,
+    // erased flash, a record from another format, or corruption
+    Decode,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode => f.write_str("record encode failed"),
+            CodecError::Decode => f.write_str("record decode failed"),
+        }
+    }
+}
+
+impl core::error::Error for CodecError {}
+
+/// compact and the historic on-flash format
+pub struct Postcard;
+
+impl Codec for Postcard {
+    fn encode<'a, T: Serialize>(value: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], CodecError> {
+        postcard::to_slice(value, buf).map_err(|e| {
+            info!("Postcard encode error {:?}", e);
+            CodecError::Encode
+        })
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        postcard::from_bytes(bytes).map_err(|e| {
+            info!("Postcard decode error {:?}", e);
+            CodecError::Decode
+        })
+    }
+}
+
+/// CBOR for integrators with existing tooling around it
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<'a, T: Serialize>(value: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], CodecError> {
+        let mut serializer =
+            minicbor_serde::Serializer::new(minicbor::encode::write::Cursor::new(&mut *buf));
+        value
+            .serialize(&mut serializer)
+            .map_err(|_| CodecError::Encode)?;
+        let len = serializer.into_encoder().into_writer().position();
+        Ok(&mut buf[..len])
+    }
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        minicbor_serde::from_slice(bytes).map_err(|_| CodecError::Decode)
+    }
+}
+
+/// the codec persistence uses, picked at compile time
+#[cfg(not(feature = "cbor"))]
+pub type DefaultCodec = Postcard;
+#[cfg(feature = "cbor")]
+pub type DefaultCodec = Cbor;
@@ -0,0 +1,243 @@
+//! `config dump` and `config load` on the console UART, so a provisioning
+//! script on the production line can configure a device without flashing an
+//! NVS image. Commands are lines, the reply is one line starting with
+//! `config: `: the configuration as JSON, `ok`, or `error` and a reason.
+//! A load starts with the build's CONSOLE_TOKEN, without one loads are refused.
+//! The JSON holds the networks, their IP settings, the roaming preset and the
+//! tx power profile. Passphrases are accepted but never dumped, anyone with a
+//! cable can read the console, and enterprise credentials stay with their own
+//! NVS records, their certificates don't fit a line
+//!
+//! ```text
+//! config load <token> {"networks":[{"ssid":"line","password":"secret"}],"preset":"Stable"}
+//! config: ok
+//! ```
+
+use embedded_io_async::{Read, Write};
+use esp_hal::{Async, uart::Uart};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AuthPolicy, Credential, Credentials, MAX_PROVISIONED, NetworkPolicy, Pmf, Tier, WIFI_REQUEST,
+    WifiRequest,
+    netconfig::{NetworkConfigs, network_configs, set_network_configs},
+    persistence::{STORE_NETWORK_CONFIGS, STORE_PROVISIONED},
+    roaming::{RoamPreset, active_preset},
+    set_provisioned_credentials,
+    txpower::{TxPowerProfile, active_tx_power},
+    with_credentials,
+};
+
+// the longest command line, a load of every network with its IP settings
+const LINE_LEN: usize = 2048;
+// what a load has to carry, from the build environment. Empty refuses every
+// load, dumps have no passphrases and always work
+const CONSOLE_TOKEN: &str = match option_env!("CONSOLE_TOKEN") {
+    Some(token) => token,
+    None => "",
+};
+// the longest reply, a dump has no passphrases so it's shorter than a load
+const REPLY_LEN: usize = 1536;
+
+/// a network as the configuration shows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkEntry {
+    pub ssid: heapless::String<32>,
+    // required on load, null in dumps
+    #[serde(default)]
+    pub password: Option<heapless::String<64>>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub auth: AuthPolicy,
+    #[serde(default)]
+    pub pmf: Pmf,
+    #[serde(default)]
+    pub tier: Tier,
+    #[serde(default)]
+    pub policy: NetworkPolicy,
+}
+
+/// what `config dump` prints and `config load` takes, also the host
+/// protocol's (src/rpc.rs). On load, a field that's null or left out keeps
+/// its current value
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConfigDoc {
+    #[serde(default)]
+    pub networks: Option<heapless::Vec<NetworkEntry, MAX_PROVISIONED>>,
+    #[serde(default)]
+    pub ip: Option<NetworkConfigs>,
+    #[serde(default)]
+    pub preset: Option<RoamPreset>,
+    #[serde(default)]
+    pub tx_power: Option<TxPowerProfile>,
+}
+
+/// the configuration in use, without the passphrases
+pub fn config_doc() -> ConfigDoc {
+    let mut networks = heapless::Vec::new();
+    with_credentials(|creds| {
+        for cred in creds.iter().take(MAX_PROVISIONED) {
+            let _ = networks.push(NetworkEntry {
+                ssid: cred.ssid.clone(),
+                password: None,
+                hidden: cred.hidden,
+                auth: cred.auth,
+                pmf: cred.pmf,
+                tier: cred.tier,
+                policy: cred.policy,
+            });
+        }
+    });
+    ConfigDoc {
+        networks: Some(networks),
+        ip: Some(network_configs()),
+        preset: Some(active_preset()),
+        tx_power: Some(active_tx_power()),
+    }
+}
+
+/// applies a loaded document and persists it, a bad network rejects all of it
+pub fn load_config(doc: ConfigDoc) -> Result<(), &'static str> {
+    let mut creds = Credentials::new();
+    if let Some(networks) = &doc.networks {
+        for network in networks {
+            let Some(password) = &network.password else {
+                return Err("password missing");
+            };
+            if network.ssid.is_empty() {
+                return Err("empty ssid");
+            }
+            // networks holds no more than the table
+            let _ = creds.push(Credential {
+                ssid: network.ssid.clone(),
+                password: password.clone(),
+                hidden: network.hidden,
+                auth: network.auth,
+                pmf: network.pmf,
+                tier: network.tier,
+                policy: network.policy,
+            });
+        }
+    }
+    // nothing is applied unless every request fits, the manager drains the
+    // queue between rounds
+    let requests = [
+        doc.preset.is_some(),
+        doc.tx_power.is_some(),
+        doc.networks.is_some(),
+    ];
+    if WIFI_REQUEST.free_capacity() < requests.iter().filter(|r| **r).count() {
+        return Err("request queue full");
+    }
+    if let Some(ip) = doc.ip {
+        set_network_configs(ip.clone());
+        STORE_NETWORK_CONFIGS.signal(ip);
+    }
+    if let Some(preset) = doc.preset {
+        send(WifiRequest::SetPreset(preset))?;
+    }
+    if let Some(profile) = doc.tx_power {
+        send(WifiRequest::SetTxPower(profile))?;
+    }
+    if doc.networks.is_some() {
+        STORE_PROVISIONED.signal(creds.clone());
+        set_provisioned_credentials(creds);
+        // the link may be to a network that was just removed
+        send(WifiRequest::Reconnect)?;
+    }
+    Ok(())
+}
+
+// whether `given` is CONSOLE_TOKEN, without stopping at the first difference
+// so the time taken doesn't give it away byte by byte
+fn token_matches(given: &[u8]) -> bool {
+    let token = CONSOLE_TOKEN.as_bytes();
+    if token.is_empty() || given.len() != token.len() {
+        return false;
+    }
+    given
+        .iter()
+        .zip(token)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn send(request: WifiRequest) -> Result<(), &'static str> {
+    WIFI_REQUEST
+        .try_send(request)
+        .map_err(|_| "request queue full")
+}
+
+async fn reply(uart: &mut Uart<'static, Async>, parts: &[&[u8]]) {
+    let _ = uart.write_all(b"config: ").await;
+    for part in parts {
+        let _ = uart.write_all(part).await;
+    }
+    let _ = uart.write_all(b"\r\n").await;
+}
+
+async fn handle(uart: &mut Uart<'static, Async>, line: &[u8], out: &mut [u8]) {
+    let line = line.trim_ascii();
+    if line == b"config dump" {
+        match serde_json_core::to_slice(&config_doc(), out) {
+            Ok(len) => reply(uart, &[&out[..len]]).await,
+            Err(_) => reply(uart, &[b"error dump too long"]).await,
+        }
+    } else if let Some(args) = line.strip_prefix(b"config load ") {
+        let (token, json) = args.split_at(args.iter().position(|&b| b == b' ').unwrap_or(0));
+        let result = match token_matches(token) {
+            false => Err("unauthorized"),
+            true => match serde_json_core::from_slice::<ConfigDoc>(json.trim_ascii_start()) {
+                Ok((doc, _)) => load_config(doc),
+                Err(_) => Err("bad json"),
+            },
+        };
+        match result {
+            Ok(()) => {
+                info!("Configuration loaded over the console");
+                reply(uart, &[b"ok"]).await
+            }
+            Err(reason) => reply(uart, &[b"error ", reason.as_bytes()]).await,
+        }
+    } else if line.starts_with(b"config") {
+        reply(uart, &[b"error unknown command"]).await;
+    }
+}
+
+/// reads commands from the console UART, see the module doc
+#[embassy_executor::task]
+pub async fn console_task(mut uart: Uart<'static, Async>) -> ! {
+    info!("Start console task");
+    let mut line = [0u8; LINE_LEN];
+    let mut out = [0u8; REPLY_LEN];
+    let mut len = 0;
+    // set while the rest of an overlong line is skipped
+    let mut overflow = false;
+    loop {
+        let mut chunk = [0u8; 64];
+        let n = match uart.read(&mut chunk).await {
+            Ok(n) => n,
+            Err(e) => {
+                info!("Console read error: {:?}", e);
+                continue;
+            }
+        };
+        for &byte in &chunk[..n] {
+            if byte == b'\n' || byte == b'\r' {
+                if overflow {
+                    reply(&mut uart, &[b"error line too long"]).await;
+                } else if len > 0 {
+                    handle(&mut uart, &line[..len], &mut out).await;
+                }
+                len = 0;
+                overflow = false;
+            } else if len < LINE_LEN {
+                line[len] = byte;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+        }
+    }
+}
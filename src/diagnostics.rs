@@ -0,0 +1,322 @@
+//! the diagnostics access point: a SoftAP kept up next to the STA link, so a
+//! technician can join the unit itself and follow it roaming on the status
+//! pages without any internet access. The AP shares the radio with the STA,
+//! so it always sits on the channel of the WG we're connected to
+
+use embassy_net::{
+    IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4,
+    udp::{PacketMetadata, UdpSocket},
+};
+use esp_radio::wifi::{AccessPointConfig, AuthMethod, ModeConfig};
+
+use crate::env_or_empty;
+
+/// the unit's own address on the diagnostics AP, the status pages are at
+/// http://192.168.4.1/status
+pub const AP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+const AP_PREFIX_LEN: u8 = 24;
+// the first address handed out, technicians get AP_POOL_START and up. It
+// follows the unit's own, so no lease can hand that out
+const AP_POOL_START: u8 = AP_ADDRESS.octets()[3] + 1;
+const AP_POOL_SIZE: usize = 4;
+/// sockets on the AP's stack: the DHCP server and HTTP
+pub const AP_SOCKETS: usize = 2;
+
+const AP_SSID: &str = "wg-scan-diag";
+const AP_PASSWORD: &str = env_or_empty(option_env!("DIAG_AP_PASSWORD"));
+// WPA2 won't take a shorter passphrase
+const MIN_PASSWORD_LEN: usize = 8;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+// a BOOTP message with room for the options we read and write
+const DHCP_PACKET_LEN: usize = 576;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_AT: usize = 240;
+// long enough to outlast a visit, short enough that the pool doesn't fill up
+const LEASE_SECS: u32 = 60 * 60;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_RELEASE: u8 = 7;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PAD: u8 = 0;
+const OPTION_END: u8 = 255;
+
+/// the diagnostics AP, handed to wifi_mgr in WifiManagerConfig
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DiagnosticsAp {
+    pub ssid: &'static str,
+    // WPA2 passphrase, DIAG_AP_PASSWORD from the environment by default
+    pub password: &'static str,
+    // technicians on the AP at once, each takes an address from the pool
+    pub max_connections: u16,
+}
+
+impl DiagnosticsAp {
+    pub const fn new() -> Self {
+        return Self {
+            ssid: AP_SSID,
+            password: AP_PASSWORD,
+            max_connections: AP_POOL_SIZE as u16,
+        };
+    }
+    pub const fn with_ssid(mut self, ssid: &'static str) -> Self {
+        self.ssid = ssid;
+        self
+    }
+    pub const fn with_password(mut self, password: &'static str) -> Self {
+        self.password = password;
+        self
+    }
+    pub const fn with_max_connections(mut self, max_connections: u16) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// the SoftAP half of the radio config, None without a usable passphrase,
+    /// the status pages aren't for anyone walking past
+    pub fn access_point_config(&self) -> Option<AccessPointConfig> {
+        if self.password.len() < MIN_PASSWORD_LEN {
+            return None;
+        }
+        Some(
+            AccessPointConfig::default()
+                .with_ssid(self.ssid.into())
+                .with_password(self.password.into())
+                .with_auth_method(AuthMethod::Wpa2Personal)
+                .with_max_connections(self.max_connections.min(AP_POOL_SIZE as u16)),
+        )
+    }
+}
+
+impl Default for DiagnosticsAp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `mode` with the diagnostics AP brought up next to the client, if there is
+/// one. Enterprise WGs have no AP+STA mode in the driver and stay client only
+pub fn with_diagnostics_ap(mode: ModeConfig, ap: Option<&DiagnosticsAp>) -> ModeConfig {
+    let Some(ap) = ap else {
+        return mode;
+    };
+    let Some(ap_config) = ap.access_point_config() else {
+        return mode;
+    };
+    match mode {
+        ModeConfig::Client(client) => ModeConfig::ApSta(client, ap_config),
+        ModeConfig::EapClient(_) => {
+            info!("Diagnostics AP is off while on an enterprise WG");
+            mode
+        }
+        mode => mode,
+    }
+}
+
+/// the AP side's network config, the unit is the only router on it
+pub fn ap_net_config() -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(AP_ADDRESS, AP_PREFIX_LEN),
+        gateway: None,
+        dns_servers: Default::default(),
+    })
+}
+
+/// hands technicians on the diagnostics AP an address from a small pool.
+/// No router or DNS is offered, so phones keep their mobile data for
+/// everything but the unit
+#[embassy_executor::task]
+pub async fn dhcp_server_task(stack: Stack<'static>) -> ! {
+    info!("Start diagnostics dhcp task");
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; DHCP_PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; DHCP_PACKET_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // the AP still comes up, technicians can set a static address
+    if let Err(e) = socket.bind(DHCP_SERVER_PORT) {
+        info!("Diagnostics DHCP bind error: {:?}", e);
+        core::future::pending().await
+    }
+    // clients don't have an address to answer to yet
+    let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), DHCP_CLIENT_PORT);
+
+    let mut leases = Leases::new();
+    let mut packet = [0u8; DHCP_PACKET_LEN];
+    loop {
+        let len = match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                info!("DHCP server recv error: {:?}", e);
+                continue;
+            }
+        };
+        let Some(request) = Request::parse(&packet[..len]) else {
+            continue;
+        };
+        let reply = match request.kind {
+            DHCP_DISCOVER => Some((DHCP_OFFER, leases.assign(request.mac))),
+            // a REQUEST naming another server is that server's business
+            DHCP_REQUEST if request.server.is_some_and(|s| s != AP_ADDRESS) => None,
+            DHCP_REQUEST => match leases.assign(request.mac) {
+                ip if request.requested.is_none_or(|r| r == ip) => Some((DHCP_ACK, ip)),
+                _ => Some((DHCP_NAK, Ipv4Address::UNSPECIFIED)),
+            },
+            DHCP_RELEASE => {
+                leases.release(request.mac);
+                None
+            }
+            _ => None,
+        };
+        let Some((kind, ip)) = reply else {
+            continue;
+        };
+        if kind == DHCP_ACK {
+            info!("Diagnostics AP: {} joined", ip);
+        }
+        let len = write_reply(&mut packet, len, kind, ip);
+        if let Err(e) = socket.send_to(&packet[..len], broadcast).await {
+            info!("DHCP server send error: {:?}", e);
+        }
+    }
+}
+
+// which client holds which pool address, by MAC
+struct Leases {
+    holders: [Option<[u8; 6]>; AP_POOL_SIZE],
+}
+
+impl Leases {
+    const fn new() -> Self {
+        Self {
+            holders: [None; AP_POOL_SIZE],
+        }
+    }
+
+    // the address `mac` already holds, else a free one. A full pool recycles
+    // the first slot, the technician before has most likely left
+    fn assign(&mut self, mac: [u8; 6]) -> Ipv4Address {
+        let slot = match self.holders.iter().position(|h| *h == Some(mac)) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.holders.iter().position(Option::is_none).unwrap_or(0);
+                self.holders[slot] = Some(mac);
+                slot
+            }
+        };
+        let [a, b, c, _] = AP_ADDRESS.octets();
+        Ipv4Address::new(a, b, c, AP_POOL_START + slot as u8)
+    }
+
+    fn release(&mut self, mac: [u8; 6]) {
+        for holder in self.holders.iter_mut().filter(|h| **h == Some(mac)) {
+            *holder = None;
+        }
+    }
+}
+
+// the parts of a client message we act on
+struct Request {
+    kind: u8,
+    mac: [u8; 6],
+    requested: Option<Ipv4Address>,
+    server: Option<Ipv4Address>,
+}
+
+impl Request {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        // BOOTREQUEST over ethernet
+        if packet.len() < DHCP_OPTIONS_AT || packet[0] != 1 || packet[1] != 1 || packet[2] != 6 {
+            return None;
+        }
+        if packet[236..DHCP_OPTIONS_AT] != DHCP_MAGIC {
+            return None;
+        }
+        let mut request = Self {
+            kind: 0,
+            mac: packet[28..34].try_into().ok()?,
+            requested: None,
+            server: None,
+        };
+        let mut at = DHCP_OPTIONS_AT;
+        while let Some(&code) = packet.get(at) {
+            match code {
+                OPTION_END => break,
+                OPTION_PAD => {
+                    at += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *packet.get(at + 1)? as usize;
+            let value = packet.get(at + 2..at + 2 + len)?;
+            match (code, value) {
+                (OPTION_MESSAGE_TYPE, [kind]) => request.kind = *kind,
+                (OPTION_REQUESTED_IP, &[a, b, c, d]) => {
+                    request.requested = Some(Ipv4Address::new(a, b, c, d))
+                }
+                (OPTION_SERVER_ID, &[a, b, c, d]) => {
+                    request.server = Some(Ipv4Address::new(a, b, c, d))
+                }
+                _ => {}
+            }
+            at += 2 + len;
+        }
+        Some(request)
+    }
+}
+
+// turns the request in `packet` into our reply in place, keeping xid, flags
+// and chaddr. Returns the reply's length
+fn write_reply(packet: &mut [u8], request_len: usize, kind: u8, ip: Ipv4Address) -> usize {
+    // BOOTREPLY, no hops
+    packet[0] = 2;
+    packet[3] = 0;
+    // secs and ciaddr
+    packet[8..10].fill(0);
+    packet[12..16].fill(0);
+    packet[16..20].copy_from_slice(&ip.octets());
+    packet[20..24].copy_from_slice(&AP_ADDRESS.octets());
+    // giaddr, sname and file
+    packet[24..28].fill(0);
+    packet[34..236].fill(0);
+    let mut at = DHCP_OPTIONS_AT;
+    let mut option = |code: u8, value: &[u8]| {
+        packet[at] = code;
+        packet[at + 1] = value.len() as u8;
+        packet[at + 2..at + 2 + value.len()].copy_from_slice(value);
+        at += 2 + value.len();
+    };
+    option(OPTION_MESSAGE_TYPE, &[kind]);
+    option(OPTION_SERVER_ID, &AP_ADDRESS.octets());
+    if kind != DHCP_NAK {
+        option(OPTION_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+        option(
+            OPTION_SUBNET_MASK,
+            &(u32::MAX << (32 - AP_PREFIX_LEN)).to_be_bytes(),
+        );
+    }
+    packet[at] = OPTION_END;
+    at += 1;
+    // some clients drop replies shorter than a BOOTP message
+    let len = at.max(300).max(request_len.min(DHCP_PACKET_LEN));
+    packet[at..len].fill(0);
+    len
+}
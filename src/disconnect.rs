@@ -0,0 +1,137 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+use esp_radio::wifi::event::{self, EventExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    eventlog::{self, Event},
+    fmt::Bytes,
+    telemetry::{self, TelemetryEvent},
+};
+
+/// longest wait between reconnect rounds, however often the WG drops us
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// our interpretation of a disconnect, the raw code is always kept alongside it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisconnectCategory {
+    // we (or the AP) left deliberately
+    Left,
+    // key/credential problems, retrying straight away won't help
+    AuthFailure,
+    // AP refused the association (capacity, capabilities, rsn mismatch)
+    AssocFailure,
+    // AP not found or stopped answering
+    ApUnreachable,
+    // AP beacons stopped arriving
+    BeaconTimeout,
+    // AP asked us to move (BSS transition) or the driver roamed
+    Roamed,
+    Other,
+}
+
+impl DisconnectCategory {
+    /// maps an esp-idf `wifi_err_reason_t`, which is the 802.11 reason code for
+    /// values < 200 and an esp specific code above that.
+    /// association failures arrive here too, the status code from the AP is
+    /// folded into 202 (auth fail) / 203 (assoc fail) by the driver
+    pub fn from_reason(reason: u16) -> Self {
+        match reason {
+            3 | 8 => Self::Left,
+            2 | 6 | 9 | 14 | 15 | 16 | 17 | 23 | 202 | 204 | 210 | 211 => Self::AuthFailure,
+            5 | 10 | 11 | 13 | 18..=22 | 24 | 203 | 208 => Self::AssocFailure,
+            4 | 7 | 201 | 205 | 212 => Self::ApUnreachable,
+            200 | 206 | 209 => Self::BeaconTimeout,
+            12 | 207 => Self::Roamed,
+            _ => Self::Other,
+        }
+    }
+
+    /// whether the WG should rank lower for it. Leaving or roaming isn't
+    /// its fault
+    pub fn blames_ap(self) -> bool {
+        !matches!(self, Self::Left | Self::Roamed)
+    }
+
+    /// the wait before the next reconnect round after one such disconnect,
+    /// doubled for each one in a row
+    pub fn base_backoff(self) -> Duration {
+        match self {
+            Self::Left | Self::Roamed => Duration::from_secs(0),
+            // the AP may be rebooting, check back soon
+            Self::ApUnreachable | Self::BeaconTimeout | Self::Other => Duration::from_secs(1),
+            Self::AssocFailure => Duration::from_secs(2),
+            // the blacklist keeps it off the top, don't hammer the others
+            Self::AuthFailure => Duration::from_secs(5),
+        }
+    }
+}
+
+/// a disconnect as reported by the driver, published verbatim to telemetry so
+/// the WG vendor can match it against the AP-side logs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisconnectReport {
+    pub bssid: [u8; 6],
+    // raw wifi_err_reason_t
+    pub reason: u16,
+    pub category: DisconnectCategory,
+    pub rssi: i8,
+}
+
+static LAST_DISCONNECT: Mutex<CriticalSectionRawMutex, Cell<Option<DisconnectReport>>> =
+    Mutex::new(Cell::new(None));
+
+/// the most recent disconnect, failed connect attempts included
+pub fn last_disconnect() -> Option<DisconnectReport> {
+    LAST_DISCONNECT.lock(|l| l.get())
+}
+
+// disconnects since the last successful connect
+static DISCONNECT_STREAK: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// how long to wait before the next reconnect round, from why the last
+/// disconnect happened and how many came in a row
+pub fn reconnect_backoff() -> Duration {
+    let Some(last) = last_disconnect() else {
+        return Duration::from_secs(0);
+    };
+    let streak = DISCONNECT_STREAK.lock(|s| s.get()).max(1);
+    let base = last.category.base_backoff().as_millis();
+    Duration::from_millis(base << (streak - 1).min(6)).min(MAX_BACKOFF)
+}
+
+/// a connect went through, the next disconnect starts a fresh streak
+pub fn reset_backoff() {
+    DISCONNECT_STREAK.lock(|s| s.set(0));
+}
+
+/// registers a driver event handler that reports every StaDisconnected,
+/// call once before the controller is started
+pub fn install_disconnect_handler() {
+    event::StaDisconnected::update_handler(|evt| {
+        let reason = evt.reason() as u16;
+        let report = DisconnectReport {
+            bssid: evt.bssid(),
+            reason,
+            category: DisconnectCategory::from_reason(reason),
+            rssi: evt.rssi(),
+        };
+        info!(
+            "Disconnected from {:02x}: reason {} ({:?})",
+            Bytes(&report.bssid),
+            report.reason,
+            report.category
+        );
+        eventlog::record(Event::Disconnected {
+            bssid: report.bssid,
+            reason: report.reason,
+        });
+        LAST_DISCONNECT.lock(|l| l.set(Some(report)));
+        DISCONNECT_STREAK.lock(|s| s.set(s.get().saturating_add(1)));
+        telemetry::publish(TelemetryEvent::Disconnected(report));
+    });
+}
@@ -0,0 +1,128 @@
+use core::{cell::Cell, fmt};
+
+use alloc::boxed::Box;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_radio::wifi::{AuthMethod, EapClientConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::WifiConfig;
+
+/// label of the data partition holding the enterprise credential
+pub const EAP_PARTITION: &str = "eap";
+/// where the certificates start in the partition, the record sits before them
+pub const EAP_BLOBS_ADDR: u32 = 4096;
+
+/// how we prove who we are to the RADIUS server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EapMethod {
+    // username and password inside a TLS tunnel
+    Peap {
+        username: heapless::String<64>,
+        password: heapless::String<64>,
+    },
+    // a client certificate and key, no password
+    Tls,
+}
+
+/// the record at the start of the "eap" partition. The blobs follow back to
+/// back from EAP_BLOBS_ADDR in this order, a length of 0 means absent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnterpriseRecord {
+    pub ssid: heapless::String<32>,
+    // outer identity, sent in the clear
+    pub identity: heapless::String<64>,
+    pub method: EapMethod,
+    pub ca_cert_len: u32,
+    pub client_cert_len: u32,
+    pub client_key_len: u32,
+}
+
+/// a WPA2-Enterprise network, loaded from flash at boot
+#[derive(Debug, Clone)]
+pub struct EnterpriseCredential {
+    pub record: EnterpriseRecord,
+    // PEM or DER, checks the server. Without one any server is believed
+    pub ca_cert: Option<&'static [u8]>,
+    pub client_cert: Option<&'static [u8]>,
+    pub client_key: Option<&'static [u8]>,
+}
+
+// secrets stay out of the logs
+#[cfg(feature = "defmt")]
+impl defmt::Format for EnterpriseCredential {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{} {} as {}, CA {}",
+            self.record.ssid.as_str(),
+            self.method_name(),
+            self.record.identity.as_str(),
+            self.ca_cert.is_some()
+        )
+    }
+}
+
+impl fmt::Display for EnterpriseCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} as {}, CA {}",
+            self.record.ssid.as_str(),
+            self.method_name(),
+            self.record.identity.as_str(),
+            self.ca_cert.is_some()
+        )
+    }
+}
+
+impl EnterpriseCredential {
+    fn method_name(&self) -> &'static str {
+        match self.record.method {
+            EapMethod::Peap { .. } => "PEAP",
+            EapMethod::Tls => "TLS",
+        }
+    }
+
+    pub fn ssid(&'static self) -> &'static str {
+        self.record.ssid.as_str()
+    }
+
+    /// the EAP config for `wifi`, a candidate on this network
+    pub fn eap_config(&self, wifi: &WifiConfig) -> EapClientConfig {
+        let mut config = EapClientConfig::default()
+            .with_ssid(self.record.ssid.as_str().into())
+            .with_bssid(wifi.bssid)
+            .with_auth_method(AuthMethod::Wpa2Enterprise)
+            .with_identity(self.record.identity.as_str().into());
+        if let EapMethod::Peap { username, password } = &self.record.method {
+            config = config
+                .with_username(username.as_str().into())
+                .with_password(password.as_str().into());
+        }
+        if let Some(ca_cert) = self.ca_cert {
+            config = config.with_ca_cert(ca_cert);
+        }
+        if let (Some(cert), Some(key)) = (self.client_cert, self.client_key) {
+            config = config.with_certificate_and_key((cert, key, None));
+        }
+        match wifi.channel {
+            0 => config,
+            channel => config.with_channel(channel),
+        }
+    }
+}
+
+static ENTERPRISE: Mutex<CriticalSectionRawMutex, Cell<Option<&'static EnterpriseCredential>>> =
+    Mutex::new(Cell::new(None));
+
+/// make `credential` a known network, called once with what persistence loaded
+pub fn set_enterprise_credential(credential: EnterpriseCredential) {
+    info!("Enterprise network {}", credential);
+    let credential: &'static EnterpriseCredential = Box::leak(Box::new(credential));
+    ENTERPRISE.lock(|e| e.set(Some(credential)));
+}
+
+/// the enterprise network, if one was provisioned
+pub fn enterprise_credential() -> Option<&'static EnterpriseCredential> {
+    ENTERPRISE.lock(|e| e.get())
+}
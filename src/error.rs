@@ -0,0 +1,65 @@
+use core::fmt;
+
+use esp_radio::wifi::WifiError;
+
+use crate::{ScanError, codec::CodecError, health::ProbeError, persistence::StoreError};
+
+/// what the library's public API fails with. Each variant keeps the error of
+/// the layer that failed, so callers can still match on the detail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Scan(ScanError),
+    // the driver refused or dropped an association
+    Connect(WifiError),
+    // a flash record couldn't be read, found or written
+    Persistence(StoreError),
+    // a record didn't encode, or what's on flash didn't decode
+    Serialization(CodecError),
+    // DNS, TCP or TLS towards the internet
+    Net(ProbeError),
+}
+
+impl From<ScanError> for Error {
+    fn from(e: ScanError) -> Self {
+        Error::Scan(e)
+    }
+}
+
+impl From<WifiError> for Error {
+    fn from(e: WifiError) -> Self {
+        Error::Connect(e)
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Self {
+        Error::Persistence(e)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<ProbeError> for Error {
+    fn from(e: ProbeError) -> Self {
+        Error::Net(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Scan(e) => write!(f, "scan failed: {:?}", e),
+            Error::Connect(e) => write!(f, "connect failed: {:?}", e),
+            Error::Persistence(e) => write!(f, "persistence failed: {:?}", e),
+            Error::Serialization(e) => write!(f, "{}", e),
+            Error::Net(e) => write!(f, "network failed: {:?}", e),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
@@ -0,0 +1,165 @@
+use core::cell::RefCell;
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, with_timeout};
+use esp_hal::rom::crc::crc32_le;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    bootguard::mark_deliberate_reset,
+    codec::{Codec, DefaultCodec},
+    persistence::{FLUSH, FLUSHED},
+    sntp::epoch_secs,
+};
+
+/// label of the data partition the log is dumped to
+pub const EVENTLOG_PARTITION: &str = "postmortem";
+/// entries kept, the oldest go first
+pub const EVENT_LOG_LEN: usize = 32;
+/// room for a framed dump of a full log
+pub const EVENTLOG_DUMP_LEN: usize = 2048;
+// "EVLG", a dump this firmware wrote
+const DUMP_MAGIC: u32 = 0x4556_4c47;
+// magic, payload length, crc
+const HEADER_LEN: usize = 10;
+// how long a deliberate reset waits for the dump
+const DUMP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// why we went down on purpose, or didn't
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetCause {
+    Panic,
+    // booting a new image
+    Ota,
+    // back to the previous image
+    Rollback,
+    // a task stopped checking in, see watchdog
+    Watchdog,
+}
+
+/// what the connection manager did
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    ScanStarted,
+    // triage picked it for an association attempt
+    CandidateChosen { bssid: [u8; 6], rssi: i8 },
+    // the driver's reason if it gave one, None for a connect timeout
+    ConnectFailed { bssid: [u8; 6], reason: Option<u16> },
+    Disconnected { bssid: [u8; 6], reason: u16 },
+    GotIp([u8; 4]),
+    Reset(ResetCause),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    pub uptime_ms: u64,
+    // None until SNTP has synced
+    pub unix_secs: Option<u64>,
+    pub event: Event,
+}
+
+pub type EventLog = heapless::Vec<Entry, EVENT_LOG_LEN>;
+
+static LOG: Mutex<CriticalSectionRawMutex, RefCell<heapless::Deque<Entry, EVENT_LOG_LEN>>> =
+    Mutex::new(RefCell::new(heapless::Deque::new()));
+// what the boot before this one dumped
+static PREVIOUS: Mutex<CriticalSectionRawMutex, RefCell<EventLog>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// write the event log at the next flush, see reset()
+pub static DUMP_EVENTLOG: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// append `event`, dropping the oldest once full
+pub fn record(event: Event) {
+    let entry = Entry {
+        uptime_ms: Instant::now().as_millis(),
+        unix_secs: epoch_secs(),
+        event,
+    };
+    LOG.lock(|log| {
+        // a panic while recording must not panic again
+        let Ok(mut log) = log.try_borrow_mut() else {
+            return;
+        };
+        if log.is_full() {
+            log.pop_front();
+        }
+        let _ = log.push_back(entry);
+    });
+}
+
+/// this boot's events, oldest first
+pub fn events() -> EventLog {
+    LOG.lock(|log| log.borrow().iter().copied().collect())
+}
+
+/// the events the previous boot dumped, empty if it didn't
+pub fn previous_boot() -> EventLog {
+    PREVIOUS.lock(|p| p.borrow().clone())
+}
+
+/// called once with what persistence loaded
+pub fn set_previous_boot(events: EventLog) {
+    info!("Previous boot logged {} events", events.len());
+    for entry in &events {
+        info!("  {:?}", entry);
+    }
+    PREVIOUS.lock(|p| *p.borrow_mut() = events);
+}
+
+/// the log framed for flash into `bytes`, returns the length
+pub fn encode_dump(bytes: &mut [u8; EVENTLOG_DUMP_LEN]) -> Option<usize> {
+    // encoded in place, a copy of the log would be another 1.5 KB of stack on
+    // the panic path. Deque and Vec encode alike, decode_dump reads a Vec
+    LOG.lock(|log| frame(&*log.try_borrow().ok()?, DUMP_MAGIC, bytes))
+}
+
+/// the log in a dump read back from flash, None if there isn't one
+pub fn decode_dump(bytes: &[u8; EVENTLOG_DUMP_LEN]) -> Option<EventLog> {
+    unframe(bytes, DUMP_MAGIC)
+}
+
+// `value` behind magic, length and crc, like the RTC wake cache. Returns the
+// framed length
+pub(crate) fn frame<T: Serialize>(value: &T, magic: u32, bytes: &mut [u8]) -> Option<usize> {
+    let (header, payload) = bytes.split_at_mut(HEADER_LEN);
+    let len = DefaultCodec::encode(value, payload).ok()?.len();
+    let crc = crc32_le(0, &payload[..len]);
+    header[0..4].copy_from_slice(&magic.to_le_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    header[6..10].copy_from_slice(&crc.to_le_bytes());
+    Some(HEADER_LEN + len)
+}
+
+pub(crate) fn unframe<T: DeserializeOwned>(bytes: &[u8], magic: u32) -> Option<T> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[6..10].try_into().unwrap());
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if header[0..4] != magic.to_le_bytes() || crc32_le(0, payload) != crc {
+        return None;
+    }
+    DefaultCodec::decode(payload).ok()
+}
+
+/// dumps the log and pending stores to flash through persistence, then resets
+pub async fn reset(cause: ResetCause) -> ! {
+    record(Event::Reset(cause));
+    DUMP_EVENTLOG.signal(());
+    FLUSHED.reset();
+    FLUSH.signal(());
+    if with_timeout(DUMP_TIMEOUT, FLUSHED.wait()).await.is_err() {
+        info!("Persistence didn't flush, resetting anyway");
+    }
+    // a watchdog reset still counts towards the boot-loop guard
+    if cause != ResetCause::Watchdog {
+        mark_deliberate_reset();
+    }
+    esp_hal::system::software_reset()
+}
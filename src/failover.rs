@@ -0,0 +1,153 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Output;
+
+use crate::{
+    status::{is_stopped, secs_since_last_probe},
+    telemetry::{self, TelemetryEvent},
+};
+
+/// a secondary uplink (e.g. an LTE modem) the product can fall back to
+#[allow(async_fn_in_trait)]
+pub trait Backhaul {
+    /// bring the backup link up, called when WiFi is declared offline
+    async fn enable(&mut self);
+    /// take it down again, called once WiFi recovered
+    async fn disable(&mut self);
+}
+
+/// a backhaul powered by an enable pin, high = on
+pub struct GpioBackhaul {
+    pub enable_pin: Output<'static>,
+}
+
+impl Backhaul for GpioBackhaul {
+    async fn enable(&mut self) {
+        self.enable_pin.set_high();
+    }
+    async fn disable(&mut self) {
+        self.enable_pin.set_low();
+    }
+}
+
+/// for products that react to FAILOVER events themselves
+pub struct NoBackhaul;
+
+impl Backhaul for NoBackhaul {
+    async fn enable(&mut self) {}
+    async fn disable(&mut self) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FailoverEvent {
+    // WiFi has been offline for FailoverPolicy::offline_after
+    FailoverRequested,
+    // WiFi passed health checks for FailoverPolicy::recover_after, back to WiFi
+    FailbackRequested,
+}
+
+const FAILOVER_SUBSCRIBERS: usize = 4;
+
+/// subscribe to hear when to switch uplinks
+pub static FAILOVER: PubSubChannel<
+    CriticalSectionRawMutex,
+    FailoverEvent,
+    2,
+    FAILOVER_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FailoverPolicy {
+    // how long without a successful internet probe before failing over
+    pub offline_after: Duration,
+    // how long the probe must keep succeeding before failing back
+    pub recover_after: Duration,
+    // how often the coordinator looks at the health signals
+    pub poll_interval: Duration,
+}
+
+impl FailoverPolicy {
+    pub const fn new() -> Self {
+        return Self {
+            offline_after: Duration::from_secs(5 * 60),
+            recover_after: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(5),
+        };
+    }
+    pub const fn with_offline_after(mut self, offline_after: Duration) -> Self {
+        self.offline_after = offline_after;
+        self
+    }
+    pub const fn with_recover_after(mut self, recover_after: Duration) -> Self {
+        self.recover_after = recover_after;
+        self
+    }
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// watches the WiFi health signals and switches `backhaul` on while WiFi is
+/// offline, publishing each decision on FAILOVER and to telemetry
+pub async fn run_failover<B: Backhaul>(backhaul: &mut B, policy: FailoverPolicy) -> ! {
+    let publisher = FAILOVER.immediate_publisher();
+    let mut failed_over = false;
+    // when the probe started succeeding again while failed over
+    let mut healthy_since: Option<Instant> = None;
+    // when the radio came back after WifiManager::stop
+    let mut started_at = Instant::from_ticks(0);
+    loop {
+        Timer::after(policy.poll_interval).await;
+        // WiFi was turned off on purpose, that's not an outage
+        if is_stopped() {
+            started_at = Instant::now();
+            continue;
+        }
+        // before the first success, count offline time from boot or start
+        let offline_secs = secs_since_last_probe()
+            .unwrap_or(u64::MAX)
+            .min(started_at.elapsed().as_secs());
+        let healthy = offline_secs <= policy.poll_interval.as_secs() * 3;
+
+        if !failed_over {
+            if offline_secs >= policy.offline_after.as_secs() {
+                info!("WiFi offline for {}s, failing over", offline_secs);
+                backhaul.enable().await;
+                publisher.publish_immediate(FailoverEvent::FailoverRequested);
+                telemetry::publish(TelemetryEvent::Failover(FailoverEvent::FailoverRequested));
+                failed_over = true;
+                healthy_since = None;
+            }
+            continue;
+        }
+
+        if !healthy {
+            healthy_since = None;
+            continue;
+        }
+        let since = *healthy_since.get_or_insert(Instant::now());
+        if since.elapsed() >= policy.recover_after {
+            info!("WiFi recovered, failing back");
+            backhaul.disable().await;
+            publisher.publish_immediate(FailoverEvent::FailbackRequested);
+            telemetry::publish(TelemetryEvent::Failover(FailoverEvent::FailbackRequested));
+            failed_over = false;
+        }
+    }
+}
+
+/// failover coordinator driving an optional modem enable pin
+#[embassy_executor::task]
+pub async fn failover_task(enable_pin: Option<Output<'static>>, policy: FailoverPolicy) -> ! {
+    info!("Start failover task");
+    match enable_pin {
+        Some(enable_pin) => run_failover(&mut GpioBackhaul { enable_pin }, policy).await,
+        None => run_failover(&mut NoBackhaul, policy).await,
+    }
+}
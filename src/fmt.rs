@@ -0,0 +1,101 @@
+//! the logging macros the crate uses, backed by defmt or log depending on the
+//! feature. Format strings have to suit both: `{}` only for what implements
+//! Display (numbers, strings), `{:?}` for everything else
+#![macro_use]
+#![allow(unused_macros)]
+
+use core::fmt::{self, Debug, Display, LowerHex};
+
+#[cfg(all(feature = "defmt", feature = "log"))]
+compile_error!("the defmt and log features are mutually exclusive, pick one");
+
+macro_rules! info {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "defmt")]
+            ::defmt::info!($s $(, $x)*);
+            #[cfg(feature = "log")]
+            ::log::info!($s $(, $x)*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
+
+macro_rules! error {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "defmt")]
+            ::defmt::error!($s $(, $x)*);
+            #[cfg(feature = "log")]
+            ::log::error!($s $(, $x)*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
+
+/// logs a byte slice as hex, e.g. a BSSID or a record, with `{:02x}`
+pub(crate) struct Bytes<'a>(pub &'a [u8]);
+
+impl Debug for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x?}", self.0)
+    }
+}
+
+impl Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x?}", self.0)
+    }
+}
+
+impl LowerHex for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x?}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Bytes<'_> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{:02x}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+pub(crate) use defmt::{Debug2Format, Display2Format};
+
+/// logs a value that only implements Debug
+#[cfg(not(feature = "defmt"))]
+pub(crate) struct Debug2Format<'a, T: Debug + ?Sized>(pub &'a T);
+
+#[cfg(not(feature = "defmt"))]
+impl<T: Debug + ?Sized> Debug for Debug2Format<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// logs a value that only implements Display
+#[cfg(not(feature = "defmt"))]
+pub(crate) struct Display2Format<'a, T: Display + ?Sized>(pub &'a T);
+
+#[cfg(not(feature = "defmt"))]
+impl<T: Display + ?Sized> Display for Display2Format<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// what `{:?}` accepts with the selected backend, for generic code that logs
+#[cfg(feature = "defmt")]
+pub(crate) trait Loggable: defmt::Format {}
+#[cfg(feature = "defmt")]
+impl<T: defmt::Format> Loggable for T {}
+
+/// what `{:?}` accepts with the selected backend, for generic code that logs
+#[cfg(not(feature = "defmt"))]
+pub(crate) trait Loggable: Debug {}
+#[cfg(not(feature = "defmt"))]
+impl<T: Debug> Loggable for T {}
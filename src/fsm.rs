@@ -0,0 +1,88 @@
+use embassy_time::Instant;
+
+use crate::{capture::CaptureRequest, disconnect::DisconnectCategory};
+
+/// where the wifi manager is, owned by its loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionFsm {
+    // no link, the next round scans if asked and triages the candidates
+    Disconnected,
+    // every candidate is blacklisted or on an attacked channel
+    Waiting,
+    // `bssid` is None when we joined the configured WG before any scan
+    Connected {
+        bssid: Option<[u8; 6]>,
+        since: Instant,
+    },
+    // the radio is lent to a capture, unassociated
+    Capturing(CaptureRequest),
+    // the radio is off until WifiManager::start
+    Stopped,
+}
+
+/// what a manager round ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FsmEvent {
+    Associated(Option<[u8; 6]>),
+    // triage went down the top candidates without luck
+    AssociationFailed,
+    // candidates exist but none may be tried right now
+    NothingToTry,
+    // the WG or the driver dropped us
+    LinkLost(Option<DisconnectCategory>),
+    // we dropped the link on purpose: reconnect request or roam
+    Left,
+    CaptureRequested(CaptureRequest),
+    CaptureDone,
+    StopRequested,
+    Started,
+    // the round changed nothing about the link, e.g. a scan or a preset
+    Stayed,
+}
+
+impl ConnectionFsm {
+    /// the transition table, None if `event` can't happen in this state
+    pub fn next(self, event: FsmEvent) -> Option<Self> {
+        use ConnectionFsm::*;
+        use FsmEvent::*;
+        let next = match (self, event) {
+            (Disconnected | Waiting, Associated(bssid)) => Connected {
+                bssid,
+                since: Instant::now(),
+            },
+            (Disconnected | Waiting, AssociationFailed) => Disconnected,
+            (Disconnected | Waiting, NothingToTry) => Waiting,
+            (Connected { .. }, LinkLost(_) | Left) => Disconnected,
+            (Connected { .. }, Stayed) => self,
+            (Disconnected | Waiting | Connected { .. }, CaptureRequested(request)) => {
+                Capturing(request)
+            }
+            (Capturing(_), CaptureDone) => Disconnected,
+            (Disconnected | Waiting | Connected { .. }, StopRequested) => Stopped,
+            (Stopped, Started) => Disconnected,
+            (Disconnected | Waiting, Stayed) => self,
+            _ => return None,
+        };
+        Some(next)
+    }
+
+    /// move on with `event`, logging the transition. An impossible event is
+    /// logged and ignored
+    pub fn apply(&mut self, event: FsmEvent) {
+        match self.next(event) {
+            Some(next) => {
+                if next != *self {
+                    info!("FSM {:?} --{:?}--> {:?}", *self, event, next);
+                }
+                *self = next;
+            }
+            None => info!("FSM ignoring {:?} in {:?}", event, *self),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected { .. })
+    }
+}
@@ -0,0 +1,655 @@
+use core::fmt::Write as _;
+
+use embassy_net::{
+    IpAddress, Ipv4Address, Stack,
+    dns::DnsQueryType,
+    icmp::{
+        PacketMetadata,
+        ping::{PingManager, PingParams},
+    },
+    tcp::TcpSocket,
+};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+
+use crate::{
+    CANDIDATES,
+    candidates::Outcome,
+    error::Error,
+    eventlog::{self, Event},
+    fmt::Display2Format,
+    status::{ConnectionState, link_status, record_probe_success, set_ip_state},
+};
+
+#[cfg(feature = "tls")]
+use {
+    crate::tls::{TlsBuffers, TlsSocket},
+    embedded_io_async::Read,
+};
+
+const PROBE_HOST: &str = env!("PROBE_HOST");
+const PROBE_PATH: &str = env!("PROBE_PATH");
+#[cfg(not(feature = "tls"))]
+const PROBE_PORT: u16 = 80;
+#[cfg(feature = "tls")]
+const PROBE_PORT: u16 = 443;
+
+/// endpoints a HealthCheck probes, the PROBE_HOST one included
+pub const MAX_PROBE_ENDPOINTS: usize = 3;
+
+/// one place the internet probe checks connectivity against
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProbeEndpoint {
+    // resolved through the stack's DNS servers on every probe
+    pub host: &'static str,
+    pub port: u16,
+    pub path: &'static str,
+    // the status a clean connection gets, anything else 2xx/3xx means a captive portal
+    pub expect_status: u16,
+}
+
+impl ProbeEndpoint {
+    pub const fn new(host: &'static str, port: u16, path: &'static str) -> Self {
+        return Self {
+            host,
+            port,
+            path,
+            expect_status: 204,
+        };
+    }
+    pub const fn with_expect_status(mut self, expect_status: u16) -> Self {
+        self.expect_status = expect_status;
+        self
+    }
+}
+
+/// ICMP echo next to the HTTP probe, for networks that filter outbound HTTP
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PingCheck {
+    // ping the lease's gateway every round, its RTT goes into the WG's candidate
+    pub gateway: bool,
+    // a public address whose answer counts toward the quorum like an endpoint
+    pub public: Option<[u8; 4]>,
+}
+
+impl PingCheck {
+    pub const fn new() -> Self {
+        return Self {
+            gateway: true,
+            public: None,
+        };
+    }
+    pub const fn with_gateway(mut self, gateway: bool) -> Self {
+        self.gateway = gateway;
+        self
+    }
+    pub const fn with_public(mut self, public: [u8; 4]) -> Self {
+        self.public = Some(public);
+        self
+    }
+}
+
+impl Default for PingCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// where and how the internet probe checks connectivity
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthCheck {
+    // PROBE_HOST/PROBE_PATH unless the builders change it
+    pub primary: ProbeEndpoint,
+    // further endpoints, probed after the primary
+    pub fallbacks: [Option<ProbeEndpoint>; MAX_PROBE_ENDPOINTS - 1],
+    // endpoints that must answer for the connection to count as healthy, all
+    // of them if there are fewer
+    pub quorum: u8,
+    pub timeout: Duration,
+    // None only probes over HTTP
+    pub ping: Option<PingCheck>,
+}
+
+impl HealthCheck {
+    pub const fn new() -> Self {
+        return Self {
+            primary: ProbeEndpoint::new(PROBE_HOST, PROBE_PORT, PROBE_PATH),
+            fallbacks: [None; MAX_PROBE_ENDPOINTS - 1],
+            quorum: 1,
+            timeout: Duration::from_secs(10),
+            ping: None,
+        };
+    }
+    pub const fn with_host(mut self, host: &'static str) -> Self {
+        self.primary.host = host;
+        self
+    }
+    pub const fn with_port(mut self, port: u16) -> Self {
+        self.primary.port = port;
+        self
+    }
+    pub const fn with_path(mut self, path: &'static str) -> Self {
+        self.primary.path = path;
+        self
+    }
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub const fn with_expect_status(mut self, expect_status: u16) -> Self {
+        self.primary.expect_status = expect_status;
+        self
+    }
+    pub const fn with_endpoint(mut self, endpoint: ProbeEndpoint) -> Self {
+        let mut i = 0;
+        while i < self.fallbacks.len() {
+            if self.fallbacks[i].is_none() {
+                self.fallbacks[i] = Some(endpoint);
+                return self;
+            }
+            i += 1;
+        }
+        panic!("more probe endpoints than MAX_PROBE_ENDPOINTS");
+    }
+    pub const fn with_ping(mut self, ping: PingCheck) -> Self {
+        self.ping = Some(ping);
+        self
+    }
+    pub const fn with_quorum(mut self, quorum: u8) -> Self {
+        assert!(quorum >= 1, "a quorum of 0 would pass without probing");
+        self.quorum = quorum;
+        self
+    }
+
+    /// the primary endpoint, then the fallbacks
+    pub fn endpoints(&self) -> impl Iterator<Item = &ProbeEndpoint> {
+        core::iter::once(&self.primary).chain(self.fallbacks.iter().flatten())
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProbeError {
+    // the name didn't resolve, DNS itself is broken or blocked
+    Dns,
+    // no TCP connection to the resolved address
+    Connect,
+    // the connection dropped or timed out mid request
+    Io,
+    // no verified TLS session, or no memory for one
+    Tls,
+    // the reply wasn't HTTP
+    BadResponse,
+    // a reply with an unexpected status code
+    Status(u16),
+    // no echo reply to a ping
+    Ping,
+    // the WG intercepted the probe, redirecting it or answering with its own page
+    CaptivePortal(u16),
+}
+
+/// DNS-resolves each probe endpoint in turn and sends it a HEAD request, only
+/// the expected status (204 for the usual generate_204 endpoints) means that
+/// one reached the internet. A public address to ping (PingCheck::public)
+/// counts as one more endpoint. Succeeds once `check.quorum` endpoints did, so
+/// a single endpoint being down doesn't fail the connection. Returns the status
+/// code of the first HTTP success, None if only the ping passed; otherwise a
+/// captive portal if any endpoint ran into one, else the last failure, as an
+/// `Error::Net`
+pub async fn probe(
+    stack: Stack<'_>,
+    check: &HealthCheck,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<Option<u16>, Error> {
+    probe_endpoints(stack, check, None, rx_buffer, tx_buffer).await
+}
+
+// probe, with the primary endpoint over `kept` when there is one
+async fn probe_endpoints(
+    stack: Stack<'_>,
+    check: &HealthCheck,
+    mut kept: Option<&mut KeptConnection<'_>>,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<Option<u16>, Error> {
+    let public = check.ping.and_then(|p| p.public);
+    let total = check.endpoints().count() + public.is_some() as usize;
+    // a quorum above the endpoints configured could never pass
+    let quorum = (check.quorum as usize).min(total);
+    let mut passed = 0;
+    let mut status = None;
+    let mut failure = ProbeError::Connect;
+    let mut tried = 0;
+    // stop as soon as the outcome is settled
+    let settled = |passed: usize, tried: usize| passed >= quorum || passed + total - tried < quorum;
+    for (i, endpoint) in check.endpoints().enumerate() {
+        if settled(passed, tried) {
+            break;
+        }
+        tried += 1;
+        let result = match kept.as_deref_mut() {
+            Some(kept) if i == 0 => kept.probe(endpoint, check.timeout).await,
+            _ => probe_once(stack, endpoint, check.timeout, rx_buffer, tx_buffer).await,
+        };
+        match result {
+            Ok(s) => {
+                passed += 1;
+                status.get_or_insert(s);
+            }
+            Err(e) => {
+                info!("Probe of {} failed {:?}", endpoint.host, e);
+                if !matches!(failure, ProbeError::CaptivePortal(_)) {
+                    failure = e;
+                }
+            }
+        }
+    }
+    if let Some(public) = public.filter(|_| !settled(passed, tried)) {
+        let target = Ipv4Address::from(public);
+        match ping(stack, target, check.timeout, rx_buffer, tx_buffer).await {
+            Ok(rtt) => {
+                info!(
+                    "Ping of {} took {}ms",
+                    Display2Format(&target),
+                    rtt.as_millis()
+                );
+                passed += 1;
+            }
+            Err(e) => {
+                if !matches!(failure, ProbeError::CaptivePortal(_)) {
+                    failure = e;
+                }
+            }
+        }
+    }
+    match passed >= quorum {
+        true => Ok(status),
+        false => Err(failure.into()),
+    }
+}
+
+/// one ICMP echo to `target`, the round trip time if it answered within
+/// `timeout`. Shares the probe's buffers, the two never run at once
+pub async fn ping(
+    stack: Stack<'_>,
+    target: Ipv4Address,
+    timeout: Duration,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<Duration, ProbeError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut pinger = PingManager::new(stack, &mut rx_meta, rx_buffer, &mut tx_meta, tx_buffer);
+    let mut params = PingParams::new(target);
+    params.set_count(1).set_timeout(timeout);
+    pinger.ping(&params).await.map_err(|e| {
+        info!("Ping error: {:?}", e);
+        ProbeError::Ping
+    })
+}
+
+async fn resolve(stack: Stack<'_>, host: &str) -> Result<IpAddress, ProbeError> {
+    match stack.dns_query(host, DnsQueryType::A).await {
+        Ok(addrs) => addrs.first().copied().ok_or(ProbeError::Dns),
+        Err(e) => {
+            info!("Probe dns error: {:?}", e);
+            Err(ProbeError::Dns)
+        }
+    }
+}
+
+async fn probe_once(
+    stack: Stack<'_>,
+    check: &ProbeEndpoint,
+    timeout: Duration,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<u16, ProbeError> {
+    let addr = resolve(stack, check.host).await?;
+
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(timeout));
+    if let Err(e) = socket.connect((addr, check.port)).await {
+        info!("Probe connect error: {:?}", e);
+        return Err(ProbeError::Connect);
+    }
+    #[cfg(feature = "tls")]
+    let mut buffers = TlsBuffers::alloc().ok_or(ProbeError::Tls)?;
+    #[cfg(feature = "tls")]
+    let mut socket = TlsSocket::open(socket, check.host, &mut buffers)
+        .await
+        .map_err(|_| ProbeError::Tls)?;
+
+    let request = head_request(check, false)?;
+    socket
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| ProbeError::Io)?;
+    socket.flush().await.map_err(|_| ProbeError::Io)?;
+
+    // the status line and the first headers: "HTTP/1.1 204 No Content"
+    let mut response = [0u8; 256];
+    let mut len = 0;
+    while len < response.len() {
+        match socket.read(&mut response[len..]).await {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(_) => return Err(ProbeError::Io),
+        }
+    }
+    #[cfg(feature = "tls")]
+    socket.close().await;
+    #[cfg(not(feature = "tls"))]
+    socket.close();
+
+    check_response(check, &response[..len])
+}
+
+fn head_request(
+    check: &ProbeEndpoint,
+    keep_alive: bool,
+) -> Result<heapless::String<256>, ProbeError> {
+    let connection = match keep_alive {
+        true => "keep-alive",
+        false => "close",
+    };
+    let mut request = heapless::String::new();
+    write!(
+        request,
+        "HEAD {} HTTP/1.1\r\nHost: {}\r\nConnection: {}\r\n\r\n",
+        check.path, check.host, connection
+    )
+    .map_err(|_| ProbeError::BadResponse)?;
+    Ok(request)
+}
+
+// the status line of a probe's response against what the endpoint should answer
+fn check_response(check: &ProbeEndpoint, response: &[u8]) -> Result<u16, ProbeError> {
+    let status = parse_status(response).ok_or(ProbeError::BadResponse)?;
+    match status {
+        status if status == check.expect_status => Ok(status),
+        300..=399 => {
+            if let Some(location) = find_header(response, "location") {
+                info!("Probe redirected to {}", location);
+            }
+            Err(ProbeError::CaptivePortal(status))
+        }
+        // a portal answering with its login page
+        200..=299 => Err(ProbeError::CaptivePortal(status)),
+        _ => Err(ProbeError::Status(status)),
+    }
+}
+
+/// the primary endpoint's TCP connection, kept open between rounds with HTTP
+/// keep-alive. A round is then one HEAD on it instead of a DNS lookup, a
+/// handshake and another ephemeral port. It's reopened after an error, once
+/// the server closes it, or when our address changed
+#[cfg_attr(feature = "tls", allow(dead_code))]
+struct KeptConnection<'a> {
+    stack: Stack<'a>,
+    socket: TcpSocket<'a>,
+    // our address when it was opened, None while closed
+    opened_from: Option<Ipv4Address>,
+}
+
+#[cfg_attr(feature = "tls", allow(dead_code))]
+impl<'a> KeptConnection<'a> {
+    fn new(stack: Stack<'a>, rx_buffer: &'a mut [u8], tx_buffer: &'a mut [u8]) -> Self {
+        Self {
+            stack,
+            socket: TcpSocket::new(stack, rx_buffer, tx_buffer),
+            opened_from: None,
+        }
+    }
+
+    async fn probe(&mut self, check: &ProbeEndpoint, timeout: Duration) -> Result<u16, ProbeError> {
+        let local = self.stack.config_v4().map(|c| c.address.address());
+        if self.opened_from.is_some() && self.opened_from != local {
+            self.close();
+        }
+        // an idle connection may have been dropped by the server or a NAT on
+        // the way, that earns one retry on a fresh one
+        if self.opened_from.is_some() {
+            match self.request(check, timeout).await {
+                Err(ProbeError::Io) => info!("Kept probe connection dropped, reopening"),
+                result => return result,
+            }
+        }
+        self.request(check, timeout).await
+    }
+
+    // any failure closes the connection, the next request opens a fresh one
+    async fn request(
+        &mut self,
+        check: &ProbeEndpoint,
+        timeout: Duration,
+    ) -> Result<u16, ProbeError> {
+        let result = self.try_request(check, timeout).await;
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    async fn try_request(
+        &mut self,
+        check: &ProbeEndpoint,
+        timeout: Duration,
+    ) -> Result<u16, ProbeError> {
+        if self.opened_from.is_none() {
+            let addr = resolve(self.stack, check.host).await?;
+            self.socket.set_timeout(Some(timeout));
+            if let Err(e) = self.socket.connect((addr, check.port)).await {
+                info!("Probe connect error: {:?}", e);
+                return Err(ProbeError::Connect);
+            }
+            self.opened_from = self.stack.config_v4().map(|c| c.address.address());
+        }
+        let request = head_request(check, true)?;
+        self.socket
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|_| ProbeError::Io)?;
+        self.socket.flush().await.map_err(|_| ProbeError::Io)?;
+
+        // a HEAD reply ends with its headers
+        let mut response = [0u8; 512];
+        let mut len = 0;
+        let complete = loop {
+            if response[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                break true;
+            }
+            if len == response.len() {
+                break false;
+            }
+            match self.socket.read(&mut response[len..]).await {
+                Ok(0) | Err(_) => return Err(ProbeError::Io),
+                Ok(n) => len += n,
+            }
+        };
+        let response = &response[..len];
+        // unread headers would garble the next reply
+        let closing =
+            find_header(response, "connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        if !complete || closing {
+            self.close();
+        }
+        check_response(check, response)
+    }
+
+    fn close(&mut self) {
+        self.opened_from = None;
+        self.socket.abort();
+    }
+}
+
+pub(crate) fn parse_status(response: &[u8]) -> Option<u16> {
+    let line = core::str::from_utf8(response.get(..12)?).ok()?;
+    if !line.starts_with("HTTP/1.") {
+        return None;
+    }
+    line.get(9..12)?.parse().ok()
+}
+
+// value of the first header called `name`, if it arrived within the buffer
+pub(crate) fn find_header<'a>(response: &'a [u8], name: &str) -> Option<&'a str> {
+    let response = core::str::from_utf8(response).ok()?;
+    response.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// the probe socket's buffers, 1 KiB each unless the application sizes them.
+/// Without `tls` the kept connection to the primary endpoint holds half of
+/// each, the fallback endpoints and pings share the other half
+pub struct ProbeBuffers<const RX: usize = 1024, const TX: usize = 1024> {
+    rx: [u8; RX],
+    tx: [u8; TX],
+}
+
+impl<const RX: usize, const TX: usize> ProbeBuffers<RX, TX> {
+    pub const fn new() -> Self {
+        Self {
+            rx: [0; RX],
+            tx: [0; TX],
+        }
+    }
+}
+
+impl<const RX: usize, const TX: usize> Default for ProbeBuffers<RX, TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the connectivity loop: waits for an address, then probes `check` every few
+/// seconds and drives CONNECTION_STATE between GotIp and InternetOk. A probe
+/// sent to a login page marks the current WG as a captive portal
+pub async fn run_health_checks<const RX: usize, const TX: usize>(
+    stack: Stack<'static>,
+    check: &HealthCheck,
+    buffers: &mut ProbeBuffers<RX, TX>,
+) -> ! {
+    // the address last put in the event log, the probe loop comes by often
+    let mut logged_ip = None;
+    // plain HTTP keeps the primary endpoint's connection open in half of each
+    // buffer, the fallbacks and pings get the other half. A TLS session would
+    // pin its record buffers on the heap, so it's opened per probe
+    #[cfg(not(feature = "tls"))]
+    let (mut primary, rx, tx) = {
+        let (kept_rx, rx) = buffers.rx.split_at_mut(RX / 2);
+        let (kept_tx, tx) = buffers.tx.split_at_mut(TX / 2);
+        (Some(KeptConnection::new(stack, kept_rx, kept_tx)), rx, tx)
+    };
+    #[cfg(feature = "tls")]
+    let (mut primary, rx, tx) = (
+        None::<KeptConnection>,
+        &mut buffers.rx[..],
+        &mut buffers.tx[..],
+    );
+
+    // the main loop is as follows
+    // wait for link up
+    //  when up, wait for dhcp assignment
+    //   when assigned,
+    loop {
+        if !stack.is_link_up() {
+            logged_ip = None;
+            if let Some(primary) = primary.as_mut() {
+                primary.close();
+            }
+            // wait for link up
+            Timer::after(Duration::from_millis(500)).await;
+        }
+        // link is up
+
+        'link_loop: loop {
+            if let Some(config) = stack.config_v4() {
+                info!("Got IP: {:#}", config.address);
+                set_ip_state(ConnectionState::GotIp);
+                if logged_ip != Some(config.address) {
+                    logged_ip = Some(config.address);
+                    eventlog::record(Event::GotIp(config.address.address().octets()));
+                }
+
+                'socket_loop: loop {
+                    Timer::after(Duration::from_secs(1)).await;
+                    info!(
+                        "Probing {}{}, quorum {}",
+                        check.primary.host, check.primary.path, check.quorum
+                    );
+
+                    // resolve the probe hosts and HEAD them, a quorum of 2xx means we're good
+                    let r = probe_endpoints(stack, check, primary.as_mut(), rx, tx).await;
+                    if check.ping.is_some_and(|p| p.gateway) {
+                        ping_gateway(stack, check.timeout, rx, tx).await;
+                    }
+
+                    if let Err(e) = r {
+                        info!("probe error: {:?}", e);
+                        if let Error::Net(ProbeError::CaptivePortal(_)) = e {
+                            mark_captive_portal(true).await;
+                        }
+                        set_ip_state(ConnectionState::GotIp);
+                        break 'link_loop;
+                    } else {
+                        info!("Probe succeeded");
+                        mark_captive_portal(false).await;
+                        record_probe_success();
+                        set_ip_state(ConnectionState::InternetOk);
+                    }
+                    Timer::after(Duration::from_millis(3000)).await;
+                }
+            } else {
+                info!("Waiting to get ip addr");
+
+                Timer::after(Duration::from_millis(5000)).await;
+            }
+        }
+
+        Timer::after(Duration::from_millis(500)).await;
+    }
+}
+
+// the round trip to the lease's gateway into the current WG's candidate
+async fn ping_gateway(
+    stack: Stack<'static>,
+    timeout: Duration,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) {
+    let Some(gateway) = stack.config_v4().and_then(|c| c.gateway) else {
+        return;
+    };
+    let Ok(rtt) = ping(stack, gateway, timeout, rx_buffer, tx_buffer).await else {
+        return;
+    };
+    let Some(current) = link_status().current else {
+        return;
+    };
+    let ms = rtt.as_millis().min(u16::MAX as u64) as u16;
+    CANDIDATES
+        .mark_result(current.bssid, Outcome::GatewayRtt(ms))
+        .await;
+}
+
+// flag the WG we're on, so the ranking puts captive portals last
+async fn mark_captive_portal(captive: bool) {
+    let Some(current) = link_status().current else {
+        return;
+    };
+    CANDIDATES
+        .mark_result(current.bssid, Outcome::CaptivePortal(captive))
+        .await;
+}
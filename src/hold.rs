@@ -0,0 +1,123 @@
+//! roaming holds: the application forbids scans, disconnects and flash erases
+//! for a critical window, e.g. a measurement or an actuation. What comes up
+//! meanwhile is queued: the wifi manager keeps the scans and reconnects it was
+//! asked for, persistence its writes, and they go ahead once the last hold is
+//! released. A link the WG drops still reconnects, that isn't ours to forbid
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+
+use crate::{SCAN_CMD, WIFI_REQUEST, WifiRequest, capture::CaptureRequest};
+
+// holds taken and not yet released
+static HOLDS: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+// what the wifi manager held back, handed back to it on release
+#[derive(Clone, Copy, Default)]
+struct Queued {
+    scan: bool,
+    // Forget reconnects too, so it wins over a Reconnect either way round
+    forget: bool,
+    reconnect: bool,
+    // the latest capture
+    capture: Option<CaptureRequest>,
+}
+
+static QUEUED: Mutex<CriticalSectionRawMutex, Cell<Queued>> = Mutex::new(Cell::new(Queued {
+    scan: false,
+    forget: false,
+    reconnect: false,
+    capture: None,
+}));
+
+// the last hold was released, for persistence
+static RELEASED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// a hold on roaming, see WifiManager::hold_roaming. Dropping it releases it
+#[must_use = "the hold ends when it's dropped"]
+pub struct RoamingHold(());
+
+impl RoamingHold {
+    pub(crate) fn new() -> Self {
+        let holds = HOLDS.lock(|h| {
+            let holds = h.get().saturating_add(1);
+            h.set(holds);
+            holds
+        });
+        if holds == 1 {
+            info!("Roaming held");
+        }
+        Self(())
+    }
+}
+
+impl Drop for RoamingHold {
+    fn drop(&mut self) {
+        let holds = HOLDS.lock(|h| {
+            let holds = h.get().saturating_sub(1);
+            h.set(holds);
+            holds
+        });
+        if holds > 0 {
+            return;
+        }
+        info!("Roaming released");
+        let queued = QUEUED.lock(|q| q.take());
+        if queued.scan {
+            SCAN_CMD.signal(());
+        }
+        let link = match (queued.forget, queued.reconnect) {
+            (true, _) => Some(WifiRequest::Forget),
+            (false, true) => Some(WifiRequest::Reconnect),
+            (false, false) => None,
+        };
+        // the link change first, the capture sees the WG it ends on
+        let capture = queued.capture.map(WifiRequest::Capture);
+        for request in [link, capture].into_iter().flatten() {
+            if WIFI_REQUEST.try_send(request).is_err() {
+                info!("Request queue full, dropped held {:?}", request);
+            }
+        }
+        RELEASED.signal(());
+    }
+}
+
+/// true while the application holds roaming
+pub fn roaming_held() -> bool {
+    HOLDS.lock(|h| h.get() > 0)
+}
+
+/// the wifi manager held back a scan, it's asked for on release
+pub(crate) fn queue_scan() {
+    QUEUED.lock(|q| {
+        let mut queued = q.get();
+        queued.scan = true;
+        q.set(queued);
+    });
+}
+
+/// the wifi manager held back a request, it's sent again on release. Only
+/// Reconnect, Forget and Capture are held
+pub(crate) fn queue_request(request: WifiRequest) {
+    QUEUED.lock(|q| {
+        let mut queued = q.get();
+        match request {
+            WifiRequest::Forget => queued.forget = true,
+            WifiRequest::Reconnect => queued.reconnect = true,
+            WifiRequest::Capture(capture) => queued.capture = Some(capture),
+            _ => {}
+        }
+        q.set(queued);
+    });
+}
+
+/// resolves once no hold is left, at once if there is none
+pub(crate) async fn wait_until_released() {
+    while roaming_held() {
+        RELEASED.wait().await;
+    }
+}
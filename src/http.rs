@@ -0,0 +1,457 @@
+use core::fmt::Write as _;
+
+use embassy_net::{Stack, tcp::TcpSocket};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+use serde::Serialize;
+
+use crate::{
+    CANDIDATES, WIFI_REQUEST, WifiConfig, WifiRequest,
+    bootguard::safe_mode,
+    capture::{CaptureRequest, capture_len, read_capture},
+    eventlog::{events, previous_boot},
+    json_stream::{ChunkedJson, write_chunk},
+    memory::{MemoryUsage, heap_usage, memory_usage},
+    ota::{OTA_AVAILABLE, OTA_START, OtaRequest},
+    panic::last_panic,
+    persistence::{FACTORY_RESET, FlashLatency, flash_latency},
+    roaming::RoamPreset,
+    rssi::{SignalStats, latest_rssi, signal_stats},
+    scan_seq,
+    scanner::scan_now,
+    security::rogue_count,
+    stats::stats,
+    status::{link_status, secs_since_last_probe},
+    telemetry::oom_count,
+    txpower::TxPowerProfile,
+};
+
+/// where the status API listens, advertised over mDNS
+pub const HTTP_PORT: u16 = 80;
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_BUFFER_LEN: usize = 512;
+const RESPONSE_BUFFER_LEN: usize = 1024;
+// holds one serialized element of a streamed response
+const SCRATCH_LEN: usize = 512;
+
+// the document served on /status
+#[derive(Serialize)]
+struct StatusDoc {
+    current: Option<WifiConfig>,
+    ip: Option<heapless::String<18>>,
+    uptime_secs: u64,
+    rssi: Option<i8>,
+    connects: u32,
+    connect_failures: u32,
+    disconnects: u32,
+    secs_since_probe: Option<u64>,
+    boots: u32,
+    // repeated unexpected resets, scoring and roaming are off this boot
+    safe_mode: bool,
+    flash: FlashLatency,
+    // allocation failures the firmware degraded around
+    oom_events: u32,
+    // scan hits dropped for not matching PINNED_BSSIDS
+    rogue_aps: u32,
+    // beacon RSSI of the current AP since we associated
+    signal: Option<SignalStats>,
+    // heap and core stacks, for sizing them
+    memory: MemoryUsage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+/// serves `/status`, `/candidates`, `/stats` and `/eventlog` as json so installers can
+/// check a unit from a laptop on the same network, `/metrics` in the
+/// Prometheus text format for scrapers on the LAN, `POST /scan` and
+/// `POST /reconnect` let operators nudge a stuck unit, `POST /preset/<name>`
+/// switches roaming preset, `POST /txpower/<profile>` the TX power limit,
+/// `POST /capture/<channel>/<secs>` sniffs management frames for `GET /capture`,
+/// `POST /ota/<crc32>` starts a firmware update (tls builds only) and `POST /factory-reset`
+/// wipes the flash. Runs once per stack it listens on: `sta` is the uplink
+/// reported on /status, and a `read_only` listener (the diagnostics AP)
+/// refuses everything but GET
+#[embassy_executor::task(pool_size = 2)]
+pub async fn http_task(stack: Stack<'static>, sta: Stack<'static>, read_only: bool) -> ! {
+    info!("Start http task");
+    let mut rx_buffer = [0; REQUEST_BUFFER_LEN];
+    let mut tx_buffer = [0; RESPONSE_BUFFER_LEN];
+    let mut request = [0; REQUEST_BUFFER_LEN];
+    let mut scratch = [0; SCRATCH_LEN];
+    loop {
+        stack.wait_config_up().await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(SOCKET_TIMEOUT));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            info!("HTTP accept error: {:?}", e);
+            continue;
+        }
+
+        if let Err(e) =
+            handle_connection(sta, read_only, &mut socket, &mut request, &mut scratch).await
+        {
+            info!("HTTP error: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn handle_connection(
+    stack: Stack<'static>,
+    read_only: bool,
+    socket: &mut TcpSocket<'_>,
+    request: &mut [u8],
+    scratch: &mut [u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    // read until the end of the headers, we never need a body
+    let mut len = 0;
+    while len < request.len() {
+        let n = socket.read(&mut request[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+        if request[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    let (method, path) = parse_request_line(&request[..len]);
+    info!("HTTP {:?} {}", method, path);
+    if read_only && method != Method::Get {
+        return write_response(socket, "403 Forbidden", b"{\"ok\":false}").await;
+    }
+    match (method, path) {
+        (Method::Get, "/status") => {
+            write_chunked_header(socket).await?;
+            let mut json = ChunkedJson::new(socket, scratch);
+            json.value(&status_doc(stack)).await?;
+            json.finish().await
+        }
+        (Method::Get, "/candidates") => {
+            write_chunked_header(socket).await?;
+            let mut json = ChunkedJson::new(socket, scratch);
+            stream_candidates(&mut json).await?;
+            json.finish().await
+        }
+        (Method::Get, "/stats") => {
+            write_chunked_header(socket).await?;
+            let mut json = ChunkedJson::new(socket, scratch);
+            stream_stats(&mut json).await?;
+            json.finish().await
+        }
+        (Method::Get, "/eventlog") => {
+            write_chunked_header(socket).await?;
+            let mut json = ChunkedJson::new(socket, scratch);
+            stream_eventlog(&mut json).await?;
+            json.finish().await
+        }
+        (Method::Get, "/metrics") => write_metrics(socket).await,
+        (Method::Post, "/scan") => {
+            scan_now();
+            write_response(socket, "202 Accepted", b"{\"ok\":true}").await
+        }
+        (Method::Post, "/reconnect") => {
+            let body: &[u8] = match WIFI_REQUEST.try_send(WifiRequest::Reconnect) {
+                Ok(_) => b"{\"ok\":true}",
+                Err(_) => b"{\"ok\":false}",
+            };
+            write_response(socket, "202 Accepted", body).await
+        }
+        (Method::Post, path) if path.starts_with("/preset/") => {
+            let Some(preset) = RoamPreset::from_name(&path["/preset/".len()..]) else {
+                return write_response(socket, "400 Bad Request", b"{\"ok\":false}").await;
+            };
+            let body: &[u8] = match WIFI_REQUEST.try_send(WifiRequest::SetPreset(preset)) {
+                Ok(_) => b"{\"ok\":true}",
+                Err(_) => b"{\"ok\":false}",
+            };
+            write_response(socket, "202 Accepted", body).await
+        }
+        (Method::Post, path) if path.starts_with("/txpower/") => {
+            let Some(profile) = TxPowerProfile::from_name(&path["/txpower/".len()..]) else {
+                return write_response(socket, "400 Bad Request", b"{\"ok\":false}").await;
+            };
+            let body: &[u8] = match WIFI_REQUEST.try_send(WifiRequest::SetTxPower(profile)) {
+                Ok(_) => b"{\"ok\":true}",
+                Err(_) => b"{\"ok\":false}",
+            };
+            write_response(socket, "202 Accepted", body).await
+        }
+        (Method::Post, path) if path.starts_with("/capture/") => {
+            let Some(capture) = CaptureRequest::from_path(&path["/capture/".len()..]) else {
+                return write_response(socket, "400 Bad Request", b"{\"ok\":false}").await;
+            };
+            let body: &[u8] = match WIFI_REQUEST.try_send(WifiRequest::Capture(capture)) {
+                Ok(_) => b"{\"ok\":true}",
+                Err(_) => b"{\"ok\":false}",
+            };
+            write_response(socket, "202 Accepted", body).await
+        }
+        (Method::Get, "/capture") => write_capture(socket, scratch).await,
+        (Method::Post, path) if path.starts_with("/ota/") => {
+            if !OTA_AVAILABLE {
+                return write_response(socket, "501 Not Implemented", b"{\"ok\":false}").await;
+            }
+            let Some(request) = OtaRequest::from_path(&path["/ota/".len()..]) else {
+                return write_response(socket, "400 Bad Request", b"{\"ok\":false}").await;
+            };
+            OTA_START.signal(request);
+            write_response(socket, "202 Accepted", b"{\"ok\":true}").await
+        }
+        (Method::Post, "/factory-reset") => {
+            // answer first, persistence reboots once the flash is wiped
+            write_response(socket, "202 Accepted", b"{\"ok\":true}").await?;
+            FACTORY_RESET.signal(());
+            Ok(())
+        }
+        _ => write_response(socket, "404 Not Found", b"{}").await,
+    }
+}
+
+// "GET /status HTTP/1.1" -> (Get, "/status")
+fn parse_request_line(request: &[u8]) -> (Method, &str) {
+    let line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let line = core::str::from_utf8(line).unwrap_or_default();
+    let mut parts = line.split(' ');
+    let method = match parts.next() {
+        Some("GET") => Method::Get,
+        Some("POST") => Method::Post,
+        _ => Method::Other,
+    };
+    let path = parts.next().unwrap_or_default();
+    // ignore any query string
+    let path = path.split('?').next().unwrap_or_default();
+    (method, path)
+}
+
+async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    body: &[u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut header: heapless::String<128> = heapless::String::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    socket.write_all(header.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.flush().await
+}
+
+// the last capture as a pcap file, copied out a scratch buffer at a time so
+// the capture lock is never held while the socket blocks
+async fn write_capture(
+    socket: &mut TcpSocket<'_>,
+    scratch: &mut [u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    let len = capture_len();
+    if len == 0 {
+        return write_response(socket, "404 Not Found", b"{}").await;
+    }
+    let mut header: heapless::String<160> = heapless::String::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.tcpdump.pcap\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        len
+    );
+    socket.write_all(header.as_bytes()).await?;
+    let mut offset = 0;
+    while offset < len {
+        let n = read_capture(offset, &mut scratch[..(len - offset).min(scratch.len())]);
+        if n == 0 {
+            // a new capture started underneath us
+            break;
+        }
+        socket.write_all(&scratch[..n]).await?;
+        offset += n;
+    }
+    socket.flush().await
+}
+
+// headers for a streamed json body, see ChunkedJson
+async fn write_chunked_header(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    socket
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .await
+}
+
+// one chunk per metric family, in the Prometheus text exposition format
+async fn write_metrics(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    socket
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    let mut family: heapless::String<640> = heapless::String::new();
+
+    let status = link_status();
+    let _ = write!(
+        family,
+        "# TYPE uptime_seconds gauge\nuptime_seconds {}\n\
+         # TYPE scans_total counter\nscans_total {}\n\
+         # TYPE connects_total counter\nconnects_total {}\n\
+         # TYPE disconnects_total counter\ndisconnects_total {}\n",
+        Instant::now().as_secs(),
+        scan_seq(),
+        status.connects,
+        status.disconnects,
+    );
+    write_chunk(socket, family.as_bytes()).await?;
+
+    family.clear();
+    let _ = family.push_str("# TYPE rssi_dbm gauge\n");
+    let current = status.current.as_ref().map(|c| c.bssid);
+    // the latest beacon reading, else what the scan saw
+    let rssi = match latest_rssi().filter(|(bssid, _)| Some(*bssid) == current) {
+        Some((_, rssi)) => Some(rssi),
+        None => status.current.as_ref().map(|c| c.signal_strength),
+    };
+    if let (Some(bssid), Some(rssi)) = (current, rssi) {
+        write_bssid_sample(&mut family, "rssi_dbm", &bssid, rssi);
+    }
+    write_chunk(socket, family.as_bytes()).await?;
+
+    // per AP over the device's lifetime, from the persisted tallies
+    let stats = stats();
+    family.clear();
+    let _ = family.push_str("# TYPE connect_failures_total counter\n");
+    for tally in &stats.aps {
+        write_bssid_sample(
+            &mut family,
+            "connect_failures_total",
+            &tally.bssid,
+            tally.failures,
+        );
+    }
+    write_chunk(socket, family.as_bytes()).await?;
+    family.clear();
+    let _ = family.push_str("# TYPE connect_successes_total counter\n");
+    for tally in &stats.aps {
+        write_bssid_sample(
+            &mut family,
+            "connect_successes_total",
+            &tally.bssid,
+            tally.successes,
+        );
+    }
+    write_chunk(socket, family.as_bytes()).await?;
+
+    let heap = heap_usage();
+    family.clear();
+    let _ = write!(
+        family,
+        "# TYPE heap_free_bytes gauge\nheap_free_bytes {}\n\
+         # TYPE heap_size_bytes gauge\nheap_size_bytes {}\n\
+         # TYPE heap_peak_bytes gauge\nheap_peak_bytes {}\n",
+        heap.size.saturating_sub(heap.used),
+        heap.size,
+        heap.peak,
+    );
+    write_chunk(socket, family.as_bytes()).await?;
+    socket.write_all(b"0\r\n\r\n").await?;
+    socket.flush().await
+}
+
+// `name{bssid="aa:bb:cc:dd:ee:ff"} value`
+fn write_bssid_sample(
+    out: &mut impl core::fmt::Write,
+    name: &str,
+    bssid: &[u8; 6],
+    value: impl core::fmt::Display,
+) {
+    let [a, b, c, d, e, f] = bssid;
+    let _ = writeln!(
+        out,
+        "{}{{bssid=\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\"}} {}",
+        name, a, b, c, d, e, f, value
+    );
+}
+
+fn status_doc(stack: Stack<'static>) -> StatusDoc {
+    let status = link_status();
+    let ip = stack.config_v4().map(|config| {
+        let mut ip = heapless::String::new();
+        let _ = write!(ip, "{}", config.address);
+        ip
+    });
+    StatusDoc {
+        rssi: status.current.as_ref().map(|c| c.signal_strength),
+        current: status.current,
+        ip,
+        uptime_secs: Instant::now().as_secs(),
+        connects: status.connects,
+        connect_failures: status.connect_failures,
+        disconnects: status.disconnects,
+        secs_since_probe: secs_since_last_probe(),
+        boots: stats().boots,
+        safe_mode: safe_mode(),
+        flash: flash_latency(),
+        oom_events: oom_count(),
+        rogue_aps: rogue_count(),
+        signal: signal_stats(),
+        memory: memory_usage(),
+    }
+}
+
+// one candidate at a time, the lock is never held while the socket blocks
+async fn stream_candidates<W: Write>(json: &mut ChunkedJson<'_, W>) -> Result<(), W::Error> {
+    json.begin_array().await?;
+    for i in 0.. {
+        match CANDIDATES.get(i).await {
+            Some(candidate) => json.element(&candidate).await?,
+            None => break,
+        }
+    }
+    json.end_array().await
+}
+
+// the panic and log the previous boot dumped and this boot's log so far, one
+// entry per chunk
+async fn stream_eventlog<W: Write>(json: &mut ChunkedJson<'_, W>) -> Result<(), W::Error> {
+    json.raw(b"{\"panic\":").await?;
+    json.value(&last_panic()).await?;
+    json.raw(b",\"previous\":").await?;
+    json.begin_array().await?;
+    for entry in &previous_boot() {
+        json.element(entry).await?;
+    }
+    json.end_array().await?;
+    json.raw(b",\"current\":").await?;
+    json.begin_array().await?;
+    for entry in &events() {
+        json.element(entry).await?;
+    }
+    json.end_array().await?;
+    json.raw(b"}").await
+}
+
+// the lifetime statistics, one AP tally per chunk
+async fn stream_stats<W: Write>(json: &mut ChunkedJson<'_, W>) -> Result<(), W::Error> {
+    let stats = stats();
+    let mut head: heapless::String<64> = heapless::String::new();
+    let _ = write!(
+        head,
+        "{{\"boots\":{},\"disconnects\":{},\"aps\":",
+        stats.boots, stats.disconnects
+    );
+    json.raw(head.as_bytes()).await?;
+    json.begin_array().await?;
+    for tally in &stats.aps {
+        json.element(tally).await?;
+    }
+    json.end_array().await?;
+    json.raw(b"}").await
+}
@@ -0,0 +1,123 @@
+//! field feedback on the board's status LED: it follows CONNECTION_STATE, so
+//! an installer can tell how far a unit got without any tooling
+
+use embassy_futures::select::select;
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::Output;
+
+use crate::{
+    scanner::{SCANNING_CHANGED, scanning},
+    status::{CONNECTION_STATE, ConnectionState, connection_state},
+};
+
+// how often a steady LED looks at the state again without a receiver
+const POLL: Duration = Duration::from_millis(100);
+
+/// what the LED does in one state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LedPattern {
+    Off,
+    // on and off for this long each
+    Blink(Duration),
+    Solid,
+}
+
+/// the pattern for each stage of the connection
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Indicator {
+    // disconnected, or a scan has the radio
+    pub scanning: LedPattern,
+    // associating, associated or waiting for the probe
+    pub connecting: LedPattern,
+    // the internet probe got through
+    pub online: LedPattern,
+    // the radio is off on purpose, see WifiManager::stop
+    pub stopped: LedPattern,
+}
+
+impl Indicator {
+    pub const fn new() -> Self {
+        return Self {
+            scanning: LedPattern::Blink(Duration::from_millis(100)),
+            connecting: LedPattern::Blink(Duration::from_millis(500)),
+            online: LedPattern::Solid,
+            stopped: LedPattern::Off,
+        };
+    }
+    pub const fn with_scanning(mut self, scanning: LedPattern) -> Self {
+        self.scanning = scanning;
+        self
+    }
+    pub const fn with_connecting(mut self, connecting: LedPattern) -> Self {
+        self.connecting = connecting;
+        self
+    }
+    pub const fn with_online(mut self, online: LedPattern) -> Self {
+        self.online = online;
+        self
+    }
+    pub const fn with_stopped(mut self, stopped: LedPattern) -> Self {
+        self.stopped = stopped;
+        self
+    }
+
+    /// the pattern for `state`, a running scan takes precedence
+    pub fn pattern(&self, state: Option<ConnectionState>, scanning: bool) -> LedPattern {
+        match state {
+            Some(ConnectionState::Stopped) => self.stopped,
+            _ if scanning => self.scanning,
+            None | Some(ConnectionState::Disconnected) => self.scanning,
+            Some(ConnectionState::InternetOk) => self.online,
+            Some(_) => self.connecting,
+        }
+    }
+}
+
+impl Default for Indicator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// drives `led` (high = on) with `indicator`'s pattern for the current
+/// connection state. It holds a CONNECTION_STATE receiver for good: a steady
+/// LED waits for the state or a scan to change, a blinking one shows a change
+/// on its next edge
+#[embassy_executor::task]
+pub async fn indicator_task(mut led: Output<'static>, indicator: Indicator) -> ! {
+    info!("Start indicator task");
+    let mut receiver = CONNECTION_STATE.receiver();
+    loop {
+        let pattern = indicator.pattern(connection_state(), scanning());
+        let period = match pattern {
+            LedPattern::Off => {
+                led.set_low();
+                None
+            }
+            LedPattern::Solid => {
+                led.set_high();
+                None
+            }
+            LedPattern::Blink(period) => {
+                led.toggle();
+                Some(period)
+            }
+        };
+        if let Some(period) = period {
+            Timer::after(period).await;
+            continue;
+        }
+        let changed = async {
+            match receiver.as_mut() {
+                Some(receiver) => {
+                    receiver.changed().await;
+                }
+                // every slot taken, poll instead
+                None => Timer::after(POLL).await,
+            }
+        };
+        select(changed, SCANNING_CHANGED.wait()).await;
+    }
+}
@@ -0,0 +1,77 @@
+use core::fmt::Write as _;
+
+use embedded_io_async::Write;
+use serde::Serialize;
+
+/// one HTTP chunk: "<len hex>\r\n<data>\r\n", nothing for empty `data`
+pub async fn write_chunk<W: Write>(out: &mut W, data: &[u8]) -> Result<(), W::Error> {
+    if data.is_empty() {
+        // a zero length chunk would end the response
+        return Ok(());
+    }
+    let mut size: heapless::String<10> = heapless::String::new();
+    let _ = write!(size, "{:x}\r\n", data.len());
+    out.write_all(size.as_bytes()).await?;
+    out.write_all(data).await?;
+    out.write_all(b"\r\n").await
+}
+
+/// writes a JSON document as HTTP/1.1 chunks, one value at a time, so a
+/// response never has to fit in RAM, only its largest element has to fit
+/// `scratch`
+pub struct ChunkedJson<'a, W: Write> {
+    out: &'a mut W,
+    scratch: &'a mut [u8],
+    // no comma before the first element of an array
+    first: bool,
+}
+
+impl<'a, W: Write> ChunkedJson<'a, W> {
+    pub fn new(out: &'a mut W, scratch: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            scratch,
+            first: true,
+        }
+    }
+
+    /// literal JSON, e.g. a `{"key":` prefix
+    pub async fn raw(&mut self, json: &[u8]) -> Result<(), W::Error> {
+        write_chunk(self.out, json).await
+    }
+
+    /// a complete value, serialized through the scratch buffer
+    pub async fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), W::Error> {
+        match serde_json_core::to_slice(value, self.scratch) {
+            Ok(len) => write_chunk(self.out, &self.scratch[..len]).await,
+            Err(_) => {
+                info!("JSON value larger than {} bytes", self.scratch.len());
+                self.raw(b"null").await
+            }
+        }
+    }
+
+    pub async fn begin_array(&mut self) -> Result<(), W::Error> {
+        self.first = true;
+        self.raw(b"[").await
+    }
+
+    /// the next array element, comma separated
+    pub async fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), W::Error> {
+        if !self.first {
+            self.raw(b",").await?;
+        }
+        self.first = false;
+        self.value(value).await
+    }
+
+    pub async fn end_array(&mut self) -> Result<(), W::Error> {
+        self.raw(b"]").await
+    }
+
+    /// the terminating zero length chunk
+    pub async fn finish(self) -> Result<(), W::Error> {
+        self.out.write_all(b"0\r\n\r\n").await?;
+        self.out.flush().await
+    }
+}
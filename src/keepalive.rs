@@ -0,0 +1,105 @@
+//! link supervision: pings the default gateway, so a WG whose association
+//! stays up after its router died is noticed long before the internet probe
+//! gives up on it
+
+use embassy_net::Stack;
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    WIFI_REQUEST, WifiRequest, fmt::Display2Format, health::ping, manager::DISCONNECT_DETECTED,
+};
+
+// an echo request and its reply, header and payload
+const PING_BUFFER_LEN: usize = 64;
+
+/// how the gateway keepalive pings
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Keepalive {
+    // time between pings
+    pub interval: Duration,
+    // a reply later than this is a miss
+    pub timeout: Duration,
+    // misses in a row before the link is given up
+    pub max_misses: u8,
+}
+
+impl Keepalive {
+    pub const fn new() -> Self {
+        return Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(2),
+            max_misses: 3,
+        };
+    }
+    pub const fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub const fn with_max_misses(mut self, max_misses: u8) -> Self {
+        self.max_misses = max_misses;
+        self
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// pings the gateway of the current lease every `keepalive.interval`. After
+/// `max_misses` unanswered pings in a row it raises DISCONNECT_DETECTED and
+/// asks the manager to reconnect, the association alone doesn't mean the WG
+/// routes anything
+#[embassy_executor::task]
+pub async fn keepalive_task(stack: Stack<'static>, keepalive: Keepalive) -> ! {
+    info!("Start keepalive task");
+    let mut rx_buffer = [0; PING_BUFFER_LEN];
+    let mut tx_buffer = [0; PING_BUFFER_LEN];
+    let mut misses = 0u8;
+    loop {
+        stack.wait_config_up().await;
+        Timer::after(keepalive.interval).await;
+        // a static config without a gateway has nothing to supervise
+        let Some(gateway) = stack.config_v4().and_then(|c| c.gateway) else {
+            misses = 0;
+            continue;
+        };
+        match ping(
+            stack,
+            gateway,
+            keepalive.timeout,
+            &mut rx_buffer,
+            &mut tx_buffer,
+        )
+        .await
+        {
+            Ok(_) => misses = 0,
+            Err(e) => {
+                misses += 1;
+                info!(
+                    "Gateway {} missed ping {}/{} {:?}",
+                    Display2Format(&gateway),
+                    misses,
+                    keepalive.max_misses,
+                    e
+                );
+            }
+        }
+        if misses >= keepalive.max_misses {
+            info!(
+                "Gateway {} unreachable, dropping the link",
+                Display2Format(&gateway)
+            );
+            misses = 0;
+            DISCONNECT_DETECTED.signal(());
+            // the queue only fills up if the manager is stuck, the watchdog handles that
+            let _ = WIFI_REQUEST.try_send(WifiRequest::Reconnect);
+        }
+    }
+}
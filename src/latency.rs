@@ -0,0 +1,65 @@
+use embassy_time::Duration;
+use serde::Serialize;
+
+// samples kept per window, p95 is taken over these
+const WINDOW_LEN: usize = 32;
+
+/// the most recent durations of some operation, in microseconds
+#[derive(Debug, Clone)]
+pub struct LatencyWindow {
+    samples: [u32; WINDOW_LEN],
+    len: usize,
+    next: usize,
+    // over the whole uptime, not just the window
+    max_us: u32,
+}
+
+/// what gets reported about a LatencyWindow
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LatencySummary {
+    pub samples: u32,
+    pub p95_us: u32,
+    pub max_us: u32,
+}
+
+impl LatencyWindow {
+    pub const fn new() -> Self {
+        return Self {
+            samples: [0; WINDOW_LEN],
+            len: 0,
+            next: 0,
+            max_us: 0,
+        };
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let us = duration.as_micros().min(u32::MAX as u64) as u32;
+        self.samples[self.next] = us;
+        self.next = (self.next + 1) % WINDOW_LEN;
+        self.len = (self.len + 1).min(WINDOW_LEN);
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut sorted = self.samples;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        // nearest rank
+        let p95_us = match self.len {
+            0 => 0,
+            len => sorted[(len * 95).div_ceil(100) - 1],
+        };
+        LatencySummary {
+            samples: self.len as u32,
+            p95_us,
+            max_us: self.max_us,
+        }
+    }
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
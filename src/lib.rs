@@ -1,33 +1,343 @@
 #![no_std]
+#![deny(
+    clippy::await_holding_lock,
+    clippy::await_holding_invalid_type,
+    reason = "an async mutex guard held across an await blocks every other task on that lock \
+    until the await completes, the embassy guard types are listed in clippy.toml"
+)]
 
-use core::{cell::RefCell, cmp::Ordering};
-
-use alloc::{
-    borrow::ToOwned,
-    string::{String, ToString},
-    vec::Vec,
-};
-use defmt::{Format, info};
+use alloc::string::{String, ToString};
+use core::cell::{Cell, RefCell};
 use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
-    mutex::Mutex,
+    blocking_mutex::{
+        Mutex,
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+    },
+    channel::Channel,
     signal::Signal,
 };
-use embassy_time::{Delay, Duration, Timer};
-use esp_radio::wifi::{AccessPointInfo, ClientConfig, ScanConfig, WifiController};
+use embassy_time::Delay;
+use esp_radio::wifi::{AuthMethod, ClientConfig, ModeConfig};
 use serde::{Deserialize, Serialize};
 
+use crate::candidates::CandidateStore;
+use crate::capture::CaptureRequest;
+use crate::enterprise::{EnterpriseCredential, enterprise_credential};
+use crate::hold::RoamingHold;
+use crate::roaming::RoamPreset;
+use crate::txpower::TxPowerProfile;
+
+// must come first so the other modules see its logging macros
+pub(crate) mod fmt;
+
+pub mod beacons;
+pub mod blacklist;
+pub mod board;
+pub mod bootguard;
+pub mod button;
+pub mod calendar;
+pub mod candidates;
+pub mod capture;
+pub mod chip;
+pub mod codec;
+pub mod console;
+pub mod diagnostics;
+pub mod disconnect;
+pub mod enterprise;
+pub mod error;
+pub mod eventlog;
+pub mod failover;
+pub mod fsm;
+pub mod health;
+pub mod hold;
+pub mod http;
+#[cfg(feature = "indicator")]
+pub mod indicator;
+pub mod json_stream;
+pub mod keepalive;
+pub mod latency;
+#[cfg(any(feature = "netlog", feature = "rpc"))]
+pub mod logring;
+pub mod mac;
+pub mod manager;
+pub mod mdns;
+pub mod memory;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(any(feature = "esp32", feature = "esp32s3"))]
+pub mod multicore;
+pub mod neighbors;
+pub mod netconfig;
+#[cfg(feature = "netlog")]
+pub mod netlog;
+pub mod nvs;
+pub mod ota;
+pub mod panic;
+#[cfg(feature = "espnow")]
+pub mod peers;
 pub mod persistence;
+pub mod realtime;
+pub mod roaming;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod rssi;
+pub mod scanner;
+pub mod scoring;
+pub mod secret;
+pub mod security;
+pub mod sleep;
+pub mod sntp;
+pub mod stats;
+pub mod status;
+pub mod telemetry;
+pub mod timeseries;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod txpower;
+pub mod watchdog;
+
+pub use scanner::{
+    ScanError, ScanMode, ScanObserver, ScanOptions, scan_and_score_wgs, scan_channel, scan_seq,
+    set_scan_observer,
+};
+pub use scoring::{
+    MAX_MISSED_SCANS, fast_transition, latency_scoring, preferred_band, set_fast_transition,
+    set_latency_scoring, set_preferred_band,
+};
 extern crate alloc;
 
+/// ask the wifi manager to scan, it clears the signal when it starts scanning
+pub static SCAN_CMD: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// commands for the wifi manager that need the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WifiRequest {
+    // drop the current association and connect to the best candidate again
+    Reconnect,
+    // like Reconnect, and keep the current AP out of the ranking for a while
+    Forget,
+    // switch roaming preset, applied straight away and persisted
+    SetPreset(RoamPreset),
+    // drop the association and sniff management frames, see capture.rs
+    Capture(CaptureRequest),
+    // change the transmit power limit, applied straight away and persisted
+    SetTxPower(TxPowerProfile),
+    // disconnect, flush persistence and turn the radio off, see WifiManager
+    Stop,
+    // turn the radio back on after Stop and reconnect
+    Start,
+}
+
+/// queue of requests handled by the wifi manager
+pub static WIFI_REQUEST: Channel<CriticalSectionRawMutex, WifiRequest, 4> = Channel::new();
+
+/// acks WifiRequest::Stop once the radio is off and flash is quiet
+pub static WIFI_STOPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// the application's handle on the wifi subsystem, e.g. around deep sleep
+pub struct WifiManager;
+
+impl WifiManager {
+    /// disconnect cleanly, write any pending persistence and stop the radio.
+    /// Resolves once it's safe to power down, the wifi tasks stay parked
+    /// until `start`
+    pub async fn stop() {
+        WIFI_STOPPED.reset();
+        WIFI_REQUEST.send(WifiRequest::Stop).await;
+        WIFI_STOPPED.wait().await;
+    }
+
+    /// turn the radio back on, the manager reconnects to the best candidate
+    pub async fn start() {
+        WIFI_REQUEST.send(WifiRequest::Start).await;
+    }
+
+    /// no scans, roams, reconnects or flash writes until the hold is released
+    /// or dropped, e.g. around a measurement. Holds nest, what they held back
+    /// goes ahead once the last one ends
+    pub fn hold_roaming() -> RoamingHold {
+        RoamingHold::new()
+    }
+
+    /// end a hold taken with `hold_roaming`, same as dropping it
+    pub fn release_roaming(hold: RoamingHold) {
+        drop(hold);
+    }
+}
+
+/// the ranked scan results, best first
+pub static CANDIDATES: CandidateStore = CandidateStore::new();
+
+/// the most candidates the store can keep, the weakest make room.
+/// `ScanOptions::with_max_candidates` lowers the cap at runtime
+pub const MAX_CANDIDATES: usize = 16;
+
+static CANDIDATE_CAP: Mutex<CriticalSectionRawMutex, Cell<usize>> =
+    Mutex::new(Cell::new(MAX_CANDIDATES));
+
+/// candidates the store keeps, MAX_CANDIDATES unless the manager was given
+/// less in `ScanOptions::max_candidates`
+pub fn candidate_cap() -> usize {
+    CANDIDATE_CAP.lock(|c| c.get())
+}
+
+/// set by the wifi manager at start, clamped to 1..=MAX_CANDIDATES. A store
+/// already holding more sheds its weakest on the next change
+pub fn set_candidate_cap(cap: usize) {
+    CANDIDATE_CAP.lock(|c| c.set(cap.clamp(1, MAX_CANDIDATES)))
+}
+
+/// the candidate table, on the heap by default
+#[cfg(not(feature = "heapless-core"))]
+pub type CandidateList = alloc::vec::Vec<WifiConfig>;
+/// the candidate table, fixed capacity so the connection manager never allocates
+#[cfg(feature = "heapless-core")]
+pub type CandidateList = heapless::Vec<WifiConfig, MAX_CANDIDATES>;
+
+/// an empty table with room for `cap` candidates, filling it up to `cap`
+/// never reallocates. Short of memory it starts empty and grows as it's filled
+#[cfg(not(feature = "heapless-core"))]
+pub fn candidate_list(cap: usize) -> CandidateList {
+    let mut list = CandidateList::new();
+    let _ = list.try_reserve_exact(cap.min(MAX_CANDIDATES));
+    list
+}
+/// an empty table, fixed capacity already
+#[cfg(feature = "heapless-core")]
+pub fn candidate_list(_cap: usize) -> CandidateList {
+    CandidateList::new()
+}
+
+/// adds `wifi` to the table, false if there was no room for it
+#[cfg(not(feature = "heapless-core"))]
+pub fn try_push_candidate(list: &mut CandidateList, wifi: WifiConfig) -> bool {
+    if list.try_reserve(1).is_err() {
+        return false;
+    }
+    list.push(wifi);
+    true
+}
+/// adds `wifi` to the table, false if there was no room for it
+#[cfg(feature = "heapless-core")]
+pub fn try_push_candidate(list: &mut CandidateList, wifi: WifiConfig) -> bool {
+    list.push(wifi).is_ok()
+}
+
+/// adds `wifi`, or once the table holds `cap` (or is out of memory) puts it
+/// in place of the entry ranked lowest by `WifiConfig`'s Ord if `wifi` ranks
+/// higher, so the table keeps the best `cap` by score. False if a WG was left
+/// out either way
+pub fn push_or_evict(list: &mut CandidateList, wifi: WifiConfig, cap: usize) -> bool {
+    if list.len() < cap.min(MAX_CANDIDATES) && try_push_candidate(list, wifi.clone()) {
+        return true;
+    }
+    let weakest = (0..list.len()).min_by(|&a, &b| list[a].cmp(&list[b]));
+    if let Some(i) = weakest {
+        // Ord, the derived PartialOrd compares field by field
+        if wifi.cmp(&list[i]).is_gt() {
+            list[i] = wifi;
+        }
+    }
+    false
+}
+
 // Represents a candidate wifi connection
-#[derive(Serialize, Deserialize, Default, Debug, Format, Clone, Eq, PartialOrd)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct WifiConfig {
     pub bssid: [u8; 6],
     pub ssid: heapless::String<32>,
     pub signal_strength: i8,
+    // primary channel the WG was last seen on, 0 if unknown
+    pub channel: u8,
     // set if/when we ever use this candidate
     pub connect_success: Option<bool>,
+    // the last attempt stalled past the connect timeout instead of failing
+    pub connect_timed_out: bool,
+    // Instant seconds of the last successful connect this boot, monotonic unlike
+    // the SNTP time
+    pub last_connected: Option<u64>,
+    // set when the health check found a captive portal behind this WG
+    pub captive_portal: bool,
+    // scan_seq() of the last scan that saw this WG
+    pub last_seen_scan: u32,
+    // moving average of signal_strength in 1/16 dB, what the ranking uses
+    pub rssi_ema_x16: i16,
+    // scans that fed rssi_ema_x16
+    pub rssi_samples: u16,
+    // what the WG advertised in the scan, we insist on it when connecting
+    pub security: Security,
+    // utilization of the WG's channel from sniffed beacons, 255 = saturated
+    pub channel_load: Option<u8>,
+    // round trip to the WG's gateway in ms from the health check's pings, averaged
+    pub gateway_rtt_ms: Option<u16>,
+    // ms connect_async took to associate, averaged
+    pub associate_ms: Option<u16>,
+    // ms from associating to an address, averaged
+    pub lease_ms: Option<u16>,
+    // mobility domain the WG's beacons advertise, it does 802.11r fast transition
+    pub mobility_domain: Option<u16>,
+    // tier of the network the scan matched it to, Primary for enterprise,
+    // Guest until a scan sets it
+    pub tier: Tier,
+}
+
+/// the auth method a WG advertises
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Security {
+    Open,
+    Wep,
+    Wpa,
+    #[default]
+    Wpa2,
+    WpaWpa2,
+    Wpa3,
+    Wpa2Wpa3,
+    // 802.1X, our PSK credentials can't log in
+    Enterprise,
+    Wapi,
+    // the scan didn't say
+    Unknown,
+}
+
+impl Security {
+    pub fn from_auth_method(auth: Option<AuthMethod>) -> Self {
+        match auth {
+            Some(AuthMethod::None) => Self::Open,
+            Some(AuthMethod::Wep) => Self::Wep,
+            Some(AuthMethod::Wpa) => Self::Wpa,
+            Some(AuthMethod::Wpa2Personal) => Self::Wpa2,
+            Some(AuthMethod::WpaWpa2Personal) => Self::WpaWpa2,
+            Some(AuthMethod::Wpa3Personal) => Self::Wpa3,
+            Some(AuthMethod::Wpa2Wpa3Personal) => Self::Wpa2Wpa3,
+            Some(AuthMethod::Wpa2Enterprise) => Self::Enterprise,
+            Some(AuthMethod::WapiPersonal) => Self::Wapi,
+            None => Self::Unknown,
+        }
+    }
+
+    /// the weakest auth the driver will accept when connecting, None leaves
+    /// the driver default (WPA2)
+    pub fn auth_method(self) -> Option<AuthMethod> {
+        match self {
+            Self::Open => Some(AuthMethod::None),
+            Self::Wep => Some(AuthMethod::Wep),
+            Self::Wpa => Some(AuthMethod::Wpa),
+            Self::Wpa2 => Some(AuthMethod::Wpa2Personal),
+            Self::WpaWpa2 => Some(AuthMethod::WpaWpa2Personal),
+            Self::Wpa3 => Some(AuthMethod::Wpa3Personal),
+            Self::Wpa2Wpa3 => Some(AuthMethod::Wpa2Wpa3Personal),
+            Self::Wapi => Some(AuthMethod::WapiPersonal),
+            Self::Enterprise | Self::Unknown => None,
+        }
+    }
+
+    /// SAE mandates PMF, and transition APs have to offer it
+    pub fn has_pmf(self) -> bool {
+        matches!(self, Self::Wpa3 | Self::Wpa2Wpa3)
+    }
 }
 
 impl WifiConfig {
@@ -36,140 +346,583 @@ impl WifiConfig {
             bssid: [0; 6],
             ssid: heapless::String::new(),
             signal_strength: i8::MIN,
+            channel: 0,
             connect_success: Some(false),
+            connect_timed_out: false,
+            last_connected: None,
+            captive_portal: false,
+            last_seen_scan: 0,
+            rssi_ema_x16: i8::MIN as i16 * 16,
+            rssi_samples: 0,
+            security: Security::Wpa2,
+            channel_load: None,
+            gateway_rtt_ms: None,
+            associate_ms: None,
+            lease_ms: None,
+            mobility_domain: None,
+            // the lowest, a candidate no scan matched mustn't outrank one that did
+            tier: Tier::Guest,
         };
     }
-    fn cmp_ss(&self, other: &Self) -> core::cmp::Ordering {
-        // we reverse because -20
-        return self.signal_strength.cmp(&other.signal_strength);
+
+    /// the WG as a later boot restores it from flash or the wake cache. The
+    /// recency and latencies are expired: the first is stamped by another
+    /// boot's clock, the others could be hours old
+    pub fn restored(mut self) -> Self {
+        self.last_connected = None;
+        self.associate_ms = None;
+        self.lease_ms = None;
+        self
     }
 }
-impl PartialEq for WifiConfig {
-    fn eq(&self, other: &Self) -> bool {
-        self.bssid == other.bssid
+
+impl Default for WifiConfig {
+    fn default() -> Self {
+        Self::new_default()
     }
 }
-// test
-impl Ord for WifiConfig {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // a wifi config
-        let a = match (self.connect_success, other.connect_success) {
-            (Some(true), Some(true)) => {
-                // both configs connected, better signal wins
-                Self::cmp_ss(&self, other)
-            }
-            (Some(true), Some(false)) => {
-                // self connected, we're better
-                core::cmp::Ordering::Greater
-            }
-            (Some(false), Some(true)) => {
-                // other connected, self didn't, it's better
-                Ordering::Less
-            }
-            (Some(false), Some(false)) => {
-                // neither connected, better signals wins
-                Self::cmp_ss(&self, other)
-            }
-            (None, None) => {
-                // never been used
-                Self::cmp_ss(&self, other)
-            }
-            (None, Some(true)) => {
-                // self never been used, other connected, it's better
-                Ordering::Less
-            }
-            (None, Some(false)) => {
-                // self never been used, other didn't connect, we're better
-                Ordering::Greater
+
+// represents credentials baked into firmware, or provisioned at the factory
+#[derive(Clone)]
+pub struct Credential {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+    // the WG doesn't broadcast its SSID, only a directed probe finds it
+    pub hidden: bool,
+    pub auth: AuthPolicy,
+    pub pmf: Pmf,
+    pub tier: Tier,
+    pub policy: NetworkPolicy,
+}
+
+impl Credential {
+    /// `ssid` with `password` and the defaults otherwise. Either too long for
+    /// the driver leaves the ssid empty, such a network is skipped
+    pub fn new(ssid: &str, password: &str) -> Self {
+        let (ssid, password) = match (ssid.try_into(), password.try_into()) {
+            (Ok(ssid), Ok(password)) => (ssid, password),
+            _ => {
+                info!("Network {} left out, SSID or passphrase too long", ssid);
+                (heapless::String::new(), heapless::String::new())
             }
-            (Some(x), None) => {
-                match x {
-                    true => {
-                        // self been used, and it connected, we're better
-                        Ordering::Greater
-                    }
-                    false => {
-                        // self been used, and it didn't connect, rather use other
-                        Ordering::Less
-                    }
+        };
+        return Self {
+            ssid,
+            password,
+            hidden: false,
+            auth: AuthPolicy::Any,
+            pmf: Pmf::Capable,
+            tier: Tier::Primary,
+            policy: NetworkPolicy::new(),
+        };
+    }
+    pub fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+    pub fn with_auth(mut self, auth: AuthPolicy) -> Self {
+        self.auth = auth;
+        self
+    }
+    pub fn with_pmf(mut self, pmf: Pmf) -> Self {
+        self.pmf = pmf;
+        self
+    }
+    pub fn with_tier(mut self, tier: Tier) -> Self {
+        self.tier = tier;
+        self
+    }
+    pub fn with_policy(mut self, policy: NetworkPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// a config for this network, any BSSID
+    pub fn client_config(&self) -> ClientConfig {
+        let config = ClientConfig::default()
+            .with_ssid(self.ssid.as_str().into())
+            .with_password(self.password.as_str().into());
+        match self.auth.auth_method() {
+            Some(auth) => config.with_auth_method(auth),
+            None => config,
+        }
+    }
+
+    // whether an AP advertising `security` could be this network
+    fn admits(&self, security: Security) -> bool {
+        let pmf_ok = self.pmf == Pmf::Capable || security.has_pmf();
+        pmf_ok && self.auth.admits(security)
+    }
+}
+
+/// the auth a network is known to use, APs with our SSID advertising
+/// anything else are skipped
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AuthPolicy {
+    // whatever the AP advertises
+    #[default]
+    Any,
+    // WPA2-PSK or better
+    Wpa2,
+    // WPA2/WPA3 transition, SAE where the AP offers it
+    Wpa2Wpa3,
+    // SAE only
+    Wpa3,
+}
+
+impl AuthPolicy {
+    const fn from_env(value: &str) -> Self {
+        match value.as_bytes() {
+            b"wpa2" => Self::Wpa2,
+            b"wpa2-wpa3" => Self::Wpa2Wpa3,
+            b"wpa3" => Self::Wpa3,
+            _ => Self::Any,
+        }
+    }
+
+    /// the value from_env reads back, as the factory namespace stores it
+    const fn env_name(self) -> &'static str {
+        match self {
+            Self::Any => "any",
+            Self::Wpa2 => "wpa2",
+            Self::Wpa2Wpa3 => "wpa2-wpa3",
+            Self::Wpa3 => "wpa3",
+        }
+    }
+
+    fn admits(self, security: Security) -> bool {
+        use Security::*;
+        match self {
+            Self::Any => true,
+            Self::Wpa2 => matches!(security, Wpa2 | WpaWpa2 | Wpa2Wpa3 | Wpa3),
+            Self::Wpa2Wpa3 => matches!(security, Wpa2Wpa3 | Wpa3),
+            Self::Wpa3 => matches!(security, Wpa2Wpa3 | Wpa3),
+        }
+    }
+
+    /// the weakest auth the driver may use on this network, None for the
+    /// driver default
+    pub fn auth_method(self) -> Option<AuthMethod> {
+        match self {
+            Self::Any => None,
+            Self::Wpa2 | Self::Wpa2Wpa3 => Some(AuthMethod::Wpa2Personal),
+            Self::Wpa3 => Some(AuthMethod::Wpa3Personal),
+        }
+    }
+}
+
+/// protected management frames, esp-radio always offers them
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pmf {
+    #[default]
+    Capable,
+    // only APs that must do PMF, i.e. WPA3 or transition, are candidates
+    Required,
+}
+
+impl Pmf {
+    const fn from_env(value: &str) -> Self {
+        match value.as_bytes() {
+            b"required" => Self::Required,
+            _ => Self::Capable,
+        }
+    }
+
+    /// the value from_env reads back, as the factory namespace stores it
+    const fn env_name(self) -> &'static str {
+        match self {
+            Self::Capable => "capable",
+            Self::Required => "required",
+        }
+    }
+}
+
+/// what kind of WG a network is. The ranking only falls back to a lower tier
+/// when nothing of a higher one can be connected to, whatever the signal
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Tier {
+    // the gateways the device is installed for
+    #[default]
+    Primary,
+    // LTE bridges and the like, metered or slower
+    Backup,
+    // someone else's network, the last resort
+    Guest,
+}
+
+impl Tier {
+    const fn from_env(value: &str) -> Self {
+        match value.as_bytes() {
+            b"backup" => Self::Backup,
+            b"guest" => Self::Guest,
+            _ => Self::Primary,
+        }
+    }
+
+    /// the value from_env reads back, as the factory namespace stores it
+    const fn env_name(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Backup => "backup",
+            Self::Guest => "guest",
+        }
+    }
+}
+
+/// what a network allows besides the connection itself, the policy of the WG
+/// we're on travels with CONNECTION_STATE
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(default)]
+pub struct NetworkPolicy {
+    // a hotspot or an LTE bridge paid by the byte, bulky transfers wait
+    pub metered: bool,
+    // no firmware downloads over this network
+    pub no_ota: bool,
+    // nothing is reported upstream over this network
+    pub no_telemetry: bool,
+}
+
+impl NetworkPolicy {
+    pub const fn new() -> Self {
+        return Self {
+            metered: false,
+            no_ota: false,
+            no_telemetry: false,
+        };
+    }
+    pub const fn with_metered(mut self, metered: bool) -> Self {
+        self.metered = metered;
+        self
+    }
+    pub const fn with_no_ota(mut self, no_ota: bool) -> Self {
+        self.no_ota = no_ota;
+        self
+    }
+    pub const fn with_no_telemetry(mut self, no_telemetry: bool) -> Self {
+        self.no_telemetry = no_telemetry;
+        self
+    }
+
+    /// whether an OTA image may be downloaded
+    pub const fn allows_ota(&self) -> bool {
+        !self.metered && !self.no_ota
+    }
+    /// whether status reports may go out, they're small
+    pub const fn allows_telemetry(&self) -> bool {
+        !self.no_telemetry
+    }
+    /// whether batches of samples may go out
+    pub const fn allows_bulk_telemetry(&self) -> bool {
+        !self.metered && !self.no_telemetry
+    }
+    /// every restriction of either
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            metered: self.metered || other.metered,
+            no_ota: self.no_ota || other.no_ota,
+            no_telemetry: self.no_telemetry || other.no_telemetry,
+        }
+    }
+
+    /// comma separated flags: "metered", "no-ota", "no-telemetry"
+    const fn from_env(value: &str) -> Self {
+        let bytes = value.as_bytes();
+        let mut policy = Self::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i <= bytes.len() {
+            if i == bytes.len() || bytes[i] == b',' {
+                if env_word(bytes, start, i, b"metered") {
+                    policy.metered = true;
+                } else if env_word(bytes, start, i, b"no-ota") {
+                    policy.no_ota = true;
+                } else if env_word(bytes, start, i, b"no-telemetry") {
+                    policy.no_telemetry = true;
                 }
+                start = i + 1;
             }
-        };
+            i += 1;
+        }
+        policy
+    }
 
-        return a;
+    /// the value from_env reads back, as the factory namespace stores it
+    fn env_value(self) -> heapless::String<32> {
+        let mut value = heapless::String::new();
+        let flags = [
+            (self.metered, "metered"),
+            (self.no_ota, "no-ota"),
+            (self.no_telemetry, "no-telemetry"),
+        ];
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            if !value.is_empty() {
+                let _ = value.push(',');
+            }
+            // all three with their commas are 27 bytes
+            let _ = value.push_str(name);
+        }
+        value
     }
 }
 
-// represents credentials baked into firmware
-pub struct Credential {
-    pub ssid: &'static str,
-    pub password: &'static str,
+// whether bytes[start..end] is `word`, spaces around it aside
+const fn env_word(bytes: &[u8], mut start: usize, mut end: usize, word: &[u8]) -> bool {
+    while start < end && bytes[start] == b' ' {
+        start += 1;
+    }
+    while end > start && bytes[end - 1] == b' ' {
+        end -= 1;
+    }
+    if end - start != word.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < word.len() {
+        if bytes[start + i] != word[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
 }
 
-pub const KNOWN_CREDS: (Credential, Credential) = (
-    Credential {
-        ssid: SSID,
-        password: PASSWORD,
-    },
-    Credential {
-        ssid: SSID2,
-        password: PASSWORD2,
-    },
-);
-
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
-const SSID2: &str = env!("SSID2");
-const PASSWORD2: &str = env!("PASSWORD2");
-
-const SCAN_COUNT: usize = 10;
-
-pub async fn scan_and_score_wgs(controller: &mut WifiController<'static>) -> Vec<WifiConfig> {
-    info!("Scanning...");
-    // worst case scan time 20ms*SCAN_COUNT
-    let scan_conf: ScanConfig<'_> = ScanConfig::default().with_max(SCAN_COUNT);
-    let result = controller.scan_with_config_async(scan_conf).await.unwrap();
-
-    let mut result = result
-        .iter()
-        .filter(|x| (x.ssid == SSID || x.ssid == SSID2))
-        .map(|x| x.to_owned())
-        .map(|x| WifiConfig {
-            bssid: x.bssid,
-            ssid: x.ssid.as_str().try_into().unwrap(),
-            signal_strength: x.signal_strength,
-            connect_success: None,
-        })
-        .collect::<Vec<WifiConfig>>();
-
-    // the best wifi candidate will sort to the top, check the Ord impl for
-    // how they're picked
-    result.sort_by(|x, y| x.cmp(y).reverse());
-
-    for ap in &result {
-        // show all aps nearby
-        info!(
-            "{:?}, {} ,({})",
-            ap.ssid.as_str(),
-            ap.bssid,
-            ap.signal_strength
-        );
-    }
-
-    result
+/// the networks the build environment names
+pub fn known_creds() -> Credentials {
+    let mut creds = Credentials::new();
+    let _ = creds.push(
+        Credential::new(SSID, PASSWORD)
+            .with_hidden(env_flag(SSID_HIDDEN))
+            .with_auth(AuthPolicy::from_env(SSID_AUTH))
+            .with_pmf(Pmf::from_env(SSID_PMF))
+            .with_tier(Tier::from_env(SSID_TIER))
+            .with_policy(NetworkPolicy::from_env(SSID_POLICY)),
+    );
+    let _ = creds.push(
+        Credential::new(SSID2, PASSWORD2)
+            .with_hidden(env_flag(SSID2_HIDDEN))
+            .with_auth(AuthPolicy::from_env(SSID2_AUTH))
+            .with_pmf(Pmf::from_env(SSID2_PMF))
+            .with_tier(Tier::from_env(SSID2_TIER))
+            .with_policy(NetworkPolicy::from_env(SSID2_POLICY)),
+    );
+    creds
+}
+
+// left out of the build environment, the networks come from the factory NVS image
+const SSID: &str = env_or_empty(option_env!("SSID"));
+const PASSWORD: &str = env_or_empty(option_env!("PASSWORD"));
+const SSID_HIDDEN: &str = env_or_empty(option_env!("SSID_HIDDEN"));
+const SSID_AUTH: &str = env_or_empty(option_env!("SSID_AUTH"));
+const SSID_PMF: &str = env_or_empty(option_env!("SSID_PMF"));
+const SSID_TIER: &str = env_or_empty(option_env!("SSID_TIER"));
+const SSID_POLICY: &str = env_or_empty(option_env!("SSID_POLICY"));
+const SSID2: &str = env_or_empty(option_env!("SSID2"));
+const PASSWORD2: &str = env_or_empty(option_env!("PASSWORD2"));
+const SSID2_HIDDEN: &str = env_or_empty(option_env!("SSID2_HIDDEN"));
+const SSID2_AUTH: &str = env_or_empty(option_env!("SSID2_AUTH"));
+const SSID2_PMF: &str = env_or_empty(option_env!("SSID2_PMF"));
+const SSID2_TIER: &str = env_or_empty(option_env!("SSID2_TIER"));
+const SSID2_POLICY: &str = env_or_empty(option_env!("SSID2_POLICY"));
+
+const fn env_or_empty(value: Option<&'static str>) -> &'static str {
+    match value {
+        Some(value) => value,
+        None => "",
+    }
+}
+
+const fn env_flag(value: &str) -> bool {
+    matches!(value.as_bytes(), b"1" | b"true")
+}
+
+/// networks a factory NVS image can provision, `ssid0` to `ssid3`
+pub const MAX_PROVISIONED: usize = 4;
+
+/// a table of networks, as many as a factory image holds
+pub type Credentials = heapless::Vec<Credential, MAX_PROVISIONED>;
+
+// the networks we know, built from the build's on first use and overwritten
+// in place by every load, nothing is held on to. None until then
+static KNOWN: Mutex<CriticalSectionRawMutex, RefCell<Option<Credentials>>> =
+    Mutex::new(RefCell::new(None));
+
+/// use the networks of the factory NVS image instead of the build's, called
+/// with what persistence loaded and again by a `config load` on the console
+pub fn set_provisioned_credentials(mut credentials: Credentials) {
+    for cred in &credentials {
+        info!("Provisioned network {}", cred.ssid.as_str());
+    }
+    credentials.retain(|c| !c.ssid.is_empty());
+    // none provisioned, the build's are built again on the next look
+    let known = (!credentials.is_empty()).then_some(credentials);
+    KNOWN.lock(|k| *k.borrow_mut() = known);
+}
+
+/// runs `f` on the networks we know: the provisioned ones if there are any,
+/// the build's otherwise. Borrowed inside a critical section, keep `f` short
+pub fn with_credentials<R>(f: impl FnOnce(&[Credential]) -> R) -> R {
+    KNOWN.lock(|k| {
+        if k.borrow().is_none() {
+            let mut creds = known_creds();
+            creds.retain(|c| !c.ssid.is_empty());
+            *k.borrow_mut() = Some(creds);
+        }
+        f(k.borrow().as_deref().unwrap_or_default())
+    })
+}
+
+/// the credential for `ssid`, if it's one we know
+pub fn credential_for(ssid: &str) -> Option<Credential> {
+    with_credentials(|creds| creds.iter().find(|c| c.ssid == ssid).cloned())
+}
+
+/// whether `ssid` is one of our networks, for the sniffer's every beacon
+pub fn knows_ssid(ssid: &str) -> bool {
+    with_credentials(|creds| creds.iter().any(|c| c.ssid == ssid))
+}
+
+// a network we can log in to
+#[derive(Clone)]
+enum KnownNetwork {
+    Psk(Credential),
+    Enterprise(&'static EnterpriseCredential),
+}
+
+impl KnownNetwork {
+    fn ssid(&self) -> &str {
+        match self {
+            Self::Psk(cred) => &cred.ssid,
+            Self::Enterprise(cred) => cred.ssid(),
+        }
+    }
+
+    fn tier(&self) -> Tier {
+        match self {
+            Self::Psk(cred) => cred.tier,
+            Self::Enterprise(_) => Tier::Primary,
+        }
+    }
+}
+
+// the network called `ssid`, PSK credentials first
+fn known_network(ssid: &str) -> Option<KnownNetwork> {
+    credential_for(ssid).map(KnownNetwork::Psk).or_else(|| {
+        enterprise_credential()
+            .filter(|e| e.ssid() == ssid)
+            .map(KnownNetwork::Enterprise)
+    })
+}
+
+/// the credential of the network `wifi` belongs to, None for an enterprise one
+pub fn credential_of(wifi: &WifiConfig) -> Option<Credential> {
+    // a scan names a hidden WG after the network it matched, never empty
+    credential_for(&wifi.ssid)
+}
+
+/// the policy of the network `wifi` belongs to, none for an enterprise one
+pub fn policy_for(wifi: &WifiConfig) -> NetworkPolicy {
+    credential_of(wifi).map_or(NetworkPolicy::new(), |c| c.policy)
+}
+
+/// every restriction any known network has, for a WG we don't know which
+/// network it belongs to
+pub fn strictest_policy() -> NetworkPolicy {
+    with_credentials(|creds| {
+        creds
+            .iter()
+            .fold(NetworkPolicy::new(), |p, c| p.union(c.policy))
+    })
+}
+
+/// the credentials of WGs that don't broadcast their SSID, a copy to scan for
+pub fn hidden_credentials() -> Credentials {
+    with_credentials(|creds| creds.iter().filter(|c| c.hidden).cloned().collect())
+}
+
+/// the radio band a channel is on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Band {
+    Ghz2_4,
+    // only on dual-band chips such as the ESP32-C5
+    Ghz5,
+}
+
+impl Band {
+    /// channels 1-14 are 2.4 GHz, 5 GHz starts at 32. None for 0 (unknown)
+    pub const fn of_channel(channel: u8) -> Option<Self> {
+        match channel {
+            1..=14 => Some(Band::Ghz2_4),
+            32.. => Some(Band::Ghz5),
+            _ => None,
+        }
+    }
+}
+
+/// the bands a scan admits candidates from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BandMask {
+    pub ghz2_4: bool,
+    pub ghz5: bool,
+}
+
+impl BandMask {
+    pub const ALL: Self = Self {
+        ghz2_4: true,
+        ghz5: true,
+    };
+    pub const GHZ2_4: Self = Self {
+        ghz2_4: true,
+        ghz5: false,
+    };
+    pub const GHZ5: Self = Self {
+        ghz2_4: false,
+        ghz5: true,
+    };
+
+    /// a channel we can't place is let through
+    pub const fn admits(&self, channel: u8) -> bool {
+        match Band::of_channel(channel) {
+            Some(Band::Ghz2_4) => self.ghz2_4,
+            Some(Band::Ghz5) => self.ghz5,
+            None => true,
+        }
+    }
+}
+
+/// the radio config for connecting to `wifi`, EAP for the enterprise network
+pub fn mode_config_for_candidate(wifi: &WifiConfig) -> ModeConfig {
+    match enterprise_credential().filter(|e| e.ssid() == wifi.ssid) {
+        Some(enterprise) => ModeConfig::EapClient(enterprise.eap_config(wifi)),
+        None => ModeConfig::Client(get_client_config_from_candidate(wifi)),
+    }
 }
 
 /// we use the bssid to identify a specific WG, as multiple will advertise on same ssid
 pub fn get_client_config_from_candidate(wifi: &WifiConfig) -> ClientConfig {
-    if wifi.ssid == KNOWN_CREDS.0.ssid {
-        ClientConfig::default()
-            .with_ssid(KNOWN_CREDS.0.ssid.into())
-            .with_bssid(wifi.bssid)
-            .with_password(KNOWN_CREDS.0.password.into())
-    } else {
-        ClientConfig::default()
-            .with_ssid(KNOWN_CREDS.1.ssid.into())
-            .with_bssid(wifi.bssid)
-            .with_password(KNOWN_CREDS.1.password.into())
+    let config = client_config_for(wifi);
+    // skips the channel sweep when associating
+    match wifi.channel {
+        0 => config,
+        channel => config.with_channel(channel),
+    }
+}
+
+fn client_config_for(wifi: &WifiConfig) -> ClientConfig {
+    let cred = credential_of(wifi).or_else(|| with_credentials(|creds| creds.last().cloned()));
+    let Some(cred) = cred else {
+        return ClientConfig::default().with_bssid(wifi.bssid);
+    };
+    let config = cred.client_config().with_bssid(wifi.bssid);
+    // the driver then won't associate with anything weaker than what we
+    // scanned. A WPA3 network stays on SAE even where the AP offers WPA2 too
+    match (cred.auth, wifi.security.auth_method()) {
+        (AuthPolicy::Wpa3, _) | (_, None) => config,
+        (_, Some(auth)) => config.with_auth_method(auth),
     }
 }
@@ -0,0 +1,177 @@
+//! a defmt logger that keeps the encoded frames in a RAM ring until a task
+//! ships them: over UDP with the `netlog` feature (src/netlog.rs), over the
+//! host protocol with `rpc` (src/rpc.rs). The frames are rzcobs encoded and
+//! end in a zero, decode them with the matching ELF
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use critical_section::{Mutex, RestoreState};
+
+#[cfg(feature = "serial-log")]
+compile_error!("netlog and rpc bring their own defmt logger, build with --no-default-features");
+#[cfg(all(feature = "netlog", feature = "rpc"))]
+compile_error!("netlog and rpc both drain the log ring, enable one of them");
+
+// frames waiting to be shipped, the newest are dropped when it fills up
+const RING_LEN: usize = 4096;
+// what espflash looks for in front of every frame on the serial port
+const FRAME_START: &[u8] = &[0xFF, 0x00];
+
+// the encoded frames, written under the logger's critical section
+struct Ring {
+    bytes: [u8; RING_LEN],
+    // oldest byte not yet sent
+    tail: usize,
+    // end of the last complete frame
+    committed: usize,
+    // end of the frame being written
+    head: usize,
+    // the frame being written didn't fit and is being dropped
+    dropping: bool,
+    dropped: u32,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; RING_LEN],
+            tail: 0,
+            committed: 0,
+            head: 0,
+            dropping: false,
+            dropped: 0,
+        }
+    }
+
+    fn used(&self) -> usize {
+        (self.head + RING_LEN - self.tail) % RING_LEN
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.dropping {
+            return;
+        }
+        // one byte stays free to tell full from empty
+        if self.used() + bytes.len() >= RING_LEN {
+            self.head = self.committed;
+            self.dropping = true;
+            return;
+        }
+        for b in bytes {
+            self.bytes[self.head] = *b;
+            self.head = (self.head + 1) % RING_LEN;
+        }
+    }
+
+    fn end_frame(&mut self) {
+        match self.dropping {
+            true => self.dropped = self.dropped.wrapping_add(1),
+            false => self.committed = self.head,
+        }
+        self.dropping = false;
+    }
+
+    // whole frames from the tail, as many as fit `out`
+    fn take(&mut self, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        let mut frame_end = 0;
+        let mut at = self.tail;
+        while at != self.committed && len < out.len() {
+            out[len] = self.bytes[at];
+            len += 1;
+            at = (at + 1) % RING_LEN;
+            // rzcobs frames end in a zero
+            if out[len - 1] == 0 {
+                frame_end = len;
+                self.tail = at;
+            }
+        }
+        // a frame longer than a datagram would block the rest forever
+        if frame_end == 0 && len == out.len() {
+            while self.tail != self.committed {
+                let b = self.bytes[self.tail];
+                self.tail = (self.tail + 1) % RING_LEN;
+                if b == 0 {
+                    break;
+                }
+            }
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        frame_end
+    }
+}
+
+static RING: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring::new()));
+
+#[defmt::global_logger]
+struct RingLogger;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut CS_RESTORE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+// with netlog the serial port gets what esp-println would send, the ring only
+// the frames. With rpc the serial port is the protocol's, only the ring gets them
+#[cfg(feature = "netlog")]
+fn write_serial(bytes: &[u8]) {
+    esp_println::Printer::write_bytes(bytes);
+}
+
+#[cfg(not(feature = "netlog"))]
+fn write_serial(_bytes: &[u8]) {}
+
+fn write_both(bytes: &[u8]) {
+    write_serial(bytes);
+    // SAFETY: only called with the logger's critical section held
+    let cs = unsafe { critical_section::CriticalSection::new() };
+    RING.borrow_ref_mut(cs).write(bytes);
+}
+
+// SAFETY: the state is only touched between acquire and release, inside a
+// critical section
+unsafe impl defmt::Logger for RingLogger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly")
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        unsafe {
+            CS_RESTORE = restore;
+            write_serial(FRAME_START);
+            (*core::ptr::addr_of_mut!(ENCODER)).start_frame(write_both);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        unsafe {
+            (*core::ptr::addr_of_mut!(ENCODER)).end_frame(write_both);
+            let cs = critical_section::CriticalSection::new();
+            RING.borrow_ref_mut(cs).end_frame();
+            TAKEN.store(false, Ordering::Relaxed);
+            critical_section::release(CS_RESTORE);
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        unsafe {
+            (*core::ptr::addr_of_mut!(ENCODER)).write(bytes, write_both);
+        }
+    }
+}
+
+/// moves whole frames from the ring to `out`, as many as fit, and returns the
+/// bytes used. A frame longer than `out` is dropped
+pub fn take_frames(out: &mut [u8]) -> usize {
+    critical_section::with(|cs| RING.borrow_ref_mut(cs).take(out))
+}
+
+/// frames dropped because they weren't shipped fast enough
+pub fn dropped_frames() -> u32 {
+    critical_section::with(|cs| RING.borrow_ref(cs).dropped)
+}
@@ -0,0 +1,82 @@
+//! the STA MAC: the factory one, a fixed override, or a random locally
+//! administered one per boot for products that mustn't be trackable from
+//! network to network. Applied before the radio is initialised, the driver
+//! only reads it once. The random one comes from the TRNG, which needs ADC1
+//! until the radio runs, so main draws it before the board takes ADC1
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::{
+    efuse::Efuse,
+    peripherals::{ADC1, RNG},
+    rng::{Trng, TrngSource},
+};
+
+use crate::fmt::{Bytes, Debug2Format};
+
+// set in the first octet of a locally administered address
+const LOCALLY_ADMINISTERED: u8 = 0x02;
+// set in the first octet of a group address, never ours
+const MULTICAST: u8 = 0x01;
+
+/// where the STA MAC comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MacPolicy {
+    // the one burned into eFuse
+    Factory,
+    // e.g. to take over the DHCP reservation of a unit that was replaced
+    Fixed([u8; 6]),
+    // a new locally administered one every boot. A deep sleep wake keeps the
+    // one it went to sleep with, its lease and association still apply
+    RandomPerBoot,
+}
+
+static STATION_MAC: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> =
+    Mutex::new(Cell::new(None));
+
+/// sets the STA MAC by `policy`, call it before `esp_radio::init`. `resumed` is
+/// the MAC a deep sleep wake went to sleep with, `random` what draw_random_mac
+/// gave. Returns the MAC in effect
+pub fn apply_mac_policy(policy: MacPolicy, resumed: Option<[u8; 6]>, random: [u8; 6]) -> [u8; 6] {
+    let factory = Efuse::mac_address();
+    let mac = match policy {
+        MacPolicy::Factory => factory,
+        MacPolicy::Fixed(mac) => mac,
+        MacPolicy::RandomPerBoot => resumed.unwrap_or(random),
+    };
+    let mac = match mac == factory {
+        true => factory,
+        false => match Efuse::set_mac_address(mac) {
+            Ok(_) => mac,
+            Err(e) => {
+                info!("MAC override refused: {:?}", Debug2Format(&e));
+                factory
+            }
+        },
+    };
+    info!("STA MAC {:02x} ({:?})", Bytes(&mac), policy);
+    STATION_MAC.lock(|m| m.set(Some(mac)));
+    mac
+}
+
+/// the MAC the station uses this boot, the factory one until a policy is applied
+pub fn station_mac() -> [u8; 6] {
+    STATION_MAC
+        .lock(|m| m.get())
+        .unwrap_or_else(Efuse::mac_address)
+}
+
+/// a unicast, locally administered MAC from the TRNG, so it can't clash with
+/// a vendor's range. Without the radio running Rng alone is predictable, the
+/// source feeds it from ADC1 while it lives
+pub fn draw_random_mac(rng: RNG<'_>, adc1: ADC1<'_>) -> [u8; 6] {
+    let _source = TrngSource::new(rng, adc1);
+    let trng = Trng::try_new().expect("the TRNG source is alive");
+    let mut mac = [0u8; 6];
+    mac[..4].copy_from_slice(&trng.random().to_le_bytes());
+    mac[4..].copy_from_slice(&trng.random().to_le_bytes()[..2]);
+    mac[0] = (mac[0] | LOCALLY_ADMINISTERED) & !MULTICAST;
+    mac
+}
@@ -0,0 +1,817 @@
+use embassy_futures::select;
+use embassy_net::{Stack, StackResources};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_radio::wifi::{self, ModeConfig, Sniffer, WifiController, WifiEvent};
+
+use crate::{
+    CANDIDATES, Pmf, SCAN_CMD, WIFI_REQUEST, WIFI_STOPPED, WifiConfig, WifiRequest, beacons,
+    blacklist::{blacklist, clear_blacklisted, forget, is_blacklisted},
+    bootguard::safe_mode,
+    calendar::QuietHours,
+    candidates::Outcome,
+    capture::run_capture,
+    credential_of,
+    diagnostics::{DiagnosticsAp, with_diagnostics_ap},
+    disconnect::{
+        DisconnectCategory, install_disconnect_handler, last_disconnect, reconnect_backoff,
+        reset_backoff,
+    },
+    eventlog::{self, Event},
+    fmt::Bytes,
+    fsm::{ConnectionFsm, FsmEvent},
+    health::{HealthCheck, ProbeBuffers},
+    hold::{queue_request, queue_scan, roaming_held},
+    keepalive::Keepalive,
+    mode_config_for_candidate,
+    neighbors::{self, NEIGHBORS, NeighborList, NeighborSource},
+    netconfig::{apply_ip_mode, ip_mode_after_connect},
+    persistence::{FLUSH, FLUSHED, SEAL_PASSPHRASES, STORE_SETTINGS, Settings},
+    policy_for,
+    roaming::{RoamPreset, active_preset, active_profile, set_active_preset},
+    rssi::SignalMonitor,
+    scan_channel,
+    scanner::{AdaptiveScan, ScanOptions, do_scan, gentle_scan, merge_scan},
+    secret::set_radio_entropy,
+    security::{DEAUTH_STORM, held_off_channel, report_deauth_storm},
+    set_candidate_cap,
+    stats::{record_connect, record_disconnect},
+    status::{
+        ConnectionState, link_status, set_associated, set_connection_state, update_link_status,
+    },
+    strictest_policy,
+    timeseries::TimeSeries,
+    txpower::{TxPowerProfile, active_tx_power, apply_tx_power, set_active_tx_power},
+    watchdog::{Watched, beat, beat_while},
+    with_credentials,
+};
+
+/// the link dropped, the scan scheduler rescans sooner
+pub static DISCONNECT_DETECTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// candidates tried per round while disconnected, each gets TRIAGE_TIMEOUT (at
+// most the connect timeout) to associate
+const TRIAGE_CANDIDATES: usize = 3;
+const TRIAGE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// the connection policy, handed to wifi_mgr, best_connection_task and
+/// run_health_checks at spawn time
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WifiManagerConfig {
+    pub scan: ScanOptions,
+    // time between scans while connected, None follows the roaming preset
+    pub connected_scan_interval: Option<Duration>,
+    // time between scans while disconnected, None follows the roaming preset
+    pub disconnected_scan_interval: Option<Duration>,
+    // back off between these while the link stays up, replaces the intervals
+    pub adaptive_scan: Option<AdaptiveScan>,
+    // pause between connect rounds
+    pub retry_delay: Duration,
+    // longest a connect_async may run before it's abandoned as timed out
+    pub connect_timeout: Duration,
+    // the internet probe: endpoint, socket timeout and expected status
+    pub health: HealthCheck,
+    // when a connected unit must not scan or roam
+    pub quiet_hours: QuietHours,
+    // while connected, each scan covers a single channel, see gentle_scan
+    pub gentle_scan: bool,
+    // how often the current AP's RSSI is read and when it counts as degraded
+    pub signal: SignalMonitor,
+    // pings the gateway to catch a dead router behind a live association, None is off
+    pub keepalive: Option<Keepalive>,
+    // a SoftAP serving the status pages next to the STA link, None is off
+    pub diagnostics_ap: Option<DiagnosticsAp>,
+    // RSSI/roaming samples uploaded in batches, None is off
+    pub time_series: Option<TimeSeries>,
+}
+
+impl WifiManagerConfig {
+    pub const fn new() -> Self {
+        return Self {
+            scan: ScanOptions::new(),
+            connected_scan_interval: None,
+            disconnected_scan_interval: None,
+            adaptive_scan: None,
+            retry_delay: Duration::from_secs(3),
+            connect_timeout: Duration::from_secs(20),
+            health: HealthCheck::new(),
+            quiet_hours: QuietHours::new(),
+            gentle_scan: false,
+            signal: SignalMonitor::new(),
+            keepalive: None,
+            diagnostics_ap: None,
+            time_series: None,
+        };
+    }
+    pub const fn with_scan(mut self, scan: ScanOptions) -> Self {
+        self.scan = scan;
+        self
+    }
+    pub const fn with_scan_count(mut self, count: usize) -> Self {
+        self.scan = self.scan.with_max_results(count);
+        self
+    }
+    pub const fn with_scan_intervals(
+        mut self,
+        connected: Duration,
+        disconnected: Duration,
+    ) -> Self {
+        self.connected_scan_interval = Some(connected);
+        self.disconnected_scan_interval = Some(disconnected);
+        self
+    }
+    pub const fn with_adaptive_scan(mut self, floor: Duration, ceiling: Duration) -> Self {
+        self.adaptive_scan = Some(AdaptiveScan::new(floor, ceiling));
+        self
+    }
+    pub const fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+    pub const fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+    pub const fn with_quiet_hours(mut self, quiet_hours: QuietHours) -> Self {
+        self.quiet_hours = quiet_hours;
+        self
+    }
+    pub const fn with_gentle_scan(mut self, gentle_scan: bool) -> Self {
+        self.gentle_scan = gentle_scan;
+        self
+    }
+    pub const fn with_signal_monitor(mut self, signal: SignalMonitor) -> Self {
+        self.signal = signal;
+        self
+    }
+    pub const fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+    pub const fn with_diagnostics_ap(mut self, diagnostics_ap: DiagnosticsAp) -> Self {
+        self.diagnostics_ap = Some(diagnostics_ap);
+        self
+    }
+    pub const fn with_time_series(mut self, time_series: TimeSeries) -> Self {
+        self.time_series = Some(time_series);
+        self
+    }
+    pub const fn with_health_check(mut self, health: HealthCheck) -> Self {
+        self.health = health;
+        self
+    }
+    pub const fn with_socket_timeout(mut self, timeout: Duration) -> Self {
+        self.health = self.health.with_timeout(timeout);
+        self
+    }
+    pub const fn with_probe_endpoint(
+        mut self,
+        host: &'static str,
+        port: u16,
+        path: &'static str,
+    ) -> Self {
+        self.health = self.health.with_host(host).with_port(port).with_path(path);
+        self
+    }
+
+    pub fn connected_scan_interval(&self) -> Duration {
+        self.connected_scan_interval
+            .unwrap_or(active_profile().connected_scan_interval)
+    }
+
+    pub fn disconnected_scan_interval(&self) -> Duration {
+        self.disconnected_scan_interval
+            .unwrap_or(active_profile().disconnected_scan_interval)
+    }
+}
+
+impl Default for WifiManagerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// sockets the library can have open at once: DHCP, DNS, the health check's
+/// kept probe connection and its fallback probe or gateway ping next to it,
+/// SNTP, HTTP, mDNS, the keepalive ping, the telemetry upload and OTA, plus
+/// MQTT and netlog with their features. smoltcp panics when a socket doesn't fit
+pub const LIBRARY_SOCKETS: usize =
+    10 + cfg!(feature = "mqtt") as usize + cfg!(feature = "netlog") as usize;
+
+/// the memory behind the network stack, sized by the application and handed
+/// over at startup. SOCKETS covers LIBRARY_SOCKETS plus the application's own
+/// sockets, PROBE_RX/PROBE_TX are the health probe's socket buffers
+pub struct NetResources<
+    const SOCKETS: usize,
+    const PROBE_RX: usize = 1024,
+    const PROBE_TX: usize = 1024,
+> {
+    pub stack: StackResources<SOCKETS>,
+    pub probe: ProbeBuffers<PROBE_RX, PROBE_TX>,
+}
+
+impl<const SOCKETS: usize, const PROBE_RX: usize, const PROBE_TX: usize>
+    NetResources<SOCKETS, PROBE_RX, PROBE_TX>
+{
+    pub const fn new() -> Self {
+        const {
+            assert!(
+                SOCKETS >= LIBRARY_SOCKETS,
+                "NetResources needs a socket for each of LIBRARY_SOCKETS, the kept probe and its fallback are two"
+            )
+        };
+        Self {
+            stack: StackResources::new(),
+            probe: ProbeBuffers::new(),
+        }
+    }
+}
+
+/// owns the radio: connects to the best candidate, roams, scans on SCAN_CMD
+/// and serves WIFI_REQUEST, one ConnectionFsm round at a time
+#[embassy_executor::task]
+pub async fn wifi_mgr(
+    mut controller: WifiController<'static>,
+    mut sniffer: Sniffer<'static>,
+    stack: Stack<'static>,
+    persisted_config: Option<WifiConfig>,
+    config: WifiManagerConfig,
+) -> ! {
+    info!("Start wifi mgr task");
+    info!("Device Capabilities: {:?}", controller.capabilities());
+    set_candidate_cap(config.scan.max_candidates);
+
+    let client_config = if let Some(persist) = persisted_config {
+        mode_config_for_candidate(&persist)
+    } else {
+        let first = with_credentials(|creds| creds.first().map(|c| c.client_config()));
+        ModeConfig::Client(first.unwrap_or_default())
+    };
+
+    let ap = config.diagnostics_ap.as_ref();
+    controller
+        .set_config(&with_diagnostics_ap(client_config, ap))
+        .unwrap();
+    install_disconnect_handler();
+
+    info!("Starting wifi");
+    controller.start_async().await.unwrap();
+    info!("Started wifi");
+    radio_started();
+    apply_preset(&mut controller, active_preset());
+    apply_tx_power(&mut controller, active_tx_power());
+
+    let mut fsm = ConnectionFsm::Disconnected;
+    loop {
+        beat(Watched::WifiMgr);
+        // the driver can drop the link between rounds, without us seeing the event
+        if fsm.is_connected()
+            && !matches!(esp_radio::wifi::sta_state(), wifi::WifiStaState::Connected)
+        {
+            fsm.apply(FsmEvent::LinkLost(last_disconnect().map(|d| d.category)));
+        }
+        let event = match fsm {
+            ConnectionFsm::Connected { bssid, since } => {
+                // the AP we're on and when we got there, for the dwell time
+                let current = bssid.map(|bssid| (bssid, since));
+                run_connected(&mut controller, &mut sniffer, current, &config).await
+            }
+            ConnectionFsm::Capturing(request) => {
+                run_capture(&mut sniffer, request).await;
+                FsmEvent::CaptureDone
+            }
+            ConnectionFsm::Stopped => run_stopped(&mut controller).await,
+            ConnectionFsm::Disconnected | ConnectionFsm::Waiting => {
+                // give the WG time to recover, depending on why we lost it
+                let backoff = reconnect_backoff();
+                if backoff > Duration::from_secs(0) {
+                    info!("Reconnect backoff {}ms", backoff.as_millis());
+                    beat_while(Watched::WifiMgr, Timer::after(backoff)).await;
+                }
+                run_disconnected(&mut controller, stack, &config).await
+            }
+        };
+        fsm.apply(event);
+        Timer::after(config.retry_delay).await
+    }
+}
+
+// one connect round: scan if asked, then triage the top candidates
+async fn run_disconnected(
+    controller: &mut WifiController<'static>,
+    stack: Stack<'static>,
+    config: &WifiManagerConfig,
+) -> FsmEvent {
+    // the next association asks its WG for neighbors again
+    neighbors::reset_request();
+    // we're currently disconnected
+    while let Ok(request) = WIFI_REQUEST.try_receive() {
+        match request {
+            // already on our way to a fresh connection
+            WifiRequest::Reconnect => {}
+            // not on an AP
+            WifiRequest::Forget => info!("Nothing to forget while disconnected"),
+            WifiRequest::SetPreset(preset) => change_preset(controller, preset),
+            WifiRequest::SetTxPower(profile) => change_tx_power(controller, profile),
+            // the rest of the queue waits for the next round
+            WifiRequest::Capture(request) => return FsmEvent::CaptureRequested(request),
+            WifiRequest::Stop => return FsmEvent::StopRequested,
+            // the radio is on
+            WifiRequest::Start => {}
+        }
+    }
+    if SCAN_CMD.signaled() {
+        // clear signal
+        SCAN_CMD.wait().await;
+        do_scan(controller, &config.scan).await
+    }
+    info!("Currently disconnected");
+    // a channel under a deauth storm is skipped until the hold off is over,
+    // a WG that rejected our credentials until its cool-off is
+    let held_off = held_off_channel();
+    let top = CANDIDATES
+        .top::<TRIAGE_CANDIDATES>(|w| {
+            held_off.is_none_or(|channel| w.channel != channel) && !is_blacklisted(&w.bssid)
+        })
+        .await;
+    let scanned = !CANDIDATES.is_empty().await;
+    if top.is_empty() && scanned {
+        info!("Every candidate is held off or blacklisted, waiting");
+        return FsmEvent::NothingToTry;
+    }
+    if top.is_empty() {
+        // nothing scanned yet, try the configured WG
+        set_connection_state(ConnectionState::Associating);
+        return match with_timeout(config.connect_timeout, controller.connect_async()).await {
+            Ok(Ok(_)) => {
+                info!("Wifi Connected!");
+                // which of the networks answered isn't known, every
+                // restriction applies until the next association
+                set_associated(strictest_policy());
+                reset_backoff();
+                update_link_status(|s| s.connects += 1);
+                FsmEvent::Associated(None)
+            }
+            failed => {
+                match failed {
+                    Ok(Err(err)) => info!("Failed to connect to wifi {:?}", err),
+                    _ => {
+                        info!("Connect timed out");
+                        // stop the radio from finishing the abandoned attempt
+                        let _ = controller.disconnect_async().await;
+                    }
+                }
+                set_connection_state(ConnectionState::Disconnected);
+                update_link_status(|s| s.connect_failures += 1);
+                FsmEvent::AssociationFailed
+            }
+        };
+    }
+
+    // triage: short association-only attempts down the top candidates, DHCP
+    // and the rest of the pipeline only run on the first one that answers
+    let timeout = TRIAGE_TIMEOUT.min(config.connect_timeout);
+    for candidate in &top {
+        let mode = mode_config_for_candidate(candidate);
+        controller
+            .set_config(&with_diagnostics_ap(mode, config.diagnostics_ap.as_ref()))
+            .unwrap();
+        info!("Attempting to connect to {:?}", candidate);
+        eventlog::record(Event::CandidateChosen {
+            bssid: candidate.bssid,
+            rssi: candidate.signal_strength,
+        });
+        set_connection_state(ConnectionState::Associating);
+        let started = Instant::now();
+        let outcome = match with_timeout(timeout, controller.connect_async()).await {
+            Ok(Ok(_)) => {
+                let associated_at = Instant::now();
+                let associate_ms =
+                    (associated_at - started).as_millis().min(u16::MAX as u64) as u16;
+                info!("Wifi Connected in {}ms!", associate_ms);
+                set_associated(policy_for(candidate));
+                apply_ip_mode(
+                    stack,
+                    ip_mode_after_connect(&candidate.bssid, &candidate.ssid),
+                );
+                clear_blacklisted(&candidate.bssid);
+                reset_backoff();
+                CANDIDATES
+                    .mark_result(candidate.bssid, Outcome::AssociateTime(associate_ms))
+                    .await;
+                let best = mark_attempt(
+                    candidate.bssid,
+                    Outcome::Connected {
+                        at: associated_at.as_secs(),
+                    },
+                )
+                .await;
+                update_link_status(|s| {
+                    s.connects += 1;
+                    s.current = best;
+                    s.associated_at = Some(associated_at);
+                });
+                return FsmEvent::Associated(Some(candidate.bssid));
+            }
+            Ok(Err(err)) => {
+                info!("Failed to connect to wifi {:?}", err);
+                let last = last_disconnect().filter(|d| d.bssid == candidate.bssid);
+                eventlog::record(Event::ConnectFailed {
+                    bssid: candidate.bssid,
+                    reason: last.map(|d| d.reason),
+                });
+                // it rejected us, retrying straight away won't change its mind
+                if last.is_some_and(|d| d.category == DisconnectCategory::AuthFailure) {
+                    blacklist(candidate);
+                }
+                Outcome::Failed
+            }
+            Err(_) => {
+                info!(
+                    "No answer from {:?} within {}ms",
+                    candidate.bssid,
+                    timeout.as_millis()
+                );
+                eventlog::record(Event::ConnectFailed {
+                    bssid: candidate.bssid,
+                    reason: None,
+                });
+                // stop the radio from finishing the abandoned attempt
+                let _ = controller.disconnect_async().await;
+                Outcome::TimedOut
+            }
+        };
+        set_connection_state(ConnectionState::Disconnected);
+        mark_attempt(candidate.bssid, outcome).await;
+        update_link_status(|s| {
+            s.connect_failures += 1;
+            s.current = None;
+        });
+    }
+    // the failures have sunk, the next round starts from a fresh top
+    FsmEvent::AssociationFailed
+}
+
+// record the outcome of a connect attempt on the candidate, returns it as it is now
+async fn mark_attempt(bssid: [u8; 6], outcome: Outcome) -> Option<WifiConfig> {
+    record_connect(bssid, matches!(outcome, Outcome::Connected { .. }));
+    CANDIDATES.mark_result(bssid, outcome).await
+}
+
+async fn run_connected(
+    controller: &mut WifiController<'static>,
+    sniffer: &mut Sniffer<'static>,
+    current: Option<([u8; 6], Instant)>,
+    config: &WifiManagerConfig,
+) -> FsmEvent {
+    info!("Connected, waiting for disconnect or scan");
+    // keep candidates fresh from beacons instead of scanning
+    if !safe_mode() {
+        beacons::start(sniffer, current.map(|(bssid, _)| bssid));
+        if let Some((bssid, _)) = current {
+            let pmf = CANDIDATES
+                .find(&bssid)
+                .await
+                .is_none_or(|c| pmf_negotiated(&c));
+            neighbors::request_report(sniffer, bssid, pmf);
+        }
+    }
+    let disconnect_evt = controller.wait_for_event(WifiEvent::StaDisconnected);
+
+    let scan_event = SCAN_CMD.wait();
+    let request = WIFI_REQUEST.receive();
+    // the deauth monitor and the WG's own neighbor hints
+    let alerts = select::select(DEAUTH_STORM.wait(), NEIGHBORS.wait());
+
+    let event = beat_while(
+        Watched::WifiMgr,
+        select::select4(disconnect_evt, scan_event, request, alerts),
+    )
+    .await;
+    // whatever happens next needs the radio
+    beacons::stop(sniffer);
+    match event {
+        // these take the link down, they wait for the application's window to end
+        select::Either4::Third(
+            request @ (WifiRequest::Reconnect | WifiRequest::Forget | WifiRequest::Capture(_)),
+        ) if roaming_held() => {
+            info!("Roaming held, {:?} queued", request);
+            queue_request(request);
+            FsmEvent::Stayed
+        }
+        select::Either4::Third(WifiRequest::Reconnect) => {
+            // drop the link, run_disconnected picks the best candidate again
+            info!("Reconnect requested");
+            if let Err(e) = controller.disconnect_async().await {
+                info!("Failed to disconnect {:?}", e);
+            }
+            set_connection_state(ConnectionState::Disconnected);
+            update_link_status(|s| s.current = None);
+            FsmEvent::Left
+        }
+        select::Either4::Third(WifiRequest::Forget) => {
+            // the candidate picker skips it, run_disconnected takes the next best
+            if let Some(current) = link_status().current {
+                info!("Forget requested");
+                forget(&current);
+            }
+            if let Err(e) = controller.disconnect_async().await {
+                info!("Failed to disconnect {:?}", e);
+            }
+            set_connection_state(ConnectionState::Disconnected);
+            update_link_status(|s| s.current = None);
+            FsmEvent::Left
+        }
+        select::Either4::Third(WifiRequest::Stop) => {
+            // leave cleanly, so the WG doesn't wait out our inactivity timer
+            if let Err(e) = controller.disconnect_async().await {
+                info!("Failed to disconnect for stop {:?}", e);
+            }
+            set_connection_state(ConnectionState::Disconnected);
+            update_link_status(|s| s.current = None);
+            FsmEvent::StopRequested
+        }
+        select::Either4::Third(WifiRequest::Start) => FsmEvent::Stayed,
+        select::Either4::Third(WifiRequest::SetPreset(preset)) => {
+            change_preset(controller, preset);
+            FsmEvent::Stayed
+        }
+        select::Either4::Third(WifiRequest::SetTxPower(profile)) => {
+            change_tx_power(controller, profile);
+            FsmEvent::Stayed
+        }
+        select::Either4::Third(WifiRequest::Capture(request)) => {
+            // the radio can only hop channels while unassociated
+            if let Err(e) = controller.disconnect_async().await {
+                info!("Failed to disconnect for capture {:?}", e);
+            }
+            set_connection_state(ConnectionState::Disconnected);
+            update_link_status(|s| s.current = None);
+            FsmEvent::CaptureRequested(request)
+        }
+        select::Either4::Fourth(select::Either::First(event)) => {
+            // the deauths will likely take the link down, the disconnect
+            // branch then knows to stay off the channel
+            report_deauth_storm(event);
+            FsmEvent::Stayed
+        }
+        select::Either4::Fourth(select::Either::Second(list)) => {
+            follow_neighbors(controller, current, list, config).await
+        }
+        select::Either4::First(_) => {
+            // we're disconnected, pick the next gateway
+            set_connection_state(ConnectionState::Disconnected);
+            update_link_status(|s| {
+                s.disconnects += 1;
+                s.current = None;
+            });
+            record_disconnect();
+            let held_off = held_off_channel();
+            let category = last_disconnect().map(|d| d.category);
+            info!("Lost the WG, {:?}", category);
+            // note the disconnect on the WG we were on, the best one if we
+            // weren't tracking which. Not its fault if we left, roamed or were
+            // knocked off by an attacker
+            let lost = match current {
+                Some((bssid, _)) => CANDIDATES.find(&bssid).await,
+                None => CANDIDATES.best().await,
+            };
+            if let Some(lost) = &lost {
+                let attacked = held_off == Some(lost.channel);
+                if category.is_none_or(|c| c.blames_ap()) && !attacked {
+                    CANDIDATES.mark_result(lost.bssid, Outcome::Failed).await;
+                }
+            }
+            let lost_channel = current.and(lost).map(|w| (w.bssid, w.channel));
+            DISCONNECT_DETECTED.signal(());
+            // usually the AP just rebooted, look where it was before sweeping every channel
+            match lost_channel {
+                _ if safe_mode() => {}
+                Some((bssid, channel)) if channel != 0 && held_off != Some(channel) => {
+                    // a failed quick scan falls back to the full one, which retries
+                    match scan_channel(controller, channel, &config.scan).await {
+                        Ok(found) => {
+                            let back = found.iter().any(|w| w.bssid == bssid);
+                            merge_scan(found).await;
+                            if !back {
+                                SCAN_CMD.signal(());
+                            }
+                        }
+                        Err(e) => {
+                            info!("Channel scan failed {:?}", e);
+                            SCAN_CMD.signal(());
+                        }
+                    }
+                }
+                _ => SCAN_CMD.signal(()),
+            }
+            FsmEvent::LinkLost(category)
+        }
+        select::Either4::Second(_) => {
+            // the scan would take the radio off channel, and a roam the link down
+            if config.quiet_hours.is_quiet() {
+                info!("Quiet hours, scan dropped");
+                return FsmEvent::Stayed;
+            }
+            if roaming_held() {
+                info!("Roaming held, scan queued");
+                queue_scan();
+                return FsmEvent::Stayed;
+            }
+            let report = current.and_then(|(bssid, _)| neighbors::report_channels(&bssid));
+            match (report, config.gentle_scan) {
+                // the WG told us where its neighbors are, no need to sweep
+                (Some(channels), _) => scan_channels(controller, &channels, config).await,
+                (None, true) => {
+                    let own_channel = match current {
+                        Some((bssid, _)) => CANDIDATES.find(&bssid).await.map(|w| w.channel),
+                        None => None,
+                    };
+                    gentle_scan(controller, &config.scan, own_channel).await
+                }
+                (None, false) => do_scan(controller, &config.scan).await,
+            }
+            roam_if_better(controller, current, false).await
+        }
+    }
+}
+
+// after a scan: leave for the best candidate if the roaming profile says so,
+// or whenever it isn't the current WG when `steered` by the WG itself
+async fn roam_if_better(
+    controller: &mut WifiController<'static>,
+    current: Option<([u8; 6], Instant)>,
+    steered: bool,
+) -> FsmEvent {
+    let Some((bssid, connected_at)) = current else {
+        return FsmEvent::Stayed;
+    };
+    let cur = CANDIDATES.find(&bssid).await;
+    // the scan refreshed our AP's RSSI
+    if let Some(cur) = &cur {
+        update_link_status(|s| s.current = Some(cur.clone()));
+    }
+    let roam = match (cur, CANDIDATES.best().await) {
+        (Some(cur), Some(best)) if steered => best.bssid != cur.bssid,
+        (Some(cur), Some(best)) => active_profile().roam.should_roam(&cur, connected_at, &best),
+        _ => false,
+    };
+    // a hold taken while we scanned, the ranking is looked at again after it
+    if roam && roaming_held() {
+        info!("Roaming held, roam from {:?} queued", bssid);
+        queue_scan();
+        return FsmEvent::Stayed;
+    }
+    if roam {
+        // the best candidate sits at the top, run_disconnected will pick it up
+        info!("Roaming away from {:?}", bssid);
+        if let Err(e) = controller.disconnect_async().await {
+            info!("Failed to disconnect for roam {:?}", e);
+        }
+        set_connection_state(ConnectionState::Disconnected);
+        return FsmEvent::Left;
+    }
+    FsmEvent::Stayed
+}
+
+// quick scans of each channel in `channels`, folded into CANDIDATES
+async fn scan_channels(
+    controller: &mut WifiController<'static>,
+    channels: &[u8],
+    config: &WifiManagerConfig,
+) {
+    for channel in channels {
+        match scan_channel(controller, *channel, &config.scan).await {
+            Ok(found) => merge_scan(found).await,
+            Err(e) => info!("Channel scan failed {:?}", e),
+        }
+    }
+}
+
+// SAE and transition WGs always do PMF, and so does a network pinned to it.
+// A WPA2 WG doing it anyway shows up as protected frames in neighbors
+fn pmf_negotiated(wifi: &WifiConfig) -> bool {
+    wifi.security.has_pmf() || credential_of(wifi).is_some_and(|c| c.pmf == Pmf::Required)
+}
+
+// a neighbor report or transition request from the WG: scan just the channels
+// it named, then roam the usual way, or straight to the best of its
+// suggestions for a transition request
+async fn follow_neighbors(
+    controller: &mut WifiController<'static>,
+    current: Option<([u8; 6], Instant)>,
+    list: NeighborList,
+    config: &WifiManagerConfig,
+) -> FsmEvent {
+    info!(
+        "{:?} from {:02x}: {} neighbors",
+        list.source,
+        Bytes(&list.from),
+        list.neighbors.len()
+    );
+    let steered = match list.source {
+        NeighborSource::Report => false,
+        NeighborSource::Transition { imminent } => {
+            // we'll be dropped anyway, the current WG has to compete without its history
+            if imminent {
+                CANDIDATES.mark_result(list.from, Outcome::Failed).await;
+            }
+            neighbors::steer(&list);
+            true
+        }
+    };
+    // the frames aren't authenticated, a forged transition request mustn't
+    // move us when we otherwise wouldn't
+    if config.quiet_hours.is_quiet() {
+        info!("Quiet hours, neighbor report kept for later scans");
+        return FsmEvent::Stayed;
+    }
+    if safe_mode() {
+        return FsmEvent::Stayed;
+    }
+    // the candidates' bonus outlasts a short hold, the queued scan finds them
+    if roaming_held() {
+        info!("Roaming held, neighbor scan queued");
+        queue_scan();
+        return FsmEvent::Stayed;
+    }
+    scan_channels(controller, &list.channels(), config).await;
+    roam_if_better(controller, current, steered).await
+}
+
+// switch preset at runtime and remember it across reboots
+fn change_preset(controller: &mut WifiController<'static>, preset: RoamPreset) {
+    info!("Switching roaming preset to {:?}", preset);
+    set_active_preset(preset);
+    apply_preset(controller, preset);
+    store_settings();
+}
+
+// e.g. drop to LowPowerIndoor next to the gateway, remembered across reboots
+fn change_tx_power(controller: &mut WifiController<'static>, profile: TxPowerProfile) {
+    set_active_tx_power(profile);
+    apply_tx_power(controller, profile);
+    store_settings();
+}
+
+fn store_settings() {
+    STORE_SETTINGS.signal(Settings {
+        preset: active_preset(),
+        tx_power: active_tx_power(),
+    });
+}
+
+// turn the radio off, flush persistence and park until WifiRequest::Start
+async fn run_stopped(controller: &mut WifiController<'static>) -> FsmEvent {
+    info!("Stopping wifi");
+    set_radio_entropy(false);
+    if let Err(e) = controller.stop_async().await {
+        info!("Failed to stop wifi {:?}", e);
+    }
+    set_connection_state(ConnectionState::Stopped);
+    // pending writes land before the application powers down
+    FLUSHED.reset();
+    FLUSH.signal(());
+    FLUSHED.wait().await;
+    WIFI_STOPPED.signal(());
+    loop {
+        match beat_while(Watched::WifiMgr, WIFI_REQUEST.receive()).await {
+            WifiRequest::Start => break,
+            WifiRequest::Stop => WIFI_STOPPED.signal(()),
+            // the radio part is applied on start
+            WifiRequest::SetPreset(preset) => {
+                set_active_preset(preset);
+                store_settings();
+            }
+            WifiRequest::SetTxPower(profile) => {
+                set_active_tx_power(profile);
+                store_settings();
+            }
+            request => info!("Ignoring {:?} while stopped", request),
+        }
+    }
+    info!("Starting wifi");
+    if let Err(e) = controller.start_async().await {
+        info!("Failed to start wifi {:?}", e);
+    }
+    radio_started();
+    apply_preset(controller, active_preset());
+    apply_tx_power(controller, active_tx_power());
+    set_connection_state(ConnectionState::Disconnected);
+    FsmEvent::Started
+}
+
+// the RNG draws on the radio's noise now, passphrases waiting in plaintext
+// can be sealed
+fn radio_started() {
+    set_radio_entropy(true);
+    SEAL_PASSPHRASES.signal(());
+}
+
+// the parts of a preset that live in the radio
+fn apply_preset(controller: &mut WifiController<'static>, preset: RoamPreset) {
+    if let Err(e) = controller.set_power_saving(preset.profile().power_save) {
+        info!("Failed to set power saving {:?}", e);
+    }
+}
@@ -0,0 +1,244 @@
+use core::fmt::Write as _;
+
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{http::HTTP_PORT, netconfig::device_name};
+
+const MDNS_ADDR: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+// what zeroconf browsers look for, <device name>._wg-scan._tcp.local
+const SERVICE: [&str; 3] = ["_wg-scan", "_tcp", "local"];
+// lets browsers list every service type on the network
+const SERVICE_ENUMERATION: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+// what the status API answers on, in the TXT record
+const TXT_PATH: &str = "path=/status";
+const PACKET_LEN: usize = 512;
+// RFC 6762: records naming the host expire quickly, the rest can live long
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// in a response: replaces what caches hold for this name and type
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// answers mDNS queries for `device_name().local` and announces the status API
+/// as a `_wg-scan._tcp` service, every time we get an address
+#[embassy_executor::task]
+pub async fn mdns_task(stack: Stack<'static>) -> ! {
+    info!("Start mdns task");
+    let host = device_name();
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; PACKET_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(MDNS_PORT).unwrap();
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_ADDR), MDNS_PORT);
+
+    let mut packet = [0u8; PACKET_LEN];
+    loop {
+        stack.wait_config_up().await;
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+        let ip = config.address.address();
+        // a no-op once joined, but the new network's IGMP snooping hears about us
+        if let Err(e) = stack.join_multicast_group(MDNS_ADDR) {
+            info!("mDNS join error: {:?}", e);
+        }
+        info!("mDNS announcing {}.local at {}", host.as_str(), ip);
+        // RFC 6762 wants at least two announcements, a second apart
+        for _ in 0..2 {
+            if let Some(len) = response(&mut packet, 0, &host, ip) {
+                let _ = socket.send_to(&packet[..len], group).await;
+            }
+            Timer::after(Duration::from_secs(1)).await;
+        }
+
+        loop {
+            let (len, from) =
+                match select(socket.recv_from(&mut packet), stack.wait_config_down()).await {
+                    Either::First(Ok((len, meta))) => (len, meta.endpoint),
+                    Either::First(Err(e)) => {
+                        info!("mDNS recv error: {:?}", e);
+                        continue;
+                    }
+                    // a new address needs announcing
+                    Either::Second(_) => break,
+                };
+            let Some(id) = asks_for_us(&packet[..len], &host) else {
+                continue;
+            };
+            // one-shot resolvers query from another port and only take a
+            // unicast answer carrying their id
+            let (id, to) = match from.port == MDNS_PORT {
+                true => (0, group),
+                false => (id, from),
+            };
+            if let Some(len) = response(&mut packet, id, &host, ip) {
+                let _ = socket.send_to(&packet[..len], to).await;
+            }
+        }
+    }
+}
+
+// the query id if `query` asks about our host, service or instance
+fn asks_for_us(query: &[u8], host: &str) -> Option<u16> {
+    let id = u16::from_be_bytes([*query.first()?, *query.get(1)?]);
+    let flags = u16::from_be_bytes([*query.get(2)?, *query.get(3)?]);
+    // responses from other responders
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([*query.get(4)?, *query.get(5)?]);
+    let mut at = 12;
+    for _ in 0..questions {
+        let (name, next) = read_name(query, at)?;
+        let qtype = u16::from_be_bytes([*query.get(next)?, *query.get(next + 1)?]);
+        at = next + 4;
+        let ours = match qtype {
+            TYPE_A => is_name(&name, &[host, "local"]),
+            TYPE_PTR => is_name(&name, &SERVICE) || is_name(&name, &SERVICE_ENUMERATION),
+            TYPE_SRV | TYPE_TXT => is_name(&name, &[host, SERVICE[0], SERVICE[1], SERVICE[2]]),
+            TYPE_ANY => {
+                is_name(&name, &[host, "local"])
+                    || is_name(&name, &[host, SERVICE[0], SERVICE[1], SERVICE[2]])
+            }
+            _ => false,
+        };
+        if ours {
+            return Some(id);
+        }
+    }
+    None
+}
+
+fn is_name(name: &str, labels: &[&str]) -> bool {
+    let mut dotted: heapless::String<128> = heapless::String::new();
+    for label in labels {
+        let _ = write!(dotted, "{}.", label);
+    }
+    name.eq_ignore_ascii_case(dotted.trim_end_matches('.'))
+}
+
+// the dotted name at `at` and where the packet continues after it,
+// following compression pointers
+fn read_name(packet: &[u8], mut at: usize) -> Option<(heapless::String<128>, usize)> {
+    let mut name = heapless::String::new();
+    let mut next = None;
+    // a pointer loop ends here
+    for _ in 0..16 {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => return Some((name, next.unwrap_or(at + 1))),
+            l if l & 0xc0 == 0xc0 => {
+                next.get_or_insert(at + 2);
+                at = (l & 0x3f) << 8 | *packet.get(at + 1)? as usize;
+            }
+            l => {
+                let label = core::str::from_utf8(packet.get(at + 1..at + 1 + l)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(label).ok()?;
+                at += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+// every record we have, the length written to `packet`
+fn response(packet: &mut [u8], id: u16, host: &str, ip: Ipv4Address) -> Option<usize> {
+    let instance = [host, SERVICE[0], SERVICE[1], SERVICE[2]];
+    let mut w = Writer { packet, len: 0 };
+    w.put(&id.to_be_bytes())?;
+    // response, authoritative
+    w.put(&0x8400u16.to_be_bytes())?;
+    // no questions, five answers
+    w.put(&[0, 0, 0, 5, 0, 0, 0, 0])?;
+    w.record(&SERVICE_ENUMERATION, TYPE_PTR, false, SERVICE_TTL, |w| {
+        w.name(&SERVICE)
+    })?;
+    w.record(&SERVICE, TYPE_PTR, false, SERVICE_TTL, |w| {
+        w.name(&instance)
+    })?;
+    w.record(&instance, TYPE_SRV, true, HOST_TTL, |w| {
+        // priority, weight
+        w.put(&[0, 0, 0, 0])?;
+        w.put(&HTTP_PORT.to_be_bytes())?;
+        w.name(&[host, "local"])
+    })?;
+    w.record(&instance, TYPE_TXT, true, SERVICE_TTL, |w| {
+        w.put(&[TXT_PATH.len() as u8])?;
+        w.put(TXT_PATH.as_bytes())
+    })?;
+    w.record(&[host, "local"], TYPE_A, true, HOST_TTL, |w| {
+        w.put(&ip.octets())
+    })?;
+    Some(w.len)
+}
+
+struct Writer<'a> {
+    packet: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.packet.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    // uncompressed, the packet is small enough
+    fn name(&mut self, labels: &[&str]) -> Option<()> {
+        for label in labels {
+            self.put(&[label.len() as u8])?;
+            self.put(label.as_bytes())?;
+        }
+        self.put(&[0])
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        unique: bool,
+        ttl: u32,
+        rdata: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.put(&rtype.to_be_bytes())?;
+        let class = match unique {
+            true => CLASS_IN | CACHE_FLUSH,
+            false => CLASS_IN,
+        };
+        self.put(&class.to_be_bytes())?;
+        self.put(&ttl.to_be_bytes())?;
+        // the length goes in front once the data is written
+        let len_at = self.len;
+        self.put(&[0, 0])?;
+        rdata(self)?;
+        let rdlen = (self.len - len_at - 2) as u16;
+        self.packet[len_at..len_at + 2].copy_from_slice(&rdlen.to_be_bytes());
+        Some(())
+    }
+}
@@ -0,0 +1,178 @@
+//! heap and stack usage, so HEAP_SIZE and the core stacks can be sized from
+//! data. The heap figures come from esp-alloc and cover the radio driver's
+//! allocations too. Embassy tasks have no stacks of their own: their futures
+//! sit in static arenas sized at link time, and whatever runs them borrows the
+//! executor's stack. The stacks worth watching are the cores': core 0's main
+//! stack and the network core's. Both are painted at boot, the high-water mark
+//! is how much of the paint has been worn off
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Ticker};
+use serde::Serialize;
+
+const PAINT: u32 = 0xa5a5_a5a5;
+// left unpainted below the painting function's own frame
+const PAINT_MARGIN: usize = 512;
+// esp-hal's guard word sits this far above a stack's bottom, watched for
+// overflows (ESP_HAL_CONFIG_STACK_GUARD_OFFSET, its default). The paint
+// starts above it, so neither overwrites the other
+const STACK_GUARD_OFFSET: usize = 4096;
+const GUARD_WORDS: usize = STACK_GUARD_OFFSET / 4 + 1;
+const REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+unsafe extern "C" {
+    // bounds of core 0's stack from the esp-hal linker script, it grows down
+    // from _stack_start_cpu0
+    static _stack_end_cpu0: u32;
+    static _stack_start_cpu0: u32;
+}
+
+/// esp-alloc's view of the heap, in bytes
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HeapUsage {
+    pub size: usize,
+    pub used: usize,
+    // the most ever in use at once since boot
+    pub peak: usize,
+    // totals since boot
+    pub allocated: usize,
+    pub freed: usize,
+}
+
+/// one core's stack, in bytes
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StackUsage {
+    pub size: usize,
+    // the deepest it has been since it was painted
+    pub peak: usize,
+}
+
+/// served on /status
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MemoryUsage {
+    pub heap: HeapUsage,
+    // core 0, None until paint_main_stack ran
+    pub main_stack: Option<StackUsage>,
+    // the network core's on dual-core chips
+    pub network_stack: Option<StackUsage>,
+}
+
+// a painted stack: its lowest word and its size in words
+#[derive(Clone, Copy)]
+struct Painted {
+    bottom: *const u32,
+    words: usize,
+}
+
+// SAFETY: only ever read, word by word, and the stacks are static
+unsafe impl Send for Painted {}
+
+static MAIN_STACK: Mutex<CriticalSectionRawMutex, Cell<Option<Painted>>> =
+    Mutex::new(Cell::new(None));
+static NETWORK_STACK: Mutex<CriticalSectionRawMutex, Cell<Option<Painted>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn heap_usage() -> HeapUsage {
+    let stats = esp_alloc::HEAP.stats();
+    HeapUsage {
+        size: stats.size,
+        used: stats.current_usage,
+        peak: stats.max_usage,
+        allocated: stats.total_allocated,
+        freed: stats.total_freed,
+    }
+}
+
+pub fn memory_usage() -> MemoryUsage {
+    MemoryUsage {
+        heap: heap_usage(),
+        main_stack: MAIN_STACK.lock(|s| s.get()).map(stack_usage),
+        network_stack: NETWORK_STACK.lock(|s| s.get()).map(stack_usage),
+    }
+}
+
+/// paints the free part of core 0's stack, call it first thing in main
+#[inline(never)]
+pub fn paint_main_stack() {
+    let marker = 0u32;
+    // linker symbols, only their addresses mean anything
+    let end = &raw const _stack_end_cpu0 as usize;
+    let start = &raw const _stack_start_cpu0 as usize;
+    let sp = &raw const marker as usize;
+    let bottom = end + GUARD_WORDS * 4;
+    let top = sp.saturating_sub(PAINT_MARGIN).max(bottom);
+    // SAFETY: below our frame and the margin the stack is unused, nothing
+    // lives there yet
+    unsafe { paint(bottom as *mut u32, (top - bottom) / 4) };
+    let painted = Painted {
+        bottom: bottom as *const u32,
+        words: start.saturating_sub(bottom) / 4,
+    };
+    MAIN_STACK.lock(|s| s.set(Some(painted)));
+}
+
+/// paints a stack before a core starts on it, see src/multicore.rs. Only
+/// above the guard word the core's startup writes
+///
+/// # Safety
+/// `bottom` must be the lowest word of `words` words nothing uses yet, that
+/// stay allocated for good
+pub unsafe fn paint_network_stack(bottom: *mut u32, words: usize) {
+    let skip = GUARD_WORDS.min(words);
+    // SAFETY: still within the caller's words
+    let bottom = unsafe { bottom.add(skip) };
+    let words = words - skip;
+    unsafe { paint(bottom, words) };
+    let painted = Painted {
+        bottom: bottom as *const u32,
+        words,
+    };
+    NETWORK_STACK.lock(|s| s.set(Some(painted)));
+}
+
+unsafe fn paint(bottom: *mut u32, words: usize) {
+    for i in 0..words {
+        unsafe { bottom.add(i).write_volatile(PAINT) };
+    }
+}
+
+// the stack grows down, so the paint left at the bottom was never reached
+fn stack_usage(painted: Painted) -> StackUsage {
+    let untouched = (0..painted.words)
+        // SAFETY: within the painted stack, read one word at a time
+        .take_while(|&i| unsafe { painted.bottom.add(i).read_volatile() } == PAINT)
+        .count();
+    StackUsage {
+        size: painted.words * 4,
+        peak: (painted.words - untouched) * 4,
+    }
+}
+
+/// logs heap and stack usage every REPORT_INTERVAL
+#[embassy_executor::task]
+pub async fn memory_task() -> ! {
+    info!("Start memory task");
+    let mut ticker = Ticker::every(REPORT_INTERVAL);
+    loop {
+        let usage = memory_usage();
+        info!(
+            "Heap {}/{} bytes, peak {}",
+            usage.heap.used, usage.heap.size, usage.heap.peak
+        );
+        if let Some(stack) = usage.main_stack {
+            info!("Core 0 stack peak {}/{} bytes", stack.peak, stack.size);
+        }
+        if let Some(stack) = usage.network_stack {
+            info!(
+                "Network core stack peak {}/{} bytes",
+                stack.peak, stack.size
+            );
+        }
+        ticker.next().await;
+    }
+}
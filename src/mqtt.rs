@@ -0,0 +1,241 @@
+use alloc::string::String;
+use embassy_net::{Stack, dns::DnsQueryType, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, Timer, with_deadline};
+use rust_mqtt::{
+    client::{
+        client::MqttClient,
+        client_config::{ClientConfig as MqttConfig, MqttVersion},
+    },
+    packet::v5::publish_packet::QualityOfService,
+    utils::rng_generator::CountingRng,
+};
+use serde::Serialize;
+
+#[cfg(feature = "tls")]
+use crate::tls::{TlsBuffers, TlsSocket};
+use crate::{
+    CANDIDATES, WIFI_REQUEST, WifiConfig, WifiRequest,
+    fmt::Debug2Format,
+    netconfig::device_name,
+    roaming::RoamPreset,
+    stats::stats,
+    status::{link_status, network_policy, secs_since_last_probe},
+    telemetry::{AllocSite, report_oom},
+    timeseries::{self, MAX_BATCH, uploads_over_mqtt},
+};
+
+const MQTT_BROKER: &str = env!("MQTT_BROKER");
+const MQTT_TOPIC: &str = env!("MQTT_TOPIC");
+// publish "stationary", "mobile" or "battery" here to switch roaming preset
+const MQTT_PRESET_TOPIC: &str = concat!(env!("MQTT_TOPIC"), "/preset");
+// sample batches, with TimeSeries uploading over MQTT
+const MQTT_SAMPLES_TOPIC: &str = concat!(env!("MQTT_TOPIC"), "/samples");
+#[cfg(not(feature = "tls"))]
+const MQTT_PORT: u16 = 1883;
+#[cfg(feature = "tls")]
+const MQTT_PORT: u16 = 8883;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+// keep the json within the mqtt buffers
+const MAX_REPORTED_CANDIDATES: usize = 5;
+const MQTT_BUFFER_LEN: usize = 1024;
+
+// the document published on MQTT_TOPIC
+#[derive(Serialize)]
+struct StatusReport<'a> {
+    client_id: &'a str,
+    current: Option<&'a WifiConfig>,
+    connects: u32,
+    reconnects: u32,
+    connect_failures: u32,
+    disconnects: u32,
+    // None until the first internet probe succeeds
+    secs_since_probe: Option<u64>,
+    boots: u32,
+    candidates: &'a [WifiConfig],
+}
+
+/// periodically publishes the current WG, its RSSI, the candidate list and
+/// reconnect counters to MQTT_TOPIC on MQTT_BROKER
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: Stack<'static>) -> ! {
+    info!("Start mqtt task");
+    let client_id = device_name();
+
+    let mut rx_buffer = [0; MQTT_BUFFER_LEN];
+    let mut tx_buffer = [0; MQTT_BUFFER_LEN];
+    loop {
+        stack.wait_config_up().await;
+        run_session(stack, &client_id, &mut rx_buffer, &mut tx_buffer).await;
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
+
+// connects to the broker and publishes until something fails
+async fn run_session(
+    stack: Stack<'static>,
+    client_id: &str,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) {
+    let addr = match stack.dns_query(MQTT_BROKER, DnsQueryType::A).await {
+        Ok(addrs) => match addrs.first() {
+            Some(addr) => *addr,
+            None => return,
+        },
+        Err(e) => {
+            info!("MQTT dns error: {:?}", e);
+            return;
+        }
+    };
+
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(SOCKET_TIMEOUT));
+    if let Err(e) = socket.connect((addr, MQTT_PORT)).await {
+        info!("MQTT connect error: {:?}", e);
+        return;
+    }
+    #[cfg(feature = "tls")]
+    let Some(mut buffers) = TlsBuffers::alloc() else {
+        return;
+    };
+    #[cfg(feature = "tls")]
+    let Ok(socket) = TlsSocket::open(socket, MQTT_BROKER, &mut buffers).await else {
+        return;
+    };
+
+    let mut config = MqttConfig::new(MqttVersion::MQTTv5, CountingRng(20000));
+    config.add_client_id(client_id);
+    config.max_packet_size = MQTT_BUFFER_LEN as u32;
+    let mut recv_buffer = [0; MQTT_BUFFER_LEN];
+    let mut write_buffer = [0; MQTT_BUFFER_LEN];
+    let mut client = MqttClient::<_, 5, _>::new(
+        socket,
+        &mut write_buffer,
+        MQTT_BUFFER_LEN,
+        &mut recv_buffer,
+        MQTT_BUFFER_LEN,
+        config,
+    );
+    if let Err(e) = client.connect_to_broker().await {
+        info!("MQTT broker refused: {:?}", Debug2Format(&e));
+        return;
+    }
+    info!("MQTT connected to {}", MQTT_BROKER);
+    if let Err(e) = client.subscribe_to_topic(MQTT_PRESET_TOPIC).await {
+        info!("MQTT subscribe error: {:?}", Debug2Format(&e));
+        return;
+    }
+
+    loop {
+        // a no-telemetry network gets no reports, a metered one no samples
+        let policy = network_policy();
+        // no report this round if it couldn't be built, commands still work
+        let report = match policy.allows_telemetry() {
+            true => build_report(client_id).await,
+            false => None,
+        };
+        if let Some(payload) = report {
+            if let Err(e) = client
+                .send_message(
+                    MQTT_TOPIC,
+                    payload.as_bytes(),
+                    QualityOfService::QoS0,
+                    false,
+                )
+                .await
+            {
+                info!("MQTT publish error: {:?}", Debug2Format(&e));
+                return;
+            }
+        }
+        // every waiting sample, a batch leaves the ring once the broker took it
+        let mut batch = [0u8; MQTT_BUFFER_LEN / 2];
+        while uploads_over_mqtt() && policy.allows_bulk_telemetry() {
+            let Some((len, last)) = timeseries::encode_batch(&mut batch, MAX_BATCH) else {
+                break;
+            };
+            if let Err(e) = client
+                .send_message(
+                    MQTT_SAMPLES_TOPIC,
+                    &batch[..len],
+                    QualityOfService::QoS1,
+                    false,
+                )
+                .await
+            {
+                info!("MQTT samples publish error: {:?}", Debug2Format(&e));
+                return;
+            }
+            timeseries::ack(last);
+        }
+
+        // listen for commands until the next report is due
+        let deadline = Instant::now() + REPORT_INTERVAL;
+        while let Ok(received) = with_deadline(deadline, client.receive_message()).await {
+            match received {
+                Ok((topic, message)) if topic == MQTT_PRESET_TOPIC => {
+                    handle_preset_message(message)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    info!("MQTT receive error: {:?}", Debug2Format(&e));
+                    return;
+                }
+            }
+        }
+        if let Err(e) = client.send_ping().await {
+            info!("MQTT ping error: {:?}", Debug2Format(&e));
+            return;
+        }
+    }
+}
+
+fn handle_preset_message(message: &[u8]) {
+    let name = core::str::from_utf8(message).unwrap_or_default().trim();
+    match RoamPreset::from_name(name) {
+        Some(preset) => {
+            if WIFI_REQUEST
+                .try_send(WifiRequest::SetPreset(preset))
+                .is_err()
+            {
+                info!("MQTT preset dropped, request queue full");
+            }
+        }
+        None => info!("MQTT unknown preset {}", name),
+    }
+}
+
+async fn build_report(client_id: &str) -> Option<String> {
+    let status = link_status();
+    let candidates = CANDIDATES.top::<MAX_REPORTED_CANDIDATES>(|_| true).await;
+    let report = StatusReport {
+        client_id,
+        current: status.current.as_ref(),
+        connects: status.connects,
+        reconnects: status.reconnects(),
+        connect_failures: status.connect_failures,
+        disconnects: status.disconnects,
+        secs_since_probe: secs_since_last_probe(),
+        boots: stats().boots,
+        candidates: &candidates,
+    };
+    let mut bytes = [0u8; MQTT_BUFFER_LEN / 2];
+    let len = match serde_json_core::to_slice(&report, &mut bytes) {
+        Ok(len) => len,
+        Err(_) => {
+            info!("MQTT report too large");
+            return None;
+        }
+    };
+    let mut payload = String::new();
+    if payload.try_reserve_exact(len).is_err() {
+        report_oom(AllocSite::MqttReport);
+        return None;
+    }
+    // serde_json_core only writes utf-8
+    payload.push_str(core::str::from_utf8(&bytes[..len]).unwrap_or_default());
+    Some(payload)
+}
@@ -0,0 +1,38 @@
+//! the second core of the ESP32 and ESP32-S3: the network side (radio, stack,
+//! scans and the sniffer) runs on its own executor on core 1, so frame
+//! processing can't delay the application on core 0. The two sides only meet
+//! in the library's statics (WIFI_REQUEST, CONNECTION_STATE, CANDIDATES, ...),
+//! which all lock through the critical section, a spinlock across cores.
+//! embassy-net's Stack isn't Send, so it's created on core 1 and stays there
+
+use embassy_executor::Spawner;
+use esp_hal::{interrupt::software::SoftwareInterrupt, peripherals::CPU_CTRL, system::Stack};
+use esp_rtos::embassy::Executor;
+use static_cell::StaticCell;
+
+use crate::memory::paint_network_stack;
+
+/// bytes of stack for core 1, the network tasks' futures live in their
+/// task arenas, not here. /status shows how much of it is used
+pub const NETWORK_CORE_STACK: usize = 8 * 1024;
+
+static STACK: StaticCell<Stack<NETWORK_CORE_STACK>> = StaticCell::new();
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+/// starts core 1 with an executor and hands `spawn` its spawner. Everything
+/// `spawn` captures crosses cores, so it has to be Send
+pub fn start_network_core(
+    cpu_ctrl: CPU_CTRL<'static>,
+    int0: SoftwareInterrupt<'static, 0>,
+    int1: SoftwareInterrupt<'static, 1>,
+    spawn: impl FnOnce(Spawner) + Send + 'static,
+) {
+    let stack = STACK.init(Stack::new());
+    // SAFETY: the stack is static and core 1 hasn't started on it
+    unsafe { paint_network_stack(stack.bottom(), NETWORK_CORE_STACK / 4) };
+    esp_rtos::start_second_core(cpu_ctrl, int0, int1, stack, move || {
+        info!("Network core up");
+        let executor = EXECUTOR.init(Executor::new());
+        executor.run(spawn)
+    });
+}
@@ -0,0 +1,289 @@
+//! roaming hints from the infrastructure: 802.11k neighbor reports, asked for
+//! after associating, and 802.11v BSS transition requests the WG sends when it
+//! wants us elsewhere. The radio driver doesn't handle either, so requests go
+//! out as raw action frames and the answers are picked up by the beacon
+//! sniffer. Both only work on links without PMF: protected action frames
+//! can't be read, and the driver can't send them. Once PMF is negotiated
+//! neither is used at all, a WG that wants to steer such a link has to drop it.
+//! Without PMF the frames are unauthenticated and anyone in range can forge
+//! them, so they never override quiet hours
+
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use esp_radio::wifi::Sniffer;
+
+use crate::{fmt::Bytes, mac::station_mac};
+
+// frame control of an action frame, type management, subtype 13
+const ACTION_FRAME: u8 = 0xd0;
+// in the second frame control byte, the body is encrypted
+const PROTECTED: u8 = 0x40;
+const HEADER_LEN: usize = 24;
+
+const CATEGORY_RADIO_MEASUREMENT: u8 = 5;
+const ACTION_NEIGHBOR_REQUEST: u8 = 4;
+const ACTION_NEIGHBOR_RESPONSE: u8 = 5;
+const CATEGORY_WNM: u8 = 10;
+const ACTION_BTM_REQUEST: u8 = 7;
+
+// BTM request mode bits
+const BTM_CANDIDATE_LIST: u8 = 0x01;
+const BTM_DISASSOC_IMMINENT: u8 = 0x04;
+const BTM_TERMINATION_INCLUDED: u8 = 0x08;
+const BTM_URL_INCLUDED: u8 = 0x10;
+// subelement id, length, TSF and duration
+const BSS_TERMINATION_LEN: usize = 12;
+
+const NEIGHBOR_REPORT_ID: u8 = 52;
+// BSSID, BSSID info, operating class, channel, PHY type
+const NEIGHBOR_REPORT_MIN_LEN: usize = 13;
+const CANDIDATE_PREFERENCE_ID: u8 = 3;
+
+/// neighbors kept from one report or transition request
+pub const MAX_NEIGHBORS: usize = 8;
+// a neighbor report is trusted for periodic scans this long
+const REPORT_TTL: Duration = Duration::from_secs(10 * 60);
+// how long a transition request's candidates keep their ranking bonus
+const STEER_TTL: Duration = Duration::from_secs(60);
+// a most preferred (255) transition candidate ranks as if its signal were this
+// much stronger, lower preferences get less
+const STEER_BONUS_MAX_DB: i32 = 10;
+
+/// a BSS the WG suggests, from a neighbor report element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Neighbor {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    // the transition candidate preference, 255 most preferred, 0 if not given
+    pub preference: u8,
+}
+
+/// what the WG told us about its neighbors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NeighborSource {
+    // 802.11k, an answer to our request
+    Report,
+    // 802.11v, the WG wants us to move, `imminent` if it's about to drop us
+    Transition { imminent: bool },
+}
+
+/// a neighbor report or transition request, for the wifi manager
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NeighborList {
+    pub from: [u8; 6],
+    pub source: NeighborSource,
+    pub neighbors: heapless::Vec<Neighbor, MAX_NEIGHBORS>,
+}
+
+impl NeighborList {
+    /// the distinct channels the neighbors are on
+    pub fn channels(&self) -> heapless::Vec<u8, MAX_NEIGHBORS> {
+        let mut channels = heapless::Vec::new();
+        for n in &self.neighbors {
+            if n.channel != 0 && !channels.contains(&n.channel) {
+                let _ = channels.push(n.channel);
+            }
+        }
+        channels
+    }
+}
+
+/// set by the sniffer callback, handled by the wifi manager while connected
+pub static NEIGHBORS: Signal<CriticalSectionRawMutex, NeighborList> = Signal::new();
+
+// the last report and when it came, for the periodic scans
+static LAST_REPORT: Mutex<CriticalSectionRawMutex, RefCell<Option<(NeighborList, Instant)>>> =
+    Mutex::new(RefCell::new(None));
+
+// the last transition request and when it came, for the ranking
+static STEERED: Mutex<CriticalSectionRawMutex, RefCell<Option<(NeighborList, Instant)>>> =
+    Mutex::new(RefCell::new(None));
+
+// the WG we last asked for a report, once per association
+static REQUESTED: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> =
+    Mutex::new(Cell::new(None));
+
+static DIALOG_TOKEN: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+// the current link negotiated PMF, or we don't know yet: hints are ignored
+static PROTECTED_LINK: AtomicBool = AtomicBool::new(true);
+
+/// asks `bssid` for a neighbor report, once per association. WGs without
+/// 802.11k don't answer, which costs nothing. With `pmf` negotiated nothing is
+/// sent and the WG's hints are ignored until the next association
+pub fn request_report(sniffer: &mut Sniffer<'static>, bssid: [u8; 6], pmf: bool) {
+    if REQUESTED.lock(|r| r.replace(Some(bssid))) == Some(bssid) {
+        return;
+    }
+    PROTECTED_LINK.store(pmf, Ordering::Relaxed);
+    if pmf {
+        info!("PMF on {:02x}, not using neighbor hints", Bytes(&bssid));
+        return;
+    }
+    let token = DIALOG_TOKEN.lock(|t| {
+        let token = t.get().wrapping_add(1).max(1);
+        t.set(token);
+        token
+    });
+    let mut frame = [0u8; HEADER_LEN + 3];
+    frame[0] = ACTION_FRAME;
+    frame[4..10].copy_from_slice(&bssid);
+    frame[10..16].copy_from_slice(&station_mac());
+    frame[16..22].copy_from_slice(&bssid);
+    frame[HEADER_LEN..].copy_from_slice(&[
+        CATEGORY_RADIO_MEASUREMENT,
+        ACTION_NEIGHBOR_REQUEST,
+        token,
+    ]);
+    match sniffer.send_raw_frame(true, &frame, true) {
+        Ok(_) => info!("Asked {:02x} for a neighbor report", Bytes(&bssid)),
+        Err(e) => info!("Neighbor report request failed {:?}", e),
+    }
+}
+
+/// forget the association we asked, the next one asks again
+pub fn reset_request() {
+    REQUESTED.lock(|r| r.set(None));
+    PROTECTED_LINK.store(true, Ordering::Relaxed);
+}
+
+/// the channels of a fresh neighbor report from `bssid`, where periodic scans
+/// can look instead of sweeping every channel
+pub fn report_channels(bssid: &[u8; 6]) -> Option<heapless::Vec<u8, MAX_NEIGHBORS>> {
+    LAST_REPORT.lock(|r| match &*r.borrow() {
+        Some((list, at)) if list.from == *bssid && at.elapsed() < REPORT_TTL => {
+            Some(list.channels())
+        }
+        _ => None,
+    })
+}
+
+/// remember a transition request's candidates for the ranking
+pub fn steer(list: &NeighborList) {
+    STEERED.lock(|s| s.replace(Some((list.clone(), Instant::now()))));
+}
+
+/// the ranking bonus for `bssid` in 1/16 dB, by its preference in a recent
+/// transition request
+pub fn steer_bonus_x16(bssid: &[u8; 6]) -> i16 {
+    STEERED.lock(|s| match &*s.borrow() {
+        Some((list, at)) if at.elapsed() < STEER_TTL => list
+            .neighbors
+            .iter()
+            .find(|n| n.bssid == *bssid)
+            .map(|n| (STEER_BONUS_MAX_DB * 16 * n.preference as i32 / 255) as i16)
+            .unwrap_or(0),
+        _ => 0,
+    })
+}
+
+/// called by the sniffer callback for every frame: picks out neighbor reports
+/// and transition requests `associated` sent us
+pub fn on_frame(frame: &[u8], associated: Option<[u8; 6]>) {
+    let Some(associated) = associated else {
+        return;
+    };
+    if PROTECTED_LINK.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(list) = parse(frame, associated) else {
+        return;
+    };
+    if list.source == NeighborSource::Report {
+        LAST_REPORT.lock(|r| r.replace(Some((list.clone(), Instant::now()))));
+    }
+    NEIGHBORS.signal(list);
+}
+
+fn parse(frame: &[u8], associated: [u8; 6]) -> Option<NeighborList> {
+    if *frame.first()? != ACTION_FRAME {
+        return None;
+    }
+    if frame.get(4..10)? != station_mac() || frame.get(10..16)? != associated {
+        return None;
+    }
+    // a WPA2 WG may do PMF without advertising it in the scan, its first
+    // protected frame tells us
+    if frame.get(1)? & PROTECTED != 0 {
+        PROTECTED_LINK.store(true, Ordering::Relaxed);
+        return None;
+    }
+    let body = frame.get(HEADER_LEN..)?;
+    let (source, elements) = match body {
+        [
+            CATEGORY_RADIO_MEASUREMENT,
+            ACTION_NEIGHBOR_RESPONSE,
+            _token,
+            rest @ ..,
+        ] => (NeighborSource::Report, rest),
+        [
+            CATEGORY_WNM,
+            ACTION_BTM_REQUEST,
+            _token,
+            mode,
+            _timer0,
+            _timer1,
+            _validity,
+            rest @ ..,
+        ] => {
+            let mut rest = rest;
+            if mode & BTM_TERMINATION_INCLUDED != 0 {
+                rest = rest.get(BSS_TERMINATION_LEN..)?;
+            }
+            if mode & BTM_URL_INCLUDED != 0 {
+                let len = *rest.first()? as usize;
+                rest = rest.get(1 + len..)?;
+            }
+            if mode & BTM_CANDIDATE_LIST == 0 {
+                rest = &[];
+            }
+            let imminent = mode & BTM_DISASSOC_IMMINENT != 0;
+            (NeighborSource::Transition { imminent }, rest)
+        }
+        _ => return None,
+    };
+    let mut list = NeighborList {
+        from: associated,
+        source,
+        neighbors: heapless::Vec::new(),
+    };
+    let mut elements = elements;
+    while let [id, len, rest @ ..] = elements {
+        let body = rest.get(..*len as usize)?;
+        elements = &rest[*len as usize..];
+        if *id != NEIGHBOR_REPORT_ID || body.len() < NEIGHBOR_REPORT_MIN_LEN {
+            continue;
+        }
+        let _ = list.neighbors.push(Neighbor {
+            bssid: body[..6].try_into().ok()?,
+            channel: body[11],
+            preference: preference(&body[NEIGHBOR_REPORT_MIN_LEN..]),
+        });
+    }
+    Some(list)
+}
+
+// the BSS transition candidate preference subelement, 0 without one
+fn preference(mut subelements: &[u8]) -> u8 {
+    while let [id, len, rest @ ..] = subelements {
+        let Some(body) = rest.get(..*len as usize) else {
+            break;
+        };
+        if *id == CANDIDATE_PREFERENCE_ID && body.len() == 1 {
+            return body[0];
+        }
+        subelements = &rest[*len as usize..];
+    }
+    0
+}
@@ -0,0 +1,348 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write as _,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use embassy_net::{ConfigV4, DhcpConfig, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, with_timeout};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CANDIDATES,
+    candidates::Outcome,
+    fmt::Bytes,
+    mac::station_mac,
+    persistence::STORE_LEASES,
+    status::{link_status, update_link_status, wait_until_online},
+};
+
+/// number of SSIDs that can carry their own IP settings
+pub const MAX_NETWORK_CONFIGS: usize = 4;
+/// BSSIDs whose last lease is remembered, the least recently used makes room
+pub const MAX_CACHED_LEASES: usize = 4;
+// a cached lease that gets no probe through in this long is taken to be
+// stale or handed to someone else, it's dropped and DHCP takes over
+const CACHED_LEASE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// seed for the first boot, STATIC_IP_SSID empty means everything uses DHCP
+const STATIC_IP_SSID: &str = env!("STATIC_IP_SSID");
+const STATIC_IP: &str = env!("STATIC_IP");
+const STATIC_PREFIX_LEN: &str = env!("STATIC_PREFIX_LEN");
+const GATEWAY_IP: &str = env!("GATEWAY_IP");
+const DNS_IP: &str = env!("DNS_IP");
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaticIp {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: [u8; 4],
+    pub dns: [u8; 4],
+}
+
+impl StaticIp {
+    /// what the stack holds right now, e.g. a DHCP lease. None without a gateway
+    pub fn from_config(config: &StaticConfigV4) -> Option<Self> {
+        let gateway = config.gateway?;
+        Some(Self {
+            address: config.address.address().octets(),
+            prefix_len: config.address.prefix_len(),
+            gateway: gateway.octets(),
+            // the gateway usually forwards DNS too
+            dns: config.dns_servers.first().unwrap_or(&gateway).octets(),
+        })
+    }
+}
+
+/// how a network hands out our address
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IpMode {
+    #[default]
+    Dhcp,
+    // for sites where DHCP is unreliable
+    Static(StaticIp),
+}
+
+/// IP settings for one SSID
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetworkConfig {
+    pub ssid: heapless::String<32>,
+    pub mode: IpMode,
+}
+
+/// the persisted network-config record, SSIDs not listed use DHCP
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetworkConfigs {
+    pub networks: heapless::Vec<NetworkConfig, MAX_NETWORK_CONFIGS>,
+}
+
+impl NetworkConfigs {
+    pub const fn new() -> Self {
+        return Self {
+            networks: heapless::Vec::new(),
+        };
+    }
+
+    /// the settings baked in through .cargo/config.toml
+    pub fn from_env() -> Self {
+        let mut configs = Self::new();
+        let ssid = STATIC_IP_SSID.trim();
+        if ssid.is_empty() {
+            return configs;
+        }
+        let parse = |ip: &str| Ipv4Addr::from_str(ip.trim()).map(|ip| ip.octets());
+        match (parse(STATIC_IP), parse(GATEWAY_IP), parse(DNS_IP)) {
+            (Ok(address), Ok(gateway), Ok(dns)) => {
+                let mode = IpMode::Static(StaticIp {
+                    address,
+                    prefix_len: STATIC_PREFIX_LEN.trim().parse().unwrap_or(24),
+                    gateway,
+                    dns,
+                });
+                let _ = configs.networks.push(NetworkConfig {
+                    ssid: heapless::String::try_from(ssid).unwrap_or_default(),
+                    mode,
+                });
+            }
+            _ => info!("Ignoring static IP settings for {}, bad address", ssid),
+        }
+        configs
+    }
+
+    pub fn mode_for(&self, ssid: &str) -> IpMode {
+        self.networks
+            .iter()
+            .find(|n| n.ssid == ssid)
+            .map(|n| n.mode)
+            .unwrap_or_default()
+    }
+}
+
+/// the last address a WG's DHCP server handed us
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CachedLease {
+    pub bssid: [u8; 6],
+    pub lease: StaticIp,
+}
+
+/// the persisted lease record, most recently used first
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LeaseCache {
+    pub leases: heapless::Vec<CachedLease, MAX_CACHED_LEASES>,
+    // the STA MAC the leases were handed to, a DHCP server won't honour them
+    // for another one
+    pub client_mac: [u8; 6],
+}
+
+impl LeaseCache {
+    pub const fn new() -> Self {
+        return Self {
+            leases: heapless::Vec::new(),
+            client_mac: [0; 6],
+        };
+    }
+
+    pub fn get(&self, bssid: &[u8; 6]) -> Option<StaticIp> {
+        self.leases
+            .iter()
+            .find(|l| l.bssid == *bssid)
+            .map(|l| l.lease)
+    }
+
+    /// remember `lease` for `bssid` as the most recent, true if that changed anything
+    pub fn insert(&mut self, bssid: [u8; 6], lease: StaticIp) -> bool {
+        let cached = CachedLease { bssid, lease };
+        if self.leases.first() == Some(&cached) {
+            return false;
+        }
+        self.leases.retain(|l| l.bssid != bssid);
+        if self.leases.is_full() {
+            self.leases.pop();
+        }
+        let _ = self.leases.insert(0, cached);
+        true
+    }
+
+    /// drop the lease of `bssid`, true if there was one
+    pub fn remove(&mut self, bssid: &[u8; 6]) -> bool {
+        let before = self.leases.len();
+        self.leases.retain(|l| l.bssid != *bssid);
+        self.leases.len() != before
+    }
+}
+
+/// "wg-scan-" and the last three bytes of the STA MAC, so units can be
+/// told apart in the gateway's lease table. A randomised MAC randomises it too
+pub fn device_name() -> heapless::String<32> {
+    let mac = station_mac();
+    let mut name = heapless::String::new();
+    let _ = write!(name, "wg-scan-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    name
+}
+
+/// DHCP settings announcing `device_name()` as our hostname. smoltcp already
+/// sends the MAC as the client identifier, so the lease follows the chip
+pub fn dhcp_config() -> DhcpConfig {
+    let mut config = DhcpConfig::default();
+    config.hostname = device_name().as_str().try_into().ok();
+    config
+}
+
+static NETWORK_CONFIGS: Mutex<CriticalSectionRawMutex, RefCell<NetworkConfigs>> =
+    Mutex::new(RefCell::new(NetworkConfigs::new()));
+
+// what we last handed the stack, so reconnects to the same kind of network don't restart DHCP.
+// the stack is created with DHCP
+static APPLIED: Mutex<CriticalSectionRawMutex, RefCell<Option<IpMode>>> =
+    Mutex::new(RefCell::new(Some(IpMode::Dhcp)));
+
+static LEASES: Mutex<CriticalSectionRawMutex, RefCell<LeaseCache>> =
+    Mutex::new(RefCell::new(LeaseCache::new()));
+
+// set while the stack runs on a cached lease, lease_task hands over to DHCP
+static ON_CACHED_LEASE: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn set_lease_cache(mut cache: LeaseCache) {
+    let mac = station_mac();
+    if cache.client_mac != mac {
+        if !cache.leases.is_empty() {
+            info!(
+                "Dropping leases cached for MAC {:02x}",
+                Bytes(&cache.client_mac)
+            );
+        }
+        cache = LeaseCache::new();
+        cache.client_mac = mac;
+    }
+    LEASES.lock(|l| *l.borrow_mut() = cache);
+}
+
+/// a lease we know about without DHCP, e.g. brought back from deep sleep.
+/// True if it was news
+pub fn remember_lease(bssid: [u8; 6], lease: StaticIp) -> bool {
+    LEASES.lock(|l| l.borrow_mut().insert(bssid, lease))
+}
+
+/// the IP mode after associating with `bssid`. A DHCP network we got a lease
+/// from before starts on that lease, lease_task renews it once traffic flows
+/// or drops it if no probe gets through within CACHED_LEASE_TIMEOUT
+pub fn ip_mode_after_connect(bssid: &[u8; 6], ssid: &str) -> IpMode {
+    let mode = ip_mode_for(ssid);
+    let cached = match mode {
+        IpMode::Dhcp => LEASES.lock(|l| l.borrow().get(bssid)),
+        IpMode::Static(_) => None,
+    };
+    ON_CACHED_LEASE.lock(|c| c.set(cached.is_some()));
+    match cached {
+        Some(lease) => {
+            info!("Starting on the cached lease {:?}", lease);
+            IpMode::Static(lease)
+        }
+        None => mode,
+    }
+}
+
+pub fn set_network_configs(configs: NetworkConfigs) {
+    NETWORK_CONFIGS.lock(|c| *c.borrow_mut() = configs);
+}
+
+pub fn network_configs() -> NetworkConfigs {
+    NETWORK_CONFIGS.lock(|c| c.borrow().clone())
+}
+
+/// the IP mode to use on `ssid`
+pub fn ip_mode_for(ssid: &str) -> IpMode {
+    NETWORK_CONFIGS.lock(|c| c.borrow().mode_for(ssid))
+}
+
+/// point the stack at `mode`, called when a candidate is applied
+pub fn apply_ip_mode(stack: Stack<'_>, mode: IpMode) {
+    if APPLIED.lock(|a| *a.borrow() == Some(mode)) {
+        return;
+    }
+    info!("IP mode {:?}", mode);
+    let config = match mode {
+        IpMode::Dhcp => ConfigV4::Dhcp(dhcp_config()),
+        IpMode::Static(ip) => {
+            let mut config = StaticConfigV4 {
+                address: Ipv4Cidr::new(Ipv4Addr::from(ip.address), ip.prefix_len),
+                gateway: Some(Ipv4Addr::from(ip.gateway)),
+                dns_servers: Default::default(),
+            };
+            let _ = config.dns_servers.push(Ipv4Addr::from(ip.dns));
+            ConfigV4::Static(config)
+        }
+    };
+    stack.set_config_v4(config);
+    APPLIED.lock(|a| *a.borrow_mut() = Some(mode));
+}
+
+// time from associating to this address into the current WG's candidate,
+// once per association
+async fn record_lease_time() {
+    let Some((bssid, associated_at)) =
+        update_link_status(|s| Some((s.current.as_ref()?.bssid, s.associated_at.take()?)))
+    else {
+        return;
+    };
+    let ms = associated_at.elapsed().as_millis().min(u16::MAX as u64) as u16;
+    info!("Address {}ms after associating", ms);
+    CANDIDATES.mark_result(bssid, Outcome::LeaseTime(ms)).await;
+}
+
+/// times each address from the association it came with, hands a cached
+/// lease back to DHCP once the internet probe got through on it (or forgets
+/// it if none does), and remembers every lease DHCP hands out for the WG it
+/// came from
+#[embassy_executor::task]
+pub async fn lease_task(stack: Stack<'static>) -> ! {
+    info!("Start lease task");
+    loop {
+        stack.wait_config_up().await;
+        record_lease_time().await;
+        if ON_CACHED_LEASE.lock(|c| c.get()) {
+            let bssid = link_status().current.map(|c| c.bssid);
+            let works = with_timeout(CACHED_LEASE_TIMEOUT, wait_until_online())
+                .await
+                .is_ok();
+            ON_CACHED_LEASE.lock(|c| c.set(false));
+            let still_on = link_status().current.map(|c| c.bssid) == bssid;
+            match (works, bssid) {
+                // usually the same address comes back, so the gap is short
+                (true, _) => info!("Cached lease works, renewing it over DHCP"),
+                (false, Some(bssid)) if still_on => {
+                    info!("No probe through on the cached lease, dropping it for DHCP");
+                    if LEASES.lock(|l| l.borrow_mut().remove(&bssid)) {
+                        STORE_LEASES.signal(LEASES.lock(|l| l.borrow().clone()));
+                    }
+                }
+                // the link went down meanwhile, the lease wasn't to blame
+                (false, _) => {}
+            }
+            apply_ip_mode(stack, IpMode::Dhcp);
+            continue;
+        }
+        wait_until_online().await;
+        let Some(current) = link_status().current else {
+            stack.wait_config_down().await;
+            continue;
+        };
+        let dhcp = APPLIED.lock(|a| *a.borrow() == Some(IpMode::Dhcp));
+        let lease = stack.config_v4().as_ref().and_then(StaticIp::from_config);
+        if let (true, Some(lease)) = (dhcp, lease) {
+            if remember_lease(current.bssid, lease) {
+                info!("New lease {:?} from {:?}", lease, current.bssid);
+                STORE_LEASES.signal(LEASES.lock(|l| l.borrow().clone()));
+            }
+        }
+        stack.wait_config_down().await;
+    }
+}
@@ -0,0 +1,63 @@
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Timer};
+
+pub use crate::logring::dropped_frames;
+use crate::logring::take_frames;
+
+// where the defmt frames go, a host name or an IP
+const NETLOG_HOST: &str = env!("NETLOG_HOST");
+// a bad port fails the build instead of the task
+const NETLOG_PORT: u16 = match u16::from_str_radix(env!("NETLOG_PORT"), 10) {
+    Ok(0) | Err(_) => panic!("NETLOG_PORT is not a UDP port"),
+    Ok(port) => port,
+};
+const DATAGRAM_LEN: usize = 1024;
+const SEND_INTERVAL: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// sends the buffered defmt frames to NETLOG_HOST:NETLOG_PORT over UDP once we
+/// have an address. Decode on the collector with the matching ELF, e.g.
+/// `nc -ul 5140 | defmt-print -e wifi-scan-demo`
+#[embassy_executor::task]
+pub async fn netlog_task(stack: Stack<'static>) -> ! {
+    info!("Start netlog task");
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; DATAGRAM_LEN * 2];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).unwrap();
+
+    let mut datagram = [0u8; DATAGRAM_LEN];
+    loop {
+        stack.wait_config_up().await;
+        let collector = match stack.dns_query(NETLOG_HOST, DnsQueryType::A).await {
+            Ok(addrs) if !addrs.is_empty() => IpEndpoint::new(addrs[0], NETLOG_PORT),
+            _ => {
+                info!("Netlog can't resolve {}", NETLOG_HOST);
+                Timer::after(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        info!("Forwarding logs to {}", collector);
+        while stack.is_config_up() {
+            let len = take_frames(&mut datagram);
+            if len == 0 {
+                Timer::after(SEND_INTERVAL).await;
+                continue;
+            }
+            // a lost datagram is a gap in the log, not worth a retry
+            let _ = socket.send_to(&datagram[..len], collector).await;
+        }
+    }
+}
@@ -0,0 +1,990 @@
+//! the nvs partition in ESP-IDF's NVS format (version 2), so the firmware can
+//! share it with IDF components and with images from `nvs_partition_gen.py`.
+//! Namespaces and blobs are written, the kinds the firmware stores, and
+//! strings are read and written, e.g. what a factory image provisions. Items
+//! fill the pages in sequence, and once only the spare page is left the full
+//! page with the most erased entries is compacted into it, as IDF does
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_hal::rom::crc::crc32_le;
+
+const PAGE_SIZE: u32 = 4096;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: u8 = 126;
+// the header is followed by the entry state bitmap, then the entries
+const BITMAP_OFFSET: u32 = 32;
+const ENTRIES_OFFSET: u32 = 64;
+// pages a partition can have, the 24 KB one in partitions.csv has 6
+const MAX_PAGES: usize = 16;
+
+// page states, each one clears another bit of the one before
+const PAGE_EMPTY: u32 = 0xffff_ffff;
+const PAGE_ACTIVE: u32 = 0xffff_fffe;
+const PAGE_FULL: u32 = 0xffff_fffc;
+const PAGE_FREEING: u32 = 0xffff_fff8;
+// version 2 of the format, version 1 pages carry 0xff
+const PAGE_VERSION: u8 = 0xfe;
+const PAGE_VERSION_1: u8 = 0xff;
+
+// entry states, 2 bits each in the page's bitmap
+const ENTRY_EMPTY: u8 = 0b11;
+const ENTRY_WRITTEN: u8 = 0b10;
+const ENTRY_ERASED: u8 = 0b00;
+
+// item types
+const TYPE_U8: u8 = 0x01;
+const TYPE_STR: u8 = 0x21;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_INDEX: u8 = 0x48;
+// the chunk index of anything that isn't a blob chunk
+const CHUNK_ANY: u8 = 0xff;
+// a blob's chunks alternate between two versions, so the old one stays
+// readable until the new one is complete
+const BLOB_VERSION_0: u8 = 0x00;
+const BLOB_VERSION_1: u8 = 0x80;
+// namespace names are U8 items of namespace 0, their value is the index
+const NAMESPACE_NS: u8 = 0;
+const MAX_NAMESPACE: u8 = 254;
+// keys are NUL terminated in 16 bytes
+const MAX_KEY_LEN: usize = 15;
+
+/// the longest blob written, a single chunk filling a page
+pub const MAX_BLOB_LEN: usize = (ENTRIES_PER_PAGE as usize - 1) * ENTRY_SIZE;
+/// the longest string written, without its NUL terminator
+pub const MAX_STR_LEN: usize = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NvsError {
+    // the flash read, write or erase failed
+    Flash,
+    // no such key
+    NotFound,
+    // no room, even after compacting
+    Full,
+    // a key over 15 bytes, or a value larger than the buffer or a page
+    TooLong,
+    // the item is there but its data doesn't check out
+    Corrupt,
+    // the partition is in IDF's version 1 format, it's neither read nor written
+    Version1,
+}
+
+// one 32 byte entry: namespace, type, span, chunk index, CRC-32, key, data
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Item([u8; ENTRY_SIZE]);
+
+impl Item {
+    fn new(ns: u8, ty: u8, span: u8, chunk: u8, key: &str, data: [u8; 8]) -> Self {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0] = ns;
+        bytes[1] = ty;
+        bytes[2] = span;
+        bytes[3] = chunk;
+        bytes[8..8 + key.len()].copy_from_slice(key.as_bytes());
+        bytes[24..32].copy_from_slice(&data);
+        let mut item = Self(bytes);
+        let crc = item.calc_crc();
+        item.0[4..8].copy_from_slice(&crc.to_le_bytes());
+        item
+    }
+    fn ns(&self) -> u8 {
+        self.0[0]
+    }
+    fn ty(&self) -> u8 {
+        self.0[1]
+    }
+    // entries the item takes, itself included
+    fn span(&self) -> u8 {
+        self.0[2]
+    }
+    fn chunk(&self) -> u8 {
+        self.0[3]
+    }
+    fn data(&self) -> &[u8] {
+        &self.0[24..32]
+    }
+    // everything but the CRC field itself
+    fn calc_crc(&self) -> u32 {
+        crc32_le(crc32_le(0xffff_ffff, &self.0[0..4]), &self.0[8..32])
+    }
+    fn is_valid(&self) -> bool {
+        (1..=ENTRIES_PER_PAGE).contains(&self.span())
+            && self.0[4..8] == self.calc_crc().to_le_bytes()
+    }
+    fn is(&self, ns: u8, ty: u8, key: &str) -> bool {
+        self.ns() == ns && self.ty() == ty && self.has_key(key)
+    }
+    fn has_key(&self, key: &str) -> bool {
+        let stored = &self.0[8..24];
+        let len = stored.iter().position(|b| *b == 0).unwrap_or(16);
+        &stored[..len] == key.as_bytes()
+    }
+    // BLOB_INDEX: total size, chunk count and the version the chunks start at
+    fn blob_size(&self) -> u32 {
+        u32::from_le_bytes(self.0[24..28].try_into().unwrap())
+    }
+    fn blob_chunks(&self) -> u8 {
+        self.0[28]
+    }
+    fn blob_version(&self) -> u8 {
+        self.0[29]
+    }
+    // BLOB_DATA and STR: length and CRC-32 of the data in the entries after it
+    fn chunk_len(&self) -> usize {
+        u16::from_le_bytes(self.0[24..26].try_into().unwrap()) as usize
+    }
+    fn chunk_crc(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Page {
+    // index of the page in the partition
+    sector: u32,
+    state: u32,
+    seq: u32,
+    // the first entry never written since the last erase
+    next_free: u8,
+    // entries written and then erased, what compacting the page gains
+    erased: u8,
+}
+
+impl Page {
+    fn base(&self) -> u32 {
+        self.sector * PAGE_SIZE
+    }
+    fn entry_addr(&self, entry: u8) -> u32 {
+        self.base() + ENTRIES_OFFSET + entry as u32 * ENTRY_SIZE as u32
+    }
+    fn room(&self) -> u8 {
+        ENTRIES_PER_PAGE - self.next_free
+    }
+}
+
+// where an item was found
+#[derive(Clone, Copy)]
+struct Found {
+    page: usize,
+    entry: u8,
+    item: Item,
+}
+
+/// the page layout of an open partition. Holds no flash, every call takes it,
+/// so the region can be handed out for other partitions in between
+pub struct Nvs {
+    // used pages oldest first, then the empty ones
+    pages: heapless::Vec<Page, MAX_PAGES>,
+    // a version 1 partition, left as it is: no pages, every write refused
+    version_1: bool,
+}
+
+impl Nvs {
+    /// true if any page carries a valid NVS header. A partition that isn't
+    /// blank and has none holds something else
+    pub fn is_formatted<S: ReadNorFlash>(flash: &mut S) -> Result<bool, NvsError> {
+        for sector in 0..page_count(flash) {
+            let (state, _, version) = read_header(flash, sector)?;
+            if state != PAGE_EMPTY && version.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// reads the page headers. Pages that aren't NVS are erased, a compaction a
+    /// reset cut short is finished and items torn by a reset are dropped. A
+    /// partition with version 1 pages is left alone, nothing is found in it
+    /// and every write fails with Version1
+    pub fn open<S: NorFlash + ReadNorFlash>(flash: &mut S) -> Result<Self, NvsError> {
+        let mut nvs = Self {
+            pages: heapless::Vec::new(),
+            version_1: false,
+        };
+        // IDF before v4 and images generated for it. Their blobs are laid out
+        // differently, and erasing the pages would lose what IDF stored
+        for sector in 0..page_count(flash) {
+            let (state, _, version) = read_header(flash, sector)?;
+            if state != PAGE_EMPTY && version == Some(PAGE_VERSION_1) {
+                info!(
+                    "NVS page {} is version 1, leaving the partition alone",
+                    sector
+                );
+                nvs.version_1 = true;
+                return Ok(nvs);
+            }
+        }
+        for sector in 0..page_count(flash) {
+            let (state, seq, version) = read_header(flash, sector)?;
+            let valid = version == Some(PAGE_VERSION);
+            let mut page = Page {
+                sector,
+                state,
+                seq,
+                next_free: 0,
+                erased: 0,
+            };
+            let usable = match state {
+                PAGE_ACTIVE | PAGE_FULL | PAGE_FREEING => valid,
+                PAGE_EMPTY => is_blank(flash, page.base(), PAGE_SIZE)?,
+                _ => false,
+            };
+            if !usable {
+                info!("NVS page {} is not NVS, erasing", sector);
+                erase_page(flash, &mut page)?;
+            }
+            let _ = nvs.pages.push(page);
+        }
+        nvs.sort();
+        for i in 0..nvs.pages.len() {
+            if nvs.pages[i].state != PAGE_EMPTY {
+                nvs.load_page(flash, i)?;
+            }
+        }
+        // only the newest page takes writes
+        let active = nvs.pages.iter().filter(|p| p.state == PAGE_ACTIVE).count();
+        for _ in 1..active {
+            let i = nvs.active().unwrap();
+            nvs.set_state(flash, i, PAGE_FULL)?;
+        }
+        if let Some(i) = nvs.pages.iter().position(|p| p.state == PAGE_FREEING) {
+            info!("NVS finishing compaction of page {}", nvs.pages[i].sector);
+            nvs.move_items(flash, i)?;
+        }
+        nvs.settle_blobs(flash)?;
+        Ok(nvs)
+    }
+
+    /// erases the whole partition, every namespace in it
+    pub fn format<S: NorFlash>(flash: &mut S) -> Result<(), NvsError> {
+        let end = page_count(flash) * PAGE_SIZE;
+        flash.erase(0, end).map_err(|_| NvsError::Flash)
+    }
+
+    /// the index of namespace `name`, None if it was never created
+    pub fn find_namespace<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        name: &str,
+    ) -> Result<Option<u8>, NvsError> {
+        let found = self.find(flash, |item| item.is(NAMESPACE_NS, TYPE_U8, name))?;
+        Ok(found.map(|f| f.item.data()[0]))
+    }
+
+    /// the index of namespace `name`, created if it doesn't exist
+    pub fn namespace<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        name: &str,
+    ) -> Result<u8, NvsError> {
+        check_key(name)?;
+        if let Some(ns) = self.find_namespace(flash, name)? {
+            return Ok(ns);
+        }
+        let mut last = 0;
+        self.for_each_item(flash, |_, _, item| {
+            if item.ns() == NAMESPACE_NS && item.ty() == TYPE_U8 {
+                last = last.max(item.data()[0]);
+            }
+            false
+        })?;
+        if last >= MAX_NAMESPACE {
+            return Err(NvsError::Full);
+        }
+        let mut data = [0xff; 8];
+        data[0] = last + 1;
+        self.write_item(
+            flash,
+            Item::new(NAMESPACE_NS, TYPE_U8, 1, CHUNK_ANY, name, data),
+            &[],
+        )?;
+        Ok(last + 1)
+    }
+
+    /// reads blob `key` of namespace `ns` into `buf`, returns its length
+    pub fn get_blob<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+        buf: &mut [u8],
+    ) -> Result<usize, NvsError> {
+        let index = self
+            .find(flash, |item| item.is(ns, TYPE_BLOB_INDEX, key))?
+            .ok_or(NvsError::NotFound)?
+            .item;
+        let size = index.blob_size() as usize;
+        if size > buf.len() {
+            return Err(NvsError::TooLong);
+        }
+        let mut len = 0;
+        for n in 0..index.blob_chunks() {
+            let chunk = index.blob_version().wrapping_add(n);
+            let found = self
+                .find(flash, |item| {
+                    item.is(ns, TYPE_BLOB_DATA, key) && item.chunk() == chunk
+                })?
+                .ok_or(NvsError::Corrupt)?;
+            let chunk_len = found.item.chunk_len();
+            let out = buf.get_mut(len..len + chunk_len).ok_or(NvsError::Corrupt)?;
+            self.read_chunk(flash, &found, out)?;
+            if crc32_le(0xffff_ffff, out) != found.item.chunk_crc() {
+                return Err(NvsError::Corrupt);
+            }
+            len += chunk_len;
+        }
+        match len == size {
+            true => Ok(len),
+            false => Err(NvsError::Corrupt),
+        }
+    }
+
+    /// reads string `key` of namespace `ns` into `buf`, returns its length
+    /// without the NUL terminator
+    pub fn get_str<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+        buf: &mut [u8],
+    ) -> Result<usize, NvsError> {
+        let found = self
+            .find(flash, |item| item.is(ns, TYPE_STR, key))?
+            .ok_or(NvsError::NotFound)?;
+        let out = buf
+            .get_mut(..found.item.chunk_len())
+            .ok_or(NvsError::TooLong)?;
+        self.read_chunk(flash, &found, out)?;
+        if crc32_le(0xffff_ffff, out) != found.item.chunk_crc() {
+            return Err(NvsError::Corrupt);
+        }
+        Ok(out.iter().position(|b| *b == 0).unwrap_or(out.len()))
+    }
+
+    /// writes string `key` of namespace `ns`, NUL terminated as IDF does. It
+    /// doesn't replace an existing value, write into a namespace that doesn't
+    /// have the key, e.g. one erase_namespace just emptied
+    pub fn set_str<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+        value: &str,
+    ) -> Result<(), NvsError> {
+        check_key(key)?;
+        if value.len() > MAX_STR_LEN {
+            return Err(NvsError::TooLong);
+        }
+        let mut bytes = [0u8; MAX_STR_LEN + 1];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        let bytes = &bytes[..value.len() + 1];
+
+        let span = 1 + bytes.len().div_ceil(ENTRY_SIZE) as u8;
+        let mut data = [0xff; 8];
+        data[0..2].copy_from_slice(&(bytes.len() as u16).to_le_bytes());
+        data[4..8].copy_from_slice(&crc32_le(0xffff_ffff, bytes).to_le_bytes());
+        self.write_item(
+            flash,
+            Item::new(ns, TYPE_STR, span, CHUNK_ANY, key, data),
+            bytes,
+        )
+    }
+
+    /// writes blob `key` of namespace `ns`. The previous value is erased only
+    /// once the new one is complete, and an unchanged value isn't rewritten
+    pub fn set_blob<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), NvsError> {
+        check_key(key)?;
+        if value.len() > MAX_BLOB_LEN {
+            return Err(NvsError::TooLong);
+        }
+        let old = self.find(flash, |item| item.is(ns, TYPE_BLOB_INDEX, key))?;
+        if let Some(old) = old {
+            if self.blob_equals(flash, ns, key, &old.item, value)? {
+                return Ok(());
+            }
+        }
+        let version = match old.map(|o| o.item.blob_version()) {
+            Some(BLOB_VERSION_0) => BLOB_VERSION_1,
+            _ => BLOB_VERSION_0,
+        };
+
+        let span = 1 + value.len().div_ceil(ENTRY_SIZE) as u8;
+        let mut data = [0xff; 8];
+        data[0..2].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        data[4..8].copy_from_slice(&crc32_le(0xffff_ffff, value).to_le_bytes());
+        self.write_item(
+            flash,
+            Item::new(ns, TYPE_BLOB_DATA, span, version, key, data),
+            value,
+        )?;
+
+        let mut data = [0xff; 8];
+        data[0..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        data[4] = 1;
+        data[5] = version;
+        self.write_item(
+            flash,
+            Item::new(ns, TYPE_BLOB_INDEX, 1, CHUNK_ANY, key, data),
+            &[],
+        )?;
+
+        // compacting may have moved the old items, look them up again
+        if let Some(old) = old {
+            let (version, chunks) = (old.item.blob_version(), old.item.blob_chunks());
+            self.erase_where(flash, false, |item| {
+                is_blob_version(item, ns, key, version, chunks)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// erases every item of namespace `ns`, the other namespaces stay
+    pub fn erase_namespace<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        ns: u8,
+    ) -> Result<(), NvsError> {
+        self.erase_where(flash, false, |item| item.ns() == ns)
+    }
+
+    /// erases item `key` of namespace `ns`, whatever its type, and overwrites
+    /// it with zeros, for a secret that mustn't stay readable
+    pub fn scrub<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+    ) -> Result<(), NvsError> {
+        self.erase_where(flash, true, |item| item.ns() == ns && item.has_key(key))
+    }
+
+    // keeps the pages in the order items are looked up in
+    fn sort(&mut self) {
+        self.pages
+            .sort_unstable_by_key(|p| (p.state == PAGE_EMPTY, p.seq, p.sector));
+    }
+
+    fn active(&self) -> Option<usize> {
+        self.pages.iter().position(|p| p.state == PAGE_ACTIVE)
+    }
+
+    // finds next_free and the erased count of page `i`, and drops items a
+    // reset tore: written but failing their CRC, or half written and not
+    // marked yet
+    fn load_page<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        i: usize,
+    ) -> Result<(), NvsError> {
+        let mut page = self.pages[i];
+        let bitmap = read_bitmap(flash, &page)?;
+        let mut last_used = None;
+        let mut entry = 0;
+        while entry < ENTRIES_PER_PAGE {
+            match entry_state(&bitmap, entry) {
+                ENTRY_WRITTEN => {
+                    let item = read_item(flash, &page, entry)?;
+                    match item.is_valid() && entry + item.span() <= ENTRIES_PER_PAGE {
+                        true => {
+                            last_used = Some(entry + item.span() - 1);
+                            entry += item.span();
+                            continue;
+                        }
+                        false => {
+                            set_entry_state(flash, &page, entry, ENTRY_ERASED)?;
+                            page.erased += 1;
+                            last_used = Some(entry);
+                        }
+                    }
+                }
+                ENTRY_EMPTY => {
+                    if !is_blank(flash, page.entry_addr(entry), ENTRY_SIZE as u32)? {
+                        set_entry_state(flash, &page, entry, ENTRY_ERASED)?;
+                        page.erased += 1;
+                        last_used = Some(entry);
+                    }
+                }
+                _ => {
+                    page.erased += 1;
+                    last_used = Some(entry);
+                }
+            }
+            entry += 1;
+        }
+        page.next_free = last_used.map_or(0, |e| e + 1);
+        self.pages[i] = page;
+        Ok(())
+    }
+
+    // calls `f` with the page, entry and item of every written item, oldest
+    // first, until it returns true
+    fn for_each_item<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        mut f: impl FnMut(usize, u8, &Item) -> bool,
+    ) -> Result<(), NvsError> {
+        for (i, page) in self.pages.iter().enumerate() {
+            if page.state == PAGE_EMPTY {
+                break;
+            }
+            let bitmap = read_bitmap(flash, page)?;
+            let mut entry = 0;
+            while entry < page.next_free {
+                if entry_state(&bitmap, entry) != ENTRY_WRITTEN {
+                    entry += 1;
+                    continue;
+                }
+                let item = read_item(flash, page, entry)?;
+                if !item.is_valid() {
+                    entry += 1;
+                    continue;
+                }
+                if f(i, entry, &item) {
+                    return Ok(());
+                }
+                entry += item.span();
+            }
+        }
+        Ok(())
+    }
+
+    // the newest item `matches` accepts
+    fn find<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        matches: impl Fn(&Item) -> bool,
+    ) -> Result<Option<Found>, NvsError> {
+        let mut found = None;
+        self.for_each_item(flash, |page, entry, item| {
+            if matches(item) {
+                found = Some(Found {
+                    page,
+                    entry,
+                    item: *item,
+                });
+            }
+            false
+        })?;
+        Ok(found)
+    }
+
+    // the data entries following a BLOB_DATA item, `out` is as long as its data
+    fn read_chunk<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        found: &Found,
+        out: &mut [u8],
+    ) -> Result<(), NvsError> {
+        let page = &self.pages[found.page];
+        let mut entry = [0u8; ENTRY_SIZE];
+        for (n, part) in out.chunks_mut(ENTRY_SIZE).enumerate() {
+            let addr = page.entry_addr(found.entry + 1 + n as u8);
+            read(flash, addr, &mut entry)?;
+            part.copy_from_slice(&entry[..part.len()]);
+        }
+        Ok(())
+    }
+
+    fn blob_equals<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        ns: u8,
+        key: &str,
+        index: &Item,
+        value: &[u8],
+    ) -> Result<bool, NvsError> {
+        if index.blob_size() as usize != value.len() || index.blob_chunks() != 1 {
+            return Ok(false);
+        }
+        let version = index.blob_version();
+        let Some(found) = self.find(flash, |item| {
+            item.is(ns, TYPE_BLOB_DATA, key) && item.chunk() == version
+        })?
+        else {
+            return Ok(false);
+        };
+        if found.item.chunk_len() != value.len() {
+            return Ok(false);
+        }
+        let page = &self.pages[found.page];
+        let mut entry = [0u8; ENTRY_SIZE];
+        for (n, part) in value.chunks(ENTRY_SIZE).enumerate() {
+            read(
+                flash,
+                page.entry_addr(found.entry + 1 + n as u8),
+                &mut entry,
+            )?;
+            if entry[..part.len()] != *part {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // writes `item` and the `payload` entries after it to the active page
+    fn write_item<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        item: Item,
+        payload: &[u8],
+    ) -> Result<(), NvsError> {
+        if self.version_1 {
+            return Err(NvsError::Version1);
+        }
+        let i = self.reserve(flash, item.span())?;
+        let page = self.pages[i];
+        let first = page.next_free;
+        write(flash, page.entry_addr(first), &item.0)?;
+        for (n, part) in payload.chunks(ENTRY_SIZE).enumerate() {
+            let mut entry = [0xff; ENTRY_SIZE];
+            entry[..part.len()].copy_from_slice(part);
+            write(flash, page.entry_addr(first + 1 + n as u8), &entry)?;
+        }
+        // marked only once complete, a torn item is dropped by the next open
+        for entry in first..first + item.span() {
+            set_entry_state(flash, &page, entry, ENTRY_WRITTEN)?;
+        }
+        self.pages[i].next_free += item.span();
+        Ok(())
+    }
+
+    // the active page with room for `span` entries, opening or compacting
+    // into a new one if needed
+    fn reserve<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        span: u8,
+    ) -> Result<usize, NvsError> {
+        for _ in 0..=self.pages.len() {
+            if let Some(i) = self.active() {
+                if self.pages[i].room() >= span {
+                    return Ok(i);
+                }
+                self.set_state(flash, i, PAGE_FULL)?;
+            }
+            let empty = self.pages.iter().filter(|p| p.state == PAGE_EMPTY).count();
+            match empty {
+                0 => return Err(NvsError::Full),
+                // the spare page, only ever filled by compacting into it
+                1 => self.compact(flash)?,
+                _ => {
+                    self.activate(flash)?;
+                }
+            }
+        }
+        Err(NvsError::Full)
+    }
+
+    // moves the live items of the full page with the most erased entries into
+    // the spare page, which becomes active, and erases it
+    fn compact<S: NorFlash + ReadNorFlash>(&mut self, flash: &mut S) -> Result<(), NvsError> {
+        let victim = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.state == PAGE_FULL && p.erased > 0)
+            .max_by_key(|(_, p)| p.erased)
+            .map(|(i, _)| i)
+            .ok_or(NvsError::Full)?;
+        info!(
+            "NVS compacting page {}, {} entries erased",
+            self.pages[victim].sector, self.pages[victim].erased
+        );
+        self.set_state(flash, victim, PAGE_FREEING)?;
+        self.move_items(flash, victim)
+    }
+
+    // copies the written items of page `from` to the active page, skipping
+    // copies an earlier attempt left, then erases it
+    fn move_items<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        from: usize,
+    ) -> Result<(), NvsError> {
+        let from_sector = self.pages[from].sector;
+        if self.active().is_none() {
+            self.activate(flash)?;
+        }
+        let src = self.pages[self.find_sector(from_sector)];
+        let bitmap = read_bitmap(flash, &src)?;
+        let mut entry = 0;
+        while entry < src.next_free {
+            if entry_state(&bitmap, entry) != ENTRY_WRITTEN {
+                entry += 1;
+                continue;
+            }
+            let item = read_item(flash, &src, entry)?;
+            if !item.is_valid() {
+                entry += 1;
+                continue;
+            }
+            let to = self.active().ok_or(NvsError::Full)?;
+            let dest = self.pages[to];
+            if dest.room() < item.span() {
+                return Err(NvsError::Full);
+            }
+            if !self.page_holds(flash, &dest, &item)? {
+                let mut bytes = [0u8; ENTRY_SIZE];
+                for n in 0..item.span() {
+                    read(flash, src.entry_addr(entry + n), &mut bytes)?;
+                    write(flash, dest.entry_addr(dest.next_free + n), &bytes)?;
+                }
+                for n in 0..item.span() {
+                    set_entry_state(flash, &dest, dest.next_free + n, ENTRY_WRITTEN)?;
+                }
+                self.pages[to].next_free += item.span();
+            }
+            entry += item.span();
+        }
+        let i = self.find_sector(from_sector);
+        erase_page(flash, &mut self.pages[i])?;
+        self.sort();
+        Ok(())
+    }
+
+    // true if `item` is already written on `page`
+    fn page_holds<S: ReadNorFlash>(
+        &self,
+        flash: &mut S,
+        page: &Page,
+        item: &Item,
+    ) -> Result<bool, NvsError> {
+        let bitmap = read_bitmap(flash, page)?;
+        for entry in 0..page.next_free {
+            if entry_state(&bitmap, entry) == ENTRY_WRITTEN
+                && read_item(flash, page, entry)? == *item
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn find_sector(&self, sector: u32) -> usize {
+        self.pages.iter().position(|p| p.sector == sector).unwrap()
+    }
+
+    // turns an empty page into the active one, with the next sequence number
+    fn activate<S: NorFlash>(&mut self, flash: &mut S) -> Result<usize, NvsError> {
+        let seq = self
+            .pages
+            .iter()
+            .filter(|p| p.state != PAGE_EMPTY)
+            .map(|p| p.seq + 1)
+            .max()
+            .unwrap_or(0);
+        let i = self
+            .pages
+            .iter()
+            .position(|p| p.state == PAGE_EMPTY)
+            .ok_or(NvsError::Full)?;
+        let mut header = [0xff; 32];
+        header[0..4].copy_from_slice(&PAGE_ACTIVE.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        header[8] = PAGE_VERSION;
+        let crc = crc32_le(0xffff_ffff, &header[4..28]);
+        header[28..32].copy_from_slice(&crc.to_le_bytes());
+        write(flash, self.pages[i].base(), &header)?;
+        self.pages[i].state = PAGE_ACTIVE;
+        self.pages[i].seq = seq;
+        self.sort();
+        Ok(self.active().unwrap())
+    }
+
+    fn set_state<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        i: usize,
+        state: u32,
+    ) -> Result<(), NvsError> {
+        write(flash, self.pages[i].base(), &state.to_le_bytes())?;
+        self.pages[i].state = state;
+        Ok(())
+    }
+
+    // erases every item `matches` accepts, and with `scrub` zeroes its entries
+    // too, an erased item's data is otherwise readable until its page is
+    fn erase_where<S: NorFlash + ReadNorFlash>(
+        &mut self,
+        flash: &mut S,
+        scrub: bool,
+        matches: impl Fn(&Item) -> bool,
+    ) -> Result<(), NvsError> {
+        if self.version_1 {
+            return Err(NvsError::Version1);
+        }
+        loop {
+            let mut batch: heapless::Vec<(usize, u8, u8), 16> = heapless::Vec::new();
+            self.for_each_item(flash, |page, entry, item| {
+                if matches(item) {
+                    let _ = batch.push((page, entry, item.span()));
+                }
+                batch.is_full()
+            })?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            for (i, entry, span) in batch {
+                let page = self.pages[i];
+                for e in entry..entry + span {
+                    set_entry_state(flash, &page, e, ENTRY_ERASED)?;
+                    if scrub {
+                        write(flash, page.entry_addr(e), &[0u8; ENTRY_SIZE])?;
+                    }
+                }
+                self.pages[i].erased += span;
+            }
+        }
+    }
+
+    // after a reset mid set_blob: keeps only the newest index of each blob
+    // and drops chunks no index refers to
+    fn settle_blobs<S: NorFlash + ReadNorFlash>(&mut self, flash: &mut S) -> Result<(), NvsError> {
+        let mut indexes: heapless::Vec<Item, 32> = heapless::Vec::new();
+        let mut stale: heapless::Vec<Item, 8> = heapless::Vec::new();
+        self.for_each_item(flash, |_, _, item| {
+            if item.ty() == TYPE_BLOB_INDEX {
+                // later ones are newer
+                if let Some(older) = indexes.iter().position(|i| same_key(i, item)) {
+                    let _ = stale.push(indexes.swap_remove(older));
+                }
+                let _ = indexes.push(*item);
+            }
+            false
+        })?;
+        for old in stale {
+            info!("NVS dropping a superseded blob index");
+            self.erase_where(flash, false, |item| *item == old)?;
+        }
+        self.erase_where(flash, false, |item| {
+            item.ty() == TYPE_BLOB_DATA
+                && !indexes.iter().any(|i| {
+                    same_key(i, item)
+                        && item.chunk().wrapping_sub(i.blob_version()) < i.blob_chunks()
+                })
+        })
+    }
+}
+
+// the index or a chunk of version `version` of blob `key`
+fn is_blob_version(item: &Item, ns: u8, key: &str, version: u8, chunks: u8) -> bool {
+    match item.ty() {
+        TYPE_BLOB_INDEX => item.is(ns, TYPE_BLOB_INDEX, key) && item.blob_version() == version,
+        TYPE_BLOB_DATA => {
+            item.is(ns, TYPE_BLOB_DATA, key) && item.chunk().wrapping_sub(version) < chunks
+        }
+        _ => false,
+    }
+}
+
+fn same_key(a: &Item, b: &Item) -> bool {
+    a.ns() == b.ns() && a.0[8..24] == b.0[8..24]
+}
+
+fn check_key(key: &str) -> Result<(), NvsError> {
+    match key.is_empty() || key.len() > MAX_KEY_LEN {
+        true => Err(NvsError::TooLong),
+        false => Ok(()),
+    }
+}
+
+fn page_count<S: ReadNorFlash>(flash: &S) -> u32 {
+    (flash.capacity() / PAGE_SIZE as usize).min(MAX_PAGES) as u32
+}
+
+fn read<S: ReadNorFlash>(flash: &mut S, addr: u32, bytes: &mut [u8]) -> Result<(), NvsError> {
+    flash.read(addr, bytes).map_err(|_| {
+        info!("NVS read error at {}", addr);
+        NvsError::Flash
+    })
+}
+
+fn write<S: NorFlash>(flash: &mut S, addr: u32, bytes: &[u8]) -> Result<(), NvsError> {
+    flash.write(addr, bytes).map_err(|_| {
+        info!("NVS write error at {}", addr);
+        NvsError::Flash
+    })
+}
+
+fn erase_page<S: NorFlash>(flash: &mut S, page: &mut Page) -> Result<(), NvsError> {
+    flash
+        .erase(page.base(), page.base() + PAGE_SIZE)
+        .map_err(|_| {
+            info!("NVS erase error at {}", page.base());
+            NvsError::Flash
+        })?;
+    page.state = PAGE_EMPTY;
+    page.seq = 0;
+    page.next_free = 0;
+    page.erased = 0;
+    Ok(())
+}
+
+// state, sequence number and whether the version and CRC check out
+// state, sequence number and, if the header checks out, the format version
+fn read_header<S: ReadNorFlash>(
+    flash: &mut S,
+    sector: u32,
+) -> Result<(u32, u32, Option<u8>), NvsError> {
+    let mut header = [0u8; 32];
+    read(flash, sector * PAGE_SIZE, &mut header)?;
+    let state = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let seq = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let crc = u32::from_le_bytes(header[28..32].try_into().unwrap());
+    let version = (crc32_le(0xffff_ffff, &header[4..28]) == crc).then_some(header[8]);
+    Ok((state, seq, version))
+}
+
+fn is_blank<S: ReadNorFlash>(flash: &mut S, addr: u32, len: u32) -> Result<bool, NvsError> {
+    let mut bytes = [0u8; ENTRY_SIZE];
+    let mut at = addr;
+    while at < addr + len {
+        read(flash, at, &mut bytes)?;
+        if bytes.iter().any(|b| *b != 0xff) {
+            return Ok(false);
+        }
+        at += ENTRY_SIZE as u32;
+    }
+    Ok(true)
+}
+
+fn read_bitmap<S: ReadNorFlash>(flash: &mut S, page: &Page) -> Result<[u8; 32], NvsError> {
+    let mut bitmap = [0u8; 32];
+    read(flash, page.base() + BITMAP_OFFSET, &mut bitmap)?;
+    Ok(bitmap)
+}
+
+fn read_item<S: ReadNorFlash>(flash: &mut S, page: &Page, entry: u8) -> Result<Item, NvsError> {
+    let mut item = Item([0u8; ENTRY_SIZE]);
+    read(flash, page.entry_addr(entry), &mut item.0)?;
+    Ok(item)
+}
+
+fn entry_state(bitmap: &[u8; 32], entry: u8) -> u8 {
+    (bitmap[entry as usize / 4] >> (entry % 4 * 2)) & 0b11
+}
+
+// flash writes only clear bits, and each state clears more of them, so the
+// word holding the entry is written with just its bits changed
+fn set_entry_state<S: NorFlash + ReadNorFlash>(
+    flash: &mut S,
+    page: &Page,
+    entry: u8,
+    state: u8,
+) -> Result<(), NvsError> {
+    let addr = page.base() + BITMAP_OFFSET + entry as u32 / 16 * 4;
+    let mut word = [0u8; 4];
+    read(flash, addr, &mut word)?;
+    let shift = entry as u32 % 16 * 2;
+    let mut bits = u32::from_le_bytes(word);
+    bits &= !(0b11 << shift);
+    bits |= (state as u32) << shift;
+    write(flash, addr, &bits.to_le_bytes())
+}
@@ -0,0 +1,304 @@
+use embassy_futures::select::{Either, select};
+use embassy_net::Stack;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    NetworkPolicy,
+    eventlog::{self, ResetCause},
+    status::{is_stopped, network_policy, wait_until_online, wait_until_online_with},
+};
+#[cfg(feature = "tls")]
+use {
+    crate::{
+        health::{find_header, parse_status},
+        tls::{TlsBuffers, TlsSocket, pinned_root_for},
+    },
+    core::fmt::Write as _,
+    embassy_net::{dns::DnsQueryType, tcp::TcpSocket},
+    embedded_io_async::{Read, Write},
+};
+
+// where firmware images are served from, over HTTPS only: the CRC catches a
+// corrupted download but anyone on the path could forge one that matches, so
+// the server has to prove itself with the root pinned to OTA_HOST
+const OTA_HOST: &str = env!("OTA_HOST");
+const OTA_PATH: &str = env!("OTA_PATH");
+#[cfg(feature = "tls")]
+const OTA_PORT: u16 = 443;
+// a stalled download is given up after this long without data
+#[cfg(feature = "tls")]
+const OTA_TIMEOUT: Duration = Duration::from_secs(30);
+// the status line and headers have to fit
+#[cfg(feature = "tls")]
+const RESPONSE_HEAD_LEN: usize = 1024;
+// a new image that can't get a probe through for this long, on any candidate,
+// is rolled back
+const ROLLBACK_AFTER: Duration = Duration::from_secs(10 * 60);
+const ROLLBACK_POLL: Duration = Duration::from_secs(10);
+
+/// whether this build can update itself, only with the tls feature
+pub const OTA_AVAILABLE: bool = cfg!(feature = "tls");
+
+/// bytes handed to persistence per flash write
+pub const OTA_CHUNK_LEN: usize = 1024;
+/// first byte of an esp-idf app image
+pub const IMAGE_MAGIC: u8 = 0xE9;
+
+/// an update to fetch from OTA_HOST. The CRC-32 of the image comes with the
+/// release, so a truncated or corrupted download is never booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OtaRequest {
+    pub crc32: u32,
+}
+
+impl OtaRequest {
+    /// "<crc32 in hex>", as used by the HTTP trigger
+    pub fn from_path(path: &str) -> Option<Self> {
+        let crc32 = u32::from_str_radix(path.trim_start_matches("0x"), 16).ok()?;
+        Some(Self { crc32 })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaError {
+    Dns,
+    Connect,
+    // no verified TLS session, no memory for one, no root pinned to
+    // OTA_HOST or a build without tls
+    Tls,
+    // the connection dropped, timed out or ended before the image did
+    Io,
+    // not a 200 with a Content-Length
+    BadResponse,
+    // the image doesn't fit the inactive slot
+    TooLarge,
+    // no OTA slots in the partition table, or otadata unreadable
+    NoOtaSlot,
+    Flash,
+    // the slot read back doesn't match the CRC, or isn't an app image
+    Verify,
+}
+
+/// what ota_task asks persistence, which owns the flash, to do with the
+/// inactive slot
+pub enum OtaOp {
+    // erase room for an image of `len` bytes
+    Begin {
+        len: u32,
+    },
+    Write {
+        offset: u32,
+        data: heapless::Vec<u8, OTA_CHUNK_LEN>,
+    },
+    // verify the image and boot it next
+    Finish {
+        len: u32,
+        crc32: u32,
+    },
+    // the running image got online, keep it
+    Confirm,
+    // the running image is broken, boot the previous one from now on
+    Rollback,
+}
+
+/// start an update, see ota_task
+pub static OTA_START: Signal<CriticalSectionRawMutex, OtaRequest> = Signal::new();
+/// slot operations for persistence
+pub static OTA_OP: Channel<CriticalSectionRawMutex, OtaOp, 1> = Channel::new();
+/// persistence's answer to each OTA_OP
+pub static OTA_OP_DONE: Signal<CriticalSectionRawMutex, Result<(), OtaError>> = Signal::new();
+/// signal from the persistence, true if this is the first boot of an update
+pub static OTA_UNCONFIRMED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+async fn slot_op(op: OtaOp) -> Result<(), OtaError> {
+    OTA_OP_DONE.reset();
+    OTA_OP.send(op).await;
+    OTA_OP_DONE.wait().await
+}
+
+/// downloads OTA_HOST/OTA_PATH into the inactive app slot on OTA_START over
+/// the WiFi we manage, and reboots into it once it's verified. The running
+/// image stays bootable until then
+#[embassy_executor::task]
+pub async fn ota_task(stack: Stack<'static>) -> ! {
+    info!("Start ota task");
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 512];
+    loop {
+        let request = OTA_START.wait().await;
+        if !network_policy().allows_ota() {
+            info!("OTA held until we're on a network that allows it");
+        }
+        wait_until_online_with(NetworkPolicy::allows_ota).await;
+        info!("OTA from {}{}, crc {:x}", OTA_HOST, OTA_PATH, request.crc32);
+        match download(stack, request, &mut rx_buffer, &mut tx_buffer).await {
+            Ok(len) => {
+                info!("OTA image of {} bytes verified, rebooting into it", len);
+                // let the logs and any HTTP answer get out
+                Timer::after(Duration::from_secs(1)).await;
+                eventlog::reset(ResetCause::Ota).await;
+            }
+            Err(e) => info!("OTA failed: {:?}", e),
+        }
+    }
+}
+
+// without TLS nothing authenticates the image, updates are refused
+#[cfg(not(feature = "tls"))]
+async fn download(
+    _stack: Stack<'_>,
+    _request: OtaRequest,
+    _rx_buffer: &mut [u8],
+    _tx_buffer: &mut [u8],
+) -> Result<u32, OtaError> {
+    info!("OTA needs the tls feature, a plain HTTP image can't be authenticated");
+    Err(OtaError::Tls)
+}
+
+// stream the image into the slot, returns its length. The server must chain
+// to the root pinned to OTA_HOST, the catch-all root isn't enough
+#[cfg(feature = "tls")]
+async fn download(
+    stack: Stack<'_>,
+    request: OtaRequest,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<u32, OtaError> {
+    let addr = match stack.dns_query(OTA_HOST, DnsQueryType::A).await {
+        Ok(addrs) => *addrs.first().ok_or(OtaError::Dns)?,
+        Err(e) => {
+            info!("OTA dns error: {:?}", e);
+            return Err(OtaError::Dns);
+        }
+    };
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(OTA_TIMEOUT));
+    if let Err(e) = socket.connect((addr, OTA_PORT)).await {
+        info!("OTA connect error: {:?}", e);
+        return Err(OtaError::Connect);
+    }
+    let Some(root) = pinned_root_for(OTA_HOST) else {
+        info!("No TLS root pinned to {}, OTA refused", OTA_HOST);
+        return Err(OtaError::Tls);
+    };
+    let mut buffers = TlsBuffers::alloc().ok_or(OtaError::Tls)?;
+    let mut socket = TlsSocket::open_with_root(socket, OTA_HOST, root, &mut buffers)
+        .await
+        .map_err(|_| OtaError::Tls)?;
+
+    let mut get: heapless::String<256> = heapless::String::new();
+    write!(
+        get,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        OTA_PATH, OTA_HOST
+    )
+    .map_err(|_| OtaError::BadResponse)?;
+    socket
+        .write_all(get.as_bytes())
+        .await
+        .map_err(|_| OtaError::Io)?;
+    socket.flush().await.map_err(|_| OtaError::Io)?;
+
+    // the head, and likely the first bytes of the image behind it
+    let mut head = [0u8; RESPONSE_HEAD_LEN];
+    let mut filled = 0;
+    let body_start = loop {
+        if filled == head.len() {
+            return Err(OtaError::BadResponse);
+        }
+        match socket.read(&mut head[filled..]).await {
+            Ok(0) | Err(_) => return Err(OtaError::Io),
+            Ok(n) => filled += n,
+        }
+        if let Some(end) = head[..filled].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    if parse_status(&head[..body_start]) != Some(200) {
+        return Err(OtaError::BadResponse);
+    }
+    let len: u32 = find_header(&head[..body_start], "content-length")
+        .and_then(|l| l.parse().ok())
+        .ok_or(OtaError::BadResponse)?;
+    info!("OTA image is {} bytes", len);
+    slot_op(OtaOp::Begin { len }).await?;
+
+    let mut chunk = [0u8; OTA_CHUNK_LEN];
+    let early = &head[body_start..filled];
+    chunk[..early.len()].copy_from_slice(early);
+    let mut chunk_len = early.len();
+    let mut offset = 0u32;
+    while offset < len {
+        let want = ((len - offset) as usize).min(OTA_CHUNK_LEN);
+        while chunk_len < want {
+            match socket.read(&mut chunk[chunk_len..want]).await {
+                // the server gave up before the image was complete
+                Ok(0) | Err(_) => return Err(OtaError::Io),
+                Ok(n) => chunk_len += n,
+            }
+        }
+        let data = heapless::Vec::from_slice(&chunk[..want]).unwrap();
+        slot_op(OtaOp::Write { offset, data }).await?;
+        offset += want as u32;
+        chunk_len = 0;
+    }
+    socket.close().await;
+
+    slot_op(OtaOp::Finish {
+        len,
+        crc32: request.crc32,
+    })
+    .await?;
+    Ok(len)
+}
+
+/// the connectivity proof for a fresh update: the first probe that gets
+/// through marks the image valid. If none does for ROLLBACK_AFTER while the
+/// radio is on, the slot is marked invalid and we reboot into the previous image
+#[embassy_executor::task]
+pub async fn rollback_task() -> ! {
+    info!("Start rollback task");
+    if OTA_UNCONFIRMED.wait().await {
+        info!(
+            "Unconfirmed update, rolling back unless online within {}s",
+            ROLLBACK_AFTER.as_secs()
+        );
+        let mut offline = Duration::from_secs(0);
+        loop {
+            match select(wait_until_online(), Timer::after(ROLLBACK_POLL)).await {
+                Either::First(_) => {
+                    match slot_op(OtaOp::Confirm).await {
+                        Ok(()) => info!("Update confirmed"),
+                        Err(e) => info!("Failed to confirm the update: {:?}", e),
+                    }
+                    break;
+                }
+                Either::Second(_) => {
+                    // the radio being off on purpose isn't the image's fault
+                    if !is_stopped() {
+                        offline += ROLLBACK_POLL;
+                    }
+                    if offline < ROLLBACK_AFTER {
+                        continue;
+                    }
+                    info!(
+                        "No probe got through for {}s, rolling back",
+                        offline.as_secs()
+                    );
+                    match slot_op(OtaOp::Rollback).await {
+                        Ok(()) => eventlog::reset(ResetCause::Rollback).await,
+                        // nothing to go back to, keep trying with this one
+                        Err(e) => info!("Rollback failed: {:?}", e),
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    core::future::pending().await
+}
@@ -0,0 +1,113 @@
+use core::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+    panic::PanicInfo,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    eventlog::{self, Event, ResetCause},
+    persistence::{PostmortemBuffers, dump_eventlog, dump_panic, flash_storage},
+};
+
+/// return addresses kept from the backtrace
+pub const PANIC_FRAMES: usize = 8;
+/// room for a framed PanicRecord
+pub const PANIC_DUMP_LEN: usize = 512;
+// "PNIC", a record this firmware wrote
+pub(crate) const PANIC_MAGIC: u32 = 0x504e_4943;
+
+/// what a panic left behind for the next boot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PanicRecord {
+    // the message and where it was raised, cut to fit
+    pub message: heapless::String<192>,
+    // innermost first, look them up with addr2line against the ELF
+    pub backtrace: heapless::Vec<u32, PANIC_FRAMES>,
+    pub uptime_ms: u64,
+}
+
+// a panic while handling one must not loop
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// the dump buffers, a panic deep in a task has no 5 KB of stack left. Only the
+// first panic gets past PANICKING to use them
+static mut POSTMORTEM: PostmortemBuffers = PostmortemBuffers::new();
+
+static LAST_PANIC: Mutex<CriticalSectionRawMutex, RefCell<Option<PanicRecord>>> =
+    Mutex::new(RefCell::new(None));
+
+/// called once with what persistence loaded
+pub fn set_last_panic(record: PanicRecord) {
+    info!("Previous boot panicked: {:?}", record);
+    LAST_PANIC.lock(|p| *p.borrow_mut() = Some(record));
+}
+
+/// the panic that ended the previous boot, if it did
+pub fn last_panic() -> Option<PanicRecord> {
+    LAST_PANIC.lock(|p| p.borrow().clone())
+}
+
+// fills the string as far as it goes instead of failing
+struct Truncating<'a, const N: usize>(&'a mut heapless::String<N>);
+
+impl<const N: usize> fmt::Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        esp_hal::system::software_reset()
+    }
+
+    let mut message = heapless::String::new();
+    let _ = write!(Truncating(&mut message), "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(
+            Truncating(&mut message),
+            " at {}:{}",
+            location.file(),
+            location.line()
+        );
+    }
+    let backtrace = esp_backtrace::Backtrace::capture()
+        .frames()
+        .iter()
+        .take(PANIC_FRAMES)
+        .map(|f| f.program_counter() as u32)
+        .collect();
+    let record = PanicRecord {
+        message,
+        backtrace,
+        uptime_ms: embassy_time::Instant::now().as_millis(),
+    };
+    // straight to the console, the logger may be what panicked
+    let mut console = esp_println::Printer;
+    let _ = writeln!(console, "panic: {}", record.message);
+    for pc in &record.backtrace {
+        let _ = writeln!(console, "  {:#010x}", pc);
+    }
+
+    eventlog::record(Event::Reset(ResetCause::Panic));
+    // persistence may be mid-write, but it won't run again
+    // SAFETY: nothing else touches the flash after a panic
+    let mut flash = flash_storage(unsafe { esp_hal::peripherals::FLASH::steal() });
+    // SAFETY: only the first panic gets here, nothing else uses POSTMORTEM
+    let buffers = unsafe { &mut *addr_of_mut!(POSTMORTEM) };
+    let _ = dump_panic(&mut flash, &record, buffers);
+    let _ = dump_eventlog(&mut flash, buffers);
+    esp_hal::system::software_reset()
+}
@@ -0,0 +1,168 @@
+//! gateway hints between units of a cluster over ESP-NOW: every unit
+//! broadcasts the WG it's on, neighbours rank the WGs their peers are happy
+//! with a little higher, so after a gateway outage the cluster settles on the
+//! survivors without each unit finding them on its own. Hints are heard on the
+//! channel the radio is on, so only from peers on the same channel
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Ticker};
+use esp_radio::esp_now::{BROADCAST_ADDRESS, EspNow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CANDIDATES,
+    fmt::Bytes,
+    scanner::scan_now,
+    status::{ConnectionState, connection_state, link_status},
+};
+
+// time between our broadcasts
+const HINT_INTERVAL: Duration = Duration::from_secs(10);
+// a peer that hasn't been heard for this long no longer counts
+const HINT_TTL: Duration = Duration::from_secs(35);
+// peers remembered at once, the oldest hint makes room
+const MAX_PEERS: usize = 8;
+// each peer on a WG ranks it as if its signal were this much stronger, up to
+// PEER_BONUS_MAX_DB, less than a weaker WG needs to win on its own
+const PEER_BONUS_DB: i16 = 2;
+const PEER_BONUS_MAX_DB: i16 = 6;
+// tells our frames from other ESP-NOW traffic, bumped with the format
+const HINT_MAGIC: [u8; 4] = *b"WGH1";
+const HINT_LEN: usize = 32;
+
+/// what a unit broadcasts, None when it isn't online through any WG
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PeerHint {
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+    // the WG's effective RSSI in the sender's ranking, dB
+    pub score: i8,
+}
+
+struct PeerEntry {
+    peer: [u8; 6],
+    hint: PeerHint,
+    heard: Instant,
+}
+
+static PEER_HINTS: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<PeerEntry, MAX_PEERS>>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+/// the ranking bonus for `bssid` in 1/16 dB, from the peers online through it
+pub fn peer_bonus_x16(bssid: &[u8; 6]) -> i16 {
+    let peers = PEER_HINTS.lock(|hints| {
+        hints
+            .borrow()
+            .iter()
+            .filter(|e| e.hint.bssid == *bssid && e.heard.elapsed() < HINT_TTL)
+            .count() as i16
+    });
+    (peers * PEER_BONUS_DB).min(PEER_BONUS_MAX_DB) * 16
+}
+
+// a peer's latest hint replaces its previous one, None withdraws it. True if
+// the peer moved to another WG
+fn merge_hint(peer: [u8; 6], hint: Option<PeerHint>) -> bool {
+    PEER_HINTS.lock(|hints| {
+        let mut hints = hints.borrow_mut();
+        let previous = hints
+            .iter()
+            .find(|e| e.peer == peer && e.heard.elapsed() < HINT_TTL)
+            .map(|e| e.hint.bssid);
+        hints.retain(|e| e.peer != peer && e.heard.elapsed() < HINT_TTL);
+        let Some(hint) = hint else {
+            return previous.is_some();
+        };
+        if hints.is_full() {
+            hints.remove(0);
+        }
+        let _ = hints.push(PeerEntry {
+            peer,
+            hint,
+            heard: Instant::now(),
+        });
+        previous != Some(hint.bssid)
+    })
+}
+
+// our own hint, only while the internet probe gets through our WG
+fn own_hint() -> Option<PeerHint> {
+    if connection_state() != Some(ConnectionState::InternetOk) {
+        return None;
+    }
+    let current = link_status().current?;
+    Some(PeerHint {
+        bssid: current.bssid,
+        rssi: current.signal_strength,
+        score: (current.effective_rssi_x16() / 16) as i8,
+    })
+}
+
+fn encode(hint: &Option<PeerHint>, buf: &mut [u8; HINT_LEN]) -> Option<usize> {
+    buf[..HINT_MAGIC.len()].copy_from_slice(&HINT_MAGIC);
+    let len = postcard::to_slice(hint, &mut buf[HINT_MAGIC.len()..])
+        .ok()?
+        .len();
+    Some(HINT_MAGIC.len() + len)
+}
+
+fn decode(frame: &[u8]) -> Option<Option<PeerHint>> {
+    let body = frame.strip_prefix(&HINT_MAGIC)?;
+    postcard::from_bytes(body).ok()
+}
+
+/// broadcasts our hint every HINT_INTERVAL and merges the ones peers send.
+/// Hints only move WGs we scanned ourselves; one we haven't seen gets a scan
+/// while we're offline, so a rogue frame can't send us anywhere
+#[embassy_executor::task]
+pub async fn peer_hints_task(esp_now: EspNow<'static>) -> ! {
+    info!("Start peer hints task");
+    let (_manager, mut sender, mut receiver) = esp_now.split();
+    let mut ticker = Ticker::every(HINT_INTERVAL);
+    let mut frame = [0u8; HINT_LEN];
+    // scans asked for by hints, at most one per HINT_INTERVAL
+    let mut last_scan: Option<Instant> = None;
+    loop {
+        match select(ticker.next(), receiver.receive_async()).await {
+            Either::First(_) => {
+                let Some(len) = encode(&own_hint(), &mut frame) else {
+                    continue;
+                };
+                if let Err(e) = sender.send_async(&BROADCAST_ADDRESS, &frame[..len]).await {
+                    info!("ESP-NOW send error: {:?}", e);
+                }
+            }
+            Either::Second(received) => {
+                let Some(hint) = decode(received.data()) else {
+                    continue;
+                };
+                let peer = received.info.src_address;
+                let moved = merge_hint(peer, hint);
+                let Some(hint) = hint else {
+                    if moved {
+                        info!("Peer {:02x} went offline", Bytes(&peer));
+                    }
+                    continue;
+                };
+                if moved {
+                    info!(
+                        "Peer {:02x} on {:02x} ({} dBm)",
+                        Bytes(&peer),
+                        Bytes(&hint.bssid),
+                        hint.rssi
+                    );
+                }
+                let offline = connection_state() == Some(ConnectionState::Disconnected);
+                let scanned_lately = last_scan.is_some_and(|t| t.elapsed() < HINT_INTERVAL);
+                if offline && !scanned_lately && CANDIDATES.find(&hint.bssid).await.is_none() {
+                    last_scan = Some(Instant::now());
+                    scan_now();
+                }
+            }
+        }
+    }
+}
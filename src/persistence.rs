@@ -1,31 +1,220 @@
-use anyhow::Error;
-use defmt::info;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Timer};
-use embedded_storage::nor_flash::{self, NorFlash, NorFlashErrorKind, ReadNorFlash};
-use esp_bootloader_esp_idf::partitions::{self, FlashRegion};
+use core::{cell::RefCell, fmt::Write};
+
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::nor_flash::{self, NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::{
+    ota::OtaImageState,
+    ota_updater::OtaUpdater,
+    partitions::{self, FlashRegion},
+};
 use esp_hal::peripherals;
 use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use crate::WifiConfig;
+#[cfg(feature = "tls")]
+use crate::tls::{MAX_ROOT_LEN, TLS_BLOBS_ADDR, TLS_PARTITION, TlsRoot, TlsRootsRecord};
+use crate::{
+    AuthPolicy, Credential, Credentials, MAX_PROVISIONED, NetworkPolicy, Pmf, Tier, WifiConfig,
+    bootguard::mark_deliberate_reset,
+    codec::{Codec, DefaultCodec},
+    enterprise::{EAP_BLOBS_ADDR, EAP_PARTITION, EnterpriseCredential, EnterpriseRecord},
+    error::Error,
+    eventlog::{
+        DUMP_EVENTLOG, EVENTLOG_DUMP_LEN, EVENTLOG_PARTITION, EventLog, decode_dump, encode_dump,
+        frame, unframe,
+    },
+    fmt::{Bytes, Loggable},
+    hold::wait_until_released,
+    latency::{LatencySummary, LatencyWindow},
+    netconfig::{LeaseCache, NetworkConfigs},
+    nvs::{Nvs, NvsError},
+    ota::{IMAGE_MAGIC, OTA_CHUNK_LEN, OTA_OP, OTA_OP_DONE, OTA_UNCONFIRMED, OtaError, OtaOp},
+    panic::{PANIC_DUMP_LEN, PANIC_MAGIC, PanicRecord},
+    roaming::RoamPreset,
+    secret::{self, SEAL_OVERHEAD},
+    stats::Stats,
+    status::wait_until_not_associating,
+    txpower::TxPowerProfile,
+    watchdog::{Watched, beat_while},
+};
 
-// starting bit of nvs where the previous best lives
-const WIFI_CONFIG_ADDR: u32 = 0;
 // number of bytes to clear before writing a sector
-const WIFI_CONFIG_SECTOR_SIZE: u32 = 4096;
+const SECTOR_SIZE: u32 = 4096;
+// the records are blobs in one namespace of the nvs partition, which other
+// IDF-style NVS users can share
+const NVS_NAMESPACE: &str = "wifi-scan";
+const WIFI_KEY: &str = "best_wg";
+const SETTINGS_KEY: &str = "settings";
+// per-SSID IP settings
+const NETWORK_CONFIG_KEY: &str = "netcfg";
+// connection statistics, rewritten far more often than the rest
+const STATS_KEY: &str = "stats";
+// the last DHCP lease per BSSID
+const LEASES_KEY: &str = "leases";
+// where an `nvs_partition_gen` image provisions networks: strings ssid<n> and
+// pass<n>, optionally hidden<n>, auth<n>, pmf<n>, tier<n> and policy<n>
+// taking the values of SSID_HIDDEN, SSID_AUTH, SSID_PMF, SSID_TIER and
+// SSID_POLICY. Only written to seal the passphrases,
+// a factory reset keeps it
+const FACTORY_NAMESPACE: &str = "wifi";
+// room for a 64 character passphrase and the terminator
+const FACTORY_VALUE_LEN: usize = 72;
+// NVS keys are at most 15 characters
+const NVS_KEY_LEN: usize = 15;
+// the raw layout before NVS, one record at the start of a sector each, read
+// once to move them over. The best WG alternated between two slots
+const LEGACY_WIFI_SLOTS: [u32; 2] = [0, 4 * SECTOR_SIZE];
+const LEGACY_SETTINGS_ADDR: u32 = SECTOR_SIZE;
+const LEGACY_NETWORK_CONFIG_ADDR: u32 = 2 * SECTOR_SIZE;
+const LEGACY_STATS_ADDR: u32 = 3 * SECTOR_SIZE;
+const LEGACY_LEASES_ADDR: u32 = 5 * SECTOR_SIZE;
+// slotted records start with sequence (u32), payload length (u16) and CRC-32 (u32)
+const SLOT_HEADER_LEN: usize = 10;
+// sectors of the "postmortem" partition
+const EVENTLOG_DUMP_ADDR: u32 = 0;
+const PANIC_DUMP_ADDR: u32 = EVENTLOG_DUMP_ADDR + SECTOR_SIZE;
+// upper bound of an encoded record
+const RECORD_LEN: usize = 256;
+// every record in NVS starts with RECORD_TAG and RECORD_LAYOUT. Bump the layout
+// with any change to the fields of WifiConfig, Settings, NetworkConfigs, Stats
+// or LeaseCache: postcard has no field names, an older record would decode
+// into the wrong fields or not at all. One of another layout loads as missing,
+// its defaults apply and the next store replaces it. 1 was untagged
+const RECORD_TAG: u8 = 0xa5;
+const RECORD_LAYOUT: u8 = 2;
+const RECORD_HEADER_LEN: usize = 2;
+// certificates above this are refused rather than exhausting the heap
+const MAX_EAP_BLOB_LEN: u32 = 8192;
+// attempts at storing a new best WG before giving up until the next scan
+const STORE_ATTEMPTS: u32 = 3;
+const STORE_RETRY_DELAY: Duration = Duration::from_millis(500);
+// best WG stores closer together than this are coalesced, only the newest is written
+const WIFI_STORE_INTERVAL: Duration = Duration::from_secs(30);
+// stats change on every connect attempt, they're written this far apart at
+// most and only the newest counts are. A flush writes them straight away
+const STATS_STORE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// longest a store is held back by an association in flight
+const FLASH_DEFER_MAX: Duration = Duration::from_secs(15);
+
+/// how long flash operations have been taking
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlashLatency {
+    pub erase: LatencySummary,
+    pub write: LatencySummary,
+}
+
+static ERASE_LATENCY: Mutex<CriticalSectionRawMutex, RefCell<LatencyWindow>> =
+    Mutex::new(RefCell::new(LatencyWindow::new()));
+static WRITE_LATENCY: Mutex<CriticalSectionRawMutex, RefCell<LatencyWindow>> =
+    Mutex::new(RefCell::new(LatencyWindow::new()));
+
+pub fn flash_latency() -> FlashLatency {
+    FlashLatency {
+        erase: ERASE_LATENCY.lock(|l| l.borrow().summary()),
+        write: WRITE_LATENCY.lock(|l| l.borrow().summary()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StoreError {
+    Erase,
+    Encode,
+    Write,
+    // the flash read itself failed
+    Read,
+    // no complete copy on flash, e.g. the first boot
+    Missing,
+    // written by a firmware with another RECORD_LAYOUT
+    Layout,
+}
 
-const SECTOR_START: u32 = WIFI_CONFIG_ADDR - (WIFI_CONFIG_ADDR % WIFI_CONFIG_SECTOR_SIZE);
-const SECTOR_END: u32 = SECTOR_START + WIFI_CONFIG_SECTOR_SIZE;
+impl From<NvsError> for StoreError {
+    fn from(e: NvsError) -> Self {
+        match e {
+            NvsError::NotFound => StoreError::Missing,
+            NvsError::TooLong => StoreError::Encode,
+            NvsError::Corrupt => StoreError::Read,
+            NvsError::Flash | NvsError::Full | NvsError::Version1 => StoreError::Write,
+        }
+    }
+}
+
+/// user choices that survive a reboot
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Settings {
+    pub preset: RoamPreset,
+    pub tx_power: TxPowerProfile,
+}
 
 // signal from the persistence to inform connection loop that previous best wifi was loaded
 pub static LOAD_WIFI: Signal<CriticalSectionRawMutex, Option<WifiConfig>> = Signal::new();
 // signal from the connection loop to inform persistence that new best wifi can be saved.
 pub static STORE_WIFI: Signal<CriticalSectionRawMutex, WifiConfig> = Signal::new();
+// signal from the persistence that the STORE_WIFI request hit the flash, or why it didn't
+pub static WIFI_STORED: Signal<CriticalSectionRawMutex, Result<(), StoreError>> = Signal::new();
+// signal from the persistence that the settings were loaded, None if there were none
+pub static LOAD_SETTINGS: Signal<CriticalSectionRawMutex, Option<Settings>> = Signal::new();
+// signal to persistence that the settings changed
+pub static STORE_SETTINGS: Signal<CriticalSectionRawMutex, Settings> = Signal::new();
+// signal from the persistence that the network configs were loaded, None if there were none
+pub static LOAD_NETWORK_CONFIGS: Signal<CriticalSectionRawMutex, Option<NetworkConfigs>> =
+    Signal::new();
+// signal to persistence that the network configs changed
+pub static STORE_NETWORK_CONFIGS: Signal<CriticalSectionRawMutex, NetworkConfigs> = Signal::new();
+// wipe every record and reboot, from the reset button or the HTTP API
+pub static FACTORY_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// signal from the persistence that the statistics were loaded, None if there were none
+pub static LOAD_STATS: Signal<CriticalSectionRawMutex, Option<Stats>> = Signal::new();
+// signal to persistence that the statistics changed, written at most every
+// STATS_STORE_INTERVAL
+pub static STORE_STATS: Signal<CriticalSectionRawMutex, Stats> = Signal::new();
+// signal from the persistence that the DHCP lease cache was loaded, None if there was none
+pub static LOAD_LEASES: Signal<CriticalSectionRawMutex, Option<LeaseCache>> = Signal::new();
+// signal to persistence that a WG handed us a new lease
+pub static STORE_LEASES: Signal<CriticalSectionRawMutex, LeaseCache> = Signal::new();
+/// write every pending store now, skipping the usual pauses. Acked on FLUSHED
+pub static FLUSH: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static FLUSHED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// signal from the persistence with the enterprise credential, None without an "eap" partition
+pub static LOAD_ENTERPRISE: Signal<CriticalSectionRawMutex, Option<EnterpriseCredential>> =
+    Signal::new();
+// signal from the persistence with the networks of the factory image, empty without one
+pub static LOAD_PROVISIONED: Signal<CriticalSectionRawMutex, Credentials> = Signal::new();
+// signal to persistence with networks loaded over the console, they replace the
+// factory image's
+pub static STORE_PROVISIONED: Signal<CriticalSectionRawMutex, Credentials> = Signal::new();
+// signal to persistence that the radio runs, plaintext passphrases can be sealed
+pub static SEAL_PASSPHRASES: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// signal from the persistence with the event log the previous boot dumped
+pub static LOAD_EVENTLOG: Signal<CriticalSectionRawMutex, Option<EventLog>> = Signal::new();
+// signal from the persistence with the panic that ended the previous boot
+pub static LOAD_PANIC: Signal<CriticalSectionRawMutex, Option<PanicRecord>> = Signal::new();
+// signal from the persistence with the TLS roots, empty without a "tls" partition
+#[cfg(feature = "tls")]
+pub static LOAD_TLS_ROOTS: Signal<CriticalSectionRawMutex, alloc::vec::Vec<TlsRoot>> =
+    Signal::new();
+
+/// the flash for persistence and the panic handler. With the network side on
+/// core 1, that core runs from flash too, so it's parked around every write
+pub(crate) fn flash_storage(flash: peripherals::FLASH<'static>) -> FlashStorage<'static> {
+    let storage = FlashStorage::new(flash);
+    #[cfg(any(feature = "esp32", feature = "esp32s3"))]
+    let storage = storage.multicore_auto_park();
+    storage
+}
 
 #[embassy_executor::task]
-pub async fn persistence(flash: peripherals::FLASH<'static>) -> ! {
+pub async fn persistence(flash: peripherals::FLASH<'static>, load_wifi: bool) -> ! {
     info!("Start persistence task");
-    let mut flash = FlashStorage::new(flash);
+    let mut flash = flash_storage(flash);
     info!("Flash size = {}", flash.capacity());
 
     let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
@@ -33,6 +222,31 @@ pub async fn persistence(flash: peripherals::FLASH<'static>) -> ! {
     // read partitions
     let pt = partitions::read_partition_table(&mut flash, &mut pt_mem).unwrap();
 
+    // provisioned separately and never rewritten, so a factory reset keeps it
+    let enterprise = pt
+        .iter()
+        .find(|p| p.label_as_str() == EAP_PARTITION)
+        .and_then(|p| load_enterprise(&mut p.as_embedded_storage(&mut flash)));
+    LOAD_ENTERPRISE.signal(enterprise);
+    // read once, so the next boot only sees newer dumps
+    let (events, panic) = pt
+        .iter()
+        .find(|p| p.label_as_str() == EVENTLOG_PARTITION)
+        .map(|p| take_postmortem(&mut p.as_embedded_storage(&mut flash)))
+        .unwrap_or_default();
+    LOAD_EVENTLOG.signal(events);
+    LOAD_PANIC.signal(panic);
+    #[cfg(feature = "tls")]
+    {
+        let roots = pt
+            .iter()
+            .find(|p| p.label_as_str() == TLS_PARTITION)
+            .map(|p| load_tls_roots(&mut p.as_embedded_storage(&mut flash)))
+            .unwrap_or_default();
+        LOAD_TLS_ROOTS.signal(roots);
+    }
+    OTA_UNCONFIRMED.signal(image_unconfirmed(&mut flash));
+
     let nvs = pt
         .find_partition(partitions::PartitionType::Data(
             partitions::DataPartitionSubType::Nvs,
@@ -42,62 +256,952 @@ pub async fn persistence(flash: peripherals::FLASH<'static>) -> ! {
     let mut nvs_partition: FlashRegion<'_, FlashStorage<'_>> = nvs.as_embedded_storage(&mut flash);
     info!("NVS partition size = {}", nvs_partition.capacity());
 
-    let conf = load_previous_wifi(&mut nvs_partition).await.ok();
+    // the reset button was held at boot, don't even load the bad records
+    if FACTORY_RESET.signaled() {
+        factory_reset(&mut nvs_partition);
+    }
+
+    // read before opening, which erases pages that aren't NVS
+    let legacy = match Nvs::is_formatted(&mut nvs_partition) {
+        Ok(false) => read_legacy(&mut nvs_partition),
+        _ => Default::default(),
+    };
+    // None if even a formatted partition won't open: every record loads as
+    // missing, the defaults apply and stores are dropped
+    let mut records = match Records::open(&mut nvs_partition) {
+        Ok(records) => Some(records),
+        Err(e) => {
+            info!("NVS unusable ({:?}), formatting", e);
+            let opened =
+                Nvs::format(&mut nvs_partition).and_then(|_| Records::open(&mut nvs_partition));
+            match opened {
+                Ok(records) => Some(records),
+                Err(e) => {
+                    error!(
+                        "NVS unusable after formatting ({:?}), running on defaults",
+                        e
+                    );
+                    None
+                }
+            }
+        }
+    };
+    if legacy.iter().any(Option::is_some) {
+        info!("Moving records from the raw layout to NVS");
+    }
+    for store in legacy.into_iter().flatten() {
+        write_store(&mut nvs_partition, &mut records, store);
+    }
+
+    // a deep sleep wake brought the WG along in RTC memory
+    let conf = match (load_wifi, &mut records) {
+        (true, Some(records)) => records
+            .load::<WifiConfig>(&mut nvs_partition, WIFI_KEY)
+            .ok()
+            .map(WifiConfig::restored),
+        _ => None,
+    };
+    let (settings, networks, stats, leases, provisioned) = match &mut records {
+        Some(records) => (
+            records
+                .load::<Settings>(&mut nvs_partition, SETTINGS_KEY)
+                .ok(),
+            records
+                .load::<NetworkConfigs>(&mut nvs_partition, NETWORK_CONFIG_KEY)
+                .ok(),
+            records.load::<Stats>(&mut nvs_partition, STATS_KEY).ok(),
+            records
+                .load::<LeaseCache>(&mut nvs_partition, LEASES_KEY)
+                .ok(),
+            records.provisioned(&mut nvs_partition),
+        ),
+        None => (None, None, None, None, Credentials::new()),
+    };
+    LOAD_PROVISIONED.signal(provisioned);
 
     // notify connection thread
     LOAD_WIFI.signal(conf);
-    let mut bytes = [0xff; 64];
+    LOAD_SETTINGS.signal(settings);
+    LOAD_NETWORK_CONFIGS.signal(networks);
+    LOAD_STATS.signal(stats);
+    LOAD_LEASES.signal(leases);
+    let mut last_wifi_store: Option<Instant> = None;
+    let mut last_stats_store: Option<Instant> = None;
     loop {
         info!("Waiting for new persistence");
-        let conf: WifiConfig = STORE_WIFI.wait().await;
-        info!("Persisting current best WG {:?}", conf);
-
-        // note: erase a full sector of flash like this is bad, but this is a prototype.
-        // ideally, one would use a key-value store with wear levelling and pagination.
-        // erase first
-        nvs_partition.erase(SECTOR_START, SECTOR_END).unwrap();
-        match postcard::to_slice::<WifiConfig>(&conf, &mut bytes) {
-            Ok(x) => {
-                match nor_flash::check_write(&nvs_partition, WIFI_CONFIG_ADDR, x.len()) {
-                    Ok(_) => info!("Write success {:02x}", x),
-                    Err(y) => match y {
-                        NorFlashErrorKind::NotAligned => info!("Write error: not aligned"),
-                        NorFlashErrorKind::OutOfBounds => info!("Write error: OOB"),
-                        NorFlashErrorKind::Other => info!("Write error: other"),
-                        _ => todo!(),
-                    },
+        let store = match beat_while(
+            Watched::Persistence,
+            select4(
+                FACTORY_RESET.wait(),
+                FLUSH.wait(),
+                next_store(last_stats_store.map(|at| at + STATS_STORE_INTERVAL)),
+                OTA_OP.receive(),
+            ),
+        )
+        .await
+        {
+            Either4::First(_) => factory_reset(&mut nvs.as_embedded_storage(&mut flash)),
+            Either4::Second(_) => {
+                flush(&mut nvs.as_embedded_storage(&mut flash), &mut records);
+                if DUMP_EVENTLOG.try_take().is_some() {
+                    match dump_eventlog(&mut flash, &mut PostmortemBuffers::new()) {
+                        true => info!("Event log dumped"),
+                        false => info!("Event log dump failed"),
+                    }
                 }
-                match nvs_partition.write(WIFI_CONFIG_ADDR, &bytes) {
-                    Ok(_) => info!("Write success {:02x}", bytes),
-                    Err(y) => info!("Write error: {}", y),
+                FLUSHED.signal(());
+                continue;
+            }
+            Either4::Third(store) => store,
+            Either4::Fourth(op) => {
+                // the rest erase, the slot at Begin and otadata after it.
+                // Chunks land in erased space, holding them up would stall the download
+                if !matches!(op, OtaOp::Write { .. }) {
+                    beat_while(Watched::Persistence, wait_until_released()).await;
+                }
+                OTA_OP_DONE.signal(ota_op(&mut flash, op));
+                continue;
+            }
+        };
+        // the OTA slots need the flash too, so the NVS region only lives for one round
+        let mut nvs_partition = nvs.as_embedded_storage(&mut flash);
+        let store = match store {
+            Store::Wifi(conf) => {
+                let conf = match last_wifi_store {
+                    Some(at) if at.elapsed() < WIFI_STORE_INTERVAL => {
+                        // hold off, a newer best arriving meanwhile replaces this one
+                        let due = Timer::at(at + WIFI_STORE_INTERVAL);
+                        match select3(FACTORY_RESET.wait(), due, FLUSH.wait()).await {
+                            Either3::First(_) => factory_reset(&mut nvs_partition),
+                            // write it now, the rest is flushed next round
+                            Either3::Third(_) => FLUSH.signal(()),
+                            Either3::Second(_) => {}
+                        }
+                        STORE_WIFI.try_take().unwrap_or(conf)
+                    }
+                    _ => conf,
+                };
+                last_wifi_store = Some(Instant::now());
+                Store::Wifi(conf)
+            }
+            Store::Stats(stats) => {
+                last_stats_store = Some(Instant::now());
+                Store::Stats(stats)
+            }
+            store => store,
+        };
+        // erasing stalls the cache, don't let it land on top of an association
+        wait_until_not_associating(FLASH_DEFER_MAX).await;
+        // a write can erase a page, the application may be timing something
+        beat_while(Watched::Persistence, wait_until_released()).await;
+        write_store(&mut nvs_partition, &mut records, store);
+        // a flush doesn't wait out the pause
+        if let Either::Second(_) =
+            select(Timer::after(Duration::from_millis(5000)), FLUSH.wait()).await
+        {
+            FLUSH.signal(());
+        }
+    }
+}
+
+// one step of an OTA update, on whichever app slot isn't running
+fn ota_op(flash: &mut FlashStorage<'_>, op: OtaOp) -> Result<(), OtaError> {
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let mut updater = OtaUpdater::new(flash, &mut pt_mem).map_err(|e| {
+        info!("OTA partitions unusable: {:?}", e);
+        OtaError::NoOtaSlot
+    })?;
+    match op {
+        OtaOp::Begin { len } => {
+            let (mut slot, slot_type) =
+                updater.next_partition().map_err(|_| OtaError::NoOtaSlot)?;
+            if len > slot.capacity() as u32 {
+                return Err(OtaError::TooLarge);
+            }
+            info!("OTA into {:?}, erasing {} bytes", slot_type, len);
+            slot.erase(0, len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE)
+                .map_err(|_| OtaError::Flash)
+        }
+        OtaOp::Write { offset, mut data } => {
+            let (mut slot, _) = updater.next_partition().map_err(|_| OtaError::NoOtaSlot)?;
+            // flash writes are whole words, the padding lands in erased space
+            while data.len() % 4 != 0 {
+                let _ = data.push(0xff);
+            }
+            slot.write(offset, &data).map_err(|_| OtaError::Flash)?;
+            // read it straight back, a bad write shows up now rather than at boot
+            let mut written = [0u8; OTA_CHUNK_LEN];
+            let written = &mut written[..data.len()];
+            slot.read(offset, written).map_err(|_| OtaError::Flash)?;
+            match *written == *data {
+                true => Ok(()),
+                false => Err(OtaError::Verify),
+            }
+        }
+        OtaOp::Finish { len, crc32 } => {
+            let (mut slot, _) = updater.next_partition().map_err(|_| OtaError::NoOtaSlot)?;
+            let mut buf = [0u8; OTA_CHUNK_LEN];
+            let mut crc = 0;
+            let mut magic = 0;
+            let mut offset = 0;
+            while offset < len {
+                let n = ((len - offset) as usize).min(OTA_CHUNK_LEN);
+                slot.read(offset, &mut buf[..n])
+                    .map_err(|_| OtaError::Flash)?;
+                if offset == 0 {
+                    magic = buf[0];
                 }
+                crc = esp_hal::rom::crc::crc32_le(crc, &buf[..n]);
+                offset += n as u32;
             }
-            Err(y) => info!("Error : {:?}", y),
+            if magic != IMAGE_MAGIC || crc != crc32 {
+                info!("OTA image crc {:x}, expected {:x}", crc, crc32);
+                return Err(OtaError::Verify);
+            }
+            // boots the new slot once, the rollback check marks it valid
+            updater
+                .activate_next_partition()
+                .and_then(|_| updater.set_current_ota_state(OtaImageState::New))
+                .map_err(|_| OtaError::Flash)
+        }
+        OtaOp::Confirm => updater
+            .set_current_ota_state(OtaImageState::Valid)
+            .map_err(|_| OtaError::Flash),
+        // done here rather than left to the bootloader, which only rolls back
+        // if it was built with rollback support
+        OtaOp::Rollback => {
+            info!("Marking {:?} invalid", updater.selected_partition().ok());
+            updater
+                .set_current_ota_state(OtaImageState::Invalid)
+                .and_then(|_| updater.activate_next_partition())
+                .and_then(|_| updater.set_current_ota_state(OtaImageState::Valid))
+                .map_err(|_| OtaError::Flash)
         }
-        Timer::after(Duration::from_millis(5000)).await;
     }
 }
 
-// load the wifi
-pub async fn load_previous_wifi<'a>(
+// the running image was booted from a fresh update and hasn't proven itself yet
+fn image_unconfirmed(flash: &mut FlashStorage<'_>) -> bool {
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let Ok(mut updater) = OtaUpdater::new(flash, &mut pt_mem) else {
+        return false;
+    };
+    matches!(
+        updater.current_ota_state(),
+        Ok(OtaImageState::New | OtaImageState::PendingVerify)
+    )
+}
+
+// one record waiting to be written
+enum Store {
+    Wifi(WifiConfig),
+    Settings(Settings),
+    Networks(NetworkConfigs),
+    Stats(Stats),
+    Leases(LeaseCache),
+    Provisioned(Credentials),
+    Seal,
+}
+
+// the next store to write. Stats aren't looked at before `stats_due`, the
+// signal keeps only the newest meanwhile
+async fn next_store(stats_due: Option<Instant>) -> Store {
+    let stats = async {
+        if let Some(due) = stats_due {
+            Timer::at(due).await;
+        }
+        STORE_STATS.wait().await
+    };
+    let store = select(
+        select4(
+            STORE_WIFI.wait(),
+            STORE_SETTINGS.wait(),
+            STORE_NETWORK_CONFIGS.wait(),
+            stats,
+        ),
+        select3(
+            STORE_LEASES.wait(),
+            STORE_PROVISIONED.wait(),
+            SEAL_PASSPHRASES.wait(),
+        ),
+    )
+    .await;
+    match store {
+        Either::First(Either4::First(conf)) => Store::Wifi(conf),
+        Either::First(Either4::Second(settings)) => Store::Settings(settings),
+        Either::First(Either4::Third(networks)) => Store::Networks(networks),
+        Either::First(Either4::Fourth(stats)) => Store::Stats(stats),
+        Either::Second(Either3::First(leases)) => Store::Leases(leases),
+        Either::Second(Either3::Second(creds)) => Store::Provisioned(creds),
+        Either::Second(Either3::Third(_)) => Store::Seal,
+    }
+}
+
+fn write_store(
     nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
-) -> Result<WifiConfig, anyhow::Error> {
-    let mut bytes = [0xff; 60];
-    match nvs_partition.read(WIFI_CONFIG_ADDR, &mut bytes) {
-        Ok(_) => info!("Read bytes {:02x}", &bytes),
-        Err(x) => info!("Errror = {:?}", x),
+    records: &mut Option<Records>,
+    store: Store,
+) {
+    let Some(records) = records else {
+        // no NVS to write to, the WG store still gets its answer
+        if let Store::Wifi(_) = store {
+            WIFI_STORED.signal(Err(StoreError::Write));
+        }
+        return;
+    };
+    match store {
+        Store::Wifi(conf) => {
+            info!("Persisting current best WG {:?}", conf);
+            WIFI_STORED.signal(records.store(nvs_partition, WIFI_KEY, &conf));
+        }
+        Store::Settings(settings) => {
+            info!("Persisting settings {:?}", settings);
+            let _ = records.store(nvs_partition, SETTINGS_KEY, &settings);
+        }
+        Store::Networks(networks) => {
+            info!("Persisting network configs {:?}", networks);
+            let _ = records.store(nvs_partition, NETWORK_CONFIG_KEY, &networks);
+        }
+        Store::Stats(stats) => {
+            info!("Persisting stats {:?}", stats);
+            let _ = records.store(nvs_partition, STATS_KEY, &stats);
+        }
+        Store::Leases(leases) => {
+            info!("Persisting DHCP leases {:?}", leases);
+            let _ = records.store(nvs_partition, LEASES_KEY, &leases);
+        }
+        Store::Provisioned(creds) => {
+            // not logged, they hold passphrases
+            info!("Provisioning {} networks", creds.len());
+            if let Err(e) = records.provision(nvs_partition, &creds) {
+                info!("Provisioning error: {:?}", e);
+            }
+        }
+        Store::Seal => records.seal_passphrases(nvs_partition),
     }
+}
+
+// write whatever is still pending, before the radio and maybe the power go
+fn flush(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>, records: &mut Option<Records>) {
+    info!("Flushing pending persistence");
+    let pending = [
+        STORE_WIFI.try_take().map(Store::Wifi),
+        STORE_SETTINGS.try_take().map(Store::Settings),
+        STORE_NETWORK_CONFIGS.try_take().map(Store::Networks),
+        STORE_STATS.try_take().map(Store::Stats),
+        STORE_LEASES.try_take().map(Store::Leases),
+        STORE_PROVISIONED.try_take().map(Store::Provisioned),
+        SEAL_PASSPHRASES.try_take().map(|_| Store::Seal),
+    ];
+    for store in pending.into_iter().flatten() {
+        write_store(nvs_partition, records, store);
+    }
+}
+
+/// what a post-mortem write needs besides the flash, about 5 KB: room for the
+/// partition table and a framed dump. The panic handler keeps them in a
+/// static, off the stack it panicked on
+pub(crate) struct PostmortemBuffers {
+    pt_mem: [u8; partitions::PARTITION_TABLE_MAX_LEN],
+    dump: [u8; EVENTLOG_DUMP_LEN],
+}
 
-    match postcard::from_bytes::<WifiConfig>(&bytes[..]) {
-        Ok(x) => {
-            info!("Config: {:?} ", x);
-            return Ok(x);
+impl PostmortemBuffers {
+    pub(crate) const fn new() -> Self {
+        return Self {
+            pt_mem: [0; partitions::PARTITION_TABLE_MAX_LEN],
+            dump: [0xff; EVENTLOG_DUMP_LEN],
+        };
+    }
+}
+
+/// writes the event log to the "postmortem" partition, false if it didn't
+/// make it. Also the panic path's, with the flash taken from under the task,
+/// so nothing here logs
+pub(crate) fn dump_eventlog(flash: &mut FlashStorage<'_>, buffers: &mut PostmortemBuffers) -> bool {
+    buffers.dump.fill(0xff);
+    let Some(len) = encode_dump(&mut buffers.dump) else {
+        return false;
+    };
+    let bytes = &buffers.dump[..len.next_multiple_of(4)];
+    write_postmortem(flash, EVENTLOG_DUMP_ADDR, &mut buffers.pt_mem, bytes)
+}
+
+/// writes `record` next to the event log, from the panic handler
+pub(crate) fn dump_panic(
+    flash: &mut FlashStorage<'_>,
+    record: &PanicRecord,
+    buffers: &mut PostmortemBuffers,
+) -> bool {
+    buffers.dump.fill(0xff);
+    let Some(len) = frame(record, PANIC_MAGIC, &mut buffers.dump[..PANIC_DUMP_LEN]) else {
+        return false;
+    };
+    let bytes = &buffers.dump[..len.next_multiple_of(4)];
+    write_postmortem(flash, PANIC_DUMP_ADDR, &mut buffers.pt_mem, bytes)
+}
+
+// one framed dump into its own sector of the "postmortem" partition. Flash
+// writes are whole words, `bytes` is padded with erased 0xff to one
+fn write_postmortem(
+    flash: &mut FlashStorage<'_>,
+    addr: u32,
+    pt_mem: &mut [u8; partitions::PARTITION_TABLE_MAX_LEN],
+    bytes: &[u8],
+) -> bool {
+    let Ok(pt) = partitions::read_partition_table(flash, pt_mem) else {
+        return false;
+    };
+    let Some(partition) = pt.iter().find(|p| p.label_as_str() == EVENTLOG_PARTITION) else {
+        return false;
+    };
+    let mut region = partition.as_embedded_storage(flash);
+    region.erase(addr, addr + SECTOR_SIZE).is_ok() && region.write(addr, bytes).is_ok()
+}
+
+// the dumps the previous boot left, erased once read
+fn take_postmortem(
+    region: &mut FlashRegion<'_, FlashStorage<'_>>,
+) -> (Option<EventLog>, Option<PanicRecord>) {
+    let mut events = [0xff; EVENTLOG_DUMP_LEN];
+    let mut panic = [0xff; PANIC_DUMP_LEN];
+    if let Err(e) = region
+        .read(EVENTLOG_DUMP_ADDR, &mut events)
+        .and_then(|_| region.read(PANIC_DUMP_ADDR, &mut panic))
+    {
+        info!("Post-mortem read error = {:?}", e);
+        return (None, None);
+    }
+    let events = decode_dump(&events);
+    let panic = unframe(&panic, PANIC_MAGIC);
+    if events.is_some() || panic.is_some() {
+        if let Err(e) = region.erase(EVENTLOG_DUMP_ADDR, PANIC_DUMP_ADDR + SECTOR_SIZE) {
+            info!("Post-mortem erase error: {:?}", e);
         }
-        Err(e) => {
-            info!("Error {:?}", e);
-            return Err(e.into());
+    }
+    (events, panic)
+}
+
+// erase every record and start over with nothing persisted. Other namespaces
+// in the partition stay, unless it has to be formatted
+fn factory_reset(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) -> ! {
+    info!("Factory reset, erasing persisted state");
+    if let Err(e) = erase_records(nvs_partition) {
+        info!("Erase error: {:?}, formatting NVS", e);
+        if let Err(e) = Nvs::format(nvs_partition) {
+            info!("Format error: {:?}", e);
         }
     }
+    mark_deliberate_reset();
+    esp_hal::system::software_reset()
+}
+
+/// writes `conf` as the new best WG and waits until it is on flash, retrying
+/// on storage errors. Callers only update their view of the persisted WG once
+/// this succeeds, so a reboot mid-write can't leave RAM ahead of flash
+pub async fn persist_wifi(conf: &WifiConfig) -> Result<(), Error> {
+    let mut result = Err(StoreError::Write);
+    for attempt in 1..=STORE_ATTEMPTS {
+        // drop a stale ack from an earlier request
+        WIFI_STORED.reset();
+        STORE_WIFI.signal(conf.clone());
+        result = WIFI_STORED.wait().await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => info!("Storing best WG failed ({:?}), attempt {}", e, attempt),
+        }
+        Timer::after(STORE_RETRY_DELAY).await;
+    }
+    result.map_err(Error::from)
+}
+
+fn erase_records(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) -> Result<(), NvsError> {
+    let mut nvs = Nvs::open(nvs_partition)?;
+    match nvs.find_namespace(nvs_partition, NVS_NAMESPACE)? {
+        Some(ns) => nvs.erase_namespace(nvs_partition, ns),
+        None => Ok(()),
+    }
+}
 
-    // starting wifi_config
+// the firmware's namespace in the nvs partition, one blob per record
+struct Records {
+    nvs: Nvs,
+    ns: u8,
+}
+
+impl Records {
+    fn open(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) -> Result<Self, NvsError> {
+        let mut flash = Timed(nvs_partition);
+        let mut nvs = Nvs::open(&mut flash)?;
+        let ns = match nvs.namespace(&mut flash, NVS_NAMESPACE) {
+            Ok(ns) => ns,
+            // IDF's version 1 partition: nothing loads and every store fails,
+            // the records only live in RAM
+            Err(NvsError::Version1) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { nvs, ns })
+    }
+
+    // encode `record` and write it as blob `key`, an unchanged one isn't rewritten
+    fn store<T: Serialize>(
+        &mut self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+        key: &str,
+        record: &T,
+    ) -> Result<(), StoreError> {
+        let mut bytes = [0xff; RECORD_LEN];
+        bytes[..RECORD_HEADER_LEN].copy_from_slice(&[RECORD_TAG, RECORD_LAYOUT]);
+        let len = match DefaultCodec::encode::<T>(record, &mut bytes[RECORD_HEADER_LEN..]) {
+            Ok(x) => RECORD_HEADER_LEN + x.len(),
+            Err(y) => {
+                info!("Error : {:?}", y);
+                return Err(StoreError::Encode);
+            }
+        };
+        match self
+            .nvs
+            .set_blob(&mut Timed(nvs_partition), self.ns, key, &bytes[..len])
+        {
+            Ok(_) => {
+                info!("Write success {} {:02x}", key, Bytes(&bytes[..len]));
+                Ok(())
+            }
+            Err(e) => {
+                info!("Write error {}: {:?}", key, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    // the networks of the factory image in order, up to the first missing ssid<n>.
+    // Not logged, they hold passphrases
+    fn provisioned(
+        &mut self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+    ) -> Credentials {
+        let mut creds = Credentials::new();
+        let Ok(Some(ns)) = self.nvs.find_namespace(nvs_partition, FACTORY_NAMESPACE) else {
+            return creds;
+        };
+        let key = secret::storage_key();
+        if key.is_none() {
+            info!("No storage key, passphrases stay in plaintext");
+        }
+        for n in 0..MAX_PROVISIONED {
+            let mut read = |name: &str| -> Option<heapless::String<FACTORY_VALUE_LEN>> {
+                let key = factory_key(name, n);
+                let mut bytes = [0u8; FACTORY_VALUE_LEN];
+                let len = self.nvs.get_str(nvs_partition, ns, &key, &mut bytes).ok()?;
+                core::str::from_utf8(&bytes[..len]).ok()?.try_into().ok()
+            };
+            let Some(ssid) = read("ssid") else {
+                break;
+            };
+            let hidden = read("hidden").is_some_and(|v| crate::env_flag(&v));
+            let auth = AuthPolicy::from_env(&read("auth").unwrap_or_default());
+            let pmf = Pmf::from_env(&read("pmf").unwrap_or_default());
+            let tier = Tier::from_env(&read("tier").unwrap_or_default());
+            let policy = NetworkPolicy::from_env(&read("policy").unwrap_or_default());
+            let Ok(ssid) = ssid.as_str().try_into() else {
+                info!("Network {} left out, SSID longer than 32 bytes", n);
+                continue;
+            };
+            let password = self.passphrase(nvs_partition, ns, n, key.as_ref());
+            // n < MAX_PROVISIONED, it fits
+            let _ = creds.push(Credential {
+                ssid,
+                password: password.unwrap_or_default(),
+                hidden,
+                auth,
+                pmf,
+                tier,
+                policy,
+            });
+        }
+        creds
+    }
+
+    // replaces the factory image's networks with `creds`, written the way an
+    // image has them. With a storage key the passphrases go straight into
+    // psk<n>, only before the radio runs are they written in plaintext for
+    // seal_passphrases. The old ones are scrubbed first
+    fn provision(
+        &mut self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+        creds: &[Credential],
+    ) -> Result<(), NvsError> {
+        let mut flash = Timed(nvs_partition);
+        let ns = self.nvs.namespace(&mut flash, FACTORY_NAMESPACE)?;
+        for n in 0..MAX_PROVISIONED {
+            self.nvs.scrub(&mut flash, ns, &factory_key("pass", n))?;
+        }
+        self.nvs.erase_namespace(&mut flash, ns)?;
+        let key = secret::storage_key();
+        for (n, cred) in creds.iter().enumerate().take(MAX_PROVISIONED) {
+            let sealed_key = factory_key("psk", n);
+            let mut sealed = [0u8; FACTORY_VALUE_LEN + SEAL_OVERHEAD];
+            let sealed_len = key.as_ref().and_then(|key| {
+                secret::seal(key, &sealed_key, cred.password.as_bytes(), &mut sealed)
+            });
+            let mut set = |name: &str, value: &str| {
+                let key = factory_key(name, n);
+                self.nvs.set_str(&mut flash, ns, &key, value)
+            };
+            set("ssid", &cred.ssid)?;
+            if sealed_len.is_none() {
+                set("pass", &cred.password)?;
+            }
+            set("auth", cred.auth.env_name())?;
+            set("pmf", cred.pmf.env_name())?;
+            set("tier", cred.tier.env_name())?;
+            let policy = cred.policy.env_value();
+            if !policy.is_empty() {
+                set("policy", &policy)?;
+            }
+            if cred.hidden {
+                set("hidden", "1")?;
+            }
+            if let Some(len) = sealed_len {
+                self.nvs
+                    .set_blob(&mut flash, ns, &sealed_key, &sealed[..len])?;
+            }
+        }
+        Ok(())
+    }
+
+    // seals every plaintext pass<n> into blob psk<n> and scrubs it, once the
+    // radio runs and seal has a true RNG for the nonces
+    fn seal_passphrases(&mut self, nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) {
+        let Some(key) = secret::storage_key() else {
+            return;
+        };
+        let Ok(Some(ns)) = self.nvs.find_namespace(nvs_partition, FACTORY_NAMESPACE) else {
+            return;
+        };
+        for n in 0..MAX_PROVISIONED {
+            self.seal_passphrase(nvs_partition, ns, n, &key);
+        }
+    }
+
+    // seals the plaintext pass<n>, if there is one
+    fn seal_passphrase(
+        &mut self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+        ns: u8,
+        n: usize,
+        key: &[u8; 32],
+    ) {
+        let plain_key = factory_key("pass", n);
+        let mut plain = [0u8; FACTORY_VALUE_LEN];
+        let Ok(len) = self.nvs.get_str(nvs_partition, ns, &plain_key, &mut plain) else {
+            return;
+        };
+        let sealed_key = factory_key("psk", n);
+        let mut sealed = [0u8; FACTORY_VALUE_LEN + SEAL_OVERHEAD];
+        let Some(sealed_len) = secret::seal(key, &sealed_key, &plain[..len], &mut sealed) else {
+            return;
+        };
+        plain.fill(0);
+        let mut flash = Timed(nvs_partition);
+        // the plaintext only goes once the sealed copy is on flash
+        match self
+            .nvs
+            .set_blob(&mut flash, ns, &sealed_key, &sealed[..sealed_len])
+            .and_then(|_| self.nvs.scrub(&mut flash, ns, &plain_key))
+        {
+            Ok(_) => info!("Sealed passphrase {}", n),
+            Err(e) => info!("Sealing passphrase {} failed: {:?}", n, e),
+        }
+    }
+
+    // the passphrase of network `n`, from blob psk<n> with a storage key or
+    // the plaintext pass<n> the factory wrote until seal_passphrases ran
+    fn passphrase(
+        &mut self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+        ns: u8,
+        n: usize,
+        key: Option<&[u8; 32]>,
+    ) -> Option<heapless::String<64>> {
+        let plain_key = factory_key("pass", n);
+        let mut plain = [0u8; FACTORY_VALUE_LEN];
+        let plain_len = self
+            .nvs
+            .get_str(nvs_partition, ns, &plain_key, &mut plain)
+            .ok();
+        let plaintext = || -> Option<heapless::String<64>> {
+            let len = plain_len?;
+            core::str::from_utf8(&plain[..len]).ok()?.try_into().ok()
+        };
+        let Some(key) = key else {
+            return plaintext();
+        };
+        if plain_len.is_some() {
+            // the factory's, or left from before the radio ran last boot
+            return plaintext();
+        }
+
+        let sealed_key = factory_key("psk", n);
+        let mut sealed = [0u8; FACTORY_VALUE_LEN + SEAL_OVERHEAD];
+        let mut opened = [0u8; FACTORY_VALUE_LEN];
+        let Ok(sealed_len) = self
+            .nvs
+            .get_blob(nvs_partition, ns, &sealed_key, &mut sealed)
+        else {
+            return plaintext();
+        };
+        match secret::open(key, &sealed_key, &sealed[..sealed_len], &mut opened) {
+            Some(len) => core::str::from_utf8(&opened[..len]).ok()?.try_into().ok(),
+            None => {
+                info!("Passphrase {} doesn't open, sealed under another key?", n);
+                plaintext()
+            }
+        }
+    }
+
+    // read and decode blob `key`
+    fn load<T: DeserializeOwned + Loggable>(
+        &self,
+        nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+        key: &str,
+    ) -> Result<T, Error> {
+        let mut bytes = [0xff; RECORD_LEN];
+        let len = match self.nvs.get_blob(nvs_partition, self.ns, key, &mut bytes) {
+            Ok(len) => {
+                info!("Read {} {:02x}", key, Bytes(&bytes[..len]));
+                len
+            }
+            Err(e) => {
+                info!("Read error {}: {:?}", key, e);
+                return Err(StoreError::from(e).into());
+            }
+        };
+        if bytes[..len].get(..RECORD_HEADER_LEN) != Some(&[RECORD_TAG, RECORD_LAYOUT][..]) {
+            info!("{} is of another layout, using defaults", key);
+            return Err(StoreError::Layout.into());
+        }
+        match DefaultCodec::decode::<T>(&bytes[RECORD_HEADER_LEN..len]) {
+            Ok(x) => {
+                info!("Config: {:?} ", x);
+                Ok(x)
+            }
+            Err(e) => {
+                info!("Error {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+// the flash with every erase and write timed into flash_latency
+struct Timed<'a, S>(&'a mut S);
+
+impl<S: nor_flash::ErrorType> nor_flash::ErrorType for Timed<'_, S> {
+    type Error = S::Error;
+}
+
+impl<S: ReadNorFlash> ReadNorFlash for Timed<'_, S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl<S: NorFlash> NorFlash for Timed<'_, S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let started = Instant::now();
+        let erased = self.0.erase(from, to);
+        ERASE_LATENCY.lock(|l| l.borrow_mut().record(started.elapsed()));
+        erased
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let started = Instant::now();
+        let written = self.0.write(offset, bytes);
+        WRITE_LATENCY.lock(|l| l.borrow_mut().record(started.elapsed()));
+        written
+    }
+}
+
+// the records of the raw layout, all None once the partition is NVS or blank
+fn read_legacy(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) -> [Option<Store>; 5] {
+    [
+        legacy_wifi(nvs_partition).map(Store::Wifi),
+        legacy_record(nvs_partition, LEGACY_SETTINGS_ADDR).map(Store::Settings),
+        legacy_record(nvs_partition, LEGACY_NETWORK_CONFIG_ADDR).map(Store::Networks),
+        legacy_record(nvs_partition, LEGACY_STATS_ADDR).map(Store::Stats),
+        legacy_record(nvs_partition, LEGACY_LEASES_ADDR).map(Store::Leases),
+    ]
+}
+
+// CRC over the sequence, length and payload of a slotted record
+fn slot_crc(header: &[u8], payload: &[u8]) -> u32 {
+    let crc = esp_hal::rom::crc::crc32_le(0, &header[..6]);
+    esp_hal::rom::crc::crc32_le(crc, payload)
+}
+
+// the slot record at `addr` into `bytes`, Some(seq, payload length) if it is complete
+fn read_slot(
+    nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+    addr: u32,
+    bytes: &mut [u8; RECORD_LEN],
+) -> Option<(u32, usize)> {
+    nvs_partition.read(addr, bytes).ok()?;
+    let seq = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let len = u16::from_le_bytes(bytes[4..6].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+    // an erased or torn slot fails one of these
+    let payload = bytes.get(SLOT_HEADER_LEN..SLOT_HEADER_LEN + len)?;
+    (slot_crc(&bytes[..], payload) == crc).then_some((seq, len))
+}
+
+// the best WG from whichever slot holds the newest complete copy
+fn legacy_wifi(nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>) -> Option<WifiConfig> {
+    let mut bytes = [0xff; RECORD_LEN];
+    let mut newest: Option<(u32, WifiConfig)> = None;
+    for addr in LEGACY_WIFI_SLOTS {
+        let Some((seq, len)) = read_slot(nvs_partition, addr, &mut bytes) else {
+            continue;
+        };
+        // sequence numbers wrap, compare by distance
+        if newest
+            .as_ref()
+            .is_some_and(|(n, _)| seq.wrapping_sub(*n) as i32 <= 0)
+        {
+            continue;
+        }
+        if let Ok(conf) =
+            DefaultCodec::decode::<WifiConfig>(&bytes[SLOT_HEADER_LEN..SLOT_HEADER_LEN + len])
+        {
+            newest = Some((seq, conf));
+        }
+    }
+    newest.map(|(_, conf)| conf)
+}
+
+// key `name` of the factory image's network `n`, e.g. ssid0
+fn factory_key(name: &str, n: usize) -> heapless::String<NVS_KEY_LEN> {
+    let mut key = heapless::String::new();
+    // the names are short and n < MAX_PROVISIONED, it always fits
+    let _ = write!(key, "{}{}", name, n);
+    key
+}
+
+// the record at `addr`, None for an erased sector
+fn legacy_record<T: DeserializeOwned>(
+    nvs_partition: &mut FlashRegion<'_, FlashStorage<'_>>,
+    addr: u32,
+) -> Option<T> {
+    let mut bytes = [0xff; RECORD_LEN];
+    nvs_partition.read(addr, &mut bytes).ok()?;
+    if bytes.iter().all(|b| *b == 0xff) {
+        return None;
+    }
+    DefaultCodec::decode::<T>(&bytes[..]).ok()
+}
+
+// the record at the start of the "eap" partition and the certificates after it.
+// Not logged, it holds secrets
+fn load_enterprise(eap: &mut FlashRegion<'_, FlashStorage<'_>>) -> Option<EnterpriseCredential> {
+    let mut bytes = [0xff; RECORD_LEN];
+    if let Err(e) = eap.read(0, &mut bytes) {
+        info!("Enterprise read error = {:?}", e);
+        return None;
+    }
+    let record = DefaultCodec::decode::<EnterpriseRecord>(&bytes[..]).ok()?;
+    // lengths that run past the address space make the whole record suspect
+    let lens = [
+        record.ca_cert_len,
+        record.client_cert_len,
+        record.client_key_len,
+    ];
+    if lens
+        .iter()
+        .try_fold(EAP_BLOBS_ADDR, |addr, len| addr.checked_add(*len))
+        .is_none()
+    {
+        info!("Enterprise record refused, blob lengths overflow");
+        return None;
+    }
+    let mut addr = EAP_BLOBS_ADDR;
+    let mut blob = |len: u32| -> Option<&'static [u8]> {
+        let at = addr;
+        // checked above
+        addr += len;
+        if len == 0 || len > MAX_EAP_BLOB_LEN {
+            return None;
+        }
+        let mut bytes = alloc::vec![0u8; len as usize];
+        match eap.read(at, &mut bytes) {
+            Ok(_) => Some(alloc::boxed::Box::leak(bytes.into_boxed_slice())),
+            Err(e) => {
+                info!("Enterprise read error = {:?}", e);
+                None
+            }
+        }
+    };
+    let ca_cert = blob(record.ca_cert_len);
+    let client_cert = blob(record.client_cert_len);
+    let client_key = blob(record.client_key_len);
+    Some(EnterpriseCredential {
+        record,
+        ca_cert,
+        client_cert,
+        client_key,
+    })
+}
+
+#[cfg(feature = "tls")]
+fn load_tls_roots(tls: &mut FlashRegion<'_, FlashStorage<'_>>) -> alloc::vec::Vec<TlsRoot> {
+    let mut roots = alloc::vec::Vec::new();
+    let mut bytes = [0xff; RECORD_LEN];
+    if let Err(e) = tls.read(0, &mut bytes) {
+        info!("TLS roots read error = {:?}", e);
+        return roots;
+    }
+    let Ok(record) = DefaultCodec::decode::<TlsRootsRecord>(&bytes[..]) else {
+        return roots;
+    };
+    // checked before any root is read, they're leaked for good
+    if record
+        .roots
+        .iter()
+        .try_fold(TLS_BLOBS_ADDR, |addr, entry| addr.checked_add(entry.len))
+        .is_none()
+    {
+        info!("TLS roots record refused, lengths overflow");
+        return roots;
+    }
+    let mut addr = TLS_BLOBS_ADDR;
+    for entry in record.roots {
+        let at = addr;
+        // checked above
+        addr += entry.len;
+        if entry.len == 0 || entry.len > MAX_ROOT_LEN {
+            info!(
+                "TLS root for {} refused, {} bytes",
+                entry.host.as_str(),
+                entry.len
+            );
+            continue;
+        }
+        let mut der = alloc::vec![0u8; entry.len as usize];
+        match tls.read(at, &mut der) {
+            Ok(_) => roots.push(TlsRoot {
+                host: entry.host,
+                der: alloc::boxed::Box::leak(der.into_boxed_slice()),
+            }),
+            Err(e) => info!("TLS roots read error = {:?}", e),
+        }
+    }
+    roots
 }
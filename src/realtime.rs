@@ -0,0 +1,57 @@
+//! a higher priority executor for the application's latency-critical tasks.
+//! It runs from a software interrupt, so its tasks preempt the thread executor
+//! the wifi manager, scanner and sniffer run on: a scan or a burst of sniffed
+//! frames can't hold them up, only critical sections and the radio's own
+//! interrupts can. The library's statics all lock through the critical
+//! section, tasks here can use them like any other
+
+use embassy_executor::SendSpawner;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::interrupt::{Priority, software::SoftwareInterrupt};
+use esp_rtos::embassy::InterruptExecutor;
+use static_cell::StaticCell;
+
+use crate::scanner::scanning;
+
+/// the interrupt priority of the executor, above the thread executor and
+/// below the radio driver's interrupts
+pub const REALTIME_PRIORITY: Priority = Priority::Priority2;
+
+// the jitter probe's wakeup period
+const JITTER_PERIOD: Duration = Duration::from_millis(1);
+// time between the jitter probe's reports
+const JITTER_REPORT: Duration = Duration::from_secs(10);
+
+static EXECUTOR: StaticCell<InterruptExecutor<2>> = StaticCell::new();
+
+/// starts the interrupt executor on the calling core. Tasks spawned with the
+/// returned spawner run at REALTIME_PRIORITY
+pub fn start_realtime_executor(int: SoftwareInterrupt<'static, 2>) -> SendSpawner {
+    let executor = EXECUTOR.init(InterruptExecutor::new(int));
+    executor.start(REALTIME_PRIORITY)
+}
+
+/// an example latency-critical task: wakes every JITTER_PERIOD and logs how
+/// late the worst wakeups were, apart for those during a scan
+#[embassy_executor::task]
+pub async fn jitter_task() -> ! {
+    info!("Start jitter task");
+    let mut deadline = Instant::now();
+    let mut report_at = deadline + JITTER_REPORT;
+    // worst lateness in us, outside and during scans
+    let mut worst = [0u64; 2];
+    loop {
+        deadline += JITTER_PERIOD;
+        Timer::at(deadline).await;
+        let late = Instant::now()
+            .saturating_duration_since(deadline)
+            .as_micros();
+        let slot = &mut worst[scanning() as usize];
+        *slot = (*slot).max(late);
+        if deadline >= report_at {
+            info!("Worst jitter {}us, {}us during scans", worst[0], worst[1]);
+            worst = [0; 2];
+            report_at += JITTER_REPORT;
+        }
+    }
+}
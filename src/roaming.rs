@@ -0,0 +1,160 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use esp_radio::wifi::PowerSaveMode;
+use serde::{Deserialize, Serialize};
+
+use crate::WifiConfig;
+
+/// knobs deciding when a connected device moves to a better WG
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RoamPolicy {
+    // after connecting, stay on the AP at least this long unless it drops us
+    pub min_dwell: Duration,
+    // a candidate must beat the current AP by this many dB to roam
+    pub hysteresis_db: u8,
+    // never roam to a candidate weaker than this
+    pub min_rssi: i8,
+}
+
+impl RoamPolicy {
+    pub const fn new() -> Self {
+        return Self {
+            min_dwell: Duration::from_secs(10 * 60),
+            hysteresis_db: 6,
+            min_rssi: -80,
+        };
+    }
+    pub const fn with_min_dwell(mut self, min_dwell: Duration) -> Self {
+        self.min_dwell = min_dwell;
+        self
+    }
+    pub const fn with_hysteresis_db(mut self, hysteresis_db: u8) -> Self {
+        self.hysteresis_db = hysteresis_db;
+        self
+    }
+    pub const fn with_min_rssi(mut self, min_rssi: i8) -> Self {
+        self.min_rssi = min_rssi;
+        self
+    }
+
+    /// true if we should leave `current` (connected since `connected_at`) for `best`
+    pub fn should_roam(
+        &self,
+        current: &WifiConfig,
+        connected_at: Instant,
+        best: &WifiConfig,
+    ) -> bool {
+        if best == current || best.smoothed_rssi() < self.min_rssi {
+            return false;
+        }
+        let dwell = Instant::now().saturating_duration_since(connected_at);
+        if dwell < self.min_dwell {
+            info!(
+                "Roam to {:?} suppressed, dwell {}s < {}s",
+                best.bssid,
+                dwell.as_secs(),
+                self.min_dwell.as_secs()
+            );
+            return false;
+        }
+        // a higher tier is worth the roam whatever the signal, a lower one never
+        if best.tier != current.tier {
+            return best.tier < current.tier;
+        }
+        // on the averages, so one noisy scan doesn't trigger a roam
+        let gain = best.smoothed_rssi() as i16 - current.smoothed_rssi() as i16;
+        return gain >= self.hysteresis_db as i16;
+    }
+}
+
+impl Default for RoamPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// named bundles of roaming knobs, so a deployment picks one instead of tuning each
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RoamPreset {
+    // fixed installs: rare scans, sticky connections
+    #[default]
+    Stationary,
+    // moving between WGs: frequent scans, quick to roam
+    Mobile,
+    // battery powered: scan as little as possible, radio sleeps
+    Battery,
+}
+
+/// everything a preset decides
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RoamProfile {
+    pub roam: RoamPolicy,
+    // time between scans while connected
+    pub connected_scan_interval: Duration,
+    // time between scans while we can't connect to anything
+    pub disconnected_scan_interval: Duration,
+    pub power_save: PowerSaveMode,
+}
+
+impl RoamPreset {
+    pub const fn profile(&self) -> RoamProfile {
+        match self {
+            RoamPreset::Stationary => RoamProfile {
+                roam: RoamPolicy::new(),
+                connected_scan_interval: Duration::from_secs(60 * 60),
+                disconnected_scan_interval: Duration::from_secs(5 * 60),
+                power_save: PowerSaveMode::Minimum,
+            },
+            RoamPreset::Mobile => RoamProfile {
+                roam: RoamPolicy::new()
+                    .with_min_dwell(Duration::from_secs(60))
+                    .with_hysteresis_db(4)
+                    .with_min_rssi(-75),
+                connected_scan_interval: Duration::from_secs(2 * 60),
+                disconnected_scan_interval: Duration::from_secs(30),
+                power_save: PowerSaveMode::None,
+            },
+            RoamPreset::Battery => RoamProfile {
+                roam: RoamPolicy::new()
+                    .with_min_dwell(Duration::from_secs(60 * 60))
+                    .with_hysteresis_db(10)
+                    .with_min_rssi(-85),
+                connected_scan_interval: Duration::from_secs(4 * 60 * 60),
+                disconnected_scan_interval: Duration::from_secs(15 * 60),
+                power_save: PowerSaveMode::Maximum,
+            },
+        }
+    }
+
+    /// "stationary" / "mobile" / "battery", as used by the HTTP and MQTT triggers
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stationary" => Some(RoamPreset::Stationary),
+            "mobile" => Some(RoamPreset::Mobile),
+            "battery" => Some(RoamPreset::Battery),
+            _ => None,
+        }
+    }
+}
+
+static ACTIVE_PRESET: Mutex<CriticalSectionRawMutex, Cell<RoamPreset>> =
+    Mutex::new(Cell::new(RoamPreset::Stationary));
+
+/// the preset in use, changed by the wifi manager on WifiRequest::SetPreset
+pub fn active_preset() -> RoamPreset {
+    ACTIVE_PRESET.lock(|p| p.get())
+}
+
+/// the knobs of the preset in use
+pub fn active_profile() -> RoamProfile {
+    active_preset().profile()
+}
+
+pub fn set_active_preset(preset: RoamPreset) {
+    ACTIVE_PRESET.lock(|p| p.set(preset))
+}
@@ -0,0 +1,206 @@
+//! a binary request/response protocol on the USB serial-JTAG port, for
+//! desktop diagnostic tools. Frames are postcard, COBS encoded and ended by a
+//! zero. The host sends a `Request`, the device answers each with a
+//! `Frame::Response` carrying the same `seq`, and once asked to streams its
+//! defmt log as `Frame::Log` in between. Postcard encodes enum variants by
+//! their index, so variants are only ever appended and a tool checks
+//! `Reply::Hello`'s version first. The port carries nothing else: the `rpc`
+//! feature replaces serial-log, the defmt frames go through src/logring.rs
+//!
+//! A tool mirrors these types (and the ones they carry: `WifiConfig`,
+//! `Stats`, `eventlog::Entry`, `ConfigDoc`) with Deserialize on the replies
+//! and Serialize on the requests
+
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use esp_hal::{
+    Async,
+    usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagTx},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CANDIDATES, CandidateList,
+    console::{ConfigDoc, config_doc, load_config},
+    eventlog::{EventLog, events},
+    logring::take_frames,
+    scanner::scan_now,
+    stats::{Stats, stats},
+};
+
+/// bumped whenever a change breaks existing tools
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// the largest frame either way, a full candidate table or a configuration
+// with every network fits
+const FRAME_LEN: usize = 2048;
+// defmt bytes per Frame::Log
+const LOG_CHUNK: usize = 512;
+// how often the log ring is drained while streaming
+const LOG_POLL: Duration = Duration::from_millis(100);
+
+/// host to device
+#[derive(Deserialize, Debug, Clone)]
+pub struct Request {
+    // echoed in the response, any value the host likes
+    pub seq: u32,
+    pub call: Call,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum Call {
+    // answered with Reply::Hello
+    Hello,
+    // scan as soon as the radio is free, Candidates has the results after
+    Scan,
+    Candidates,
+    Stats,
+    // this boot's event log
+    Events,
+    GetConfig,
+    // same as `config load` on the console, see src/console.rs
+    SetConfig(ConfigDoc),
+    // start or stop Frame::Log
+    StreamLogs(bool),
+}
+
+/// device to host
+#[derive(Serialize, Debug)]
+pub enum Frame<'a> {
+    Response { seq: u32, reply: Reply },
+    // whole defmt frames, rzcobs encoded and zero terminated, decode them
+    // with the matching ELF
+    Log(&'a [u8]),
+}
+
+#[derive(Serialize, Debug)]
+pub enum Reply {
+    Hello { version: u16 },
+    // the call was carried out
+    Ok,
+    Error(RpcError),
+    Candidates(CandidateList),
+    Stats(Stats),
+    Events(EventLog),
+    // passphrases are None
+    Config(ConfigDoc),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RpcError {
+    // the request didn't decode, answered with seq 0
+    Malformed,
+    // the request or the reply didn't fit a frame
+    TooLong,
+    // SetConfig refused the configuration, and why
+    Rejected(&'static str),
+}
+
+async fn call(call: Call, streaming: &mut bool) -> Reply {
+    match call {
+        Call::Hello => Reply::Hello {
+            version: PROTOCOL_VERSION,
+        },
+        Call::Scan => {
+            scan_now();
+            Reply::Ok
+        }
+        Call::Candidates => Reply::Candidates(CANDIDATES.snapshot().await),
+        Call::Stats => Reply::Stats(stats()),
+        Call::Events => Reply::Events(events()),
+        Call::GetConfig => Reply::Config(config_doc()),
+        Call::SetConfig(doc) => match load_config(doc) {
+            Ok(()) => {
+                info!("Configuration loaded over rpc");
+                Reply::Ok
+            }
+            Err(reason) => Reply::Error(RpcError::Rejected(reason)),
+        },
+        Call::StreamLogs(on) => {
+            *streaming = on;
+            Reply::Ok
+        }
+    }
+}
+
+async fn send(tx: &mut UsbSerialJtagTx<'static, Async>, frame: &Frame<'_>, out: &mut [u8]) {
+    let bytes = match postcard::to_slice_cobs(frame, out) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let Frame::Response { seq, .. } = frame else {
+                return;
+            };
+            let too_long = Frame::Response {
+                seq: *seq,
+                reply: Reply::Error(RpcError::TooLong),
+            };
+            match postcard::to_slice_cobs(&too_long, out) {
+                Ok(bytes) => bytes,
+                Err(_) => return,
+            }
+        }
+    };
+    let _ = tx.write_all(bytes).await;
+    let _ = tx.flush().await;
+}
+
+/// serves the protocol on the USB serial-JTAG port, see the module doc. A
+/// host that stops reading while logs stream stalls the task until it reads
+/// again, nothing else waits on it
+#[embassy_executor::task]
+pub async fn rpc_task(usb: UsbSerialJtag<'static, Async>) -> ! {
+    info!("Start rpc task");
+    let (mut rx, mut tx) = usb.split();
+    let mut request = [0u8; FRAME_LEN];
+    let mut out = [0u8; FRAME_LEN];
+    let mut len = 0;
+    // set while the rest of an overlong frame is skipped
+    let mut overflow = false;
+    let mut streaming = false;
+    loop {
+        let mut chunk = [0u8; 64];
+        let n = match select(rx.read(&mut chunk), Timer::after(LOG_POLL)).await {
+            Either::First(Ok(n)) => n,
+            Either::First(Err(e)) => {
+                info!("Rpc read error: {:?}", e);
+                0
+            }
+            Either::Second(()) => 0,
+        };
+        for &byte in &chunk[..n] {
+            if byte != 0 {
+                if len < FRAME_LEN {
+                    request[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+                continue;
+            }
+            if len == 0 && !overflow {
+                continue;
+            }
+            let (seq, reply) = if overflow {
+                (0, Reply::Error(RpcError::TooLong))
+            } else {
+                match postcard::from_bytes_cobs::<Request>(&mut request[..len]) {
+                    Ok(req) => (req.seq, call(req.call, &mut streaming).await),
+                    Err(_) => (0, Reply::Error(RpcError::Malformed)),
+                }
+            };
+            len = 0;
+            overflow = false;
+            send(&mut tx, &Frame::Response { seq, reply }, &mut out).await;
+        }
+        while streaming {
+            let mut logs = [0u8; LOG_CHUNK];
+            let taken = take_frames(&mut logs);
+            if taken == 0 {
+                break;
+            }
+            send(&mut tx, &Frame::Log(&logs[..taken]), &mut out).await;
+        }
+    }
+}
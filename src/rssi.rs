@@ -0,0 +1,193 @@
+//! RSSI of the AP we're on, sampled from the beacons the sniffer picks up
+//! while associated
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+use serde::Serialize;
+
+use crate::{
+    scanner::scan_now,
+    status::link_status,
+    telemetry::{self, TelemetryEvent},
+};
+
+// readings in a row below the threshold before it counts, one weak beacon isn't a trend
+const DEGRADED_READINGS: u8 = 3;
+// back above the threshold by this much before it can degrade again
+const RECOVERY_MARGIN_DB: i8 = 3;
+
+/// when the signal counts as degraded and how often it's read
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SignalMonitor {
+    // time between readings
+    pub interval: Duration,
+    // readings below this are weak
+    pub degraded_below: i8,
+}
+
+impl SignalMonitor {
+    pub const fn new() -> Self {
+        return Self {
+            interval: Duration::from_secs(5),
+            degraded_below: -75,
+        };
+    }
+    pub const fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub const fn with_degraded_below(mut self, degraded_below: i8) -> Self {
+        self.degraded_below = degraded_below;
+        self
+    }
+}
+
+impl Default for SignalMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the signal of the current AP went below SignalMonitor::degraded_below
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SignalDegraded {
+    pub bssid: [u8; 6],
+    pub rssi: i8,
+    pub threshold: i8,
+}
+
+/// readings of the current AP since we associated
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SignalStats {
+    pub bssid: [u8; 6],
+    pub min: i8,
+    pub avg: i8,
+    pub max: i8,
+    pub readings: u32,
+}
+
+// running totals behind SignalStats
+#[derive(Clone, Copy)]
+struct Tally {
+    bssid: [u8; 6],
+    min: i8,
+    max: i8,
+    sum: i32,
+    readings: u32,
+    last: i8,
+}
+
+impl Tally {
+    fn new(bssid: [u8; 6], rssi: i8) -> Self {
+        Self {
+            bssid,
+            min: rssi,
+            max: rssi,
+            sum: rssi as i32,
+            readings: 1,
+            last: rssi,
+        }
+    }
+
+    fn add(&mut self, rssi: i8) {
+        self.min = self.min.min(rssi);
+        self.max = self.max.max(rssi);
+        self.sum += rssi as i32;
+        self.readings += 1;
+        self.last = rssi;
+    }
+
+    fn stats(&self) -> SignalStats {
+        SignalStats {
+            bssid: self.bssid,
+            min: self.min,
+            avg: (self.sum / self.readings as i32) as i8,
+            max: self.max,
+            readings: self.readings,
+        }
+    }
+}
+
+// the newest beacon RSSI of the associated AP, set from the sniffer callback
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Option<([u8; 6], i8)>>> =
+    Mutex::new(Cell::new(None));
+
+static TALLY: Mutex<CriticalSectionRawMutex, Cell<Option<Tally>>> = Mutex::new(Cell::new(None));
+
+/// a beacon from the AP we're associated with, called by the sniffer
+pub fn note_beacon(bssid: [u8; 6], rssi: i8) {
+    LATEST.lock(|l| l.set(Some((bssid, rssi))));
+}
+
+/// min/avg/max of the current AP, None while disconnected or before the first reading
+pub fn signal_stats() -> Option<SignalStats> {
+    TALLY.lock(|t| t.get()).map(|t| t.stats())
+}
+
+/// the current AP's BSSID and its latest reading, None like signal_stats
+pub fn latest_rssi() -> Option<([u8; 6], i8)> {
+    TALLY.lock(|t| t.get()).map(|t| (t.bssid, t.last))
+}
+
+/// reads the current AP's RSSI every `monitor.interval` into signal_stats. A
+/// signal staying below `monitor.degraded_below` publishes SignalDegraded and
+/// asks for a scan, so a better WG is known before the link drops
+#[embassy_executor::task]
+pub async fn signal_task(monitor: SignalMonitor) -> ! {
+    info!("Start signal task");
+    let mut weak_readings = 0u8;
+    let mut degraded = false;
+    loop {
+        Timer::after(monitor.interval).await;
+        let current = link_status().current.map(|c| c.bssid);
+        // not on the AP we were tallying anymore, start over
+        if signal_stats().is_some_and(|s| Some(s.bssid) != current) {
+            TALLY.lock(|t| t.set(None));
+            weak_readings = 0;
+            degraded = false;
+        }
+        // no beacon since the last reading, e.g. while a scan had the radio
+        let Some((bssid, rssi)) = LATEST
+            .lock(|l| l.take())
+            .filter(|(bssid, _)| Some(*bssid) == current)
+        else {
+            continue;
+        };
+        TALLY.lock(|t| {
+            let tally = match t.get() {
+                Some(mut tally) if tally.bssid == bssid => {
+                    tally.add(rssi);
+                    tally
+                }
+                _ => Tally::new(bssid, rssi),
+            };
+            t.set(Some(tally));
+        });
+
+        if rssi >= monitor.degraded_below.saturating_add(RECOVERY_MARGIN_DB) {
+            degraded = false;
+        }
+        weak_readings = match rssi < monitor.degraded_below {
+            true => weak_readings.saturating_add(1),
+            false => 0,
+        };
+        if weak_readings >= DEGRADED_READINGS && !degraded {
+            degraded = true;
+            info!(
+                "Signal degraded, {} dBm below {} dBm",
+                rssi, monitor.degraded_below
+            );
+            telemetry::publish(TelemetryEvent::SignalDegraded(SignalDegraded {
+                bssid,
+                rssi,
+                threshold: monitor.degraded_below,
+            }));
+            scan_now();
+        }
+    }
+}
@@ -0,0 +1,651 @@
+use core::cell::Cell;
+
+use embassy_futures::select::{Either3, select, select3};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use esp_radio::wifi::{
+    self, AccessPointInfo, ScanConfig, ScanTypeConfig, WifiController, WifiError,
+};
+
+use crate::{
+    BandMask, CANDIDATES, CandidateList, Credential, KnownNetwork, MAX_CANDIDATES, SCAN_CMD,
+    Security, WifiConfig,
+    bootguard::safe_mode,
+    candidate_list,
+    error::Error,
+    eventlog, hidden_credentials, known_network,
+    manager::{DISCONNECT_DETECTED, WifiManagerConfig},
+    persistence::persist_wifi,
+    push_or_evict, security,
+    status::wait_until_started,
+    telemetry::{AllocSite, report_oom},
+    watchdog::{Watched, beat, beat_while},
+};
+
+/// a scan landed in CANDIDATES
+pub static SCAN_COMPLETE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// a scan was asked for from outside, see scan_now
+static SCAN_NOW: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// scan as soon as the radio is free and restart the adaptive schedule from its
+/// floor, e.g. when the application knows the surroundings changed
+pub fn scan_now() {
+    SCAN_NOW.signal(());
+    SCAN_CMD.signal(());
+}
+
+/// bounds of the adaptive scan interval: right after the link was unstable it
+/// scans every `floor`, each stable interval doubles the wait up to `ceiling`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdaptiveScan {
+    pub floor: Duration,
+    pub ceiling: Duration,
+}
+
+impl AdaptiveScan {
+    pub const fn new(floor: Duration, ceiling: Duration) -> Self {
+        Self { floor, ceiling }
+    }
+}
+
+// the wait before the next scan while connected
+struct ScanSchedule {
+    adaptive: Option<AdaptiveScan>,
+    interval: Duration,
+}
+
+impl ScanSchedule {
+    fn new(adaptive: Option<AdaptiveScan>) -> Self {
+        Self {
+            adaptive,
+            interval: adaptive.map_or(Duration::from_secs(0), |a| a.floor),
+        }
+    }
+
+    // the wait while connected, the fixed one without bounds
+    fn connected_interval(&self, config: &WifiManagerConfig) -> Duration {
+        match self.adaptive {
+            Some(_) => self.interval,
+            None => config.connected_scan_interval(),
+        }
+    }
+
+    // the wait while disconnected, the floor with bounds
+    fn disconnected_interval(&self, config: &WifiManagerConfig) -> Duration {
+        match self.adaptive {
+            Some(adaptive) => adaptive.floor,
+            None => config.disconnected_scan_interval(),
+        }
+    }
+
+    // the link dropped or a scan was asked for, back to the floor
+    fn unstable(&mut self) {
+        if let Some(adaptive) = self.adaptive {
+            self.interval = adaptive.floor;
+        }
+    }
+
+    // a whole interval went by connected, wait longer next time
+    fn stable(&mut self) {
+        if let Some(adaptive) = self.adaptive {
+            self.interval = (self.interval * 2).min(adaptive.ceiling);
+            info!("Stable, next scan in {}s", self.interval.as_secs());
+        }
+    }
+}
+
+// a failed scan is retried after SCAN_RETRY_DELAY, doubling each time
+const SCAN_ATTEMPTS: u32 = 3;
+const SCAN_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// gentle scans sweep the 2.4 GHz channels, none of the supported chips do 5 GHz
+const LAST_CHANNEL: u8 = 13;
+
+/// whether scans send probe requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanMode {
+    // only listen for beacons, the radio never transmits while scanning
+    Passive,
+    // send probe requests, quicker but visible
+    Active,
+}
+
+/// how scan_and_score_wgs looks for WGs
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanOptions {
+    pub mode: ScanMode,
+    // time spent on each channel, passive needs at least a beacon interval (~102ms)
+    pub dwell: Duration,
+    // rank open APs carrying our SSIDs. Off, an evil twin can't just go
+    // without a password. WGs provisioned without a password are always allowed,
+    // networks pinned to an auth or PMF never
+    pub allow_open: bool,
+    // APs on other bands are left out of the results
+    pub bands: BandMask,
+    // APs the driver reports per scan, the strongest first. Bounds the
+    // driver's result list, one AccessPointInfo each
+    pub max_results: usize,
+    // known WGs a scan and CANDIDATES keep, the lowest ranked make room
+    pub max_candidates: usize,
+}
+
+impl ScanOptions {
+    pub const fn new() -> Self {
+        return Self {
+            mode: ScanMode::Passive,
+            dwell: Duration::from_millis(120),
+            allow_open: false,
+            bands: BandMask::ALL,
+            max_results: 10,
+            max_candidates: MAX_CANDIDATES,
+        };
+    }
+    pub const fn with_mode(mut self, mode: ScanMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    pub const fn with_dwell(mut self, dwell: Duration) -> Self {
+        self.dwell = dwell;
+        self
+    }
+    pub const fn with_allow_open(mut self, allow_open: bool) -> Self {
+        self.allow_open = allow_open;
+        self
+    }
+    pub const fn with_bands(mut self, bands: BandMask) -> Self {
+        self.bands = bands;
+        self
+    }
+    pub const fn with_max_results(mut self, max_results: usize) -> Self {
+        self.max_results = max_results;
+        self
+    }
+    pub const fn with_max_candidates(mut self, max_candidates: usize) -> Self {
+        assert!(
+            max_candidates >= 1 && max_candidates <= MAX_CANDIDATES,
+            "max_candidates is 1 to MAX_CANDIDATES"
+        );
+        self.max_candidates = max_candidates;
+        self
+    }
+    /// as many candidates as fit `bytes`, for small-heap variants. The
+    /// table takes max_candidates * size_of::<WifiConfig>(), once for the
+    /// scan and once for CANDIDATES, so both copies have to fit. With
+    /// heapless-core the tables are MAX_CANDIDATES entries whatever the cap,
+    /// so the budget only limits the count, not the memory
+    pub const fn with_memory_budget(self, bytes: usize) -> Self {
+        let fit = bytes / (2 * core::mem::size_of::<WifiConfig>());
+        let fit = if fit > MAX_CANDIDATES {
+            MAX_CANDIDATES
+        } else {
+            fit
+        };
+        self.with_max_candidates(fit)
+    }
+
+    fn scan_config(&self) -> ScanConfig<'static> {
+        let dwell = core::time::Duration::from_millis(self.dwell.as_millis());
+        let scan_type = match self.mode {
+            ScanMode::Passive => ScanTypeConfig::Passive(dwell),
+            ScanMode::Active => ScanTypeConfig::Active {
+                min: core::time::Duration::ZERO,
+                max: dwell,
+            },
+        };
+        ScanConfig::default()
+            .with_scan_type(scan_type)
+            .with_max(self.max_results)
+    }
+
+    // probes for `ssid` by name, whatever the mode. A hidden WG doesn't
+    // answer anything else
+    fn directed_config<'a>(&self, ssid: &'a str) -> ScanConfig<'a> {
+        let dwell = core::time::Duration::from_millis(self.dwell.as_millis());
+        ScanConfig::default()
+            .with_ssid(ssid)
+            .with_show_hidden(true)
+            .with_scan_type(ScanTypeConfig::Active {
+                min: core::time::Duration::ZERO,
+                max: dwell,
+            })
+            .with_max(self.max_results)
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// sees every AP a scan found, all fields, before the known-SSID filter
+pub type ScanObserver = fn(&[AccessPointInfo]);
+
+// scans since boot
+static SCAN_SEQ: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// number of the latest scan, WifiConfig::last_seen_scan refers to it
+pub fn scan_seq() -> u32 {
+    SCAN_SEQ.lock(|s| s.get())
+}
+
+// the radio is off our channel for a scan
+static SCANNING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// signalled when a scan starts or ends, for the status LED
+pub static SCANNING_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// true while a scan runs, e.g. to tell apart latency measured during scans
+pub fn scanning() -> bool {
+    SCANNING.lock(|s| s.get())
+}
+
+// clears SCANNING when the scan ends, also when its future is dropped
+struct ScanningGuard;
+
+impl ScanningGuard {
+    fn new() -> Self {
+        SCANNING.lock(|s| s.set(true));
+        SCANNING_CHANGED.signal(());
+        Self
+    }
+}
+
+impl Drop for ScanningGuard {
+    fn drop(&mut self) {
+        SCANNING.lock(|s| s.set(false));
+        SCANNING_CHANGED.signal(());
+    }
+}
+
+static SCAN_OBSERVER: Mutex<CriticalSectionRawMutex, Cell<Option<ScanObserver>>> =
+    Mutex::new(Cell::new(None));
+
+/// hook raw scan results, e.g. for a site survey or security monitoring.
+/// replaces any previous observer, None removes it. The observer runs inside
+/// the scan, keep it short
+pub fn set_scan_observer(observer: Option<ScanObserver>) {
+    SCAN_OBSERVER.lock(|o| o.set(observer));
+}
+
+/// why a scan produced nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanError {
+    // the driver refused or failed the scan, usually transient
+    Radio(WifiError),
+}
+
+impl From<WifiError> for ScanError {
+    fn from(e: WifiError) -> Self {
+        ScanError::Radio(e)
+    }
+}
+
+/// scans and ranks the known WGs in range, best first. A failed scan doesn't
+/// count for candidate aging, the caller decides whether to retry
+pub async fn scan_and_score_wgs(
+    controller: &mut WifiController<'static>,
+    options: &ScanOptions,
+) -> Result<CandidateList, Error> {
+    info!("Scanning ({:?})...", options.mode);
+    eventlog::record(eventlog::Event::ScanStarted);
+    let seq = scan_seq().wrapping_add(1);
+    let wgs = scan_all(controller, options, None, seq).await?;
+    SCAN_SEQ.lock(|s| s.set(seq));
+    Ok(wgs)
+}
+
+/// a quick scan of `channel` only, e.g. where the WG we just lost lives.
+/// Doesn't count as a scan for candidate aging
+pub async fn scan_channel(
+    controller: &mut WifiController<'static>,
+    channel: u8,
+    options: &ScanOptions,
+) -> Result<CandidateList, Error> {
+    info!("Scanning channel {}...", channel);
+    Ok(scan_all(controller, options, Some(channel), scan_seq()).await?)
+}
+
+// the broadcast scan, then a directed one per hidden SSID
+async fn scan_all(
+    controller: &mut WifiController<'static>,
+    options: &ScanOptions,
+    channel: Option<u8>,
+    seq: u32,
+) -> Result<CandidateList, ScanError> {
+    fn with_channel(conf: ScanConfig<'_>, channel: Option<u8>) -> ScanConfig<'_> {
+        match channel {
+            Some(channel) => conf.with_channel(channel),
+            None => conf,
+        }
+    }
+    // every scan below fills this one table in place
+    let mut wgs = candidate_list(options.max_candidates);
+    // worst case scan time dwell * 13 channels, again for each hidden SSID
+    let scan_conf = with_channel(options.scan_config(), channel);
+    let mut left_out = scan_with(controller, scan_conf, options, seq, None, &mut wgs).await?;
+    for cred in hidden_credentials() {
+        let scan_conf = with_channel(options.directed_config(&cred.ssid), channel);
+        left_out |= scan_with(controller, scan_conf, options, seq, Some(&cred), &mut wgs).await?;
+    }
+    if left_out {
+        report_left_out(&wgs, options.max_candidates);
+    }
+
+    // the best wifi candidate will sort to the top, check the Ord impl for
+    // how they're picked
+    wgs.sort_by(|x, y| x.cmp(y).reverse());
+
+    for ap in &wgs {
+        // show all aps nearby
+        info!(
+            "{:?}, {:?} ,({}, avg {})",
+            ap.ssid.as_str(),
+            ap.bssid,
+            ap.signal_strength,
+            ap.smoothed_rssi()
+        );
+    }
+
+    Ok(wgs)
+}
+
+// adds the known WGs the scan found to `wgs`, straight from the driver's
+// results, skipping BSSIDs already in it. True if one was left out for lack
+// of room. `hidden` is the SSID a directed scan probed for. Its probe
+// responses carry that SSID, an empty one is any hidden WG's beacon and only
+// taken to be it as is_hidden_wg says
+async fn scan_with(
+    controller: &mut WifiController<'static>,
+    scan_conf: ScanConfig<'_>,
+    options: &ScanOptions,
+    seq: u32,
+    hidden: Option<&Credential>,
+    wgs: &mut CandidateList,
+) -> Result<bool, ScanError> {
+    let scanning = ScanningGuard::new();
+    let result = controller.scan_with_config_async(scan_conf).await?;
+    drop(scanning);
+
+    if let Some(observer) = SCAN_OBSERVER.lock(|o| o.get()) {
+        observer(&result);
+    }
+
+    let mut left_out = false;
+    for x in result.iter() {
+        let network = match hidden {
+            Some(cred) => {
+                let answered = x.ssid == cred.ssid.as_str();
+                if !answered
+                    && !(x.ssid.is_empty() && is_hidden_wg(cred, &x.bssid, x.channel).await)
+                {
+                    continue;
+                }
+                KnownNetwork::Psk(cred.clone())
+            }
+            None => match known_network(&x.ssid) {
+                Some(network) => network,
+                None => continue,
+            },
+        };
+        let ssid = network.ssid();
+        let Ok(ssid32) = ssid.try_into() else {
+            info!("Ignoring {}, SSID longer than 32 bytes", ssid);
+            continue;
+        };
+        if !options.bands.admits(x.channel) || wgs.iter().any(|w| w.bssid == x.bssid) {
+            continue;
+        }
+        if !security::is_legitimate(&x.bssid) {
+            security::report_rogue_ap(ssid, x.bssid, x.channel, x.signal_strength);
+            continue;
+        }
+        let security = Security::from_auth_method(x.auth_method);
+        if !accepts(&network, security, options) {
+            info!("Ignoring {} on {:?}, {:?}", ssid, x.bssid, security);
+            continue;
+        }
+        let wifi = WifiConfig {
+            bssid: x.bssid,
+            ssid: ssid32,
+            signal_strength: x.signal_strength,
+            channel: x.channel,
+            connect_success: None,
+            connect_timed_out: false,
+            last_connected: None,
+            captive_portal: false,
+            last_seen_scan: seq,
+            rssi_ema_x16: x.signal_strength as i16 * 16,
+            rssi_samples: 1,
+            security,
+            channel_load: None,
+            gateway_rtt_ms: None,
+            associate_ms: None,
+            lease_ms: None,
+            // only beacons carry it, the sniffer fills it in
+            mobility_domain: None,
+            // looked up once here, the ranking compares it on every sort
+            tier: network.tier(),
+        };
+        // rank what fit, a short list beats a panic
+        left_out |= !push_or_evict(wgs, wifi, options.max_candidates);
+    }
+    Ok(left_out)
+}
+
+// whether a hidden WG's beacon on `channel` is `cred`'s network: its BSSID is
+// pinned on its own in PINNED_BSSIDS, or it answered an earlier probe for
+// `cred` and is still on that channel
+async fn is_hidden_wg(cred: &Credential, bssid: &[u8; 6], channel: u8) -> bool {
+    if security::with_pins(|pins| pins.contains(&security::Pin::Bssid(*bssid))) {
+        return true;
+    }
+    CANDIDATES
+        .find(bssid)
+        .await
+        .is_some_and(|w| w.ssid == cred.ssid && w.channel == channel)
+}
+
+// a table below the cap ran out of memory, one at the cap is the limit
+// doing its job and isn't counted
+fn report_left_out(wgs: &CandidateList, cap: usize) {
+    match wgs.len() < cap.min(MAX_CANDIDATES) {
+        true => report_oom(AllocSite::Scan),
+        false => info!("Scan matched more WGs than the cap, kept the strongest"),
+    }
+}
+
+// whether an AP advertising `security` could be the WG `cred` logs in to
+fn accepts(network: &KnownNetwork, security: Security, options: &ScanOptions) -> bool {
+    match (network, security) {
+        (KnownNetwork::Enterprise(_), security) => security == Security::Enterprise,
+        // a network pinned to an auth or to PMF is never open, allow_open
+        // doesn't override that
+        (KnownNetwork::Psk(cred), Security::Open) => {
+            cred.admits(Security::Open) && (options.allow_open || cred.password.is_empty())
+        }
+        // can't log in with a PSK
+        (KnownNetwork::Psk(_), Security::Enterprise) => false,
+        (KnownNetwork::Psk(cred), security) => cred.admits(security),
+    }
+}
+
+/// scans with `options` and folds the result into CANDIDATES, retrying a
+/// failed scan with backoff. Does nothing in safe mode
+pub async fn do_scan(controller: &mut WifiController<'static>, options: &ScanOptions) {
+    // CANDIDATES stays empty, run_disconnected connects to the configured WG
+    if safe_mode() {
+        info!("Safe mode, not scanning");
+        return;
+    }
+    // the radio usually recovers within a second or two
+    let mut delay = SCAN_RETRY_DELAY;
+    for attempt in 1..=SCAN_ATTEMPTS {
+        match scan_and_score_wgs(controller, options).await {
+            Ok(wg) => return merge_scan(wg).await,
+            Err(e) => info!("Scan attempt {} failed {:?}", attempt, e),
+        }
+        if attempt < SCAN_ATTEMPTS {
+            Timer::after(delay).await;
+            delay = delay * 2;
+        }
+    }
+    // the candidates stay as they were, the scheduler asks again later
+    info!("Giving up on this scan");
+}
+
+// the channel the next gentle scan covers
+static GENTLE_CHANNEL: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(1));
+
+/// scans a single channel, the next of a sweep over channels 1 to 13, and folds
+/// the result into CANDIDATES. The radio only leaves our channel for one dwell,
+/// so traffic keeps flowing. `skip` is the channel we're on, the beacon sniffer
+/// already covers it. A whole sweep counts as one scan for candidate aging
+pub async fn gentle_scan(
+    controller: &mut WifiController<'static>,
+    options: &ScanOptions,
+    skip: Option<u8>,
+) {
+    if safe_mode() {
+        info!("Safe mode, not scanning");
+        return;
+    }
+    let channel = next_gentle_channel(skip);
+    match scan_channel(controller, channel, options).await {
+        Ok(wg) => merge_scan(wg).await,
+        // the next sweep comes back to it
+        Err(e) => info!("Gentle scan of channel {} failed {:?}", channel, e),
+    }
+}
+
+// advances the sweep, numbering a new scan each time it starts over
+fn next_gentle_channel(skip: Option<u8>) -> u8 {
+    GENTLE_CHANNEL.lock(|next| {
+        loop {
+            let channel = next.get();
+            next.set(channel % LAST_CHANNEL + 1);
+            if channel == 1 {
+                SCAN_SEQ.lock(|s| s.set(s.get().wrapping_add(1)));
+            }
+            if skip != Some(channel) {
+                return channel;
+            }
+        }
+    })
+}
+
+/// folds fresh scan results into CANDIDATES and tells the scheduler
+pub async fn merge_scan(wg: CandidateList) {
+    CANDIDATES.replace(wg).await;
+    SCAN_COMPLETE.signal(());
+}
+
+/// schedules scans, by the active roaming profile or adaptively (see
+/// WifiManagerConfig::with_adaptive_scan), and persists a new best WG once a
+/// scan finds one
+#[embassy_executor::task]
+pub async fn best_connection_task(
+    persisted_config: Option<WifiConfig>,
+    scan_at_boot: bool,
+    config: WifiManagerConfig,
+) -> ! {
+    // persistence will load the previous connection from flash, if any
+
+    let mut local_persisted = persisted_config.clone();
+    // on first boot, scan nearby wifis. A deep sleep wake already knows its WG
+    if scan_at_boot {
+        SCAN_CMD.signal(());
+    }
+
+    let mut schedule = ScanSchedule::new(config.adaptive_scan);
+    let mut new_best_found = false;
+    loop {
+        beat(Watched::BestConnection);
+        // nothing to scan with while the radio is off
+        beat_while(Watched::BestConnection, wait_until_started()).await;
+        if SCAN_COMPLETE.signaled() {
+            SCAN_COMPLETE.wait().await;
+            // don't hold the candidates while waiting on flash
+            let best_candidate = CANDIDATES.best().await;
+            info!("Scan complete, best = {:?}", best_candidate);
+            match (&best_candidate, &local_persisted) {
+                (None, None) => {
+                    // no candidates and no persisted
+                }
+                (None, Some(x)) => {
+                    // no candidates, persisted still better
+                }
+                (Some(c), None) => {
+                    // a new winner emerges, only adopted once it is on flash
+                    if persist_wifi(c).await.is_ok() {
+                        local_persisted = Some(c.clone());
+                        new_best_found = true;
+                    }
+                }
+                (Some(c), Some(p)) => {
+                    if c == p {
+                        // same as persisted,
+                        new_best_found = true;
+                    }
+                    if c > p && persist_wifi(c).await.is_ok() {
+                        local_persisted = Some(c.clone());
+                        new_best_found = true;
+                    }
+                }
+            }
+        }
+
+        {
+            match wifi::sta_state() {
+                wifi::WifiStaState::Connected => {
+                    // scan once per interval if we haven't found a new best
+                    if !new_best_found {
+                        match beat_while(
+                            Watched::BestConnection,
+                            select3(
+                                Timer::after(schedule.connected_interval(&config)),
+                                DISCONNECT_DETECTED.wait(),
+                                SCAN_NOW.wait(),
+                            ),
+                        )
+                        .await
+                        {
+                            Either3::First(_) if config.quiet_hours.is_quiet() => {
+                                info!("Quiet hours, scan skipped");
+                            }
+                            Either3::First(_) => {
+                                schedule.stable();
+                                SCAN_CMD.signal(())
+                            }
+                            // scan_now already asked for the scan
+                            Either3::Second(_) | Either3::Third(_) => schedule.unstable(),
+                        }
+                    }
+                }
+                wifi::WifiStaState::Disconnected => {
+                    // scan more often if we are currently chronically disconnected
+                    schedule.unstable();
+                    beat_while(
+                        Watched::BestConnection,
+                        select(
+                            Timer::after(schedule.disconnected_interval(&config)),
+                            SCAN_NOW.wait(),
+                        ),
+                    )
+                    .await;
+                    SCAN_CMD.signal(());
+                }
+                _ => {}
+            }
+        }
+        Timer::after(Duration::from_secs(10)).await
+    }
+}
@@ -0,0 +1,235 @@
+use core::{cell::Cell, cmp::Ordering};
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+
+use crate::{Band, WifiConfig, neighbors::steer_bonus_x16, status::current_mobility_domain};
+
+/// candidates missing from this many scans in a row are forgotten
+pub const MAX_MISSED_SCANS: u32 = 3;
+
+// the average weighs a new sample 1/RSSI_EMA_DEN once there are enough samples
+const RSSI_EMA_DEN: i16 = 4;
+
+// a saturated channel costs this much signal in the ranking
+const FULL_LOAD_PENALTY_DB: i16 = 10;
+
+// a WG on the preferred band ranks as if its signal were this much stronger
+const PREFERRED_BAND_BONUS_DB: i16 = 6;
+
+// with latency scoring on, each second to associate and get an address costs
+// this much signal, up to LATENCY_PENALTY_MAX_DB
+const LATENCY_PENALTY_DB_PER_SEC: i32 = 1;
+const LATENCY_PENALTY_MAX_DB: i32 = 10;
+
+// with fast transition on, a WG in the mobility domain of the one we're on
+// ranks as if its signal were this much stronger, roaming to it skips the
+// full handshake
+const FAST_TRANSITION_BONUS_DB: i16 = 3;
+
+// connects within the same day are considered equally recent
+const RECENCY_BUCKET_SECS: u64 = 24 * 60 * 60;
+
+impl WifiConfig {
+    /// the averaged RSSI in dB
+    pub fn smoothed_rssi(&self) -> i8 {
+        (self.rssi_ema_x16 / 16) as i8
+    }
+    /// fold this scan's signal_strength into the average carried over from
+    /// `previous`, the same WG in an earlier scan
+    pub fn update_rssi_ema(&mut self, previous: &Self) {
+        let sample = self.signal_strength as i16 * 16;
+        self.rssi_samples = previous.rssi_samples.saturating_add(1);
+        // plain average while there are few samples, so the first one doesn't dominate
+        let den = self.rssi_samples.min(RSSI_EMA_DEN as u16) as i16;
+        self.rssi_ema_x16 = previous.rssi_ema_x16 + (sample - previous.rssi_ema_x16) / den;
+    }
+    /// missed the last MAX_MISSED_SCANS scans, it has probably moved out of range
+    pub fn is_stale(&self, scan_seq: u32) -> bool {
+        scan_seq.wrapping_sub(self.last_seen_scan) >= MAX_MISSED_SCANS
+    }
+    /// the band of the channel it was last seen on, None if that's unknown
+    pub fn band(&self) -> Option<Band> {
+        Band::of_channel(self.channel)
+    }
+    /// the averaged RSSI in 1/16 dB, with latency scoring less up to
+    /// LATENCY_PENALTY_MAX_DB for a slow connect, plus PREFERRED_BAND_BONUS_DB
+    /// on the preferred band and, with the espnow feature, a bonus for peers
+    /// on it. A WG an 802.11v transition request suggested gets up to 10 dB for
+    /// a minute, and with fast transition on a WG in our WG's mobility domain
+    /// FAST_TRANSITION_BONUS_DB. An unknown latency costs nothing. Channel load
+    /// is left to cmp_ss, it only counts between two WGs that both report it
+    pub fn effective_rssi_x16(&self) -> i16 {
+        let bonus = match preferred_band() {
+            Some(band) if self.band() == Some(band) => PREFERRED_BAND_BONUS_DB * 16,
+            _ => 0,
+        };
+        self.rssi_ema_x16
+            .saturating_sub(self.latency_penalty_x16())
+            .saturating_add(bonus)
+            .saturating_add(self.peer_bonus_x16())
+            .saturating_add(steer_bonus_x16(&self.bssid))
+            .saturating_add(self.fast_transition_bonus_x16())
+    }
+    // with fast transition on, a cheap roam from the WG we're on
+    fn fast_transition_bonus_x16(&self) -> i16 {
+        if !fast_transition() || self.mobility_domain.is_none() {
+            return 0;
+        }
+        match current_mobility_domain() {
+            Some((bssid, domain)) if bssid != self.bssid && domain == self.mobility_domain => {
+                FAST_TRANSITION_BONUS_DB * 16
+            }
+            _ => 0,
+        }
+    }
+    // with ESP-NOW hints, peers online through this WG vouch for it
+    fn peer_bonus_x16(&self) -> i16 {
+        #[cfg(feature = "espnow")]
+        return crate::peers::peer_bonus_x16(&self.bssid);
+        #[cfg(not(feature = "espnow"))]
+        return 0;
+    }
+    // the averaged time to associate plus the time to an address, in 1/16 dB
+    fn latency_penalty_x16(&self) -> i16 {
+        if !latency_scoring() {
+            return 0;
+        }
+        let ms = self.associate_ms.unwrap_or(0) as i32 + self.lease_ms.unwrap_or(0) as i32;
+        (ms * LATENCY_PENALTY_DB_PER_SEC * 16 / 1000).min(LATENCY_PENALTY_MAX_DB * 16) as i16
+    }
+    // up to FULL_LOAD_PENALTY_DB for a busy channel, in 1/16 dB
+    fn load_penalty_x16(load: u8) -> i16 {
+        (FULL_LOAD_PENALTY_DB as i32 * 16 * load as i32 / 255) as i16
+    }
+    fn cmp_ss(&self, other: &Self) -> core::cmp::Ordering {
+        let this = self.effective_rssi_x16();
+        let that = other.effective_rssi_x16();
+        // a WG that doesn't report its load would otherwise beat every one
+        // that does, only compare loads when both have one. Among a mix the
+        // order can be cyclic, the table is small enough to be insertion
+        // sorted, which settles on some order rather than panicking
+        let (this, that) = match (self.channel_load, other.channel_load) {
+            (Some(a), Some(b)) => (
+                this.saturating_sub(Self::load_penalty_x16(a)),
+                that.saturating_sub(Self::load_penalty_x16(b)),
+            ),
+            _ => (this, that),
+        };
+        // we reverse because -20
+        return this.cmp(&that);
+    }
+    // more recently connected wins, within a day it's a tie
+    fn cmp_recency(&self, other: &Self) -> core::cmp::Ordering {
+        let this = self.last_connected.map(|t| t / RECENCY_BUCKET_SECS);
+        let that = other.last_connected.map(|t| t / RECENCY_BUCKET_SECS);
+        return this.cmp(&that);
+    }
+}
+
+impl PartialEq for WifiConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.bssid == other.bssid
+    }
+}
+// test
+impl Ord for WifiConfig {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // a wifi config
+        let a = match (self.connect_success, other.connect_success) {
+            (Some(true), Some(true)) => {
+                // both configs connected, most recent wins, then better signal
+                Self::cmp_recency(&self, other).then_with(|| Self::cmp_ss(&self, other))
+            }
+            (Some(true), Some(false)) => {
+                // self connected, we're better
+                core::cmp::Ordering::Greater
+            }
+            (Some(false), Some(true)) => {
+                // other connected, self didn't, it's better
+                Ordering::Less
+            }
+            (Some(false), Some(false)) => {
+                // neither connected now, one that answered at all beats one that
+                // stalled, then one that worked recently, then better signal
+                other
+                    .connect_timed_out
+                    .cmp(&self.connect_timed_out)
+                    .then_with(|| Self::cmp_recency(&self, other))
+                    .then_with(|| Self::cmp_ss(&self, other))
+            }
+            (None, None) => {
+                // never been used
+                Self::cmp_ss(&self, other)
+            }
+            (None, Some(true)) => {
+                // self never been used, other connected, it's better
+                Ordering::Less
+            }
+            (None, Some(false)) => {
+                // self never been used, other didn't connect, we're better
+                Ordering::Greater
+            }
+            (Some(x), None) => {
+                match x {
+                    true => {
+                        // self been used, and it connected, we're better
+                        Ordering::Greater
+                    }
+                    false => {
+                        // self been used, and it didn't connect, rather use other
+                        Ordering::Less
+                    }
+                }
+            }
+        };
+
+        // a lower tier only wins if nothing of a higher one is left, whatever
+        // its signal. A WG behind a captive portal only wins if everything
+        // else in its tier is too
+        return other
+            .tier
+            .cmp(&self.tier)
+            .then_with(|| other.captive_portal.cmp(&self.captive_portal))
+            .then(a);
+    }
+}
+
+static PREFERRED_BAND: Mutex<CriticalSectionRawMutex, Cell<Option<Band>>> =
+    Mutex::new(Cell::new(None));
+
+/// the band the ranking favours, None ranks on signal alone
+pub fn preferred_band() -> Option<Band> {
+    PREFERRED_BAND.lock(|b| b.get())
+}
+
+/// favour WGs on `band`, the other band is still used when nothing on
+/// `band` is in range or it's much weaker. Re-sorts CANDIDATES on the next scan
+pub fn set_preferred_band(band: Option<Band>) {
+    PREFERRED_BAND.lock(|b| b.set(band))
+}
+
+static LATENCY_SCORING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// whether the ranking holds slow connects against a WG
+pub fn latency_scoring() -> bool {
+    LATENCY_SCORING.lock(|l| l.get())
+}
+
+/// rank WGs that take long to associate or hand out an address lower, see
+/// WifiConfig::effective_rssi_x16. Re-sorts CANDIDATES on the next scan
+pub fn set_latency_scoring(enabled: bool) {
+    LATENCY_SCORING.lock(|l| l.set(enabled))
+}
+
+static FAST_TRANSITION: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// whether the ranking favours roams within a mobility domain
+pub fn fast_transition() -> bool {
+    FAST_TRANSITION.lock(|f| f.get())
+}
+
+/// favour WGs sharing the current WG's 802.11r mobility domain, for radios
+/// that do the FT handshake. Re-sorts CANDIDATES on the next scan
+pub fn set_fast_transition(enabled: bool) {
+    FAST_TRANSITION.lock(|f| f.set(enabled))
+}
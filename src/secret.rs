@@ -0,0 +1,141 @@
+//! secrets at rest: sealed with AES-256-GCM under a key the HMAC peripheral
+//! derives from a read-protected eFuse key (chip::STORAGE_KEY), so neither a
+//! dump of the flash nor `espefuse.py summary` gives the passphrases away.
+//! Each sealed value is bound to its label, a copy moved to another key
+//! doesn't open. The ESP32 has no HMAC peripheral, there only flash
+//! encryption protects them
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce, Tag, aead::AeadInPlace};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_hal::rng::Rng;
+
+#[cfg(not(feature = "esp32"))]
+use crate::fmt::Debug2Format;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// what sealing adds to a value: the nonce before it and the tag after it
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+// what the HMAC peripheral is asked to sign, the storage key is the signature.
+// Changing it changes the key, every sealed passphrase stops opening
+#[cfg(not(feature = "esp32"))]
+const KEY_DERIVATION_MESSAGE: &[u8] = b"wifi-scan-demo storage key v1";
+
+static STORAGE_KEY: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; KEY_LEN]>>> =
+    Mutex::new(Cell::new(None));
+
+// set once the radio runs, before that Rng isn't fed by its noise
+static RADIO_ENTROPY: AtomicBool = AtomicBool::new(false);
+
+/// derives the storage key with the HMAC peripheral from eFuse key
+/// chip::STORAGE_KEY (purpose HMAC_UP), called by main before persistence
+/// starts. The key block stays read protected, only the peripheral sees it.
+/// Without such a key the derivation fails and storage_key() stays None
+#[cfg(not(feature = "esp32"))]
+pub fn derive_storage_key(hmac: esp_hal::peripherals::HMAC<'static>) {
+    use esp_hal::hmac::{Hmac, HmacPurpose};
+
+    let mut hmac = Hmac::new(hmac);
+    hmac.init();
+    if let Err(e) = nb::block!(hmac.configure(HmacPurpose::ToUser, crate::chip::STORAGE_KEY)) {
+        info!("No HMAC storage key in eFuse: {:?}", Debug2Format(&e));
+        return;
+    }
+    let mut remaining = KEY_DERIVATION_MESSAGE;
+    while !remaining.is_empty() {
+        remaining = nb::block!(hmac.update(remaining)).unwrap();
+    }
+    let mut key = [0u8; KEY_LEN];
+    nb::block!(hmac.finalize(&mut key)).unwrap();
+    STORAGE_KEY.lock(|k| k.set(Some(key)));
+}
+
+/// the ESP32 has no HMAC peripheral and seals nothing, only flash encryption
+/// protects its passphrases. Called by main before persistence starts, logs an
+/// error on every boot while flash encryption is off
+#[cfg(feature = "esp32")]
+pub fn check_flash_encryption() {
+    use esp_hal::efuse::{Efuse, FLASH_CRYPT_CNT};
+
+    // an odd number of bits set means flash encryption is on
+    let count: u8 = Efuse::read_field_le(FLASH_CRYPT_CNT);
+    if count.count_ones() % 2 == 0 {
+        error!(
+            "Flash encryption is off, the ESP32 can't seal: passphrases are in plaintext on flash"
+        );
+    }
+}
+
+/// the key derive_storage_key got from the HMAC peripheral, None without one
+/// and always on the ESP32
+pub fn storage_key() -> Option<[u8; KEY_LEN]> {
+    STORAGE_KEY.lock(|k| k.get())
+}
+
+/// set by the wifi manager as it starts and stops the radio, sealing only
+/// happens while it runs
+pub fn set_radio_entropy(running: bool) {
+    RADIO_ENTROPY.store(running, Ordering::Relaxed);
+}
+
+/// whether seal can draw nonces yet
+pub fn can_seal() -> bool {
+    RADIO_ENTROPY.load(Ordering::Relaxed)
+}
+
+/// seals `plain` into `out` as nonce, ciphertext and tag, returns the length.
+/// None if `out` is shorter than the value plus SEAL_OVERHEAD, or before the
+/// radio runs: a repeated nonce would give the key stream away
+pub fn seal(key: &[u8; KEY_LEN], label: &str, plain: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !can_seal() {
+        return None;
+    }
+    let len = plain.len() + SEAL_OVERHEAD;
+    let out = out.get_mut(..len)?;
+    let (nonce, rest) = out.split_at_mut(NONCE_LEN);
+    let (body, tag) = rest.split_at_mut(plain.len());
+    // the radio runs, so the RNG is a true one
+    let rng = Rng::new();
+    for chunk in nonce.chunks_mut(4) {
+        chunk.copy_from_slice(&rng.random().to_le_bytes()[..chunk.len()]);
+    }
+    body.copy_from_slice(plain);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let sealed = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), label.as_bytes(), body)
+        .ok()?;
+    tag.copy_from_slice(&sealed);
+    Some(len)
+}
+
+/// opens what `seal` made of a value labelled `label` into `out`, returns its
+/// length. None if it was altered or sealed under another key or label
+pub fn open(key: &[u8; KEY_LEN], label: &str, sealed: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = sealed.len().checked_sub(SEAL_OVERHEAD)?;
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (body, tag) = rest.split_at(len);
+    let out = out.get_mut(..len)?;
+    out.copy_from_slice(body);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    match cipher.decrypt_in_place_detached(
+        Nonce::from_slice(nonce),
+        label.as_bytes(),
+        out,
+        Tag::from_slice(tag),
+    ) {
+        Ok(_) => Some(len),
+        Err(_) => {
+            // don't hand back a half-decrypted buffer
+            out.fill(0);
+            None
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+
+use crate::telemetry::{self, TelemetryEvent};
+
+// "aa:bb:cc:dd:ee:ff" BSSIDs or "aa:bb:cc" OUI prefixes, comma separated.
+// Empty turns pinning off and any BSSID with our SSID is a candidate
+const PINNED_BSSIDS: &str = env!("PINNED_BSSIDS");
+
+/// entries PINNED_BSSIDS can hold, the rest are ignored
+pub const MAX_PINS: usize = 8;
+
+// this many deauth/disassoc frames for our BSSID within STORM_WINDOW is an
+// attack, a genuine one comes alone
+const STORM_FRAMES: u32 = 8;
+const STORM_WINDOW: Duration = Duration::from_secs(2);
+/// how long the attacked channel is avoided after a deauth storm
+pub const STORM_HOLDOFF: Duration = Duration::from_secs(60);
+
+/// something that looks like an attack on our WiFi
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityEvent {
+    // an AP with one of our SSIDs and a BSSID that isn't pinned
+    RogueAp {
+        ssid: heapless::String<32>,
+        bssid: [u8; 6],
+        channel: u8,
+        signal_strength: i8,
+    },
+    // a burst of deauth/disassoc frames against the WG we're on
+    DeauthStorm {
+        bssid: [u8; 6],
+        channel: u8,
+        frames: u32,
+    },
+}
+
+/// raised by the sniffer, the wifi manager reacts
+pub static DEAUTH_STORM: Signal<CriticalSectionRawMutex, SecurityEvent> = Signal::new();
+
+// deauth/disassoc frames counted in the current window
+struct DeauthCount {
+    window_start: Instant,
+    frames: u32,
+}
+
+static DEAUTHS: Mutex<CriticalSectionRawMutex, RefCell<DeauthCount>> =
+    Mutex::new(RefCell::new(DeauthCount {
+        window_start: Instant::from_ticks(0),
+        frames: 0,
+    }));
+
+/// count a deauth or disassoc frame for `bssid`, signals DEAUTH_STORM once
+/// per window when there are too many
+pub fn note_deauth(bssid: [u8; 6], channel: u8) {
+    let frames = DEAUTHS.lock(|d| {
+        let mut d = d.borrow_mut();
+        if d.window_start.elapsed() > STORM_WINDOW {
+            d.window_start = Instant::now();
+            d.frames = 0;
+        }
+        d.frames += 1;
+        d.frames
+    });
+    if frames == STORM_FRAMES {
+        DEAUTH_STORM.signal(SecurityEvent::DeauthStorm {
+            bssid,
+            channel,
+            frames,
+        });
+    }
+}
+
+// the channel under attack and until when it's avoided
+static HOLD_OFF: Mutex<CriticalSectionRawMutex, Cell<Option<(u8, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// log loudly, publish and avoid the channel for STORM_HOLDOFF
+pub fn report_deauth_storm(event: SecurityEvent) {
+    if let SecurityEvent::DeauthStorm {
+        bssid,
+        channel,
+        frames,
+    } = event
+    {
+        info!(
+            "SECURITY: deauth storm against {:?} on channel {}, {} frames in {}ms",
+            bssid,
+            channel,
+            frames,
+            STORM_WINDOW.as_millis()
+        );
+        HOLD_OFF.lock(|h| h.set(Some((channel, Instant::now() + STORM_HOLDOFF))));
+    }
+    telemetry::publish(TelemetryEvent::Security(event));
+}
+
+/// the channel a deauth storm was seen on, until STORM_HOLDOFF is over
+pub fn held_off_channel() -> Option<u8> {
+    HOLD_OFF.lock(|h| match h.get() {
+        Some((channel, until)) if Instant::now() < until => Some(channel),
+        _ => None,
+    })
+}
+
+/// a legitimate gateway, or a vendor's whole range of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pin {
+    Bssid([u8; 6]),
+    Oui([u8; 3]),
+}
+
+impl Pin {
+    /// "aa:bb:cc:dd:ee:ff" or "aa:bb:cc"
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut bytes = [0u8; 6];
+        let mut len = 0;
+        for part in s.trim().split(':') {
+            if len == bytes.len() || part.len() != 2 {
+                return None;
+            }
+            bytes[len] = u8::from_str_radix(part, 16).ok()?;
+            len += 1;
+        }
+        match len {
+            6 => Some(Self::Bssid(bytes)),
+            3 => Some(Self::Oui([bytes[0], bytes[1], bytes[2]])),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, bssid: &[u8; 6]) -> bool {
+        match self {
+            Self::Bssid(pinned) => pinned == bssid,
+            Self::Oui(oui) => bssid.starts_with(oui),
+        }
+    }
+}
+
+// PINNED_BSSIDS parsed, on first use
+static PINS: Mutex<CriticalSectionRawMutex, RefCell<Option<heapless::Vec<Pin, MAX_PINS>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// runs `f` on the provisioned pins, parsed once. Malformed entries are skipped
+pub fn with_pins<R>(f: impl FnOnce(&[Pin]) -> R) -> R {
+    PINS.lock(|p| {
+        if p.borrow().is_none() {
+            *p.borrow_mut() = Some(parse_pins());
+        }
+        f(p.borrow().as_deref().unwrap_or_default())
+    })
+}
+
+fn parse_pins() -> heapless::Vec<Pin, MAX_PINS> {
+    let mut pins = heapless::Vec::new();
+    for entry in PINNED_BSSIDS.split(',').filter(|e| !e.trim().is_empty()) {
+        match Pin::parse(entry) {
+            Some(pin) => {
+                let _ = pins.push(pin);
+            }
+            None => info!("Ignoring pinned BSSID {}", entry),
+        }
+    }
+    pins
+}
+
+/// whether scans are checked against a pinned BSSID list
+pub fn pinning_enabled() -> bool {
+    !PINNED_BSSIDS.trim().is_empty()
+}
+
+/// true unless pinning is on and `bssid` matches none of the pins
+pub fn is_legitimate(bssid: &[u8; 6]) -> bool {
+    !pinning_enabled() || with_pins(|pins| pins.iter().any(|p| p.matches(bssid)))
+}
+
+static ROGUE_COUNT: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// log and publish an AP impersonating one of our WGs
+pub fn report_rogue_ap(ssid: &str, bssid: [u8; 6], channel: u8, signal_strength: i8) {
+    info!(
+        "SECURITY: rogue AP {} on {:?} (channel {}, {}), not pinned",
+        ssid, bssid, channel, signal_strength
+    );
+    ROGUE_COUNT.lock(|c| c.set(c.get().saturating_add(1)));
+    telemetry::publish(TelemetryEvent::Security(SecurityEvent::RogueAp {
+        ssid: ssid.try_into().unwrap_or_default(),
+        bssid,
+        channel,
+        signal_strength,
+    }));
+}
+
+/// scan hits excluded as rogue APs since boot
+pub fn rogue_count() -> u32 {
+    ROGUE_COUNT.lock(|c| c.get())
+}
@@ -0,0 +1,119 @@
+use core::ptr::addr_of_mut;
+
+use embassy_net::Stack;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Duration;
+use esp_hal::{
+    peripherals::LPWR,
+    rom::crc::crc32_le,
+    rtc_cntl::{Rtc, SocResetReason, reset_reason, sleep::TimerWakeupSource},
+    system::Cpu,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    WifiConfig, WifiManager,
+    codec::{Codec, DefaultCodec},
+    mac::station_mac,
+    netconfig::StaticIp,
+    status::link_status,
+};
+
+// "WGS2", a cache this firmware wrote. Bumped with WakeCache's layout, a wake
+// after an update starts cold rather than decoding the old one
+const CACHE_MAGIC: u32 = 0x5747_5332;
+const CACHE_LEN: usize = 256;
+// magic, payload length, crc
+const HEADER_LEN: usize = 10;
+
+/// what a wake needs to reconnect without the flash read or a scan
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WakeCache {
+    // the WG we were on, its BSSID and channel are what matter
+    pub wifi: WifiConfig,
+    // the address we held on it
+    pub lease: Option<StaticIp>,
+    // our STA MAC, a randomised one is kept across the wake
+    pub mac: [u8; 6],
+}
+
+// survives deep sleep but not a power cycle, so it's only trusted after a
+// deep sleep wake
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RTC_CACHE: [u8; CACHE_LEN] = [0; CACHE_LEN];
+
+// write `cache` to RTC fast memory, framed like the flash records
+fn stash(cache: &WakeCache) {
+    let mut payload = [0u8; CACHE_LEN - HEADER_LEN];
+    let Ok(encoded) = DefaultCodec::encode(cache, &mut payload) else {
+        info!("Wake cache doesn't fit, the wake starts cold");
+        return;
+    };
+    let len = encoded.len();
+    let crc = crc32_le(0, &payload[..len]);
+    critical_section::with(|_| {
+        // SAFETY: RTC_CACHE is only touched inside critical sections
+        let rtc = unsafe { &mut *addr_of_mut!(RTC_CACHE) };
+        rtc[0..4].copy_from_slice(&CACHE_MAGIC.to_le_bytes());
+        rtc[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+        rtc[6..10].copy_from_slice(&crc.to_le_bytes());
+        rtc[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&payload[..len]);
+    });
+}
+
+/// the cache stashed before the deep sleep we woke from, if any. Taken once,
+/// any later reset starts cold
+pub fn take_wake_cache() -> Option<WakeCache> {
+    let woke = reset_reason(Cpu::ProCpu) == Some(SocResetReason::CoreDeepSleep);
+    let rtc = critical_section::with(|_| {
+        // SAFETY: RTC_CACHE is only touched inside critical sections
+        let rtc = unsafe { &mut *addr_of_mut!(RTC_CACHE) };
+        let copy = *rtc;
+        rtc[0..4].fill(0);
+        copy
+    });
+    if !woke {
+        return None;
+    }
+    let magic = u32::from_le_bytes(rtc[0..4].try_into().unwrap());
+    let len = u16::from_le_bytes(rtc[4..6].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(rtc[6..10].try_into().unwrap());
+    let payload = rtc.get(HEADER_LEN..HEADER_LEN + len)?;
+    if magic != CACHE_MAGIC || crc32_le(0, payload) != crc {
+        info!("Woke from deep sleep without a usable cache");
+        return None;
+    }
+    let mut cache: WakeCache = DefaultCodec::decode(payload).ok()?;
+    info!("Woke from deep sleep, cached {:?}", cache);
+    cache.wifi = cache.wifi.restored();
+    Some(cache)
+}
+
+/// put the chip into deep sleep for this long, see sleep_task
+pub static DEEP_SLEEP: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+/// waits for DEEP_SLEEP, then stops WiFi, stashes the WG and lease we were on
+/// in RTC fast memory and sleeps. The wake boots from scratch and reconnects
+/// straight to the cached BSSID/channel
+#[embassy_executor::task]
+pub async fn sleep_task(stack: Stack<'static>, lpwr: LPWR<'static>) -> ! {
+    info!("Start sleep task");
+    let mut rtc = Rtc::new(lpwr);
+    let duration = DEEP_SLEEP.wait().await;
+    // before the stop drops the link
+    let current = link_status().current;
+    let lease = stack.config_v4().as_ref().and_then(StaticIp::from_config);
+    WifiManager::stop().await;
+    match current {
+        Some(wifi) => stash(&WakeCache {
+            wifi,
+            lease,
+            mac: station_mac(),
+        }),
+        None => info!("Not connected, the wake starts cold"),
+    }
+    info!("Deep sleep for {}s", duration.as_secs());
+    let timer = TimerWakeupSource::new(core::time::Duration::from_micros(duration.as_micros()));
+    rtc.sleep_deep(&[&timer])
+}
@@ -0,0 +1,117 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+
+use crate::status::wait_until_online;
+
+const NTP_HOST: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+// seconds between 1900-01-01 (ntp era 0) and 1970-01-01 (unix epoch)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const NTP_PACKET_LEN: usize = 48;
+
+// resync once an hour, drift on the esp32 timer is small enough for that
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// unix seconds at boot, i.e. epoch = EPOCH_OFFSET + Instant::now()
+static EPOCH_OFFSET: Mutex<Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// the unix time in seconds, if SNTP has synced at least once
+pub fn epoch_secs() -> Option<u64> {
+    critical_section::with(|cs| EPOCH_OFFSET.borrow(cs).get())
+        .map(|offset| offset + Instant::now().as_secs())
+}
+
+/// the unix time in seconds, falling back to seconds since boot before SNTP syncs
+pub fn now_secs() -> u64 {
+    epoch_secs().unwrap_or_else(|| Instant::now().as_secs())
+}
+
+/// the unix time at boot, if SNTP has synced at least once
+pub fn epoch_offset() -> Option<u64> {
+    critical_section::with(|cs| EPOCH_OFFSET.borrow(cs).get())
+}
+
+/// keeps EPOCH_OFFSET in sync with pool.ntp.org once we have a working connection
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) -> ! {
+    info!("Start sntp task");
+    loop {
+        // only try once the main loop confirms we can reach the internet
+        wait_until_online().await;
+
+        match sync_once(stack).await {
+            Some(unix_secs) => {
+                let offset = unix_secs.saturating_sub(Instant::now().as_secs());
+                critical_section::with(|cs| EPOCH_OFFSET.borrow(cs).set(Some(offset)));
+                info!("SNTP synced, unix time = {}", unix_secs);
+                Timer::after(RESYNC_INTERVAL).await;
+            }
+            None => Timer::after(RETRY_INTERVAL).await,
+        }
+    }
+}
+
+async fn sync_once(stack: Stack<'static>) -> Option<u64> {
+    let addr = match stack.dns_query(NTP_HOST, DnsQueryType::A).await {
+        Ok(addrs) => *addrs.first()?,
+        Err(e) => {
+            info!("SNTP dns error: {:?}", e);
+            return None;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; NTP_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; NTP_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        info!("SNTP bind error: {:?}", e);
+        return None;
+    }
+
+    // LI = 0, VN = 4, mode = 3 (client), everything else zero
+    let mut packet = [0u8; NTP_PACKET_LEN];
+    packet[0] = 0x23;
+    if let Err(e) = socket
+        .send_to(&packet, IpEndpoint::new(addr, NTP_PORT))
+        .await
+    {
+        info!("SNTP send error: {:?}", e);
+        return None;
+    }
+
+    let len = match with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut packet)).await {
+        Ok(Ok((len, _))) => len,
+        Ok(Err(e)) => {
+            info!("SNTP recv error: {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            info!("SNTP timed out");
+            return None;
+        }
+    };
+    if len < NTP_PACKET_LEN {
+        return None;
+    }
+
+    // transmit timestamp, seconds part
+    let secs = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    secs.checked_sub(NTP_UNIX_OFFSET)
+}
@@ -0,0 +1,136 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use serde::{Deserialize, Serialize};
+
+use crate::{WifiConfig, persistence::STORE_STATS};
+
+/// APs with their own tallies, the least used one makes room for a new one
+pub const MAX_TRACKED_APS: usize = 8;
+
+/// connect attempts on one AP, over the lifetime of the device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ApTally {
+    pub bssid: [u8; 6],
+    pub successes: u32,
+    pub failures: u32,
+}
+
+/// connection statistics that survive a reboot
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    pub boots: u32,
+    // established associations that dropped
+    pub disconnects: u32,
+    pub aps: heapless::Vec<ApTally, MAX_TRACKED_APS>,
+    // crashes, watchdogs and other resets we didn't ask for since the last
+    // stable boot, see bootguard
+    pub unexpected_resets: u32,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        return Self {
+            boots: 0,
+            disconnects: 0,
+            aps: heapless::Vec::new(),
+            unexpected_resets: 0,
+        };
+    }
+
+    pub fn tally(&self, bssid: &[u8; 6]) -> Option<&ApTally> {
+        self.aps.iter().find(|t| t.bssid == *bssid)
+    }
+
+    fn tally_mut(&mut self, bssid: [u8; 6]) -> &mut ApTally {
+        let index = match self.aps.iter().position(|t| t.bssid == bssid) {
+            Some(index) => index,
+            None => {
+                if self.aps.is_full() {
+                    // forget the AP we know least about
+                    let least = (0..self.aps.len())
+                        .min_by_key(|&i| self.aps[i].successes + self.aps[i].failures)
+                        .unwrap();
+                    self.aps.swap_remove(least);
+                }
+                let _ = self.aps.push(ApTally {
+                    bssid,
+                    successes: 0,
+                    failures: 0,
+                });
+                self.aps.len() - 1
+            }
+        };
+        &mut self.aps[index]
+    }
+
+    /// a fresh scan result has no history, give it the one from flash
+    pub fn seed(&self, wifi: &mut WifiConfig) {
+        if wifi.connect_success.is_some() {
+            return;
+        }
+        if let Some(tally) = self.tally(&wifi.bssid) {
+            wifi.connect_success = Some(tally.successes >= tally.failures);
+        }
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<Stats>> =
+    Mutex::new(RefCell::new(Stats::new()));
+
+/// a copy of the current statistics
+pub fn stats() -> Stats {
+    STATS.lock(|s| s.borrow().clone())
+}
+
+// change the statistics and hand them to persistence
+fn update_stats(f: impl FnOnce(&mut Stats)) {
+    let stats = STATS.lock(|s| {
+        let mut stats = s.borrow_mut();
+        f(&mut stats);
+        stats.clone()
+    });
+    STORE_STATS.signal(stats);
+}
+
+/// start from what persistence loaded and count this boot, returns the
+/// unexpected resets in a row including this one
+pub fn record_boot(loaded: Option<Stats>, unexpected: bool) -> u32 {
+    STATS.lock(|s| *s.borrow_mut() = loaded.unwrap_or_default());
+    update_stats(|s| {
+        s.boots += 1;
+        if unexpected {
+            s.unexpected_resets += 1;
+        }
+    });
+    STATS.lock(|s| s.borrow().unexpected_resets)
+}
+
+/// this boot ran long enough, the next one starts with a clean slate
+pub fn record_stable() {
+    if STATS.lock(|s| s.borrow().unexpected_resets) != 0 {
+        update_stats(|s| s.unexpected_resets = 0);
+    }
+}
+
+pub fn record_disconnect() {
+    update_stats(|s| s.disconnects += 1);
+}
+
+pub fn record_connect(bssid: [u8; 6], success: bool) {
+    update_stats(|s| {
+        let tally = s.tally_mut(bssid);
+        if success {
+            tally.successes += 1;
+        } else {
+            tally.failures += 1;
+        }
+    });
+}
+
+/// seed a scan result from the stored tallies
+pub fn seed_from_stats(wifi: &mut WifiConfig) {
+    STATS.lock(|s| s.borrow().seed(wifi))
+}
@@ -0,0 +1,258 @@
+use core::cell::RefCell;
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    watch::Watch,
+};
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::{NetworkPolicy, WifiConfig};
+
+/// what the station is doing right now, for anything reporting upstream
+#[derive(Serialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStatus {
+    // the WG we're associated with, if any
+    pub current: Option<WifiConfig>,
+    // successful associations since boot, the first one included
+    pub connects: u32,
+    // failed association attempts since boot
+    pub connect_failures: u32,
+    // times an established association dropped
+    pub disconnects: u32,
+    // when the current association came up, until the address it got is timed
+    #[serde(skip)]
+    pub associated_at: Option<Instant>,
+}
+
+impl LinkStatus {
+    pub const fn new() -> Self {
+        return Self {
+            current: None,
+            connects: 0,
+            connect_failures: 0,
+            disconnects: 0,
+            associated_at: None,
+        };
+    }
+    /// associations after the first one
+    pub fn reconnects(&self) -> u32 {
+        self.connects.saturating_sub(1)
+    }
+}
+
+static LINK_STATUS: Mutex<CriticalSectionRawMutex, RefCell<LinkStatus>> =
+    Mutex::new(RefCell::new(LinkStatus::new()));
+
+/// a copy of the current link status
+pub fn link_status() -> LinkStatus {
+    LINK_STATUS.lock(|s| s.borrow().clone())
+}
+
+/// the BSSID and mobility domain of the WG we're associated with, for the
+/// ranking, without copying the whole status
+pub fn current_mobility_domain() -> Option<([u8; 6], Option<u16>)> {
+    LINK_STATUS.lock(|s| {
+        let s = s.try_borrow().ok()?;
+        let current = s.current.as_ref()?;
+        Some((current.bssid, current.mobility_domain))
+    })
+}
+
+/// the connection manager reports what it did here
+pub fn update_link_status<R>(f: impl FnOnce(&mut LinkStatus) -> R) -> R {
+    LINK_STATUS.lock(|s| f(&mut s.borrow_mut()))
+}
+
+// max tasks holding a PROBE_OK receiver at the same time
+const PROBE_WATCHERS: usize = 4;
+
+/// when the internet probe last succeeded, watch it to gate uploads on a fresh probe
+pub static PROBE_OK: Watch<CriticalSectionRawMutex, Instant, PROBE_WATCHERS> = Watch::new();
+
+/// the health check calls this whenever the probe gets through
+pub fn record_probe_success() {
+    PROBE_OK.sender().send(Instant::now());
+}
+
+/// seconds since the internet probe last succeeded, None if it never has
+pub fn secs_since_last_probe() -> Option<u64> {
+    PROBE_OK.try_get().map(|at| at.elapsed().as_secs())
+}
+
+/// resolves once a probe has succeeded within `max_age`
+pub async fn wait_for_fresh_probe(max_age: Duration) {
+    if let Some(at) = PROBE_OK.try_get() {
+        if at.elapsed() <= max_age {
+            return;
+        }
+    }
+    match PROBE_OK.receiver() {
+        Some(mut receiver) => {
+            // a new receiver first sees the stale value, the next one is fresh
+            while receiver.changed().await.elapsed() > max_age {}
+        }
+        None => {
+            // all receivers taken, poll instead
+            while secs_since_last_probe().is_none_or(|secs| secs > max_age.as_secs()) {
+                embassy_time::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// how far along the connection is, each state implies the ones before it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionState {
+    // the radio is off on purpose, see WifiManager::stop
+    Stopped,
+    Disconnected,
+    // an association is in flight, timing sensitive, keep flash work away
+    Associating,
+    // associated, no address yet
+    Associated,
+    GotIp,
+    // the internet probe got through
+    InternetOk,
+}
+
+/// the connection state and the policy of the network it's on
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Connection {
+    pub state: ConnectionState,
+    // no restrictions until associated
+    pub policy: NetworkPolicy,
+}
+
+/// max tasks holding a CONNECTION_STATE receiver at the same time,
+/// `anon_receiver()` doesn't count against it. The status LED keeps one for good
+pub const CONNECTION_WATCHERS: usize = 5;
+
+/// the connection state, for application tasks to follow or wait on. The wifi
+/// manager drives it up to Associated, the health check beyond. The policy
+/// comes with it, so OTA and telemetry see a fallback to a metered network
+/// in the same update
+pub static CONNECTION_STATE: Watch<CriticalSectionRawMutex, Connection, CONNECTION_WATCHERS> =
+    Watch::new();
+
+/// the current state, None before the manager set one
+pub fn connection_state() -> Option<ConnectionState> {
+    CONNECTION_STATE.try_get().map(|c| c.state)
+}
+
+/// the policy of the network we're associated with
+pub fn network_policy() -> NetworkPolicy {
+    CONNECTION_STATE
+        .try_get()
+        .map_or(NetworkPolicy::new(), |c| c.policy)
+}
+
+/// set by the wifi manager. Below Associated the policy is dropped, see
+/// `set_associated` for getting on a network
+pub fn set_connection_state(state: ConnectionState) {
+    CONNECTION_STATE.sender().send_if_modified(|current| {
+        let policy = match current {
+            Some(c) if state >= ConnectionState::Associated => c.policy,
+            _ => NetworkPolicy::new(),
+        };
+        let next = Some(Connection { state, policy });
+        let changed = *current != next;
+        *current = next;
+        changed
+    });
+}
+
+/// set by the wifi manager once associated with a network under `policy`
+pub fn set_associated(policy: NetworkPolicy) {
+    if policy != NetworkPolicy::new() {
+        info!("Network policy {:?}", policy);
+    }
+    CONNECTION_STATE.sender().send(Connection {
+        state: ConnectionState::Associated,
+        policy,
+    });
+}
+
+/// set by the health check, ignored unless the manager has us associated, so
+/// a late probe result can't resurrect a dropped link
+pub fn set_ip_state(state: ConnectionState) {
+    CONNECTION_STATE.sender().send_if_modified(|current| {
+        let Some(c) = current else {
+            return false;
+        };
+        let changed = c.state >= ConnectionState::Associated && c.state != state;
+        if changed {
+            c.state = state;
+        }
+        changed
+    });
+}
+
+/// resolves once the internet probe has got through
+pub async fn wait_until_online() {
+    wait_until_online_with(|_| true).await
+}
+
+/// resolves once the internet probe has got through on a network whose
+/// policy passes `allows`, e.g. `NetworkPolicy::allows_ota`
+pub async fn wait_until_online_with(allows: impl Fn(&NetworkPolicy) -> bool) {
+    let ready = |c: &Connection| c.state == ConnectionState::InternetOk && allows(&c.policy);
+    if CONNECTION_STATE.try_get().is_some_and(|c| ready(&c)) {
+        return;
+    }
+    match CONNECTION_STATE.receiver() {
+        Some(mut receiver) => {
+            receiver.get_and(ready).await;
+        }
+        // every slot taken, poll instead
+        None => {
+            while !CONNECTION_STATE.try_get().is_some_and(|c| ready(&c)) {
+                embassy_time::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+pub fn is_stopped() -> bool {
+    connection_state() == Some(ConnectionState::Stopped)
+}
+
+/// resolves once the radio is back on after WifiManager::stop, at once if it
+/// wasn't stopped
+pub async fn wait_until_started() {
+    if !is_stopped() {
+        return;
+    }
+    match CONNECTION_STATE.receiver() {
+        Some(mut receiver) => {
+            receiver
+                .get_and(|c| c.state != ConnectionState::Stopped)
+                .await;
+        }
+        // every slot taken, poll instead
+        None => {
+            while is_stopped() {
+                embassy_time::Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// resolves once no association is in flight, or after `max_wait` so a stuck
+/// association can't hold the caller forever
+pub async fn wait_until_not_associating(max_wait: Duration) {
+    if connection_state() != Some(ConnectionState::Associating) {
+        return;
+    }
+    let Some(mut receiver) = CONNECTION_STATE.receiver() else {
+        return;
+    };
+    let _ = embassy_time::with_timeout(
+        max_wait,
+        receiver.get_and(|c| c.state != ConnectionState::Associating),
+    )
+    .await;
+}
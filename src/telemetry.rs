@@ -0,0 +1,65 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+
+use crate::{
+    disconnect::DisconnectReport, failover::FailoverEvent, rssi::SignalDegraded,
+    security::SecurityEvent,
+};
+
+const TELEMETRY_QUEUE_LEN: usize = 8;
+
+/// everything the firmware reports upstream
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TelemetryEvent {
+    Disconnected(DisconnectReport),
+    Failover(FailoverEvent),
+    // an optional subsystem couldn't allocate and degraded instead of panicking
+    OutOfMemory(AllocSite),
+    Security(SecurityEvent),
+    // the current AP's signal stayed below the monitor's threshold
+    SignalDegraded(SignalDegraded),
+}
+
+/// where an allocation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AllocSite {
+    // scan results were cut short below the candidate cap
+    Scan,
+    // WGs missing from a scan were forgotten early
+    Candidates,
+    // the MQTT report was skipped
+    MqttReport,
+    // no TLS session this time
+    Tls,
+}
+
+// queue of events waiting for a reporter to pick them up
+pub static TELEMETRY: Channel<CriticalSectionRawMutex, TelemetryEvent, TELEMETRY_QUEUE_LEN> =
+    Channel::new();
+
+/// queue an event, dropping it if no reporter is keeping up
+pub fn publish(event: TelemetryEvent) {
+    if TELEMETRY.try_send(event).is_err() {
+        info!("Telemetry queue full, dropping event");
+    }
+}
+
+static OOM_COUNT: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// an allocation failed at `site` and the caller is carrying on without it
+pub fn report_oom(site: AllocSite) {
+    info!("Out of memory in {:?}, degrading", site);
+    OOM_COUNT.lock(|c| c.set(c.get().saturating_add(1)));
+    publish(TelemetryEvent::OutOfMemory(site));
+}
+
+/// allocation failures since boot
+pub fn oom_count() -> u32 {
+    OOM_COUNT.lock(|c| c.get())
+}
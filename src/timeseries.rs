@@ -0,0 +1,363 @@
+//! RSSI and roaming time series: samples the link every `TimeSeries::interval`
+//! into a RAM ring and uploads them in batches once we're online, by HTTP POST
+//! to TELEMETRY_HOST/TELEMETRY_PATH or, with the mqtt feature, on
+//! MQTT_TOPIC/samples. The ring holds SERIES_LEN samples, while offline the
+//! oldest make room for new ones
+
+use core::{
+    cell::RefCell,
+    fmt::Write as _,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_net::{Stack, dns::DnsQueryType, tcp::TcpSocket};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+use serde::Serialize;
+
+use crate::{
+    NetworkPolicy,
+    health::parse_status,
+    netconfig::device_name,
+    rssi::latest_rssi,
+    sntp::epoch_offset,
+    status::{link_status, network_policy, wait_until_online_with},
+};
+#[cfg(feature = "tls")]
+use {
+    crate::tls::{TlsBuffers, TlsSocket},
+    embedded_io_async::Read,
+};
+
+// the collector, plain HTTP on port 80 or HTTPS with the tls feature
+const TELEMETRY_HOST: &str = env!("TELEMETRY_HOST");
+const TELEMETRY_PATH: &str = env!("TELEMETRY_PATH");
+#[cfg(not(feature = "tls"))]
+const TELEMETRY_PORT: u16 = 80;
+#[cfg(feature = "tls")]
+const TELEMETRY_PORT: u16 = 443;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+// a failed upload is retried after this long
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// the status line has to fit, the rest of the answer is ignored
+const RESPONSE_HEAD_LEN: usize = 256;
+
+/// samples kept in RAM, 24 bytes each
+pub const SERIES_LEN: usize = 128;
+/// most samples in one upload
+pub const MAX_BATCH: usize = 16;
+/// the largest encoded batch, MAX_BATCH samples fit
+pub const BATCH_LEN: usize = 2048;
+
+/// where the batches go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SeriesUpload {
+    // POST to TELEMETRY_HOST/TELEMETRY_PATH
+    Http,
+    // published by the MQTT session next to its reports
+    #[cfg(feature = "mqtt")]
+    Mqtt,
+}
+
+/// how often the link is sampled and how the samples are shipped
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimeSeries {
+    // time between samples
+    pub interval: Duration,
+    // an upload starts once this many samples are waiting
+    pub batch: usize,
+    pub upload: SeriesUpload,
+}
+
+impl TimeSeries {
+    pub const fn new() -> Self {
+        return Self {
+            interval: Duration::from_secs(30),
+            batch: MAX_BATCH,
+            upload: SeriesUpload::Http,
+        };
+    }
+    pub const fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+    pub const fn with_batch(mut self, batch: usize) -> Self {
+        assert!(
+            batch >= 1 && batch <= MAX_BATCH,
+            "a batch is 1 to MAX_BATCH samples"
+        );
+        self.batch = batch;
+        self
+    }
+    pub const fn with_upload(mut self, upload: SeriesUpload) -> Self {
+        self.upload = upload;
+        self
+    }
+}
+
+impl Default for TimeSeries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UploadError {
+    Dns,
+    Connect,
+    // no verified TLS session, or no memory for one
+    Tls,
+    // the connection dropped or timed out before the collector answered
+    Io,
+    // an answer other than 2xx
+    Status(u16),
+}
+
+/// the link at one point in time
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    // counts up from boot, a gap means samples were dropped
+    pub seq: u32,
+    // seconds since boot, the batch carries the boot's unix time
+    pub uptime: u32,
+    // None while disconnected
+    pub bssid: Option<[u8; 6]>,
+    // the latest beacon reading, None before the first one
+    pub rssi: Option<i8>,
+    pub channel: u8,
+    // associations after the first one since boot, a roam shows as a step
+    pub reconnects: u32,
+}
+
+/// one upload, as JSON
+#[derive(Serialize, Debug)]
+pub struct Batch<'a> {
+    pub device: &'a str,
+    // unix time at boot, None before SNTP synced
+    pub boot_epoch: Option<u64>,
+    // samples lost to a full ring since boot
+    pub dropped: u32,
+    pub samples: &'a [Sample],
+}
+
+// the samples not yet acknowledged, oldest first
+struct Series {
+    samples: heapless::Deque<Sample, SERIES_LEN>,
+    next_seq: u32,
+    dropped: u32,
+}
+
+impl Series {
+    const fn new() -> Self {
+        Self {
+            samples: heapless::Deque::new(),
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, mut sample: Sample) {
+        sample.seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if self.samples.is_full() {
+            self.samples.pop_front();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        // room was made above
+        let _ = self.samples.push_back(sample);
+    }
+
+    // removes everything up to and including `seq`, the ring may have
+    // dropped some of it already
+    fn ack(&mut self, seq: u32) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|s| s.seq.wrapping_sub(seq) as i32 <= 0)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+static SERIES: Mutex<CriticalSectionRawMutex, RefCell<Series>> =
+    Mutex::new(RefCell::new(Series::new()));
+
+// set by sample_task when the MQTT session is to ship the batches
+static OVER_MQTT: AtomicBool = AtomicBool::new(false);
+
+/// samples waiting for an upload
+pub fn pending_samples() -> usize {
+    SERIES.lock(|s| s.borrow().samples.len())
+}
+
+/// samples dropped since boot because the ring was full
+pub fn dropped_samples() -> u32 {
+    SERIES.lock(|s| s.borrow().dropped)
+}
+
+/// true if the MQTT session uploads the batches
+pub fn uploads_over_mqtt() -> bool {
+    OVER_MQTT.load(Ordering::Relaxed)
+}
+
+/// encodes the oldest waiting samples into `out`, at most `max` and as many as
+/// fit. Returns the length and the seq to `ack` once the batch is delivered,
+/// None if nothing is waiting or not even one sample fits
+pub fn encode_batch(out: &mut [u8], max: usize) -> Option<(usize, u32)> {
+    let mut samples: heapless::Vec<Sample, MAX_BATCH> = heapless::Vec::new();
+    SERIES.lock(|s| {
+        let s = s.borrow();
+        samples.extend(s.samples.iter().take(max.min(MAX_BATCH)).copied());
+    });
+    let device = device_name();
+    let dropped = dropped_samples();
+    while !samples.is_empty() {
+        let batch = Batch {
+            device: &device,
+            boot_epoch: epoch_offset(),
+            dropped,
+            samples: &samples,
+        };
+        if let Ok(len) = serde_json_core::to_slice(&batch, out) {
+            return Some((len, samples.last()?.seq));
+        }
+        samples.truncate(samples.len() / 2);
+    }
+    None
+}
+
+/// a batch up to `seq` was delivered, forget it
+pub fn ack(seq: u32) {
+    SERIES.lock(|s| s.borrow_mut().ack(seq));
+}
+
+fn sample() -> Sample {
+    let status = link_status();
+    let current = status.current.as_ref();
+    let bssid = current.map(|c| c.bssid);
+    Sample {
+        seq: 0,
+        uptime: Instant::now().as_secs() as u32,
+        bssid,
+        rssi: latest_rssi()
+            .filter(|(b, _)| Some(*b) == bssid)
+            .map(|(_, rssi)| rssi),
+        channel: current.map_or(0, |c| c.channel),
+        reconnects: status.reconnects(),
+    }
+}
+
+/// adds a sample of the link to the ring every `series.interval`, connected
+/// or not
+#[embassy_executor::task]
+pub async fn sample_task(series: TimeSeries) -> ! {
+    info!("Start sample task");
+    #[cfg(feature = "mqtt")]
+    OVER_MQTT.store(series.upload == SeriesUpload::Mqtt, Ordering::Relaxed);
+    loop {
+        Timer::after(series.interval).await;
+        let sample = sample();
+        SERIES.lock(|s| s.borrow_mut().push(sample));
+    }
+}
+
+/// POSTs the samples to TELEMETRY_HOST/TELEMETRY_PATH in batches of
+/// `series.batch` while the internet probe gets through and the network's
+/// policy allows bulk telemetry. A batch only leaves the ring once the
+/// collector answered 2xx
+#[embassy_executor::task]
+pub async fn upload_task(stack: Stack<'static>, series: TimeSeries) -> ! {
+    info!("Start telemetry upload task");
+    let mut rx_buffer = [0; RESPONSE_HEAD_LEN];
+    let mut tx_buffer = [0; BATCH_LEN];
+    let mut body = [0u8; BATCH_LEN];
+    loop {
+        while pending_samples() < series.batch {
+            Timer::after(series.interval).await;
+        }
+        // held while on a metered or no-telemetry network, the ring keeps
+        // filling and drops its oldest
+        wait_until_online_with(NetworkPolicy::allows_bulk_telemetry).await;
+        while pending_samples() >= series.batch && network_policy().allows_bulk_telemetry() {
+            let Some((len, last)) = encode_batch(&mut body, series.batch) else {
+                break;
+            };
+            match post(stack, &body[..len], &mut rx_buffer, &mut tx_buffer).await {
+                Ok(()) => ack(last),
+                Err(e) => {
+                    info!("Telemetry upload failed: {:?}", e);
+                    Timer::after(RETRY_INTERVAL).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// one POST of `body`, Ok once the collector answered 2xx
+async fn post(
+    stack: Stack<'_>,
+    body: &[u8],
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<(), UploadError> {
+    let addr = match stack.dns_query(TELEMETRY_HOST, DnsQueryType::A).await {
+        Ok(addrs) => *addrs.first().ok_or(UploadError::Dns)?,
+        Err(e) => {
+            info!("Telemetry dns error: {:?}", e);
+            return Err(UploadError::Dns);
+        }
+    };
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(UPLOAD_TIMEOUT));
+    if let Err(e) = socket.connect((addr, TELEMETRY_PORT)).await {
+        info!("Telemetry connect error: {:?}", e);
+        return Err(UploadError::Connect);
+    }
+    #[cfg(feature = "tls")]
+    let mut buffers = TlsBuffers::alloc().ok_or(UploadError::Tls)?;
+    #[cfg(feature = "tls")]
+    let mut socket = TlsSocket::open(socket, TELEMETRY_HOST, &mut buffers)
+        .await
+        .map_err(|_| UploadError::Tls)?;
+
+    let mut head: heapless::String<256> = heapless::String::new();
+    write!(
+        head,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        TELEMETRY_PATH,
+        TELEMETRY_HOST,
+        body.len()
+    )
+    .map_err(|_| UploadError::Io)?;
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|_| UploadError::Io)?;
+    socket.write_all(body).await.map_err(|_| UploadError::Io)?;
+    socket.flush().await.map_err(|_| UploadError::Io)?;
+
+    let mut response = [0u8; 12];
+    let mut filled = 0;
+    while filled < response.len() {
+        match socket.read(&mut response[filled..]).await {
+            Ok(0) | Err(_) => return Err(UploadError::Io),
+            Ok(n) => filled += n,
+        }
+    }
+    #[cfg(feature = "tls")]
+    socket.close().await;
+    #[cfg(not(feature = "tls"))]
+    socket.close();
+    match parse_status(&response) {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(status) => Err(UploadError::Status(status)),
+        None => Err(UploadError::Io),
+    }
+}
@@ -0,0 +1,230 @@
+use core::cell::Cell;
+
+use alloc::{boxed::Box, vec::Vec};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CryptoProvider, TlsConfig, TlsConnection, TlsContext, TlsError,
+    TlsVerifier,
+    webpki::{CertVerifier, TlsClock},
+};
+use esp_hal::rng::Rng;
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sntp::epoch_secs,
+    telemetry::{AllocSite, report_oom},
+};
+
+/// label of the data partition holding the root certificates
+pub const TLS_PARTITION: &str = "tls";
+/// where the certificates start in the partition, the record sits before them
+pub const TLS_BLOBS_ADDR: u32 = 4096;
+/// DER roots above this are refused, it's also what the verifier can hold
+pub const MAX_ROOT_LEN: u32 = 4096;
+const MAX_ROOTS: usize = 4;
+
+// a server may send full 16 KB records, what we send is small
+const READ_RECORD_LEN: usize = 16640;
+const WRITE_RECORD_LEN: usize = 2048;
+
+/// one root in the "tls" partition
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RootEntry {
+    // the server it vouches for, empty for every host without its own root
+    pub host: heapless::String<64>,
+    pub len: u32,
+}
+
+/// the record at the start of the "tls" partition. The DER blobs follow back
+/// to back from TLS_BLOBS_ADDR in this order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TlsRootsRecord {
+    pub roots: heapless::Vec<RootEntry, MAX_ROOTS>,
+}
+
+/// a root certificate, loaded from flash at boot
+#[derive(Debug, Clone)]
+pub struct TlsRoot {
+    pub host: heapless::String<64>,
+    pub der: &'static [u8],
+}
+
+static ROOTS: Mutex<CriticalSectionRawMutex, Cell<&'static [TlsRoot]>> = Mutex::new(Cell::new(&[]));
+
+/// trust `roots` from now on, called once with what persistence loaded
+pub fn set_tls_roots(roots: Vec<TlsRoot>) {
+    for root in &roots {
+        info!("TLS root for {}", root.host.as_str());
+    }
+    let roots: &'static [TlsRoot] = Box::leak(roots.into_boxed_slice());
+    ROOTS.lock(|r| r.set(roots));
+}
+
+/// the root that `host` has to chain to
+pub fn root_for(host: &str) -> Option<&'static [u8]> {
+    let roots = ROOTS.lock(|r| r.get());
+    roots
+        .iter()
+        .find(|r| r.host == host)
+        .or_else(|| roots.iter().find(|r| r.host.is_empty()))
+        .map(|r| r.der)
+}
+
+/// the root pinned to `host` itself, the catch-all one doesn't count. For
+/// servers whose content we trust, such as the OTA host
+pub fn pinned_root_for(host: &str) -> Option<&'static [u8]> {
+    let roots = ROOTS.lock(|r| r.get());
+    roots.iter().find(|r| r.host == host).map(|r| r.der)
+}
+
+// certificate validity needs the wall clock, before SNTP syncs it's skipped
+struct SntpClock;
+
+impl TlsClock for SntpClock {
+    fn now() -> Option<u64> {
+        epoch_secs()
+    }
+}
+
+// with the radio running the RNG is fed by RF noise
+struct RadioRng(Rng);
+
+impl RngCore for RadioRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+    fn next_u64(&mut self) -> u64 {
+        (self.0.random() as u64) << 32 | self.0.random() as u64
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest);
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for RadioRng {}
+
+struct Provider {
+    rng: RadioRng,
+    verifier: CertVerifier<Aes128GcmSha256, SntpClock, { MAX_ROOT_LEN as usize }>,
+}
+
+impl CryptoProvider for Provider {
+    // no client certificates, so the default signer is fine
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+/// TLS over a connected TcpSocket, verified against the root for the host.
+/// The record buffers come from the heap for the lifetime of the session
+pub struct TlsSocket<'a> {
+    conn: TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>,
+}
+
+/// record buffers for one session
+pub struct TlsBuffers {
+    read: Box<[u8]>,
+    write: Box<[u8]>,
+}
+
+impl TlsBuffers {
+    /// None, and an OutOfMemory report, if the heap can't spare them
+    pub fn alloc() -> Option<Self> {
+        let mut read = Vec::new();
+        let mut write = Vec::new();
+        if read.try_reserve_exact(READ_RECORD_LEN).is_err()
+            || write.try_reserve_exact(WRITE_RECORD_LEN).is_err()
+        {
+            report_oom(AllocSite::Tls);
+            return None;
+        }
+        read.resize(READ_RECORD_LEN, 0);
+        write.resize(WRITE_RECORD_LEN, 0);
+        Some(Self {
+            read: read.into_boxed_slice(),
+            write: write.into_boxed_slice(),
+        })
+    }
+}
+
+impl<'a> TlsSocket<'a> {
+    /// handshakes with `host` over `socket`. Without a root for `host` the
+    /// server can't be verified and nothing is sent
+    pub async fn open(
+        socket: TcpSocket<'a>,
+        host: &str,
+        buffers: &'a mut TlsBuffers,
+    ) -> Result<Self, TlsError> {
+        let Some(root) = root_for(host) else {
+            info!("No TLS root for {}", host);
+            return Err(TlsError::InvalidCertificate);
+        };
+        Self::open_with_root(socket, host, root, buffers).await
+    }
+
+    /// handshakes with `host` over `socket`, which has to chain to `root`
+    pub async fn open_with_root(
+        socket: TcpSocket<'a>,
+        host: &str,
+        root: &'static [u8],
+        buffers: &'a mut TlsBuffers,
+    ) -> Result<Self, TlsError> {
+        let config = TlsConfig::new()
+            .with_server_name(host)
+            .with_ca(Certificate::X509(root));
+        let provider = Provider {
+            rng: RadioRng(Rng::new()),
+            verifier: CertVerifier::new(),
+        };
+        let mut conn = TlsConnection::new(socket, &mut buffers.read, &mut buffers.write);
+        if let Err(e) = conn.open(TlsContext::new(&config, provider)).await {
+            info!("TLS handshake with {} failed: {:?}", host, e);
+            return Err(e);
+        }
+        Ok(Self { conn })
+    }
+
+    /// sends close_notify and closes the socket
+    pub async fn close(self) {
+        match self.conn.close().await {
+            Ok(mut socket) => socket.close(),
+            Err((mut socket, _)) => socket.close(),
+        }
+    }
+}
+
+impl ErrorType for TlsSocket<'_> {
+    type Error = TlsError;
+}
+
+impl Read for TlsSocket<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.conn.read(buf).await
+    }
+}
+
+impl Write for TlsSocket<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.conn.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.conn.flush().await
+    }
+}
@@ -0,0 +1,70 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_radio::wifi::WifiController;
+use serde::{Deserialize, Serialize};
+
+// seed for the first boot: "full", "low-power-indoor" or a dBm value
+const TX_POWER: &str = env!("TX_POWER");
+
+// what the radio can do, esp-idf takes the limit in 0.25 dBm steps from 2 to 20 dBm
+const MIN_DBM: i8 = 2;
+const MAX_DBM: i8 = 20;
+
+/// how loud the radio transmits, chosen for where the unit is deployed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TxPowerProfile {
+    // the driver's maximum
+    #[default]
+    Full,
+    // enclosures centimetres from the gateway, full power only adds interference
+    LowPowerIndoor,
+    // a site survey picked this, in dBm
+    Dbm(i8),
+}
+
+impl TxPowerProfile {
+    /// the transmit power limit in dBm
+    pub fn dbm(&self) -> i8 {
+        match self {
+            TxPowerProfile::Full => MAX_DBM,
+            TxPowerProfile::LowPowerIndoor => 8,
+            TxPowerProfile::Dbm(dbm) => (*dbm).clamp(MIN_DBM, MAX_DBM),
+        }
+    }
+
+    /// "full", "low-power-indoor" or a dBm value, as used by TX_POWER and HTTP
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "full" => Some(TxPowerProfile::Full),
+            "low-power-indoor" => Some(TxPowerProfile::LowPowerIndoor),
+            dbm => dbm.parse().ok().map(TxPowerProfile::Dbm),
+        }
+    }
+
+    /// the profile baked in through .cargo/config.toml, Full if empty or unknown
+    pub fn from_env() -> Self {
+        Self::from_name(TX_POWER).unwrap_or_default()
+    }
+}
+
+static ACTIVE_TX_POWER: Mutex<CriticalSectionRawMutex, Cell<TxPowerProfile>> =
+    Mutex::new(Cell::new(TxPowerProfile::Full));
+
+/// the profile in use, changed by the wifi manager on WifiRequest::SetTxPower
+pub fn active_tx_power() -> TxPowerProfile {
+    ACTIVE_TX_POWER.lock(|p| p.get())
+}
+
+pub fn set_active_tx_power(profile: TxPowerProfile) {
+    ACTIVE_TX_POWER.lock(|p| p.set(profile))
+}
+
+/// limit the radio to `profile`, only takes effect once it's started
+pub fn apply_tx_power(controller: &mut WifiController<'static>, profile: TxPowerProfile) {
+    info!("TX power {:?} ({} dBm)", profile, profile.dbm());
+    if let Err(e) = controller.set_max_tx_power(profile.dbm() * 4) {
+        info!("Failed to set TX power {:?}", e);
+    }
+}
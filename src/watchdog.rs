@@ -0,0 +1,111 @@
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    peripherals::TIMG0,
+    timer::timg::{MwdtStage, Wdt},
+};
+
+use crate::{
+    disconnect::MAX_BACKOFF,
+    eventlog::{self, ResetCause},
+};
+
+// how often the checker runs and feeds the hardware watchdog
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// a task waiting on purpose checks in this often, see beat_while
+const BEAT_INTERVAL: Duration = Duration::from_secs(10);
+// when the executor itself hangs the checker can't run, the hardware watchdog
+// resets us instead. Erasing a whole OTA slot blocks it for tens of seconds
+const HARDWARE_TIMEOUT_SECS: u64 = 60;
+
+/// the tasks that check in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Watched {
+    WifiMgr,
+    BestConnection,
+    Persistence,
+}
+
+impl Watched {
+    const ALL: [Watched; 3] = [
+        Watched::WifiMgr,
+        Watched::BestConnection,
+        Watched::Persistence,
+    ];
+
+    /// longest a round of the task may take between check-ins
+    pub const fn deadline(self) -> Duration {
+        match self {
+            // the backoff is waited out under beat_while, a scan and the
+            // connect take less than the longest backoff
+            Watched::WifiMgr => Duration::from_secs(2 * MAX_BACKOFF.as_secs()),
+            // persist_wifi retries while persistence holds a store back for
+            // the manager's association, as long as one of its rounds
+            Watched::BestConnection => Duration::from_secs(2 * MAX_BACKOFF.as_secs()),
+            // the coalescing window plus an association in flight
+            Watched::Persistence => Duration::from_secs(90),
+        }
+    }
+}
+
+// uptime in seconds at the last check-in, plus one. 0 until the task first
+// checks in, tasks this boot didn't spawn aren't watched
+static LAST_BEAT: [AtomicU32; Watched::ALL.len()] =
+    [const { AtomicU32::new(0) }; Watched::ALL.len()];
+
+/// the task is making progress
+pub fn beat(task: Watched) {
+    LAST_BEAT[task as usize].store(Instant::now().as_secs() as u32 + 1, Ordering::Relaxed);
+}
+
+/// awaits `fut`, checking in for `task` meanwhile. For waits that may last as
+/// long as they like (the next request, a disconnect), not for locks or radio calls
+pub async fn beat_while<F: Future>(task: Watched, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    loop {
+        beat(task);
+        if let Either::First(out) = select(fut.as_mut(), Timer::after(BEAT_INTERVAL)).await {
+            return out;
+        }
+    }
+}
+
+// the first task past its deadline, with the seconds since it checked in
+fn stalled() -> Option<(Watched, u32)> {
+    let now = Instant::now().as_secs() as u32 + 1;
+    Watched::ALL.into_iter().find_map(|task| {
+        let last = LAST_BEAT[task as usize].load(Ordering::Relaxed);
+        let silent = now.saturating_sub(last);
+        (last != 0 && silent as u64 > task.deadline().as_secs()).then_some((task, silent))
+    })
+}
+
+/// resets through the event log when a watched task misses its deadline, and
+/// feeds the TIMG0 watchdog so a hung executor resets too
+#[embassy_executor::task]
+pub async fn watchdog_task(mut wdt: Wdt<TIMG0<'static>>) -> ! {
+    info!("Start watchdog task");
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(HARDWARE_TIMEOUT_SECS),
+    );
+    wdt.enable();
+    loop {
+        wdt.feed();
+        if let Some((task, silent)) = stalled() {
+            info!(
+                "Watchdog: {:?} hasn't checked in for {}s, resetting",
+                task, silent
+            );
+            eventlog::reset(ResetCause::Watchdog).await
+        }
+        Timer::after(CHECK_INTERVAL).await;
+    }
+}